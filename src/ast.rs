@@ -0,0 +1,438 @@
+#![deny(missing_docs)]
+//! Module holding the abstract syntax tree produced by the [`Parser`](crate::parser::Parser).
+
+use crate::span::Span;
+
+/// Identifies a node of the syntax tree. Every [`Expr`], [`Stmt`], [`Param`] and [`Func`] of a
+/// program gets a different `NodeId`, so later passes can store information about nodes in side
+/// tables.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct NodeId(pub u32);
+
+/// A name written in the source, together with where it was written.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Ident<'src> {
+    /// The text of the identifier.
+    pub name: &'src str,
+    /// Where the identifier was written.
+    pub span: Span<'src>,
+}
+
+/// A dotted path of identifiers (e.g. `hello-world.entrypoint`).
+#[derive(Debug, Clone, PartialEq)]
+pub struct Path<'src> {
+    /// Every segment of the path, in order.
+    pub segments: Vec<Ident<'src>>,
+    /// The span of the whole path.
+    pub span: Span<'src>,
+}
+
+impl<'src> Path<'src> {
+    /// Joins the segments of the path with dots.
+    pub fn to_dotted(&self) -> String {
+        let names: Vec<_> = self.segments.iter().map(|x| x.name).collect();
+        names.join(".")
+    }
+}
+
+/// A type as written in the source.
+#[derive(Debug, Clone, PartialEq)]
+pub enum TypeExpr<'src> {
+    /// The `void` type.
+    Void(Span<'src>),
+    /// A named type (e.g. `i32`, `string` or `printer`).
+    Named(Ident<'src>),
+    /// An array with a known element type (e.g. `string[]`).
+    Array(Box<TypeExpr<'src>>, Span<'src>),
+}
+
+impl<'src> TypeExpr<'src> {
+    /// Gets the span of the type.
+    pub fn span(&self) -> Span<'src> {
+        match self {
+            TypeExpr::Void(span) => *span,
+            TypeExpr::Named(ident) => ident.span,
+            TypeExpr::Array(_, span) => *span,
+        }
+    }
+}
+
+/// A top-level item of a source file.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Declaration<'src> {
+    /// The `identifier a.b` module header.
+    Identifier(Path<'src>),
+    /// An import of a module or of a single item (`use hello-world.printer`).
+    Use(Path<'src>),
+    /// A function with a body.
+    Func(Func<'src>),
+    /// An `extern func` declaration.
+    Extern(ExternFunc<'src>),
+    /// An `obj` type declaration.
+    Obj(Obj<'src>),
+    /// An `impl` block.
+    Impl(Impl<'src>),
+}
+
+/// A function parameter.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Param<'src> {
+    /// Id of the parameter.
+    pub id: NodeId,
+    /// Name of the parameter.
+    pub name: Ident<'src>,
+    /// Type of the parameter.
+    pub ty: TypeExpr<'src>,
+}
+
+/// The signature of a function: everything before `is`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FuncSig<'src> {
+    /// Name of the function.
+    pub name: Ident<'src>,
+    /// Return type. `None` if the return type was left out, which is the same as `void`.
+    pub ret: Option<TypeExpr<'src>>,
+    /// Parameters of the function.
+    pub params: Vec<Param<'src>>,
+    /// Span of the whole signature.
+    pub span: Span<'src>,
+}
+
+/// A function with a body (`func void start() is ... end`).
+#[derive(Debug, Clone, PartialEq)]
+pub struct Func<'src> {
+    /// Id of the function. Methods use it to identify their `self` parameter.
+    pub id: NodeId,
+    /// Whether the function was marked `pub`.
+    pub public: bool,
+    /// Signature of the function.
+    pub sig: FuncSig<'src>,
+    /// Statements of the function body.
+    pub body: Block<'src>,
+}
+
+/// A function implemented outside of Escoop (`extern func void print(str msg)`).
+#[derive(Debug, Clone, PartialEq)]
+pub struct ExternFunc<'src> {
+    /// Whether the function was marked `pub`.
+    pub public: bool,
+    /// Signature of the function.
+    pub sig: FuncSig<'src>,
+}
+
+/// A field of an `obj` type.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Field<'src> {
+    /// Whether the field was marked `pub`.
+    pub public: bool,
+    /// Name of the field.
+    pub name: Ident<'src>,
+    /// Type of the field.
+    pub ty: TypeExpr<'src>,
+    /// Default value of the field, if any.
+    pub default: Option<Expr<'src>>,
+}
+
+/// An object type declaration (`obj printer is ... end`).
+#[derive(Debug, Clone, PartialEq)]
+pub struct Obj<'src> {
+    /// Whether the type was marked `pub`.
+    pub public: bool,
+    /// Name of the type.
+    pub name: Ident<'src>,
+    /// Fields of the type, in declaration order.
+    pub fields: Vec<Field<'src>>,
+}
+
+/// An `impl` block adding methods to an object type.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Impl<'src> {
+    /// The type the methods belong to.
+    pub target: Ident<'src>,
+    /// Methods of the block.
+    pub methods: Vec<Func<'src>>,
+}
+
+/// A sequence of statements.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Block<'src> {
+    /// The statements, in order.
+    pub stmts: Vec<Stmt<'src>>,
+    /// Span of the whole block.
+    pub span: Span<'src>,
+}
+
+/// A statement.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Stmt<'src> {
+    /// Id of the statement. Local declarations are identified by the id of their statement.
+    pub id: NodeId,
+    /// The kind of statement.
+    pub kind: StmtKind<'src>,
+    /// Span of the whole statement.
+    pub span: Span<'src>,
+}
+
+/// Every kind of [`Stmt`].
+#[derive(Debug, Clone, PartialEq)]
+pub enum StmtKind<'src> {
+    /// A local variable declaration (`i32 val = 0`, or `let val = 0` to infer the type).
+    Local {
+        /// Declared type of the variable, or `None` for `let`.
+        ty: Option<TypeExpr<'src>>,
+        /// Name of the variable.
+        name: Ident<'src>,
+        /// Initial value of the variable.
+        init: Expr<'src>,
+    },
+    /// An assignment (`self.text = []`).
+    Assign {
+        /// The place being assigned to.
+        target: Expr<'src>,
+        /// The value being assigned.
+        value: Expr<'src>,
+    },
+    /// An increment (`val++`).
+    Increment(Expr<'src>),
+    /// An expression evaluated for its side effects.
+    Expr(Expr<'src>),
+    /// A return from the current function, with the returned value if any (`return val`).
+    Return(Option<Expr<'src>>),
+    /// A conditional (`if a < b is ... else ... end`). `else if` chains are nested in the
+    /// `else` block.
+    If {
+        /// The condition, of type `bool`.
+        cond: Expr<'src>,
+        /// The block run if the condition is true.
+        then: Block<'src>,
+        /// The block run if the condition is false, if any.
+        els: Option<Block<'src>>,
+    },
+    /// A loop running while a condition is true (`while a < b is ... end`).
+    While {
+        /// The condition, of type `bool`.
+        cond: Expr<'src>,
+        /// The body of the loop.
+        body: Block<'src>,
+    },
+    /// A loop over the items of an array (`for line in self.text is ... end`). The loop variable
+    /// is identified by the id of the statement.
+    For {
+        /// Name of the loop variable.
+        name: Ident<'src>,
+        /// The array being iterated.
+        iter: Expr<'src>,
+        /// The body of the loop.
+        body: Block<'src>,
+    },
+    /// A call made once for every item of the array passed as its last argument, with the item
+    /// in place of the array (`bulk print(self.text)`). Other arguments are evaluated once.
+    Bulk(Expr<'src>),
+    /// An exit from the innermost loop.
+    Break,
+    /// A jump to the next iteration of the innermost loop.
+    Continue,
+}
+
+impl<'src> Block<'src> {
+    /// Checks if running the block never reaches its end, because every path through it
+    /// returns, leaves it through `break`/`continue` or loops forever.
+    pub fn diverges(&self) -> bool {
+        self.stmts.iter().any(Stmt::diverges)
+    }
+}
+
+impl<'src> Stmt<'src> {
+    /// Checks if running the statement never reaches the next one. See [`Block::diverges`].
+    pub fn diverges(&self) -> bool {
+        match &self.kind {
+            StmtKind::Return(_) | StmtKind::Break | StmtKind::Continue => true,
+            StmtKind::If {
+                then,
+                els: Some(els),
+                ..
+            } => then.diverges() && els.diverges(),
+            StmtKind::While { cond, body } => {
+                matches!(cond.kind, ExprKind::Bool(true)) && !body.breaks()
+            }
+            _ => false,
+        }
+    }
+}
+
+impl<'src> Block<'src> {
+    /// Checks if the block contains a `break` out of the loop it is the body of.
+    fn breaks(&self) -> bool {
+        self.stmts.iter().any(|x| match &x.kind {
+            StmtKind::Break => true,
+            StmtKind::If { then, els, .. } => {
+                then.breaks() || els.as_ref().is_some_and(Block::breaks)
+            }
+            _ => false,
+        })
+    }
+}
+
+/// An expression.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Expr<'src> {
+    /// Id of the expression.
+    pub id: NodeId,
+    /// The kind of expression.
+    pub kind: ExprKind<'src>,
+    /// Span of the whole expression.
+    pub span: Span<'src>,
+}
+
+/// Every kind of [`Expr`].
+#[derive(Debug, Clone, PartialEq)]
+pub enum ExprKind<'src> {
+    /// A use of a name (`val`).
+    Name(Ident<'src>),
+    /// A string literal, without its quotes.
+    Str(&'src str),
+    /// An integer literal.
+    Int(i64),
+    /// A floating point literal.
+    Float(f64),
+    /// A boolean literal (`true`, `false`).
+    Bool(bool),
+    /// An array literal (`['a', 'b']`).
+    Array(Vec<Expr<'src>>),
+    /// A field access (`self.text`).
+    Field {
+        /// The object being accessed.
+        base: Box<Expr<'src>>,
+        /// The accessed field.
+        field: Ident<'src>,
+    },
+    /// A call (`print('a')`). Method calls are calls with a [`Field`](ExprKind::Field) callee.
+    Call {
+        /// The called expression.
+        callee: Box<Expr<'src>>,
+        /// Arguments of the call.
+        args: Vec<Expr<'src>>,
+    },
+    /// A constructor with explicitly initialized fields (`printer(text: [])`). A bare type name
+    /// used as a value is a constructor too, leaving every field at its default value.
+    Construct {
+        /// The constructed type.
+        ty: Ident<'src>,
+        /// The initialized fields, in source order.
+        fields: Vec<FieldInit<'src>>,
+    },
+    /// A binary operation (`'a' + b`).
+    Binary {
+        /// The operator.
+        op: BinOp,
+        /// Left hand side.
+        lhs: Box<Expr<'src>>,
+        /// Right hand side.
+        rhs: Box<Expr<'src>>,
+    },
+    /// A unary operation (`-a`).
+    Unary {
+        /// The operator.
+        op: UnOp,
+        /// The operand.
+        operand: Box<Expr<'src>>,
+    },
+}
+
+/// A field initialized in a [`Construct`](ExprKind::Construct) expression (`text: []`).
+#[derive(Debug, Clone, PartialEq)]
+pub struct FieldInit<'src> {
+    /// Name of the field.
+    pub name: Ident<'src>,
+    /// Value of the field.
+    pub value: Expr<'src>,
+}
+
+/// Binary operators.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum BinOp {
+    /// `+`
+    Add,
+    /// `-`
+    Sub,
+    /// `*`
+    Mul,
+    /// `/`
+    Div,
+    /// `==`
+    Eq,
+    /// `!=`
+    Ne,
+    /// `<`
+    Lt,
+    /// `<=`
+    Le,
+    /// `>`
+    Gt,
+    /// `>=`
+    Ge,
+    /// `and`, which only evaluates the right hand side if the left hand side is true.
+    And,
+    /// `or`, which only evaluates the right hand side if the left hand side is false.
+    Or,
+}
+
+impl BinOp {
+    /// Gets the operator as written in the source.
+    pub fn symbol(self) -> &'static str {
+        match self {
+            BinOp::Add => "+",
+            BinOp::Sub => "-",
+            BinOp::Mul => "*",
+            BinOp::Div => "/",
+            BinOp::Eq => "==",
+            BinOp::Ne => "!=",
+            BinOp::Lt => "<",
+            BinOp::Le => "<=",
+            BinOp::Gt => ">",
+            BinOp::Ge => ">=",
+            BinOp::And => "and",
+            BinOp::Or => "or",
+        }
+    }
+
+    /// Gets the name of the method that overloads the operator for object types, if it can be
+    /// overloaded. `!=` is overloaded through the same method as `==`, and negates its result.
+    pub fn method(self) -> Option<&'static str> {
+        match self {
+            BinOp::Add => Some("op-add"),
+            BinOp::Sub => Some("op-sub"),
+            BinOp::Mul => Some("op-mul"),
+            BinOp::Div => Some("op-div"),
+            BinOp::Eq | BinOp::Ne => Some("op-eq"),
+            _ => None,
+        }
+    }
+
+    /// Checks if the operator compares its operands, giving a `bool`.
+    pub fn is_comparison(self) -> bool {
+        matches!(
+            self,
+            BinOp::Eq | BinOp::Ne | BinOp::Lt | BinOp::Le | BinOp::Gt | BinOp::Ge
+        )
+    }
+}
+
+/// Unary operators.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum UnOp {
+    /// `-`
+    Neg,
+    /// `not`
+    Not,
+}
+
+impl UnOp {
+    /// Gets the operator as written in the source.
+    pub fn symbol(self) -> &'static str {
+        match self {
+            UnOp::Neg => "-",
+            UnOp::Not => "not",
+        }
+    }
+}
@@ -0,0 +1,261 @@
+//! `esci bench`: measures how long each stage of the pipeline takes on a file, and how long
+//! running the program takes in the tree-walking interpreter and in the virtual machine. Build
+//! with `cargo run --profile profiling -- bench` to measure optimized code that profilers can
+//! still symbolize.
+
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    hint::black_box,
+    io,
+    path::PathBuf,
+    sync::atomic::{AtomicUsize, Ordering},
+    time::{Duration, Instant},
+};
+
+use clap::ValueEnum;
+use escoop::{
+    Source, borrow,
+    bytecode::{self, from_mir, vm},
+    diag,
+    emit::HirInfo,
+    interp,
+    lexer::Lexer,
+    mir,
+    modules::{ModuleGraph, ModuleId},
+    ownership,
+    parser::Parser,
+    project::{Module, Project},
+    resolve,
+    ty::check,
+};
+
+/// Allocator counting every allocation, so benchmarks can report allocations per iteration.
+struct CountingAlloc;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        unsafe { System.alloc(layout) }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        unsafe { System.dealloc(ptr, layout) }
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        unsafe { System.alloc_zeroed(layout) }
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        unsafe { System.realloc(ptr, layout, new_size) }
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAlloc = CountingAlloc;
+
+#[derive(clap::Args)]
+pub struct BenchArgs {
+    /// File to benchmark, instead of the project in the current directory
+    #[arg(value_name = "FILE")]
+    pub file: Option<PathBuf>,
+
+    /// Number of measured iterations per stage
+    #[arg(short = 'n', long, default_value_t = 1000)]
+    iterations: usize,
+
+    /// Number of unmeasured iterations run before measuring each stage
+    #[arg(short, long, default_value_t = 100)]
+    warmup: usize,
+
+    /// Stages to benchmark, every stage if left out
+    #[arg(short, long, value_delimiter = ',')]
+    stage: Vec<BenchStage>,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, ValueEnum)]
+enum BenchStage {
+    Lex,
+    Parse,
+    /// Compiling the checked program to bytecode, through the unoptimized IR
+    Compile,
+    /// Running the program in the tree-walking interpreter
+    Interp,
+    /// Running the compiled program in the virtual machine
+    Vm,
+}
+
+impl BenchStage {
+    /// Whether the stage needs the checked program, rather than just the source of the file.
+    fn checked(self) -> bool {
+        !matches!(self, BenchStage::Lex | BenchStage::Parse)
+    }
+}
+
+struct Measurement {
+    timings: Vec<Duration>,
+    allocations: usize,
+}
+
+impl Measurement {
+    fn mean(&self) -> Duration {
+        self.timings.iter().sum::<Duration>() / self.timings.len() as u32
+    }
+
+    /// Gets the timing at `percent` percent of the sorted timings.
+    fn percentile(&self, percent: usize) -> Duration {
+        let index = (self.timings.len() - 1) * percent / 100;
+        self.timings[index]
+    }
+}
+
+fn measure(args: &BenchArgs, mut stage: impl FnMut()) -> Measurement {
+    for _ in 0..args.warmup {
+        stage();
+    }
+    let mut timings = Vec::with_capacity(args.iterations);
+    let allocations = ALLOCATIONS.load(Ordering::Relaxed);
+    for _ in 0..args.iterations {
+        let start = Instant::now();
+        stage();
+        timings.push(start.elapsed());
+    }
+    // The timings vector never reallocates, so everything counted here comes from the stage.
+    let allocations = ALLOCATIONS.load(Ordering::Relaxed) - allocations;
+    timings.sort();
+    Measurement {
+        timings,
+        allocations,
+    }
+}
+
+/// Benchmarks the stages on the file at `entry` of the project. Stages running the program run
+/// the whole project, and are skipped if it has errors.
+pub fn run(project: &Project, sources: &[Source], entry: usize, args: &BenchArgs) {
+    if args.iterations == 0 {
+        println!("nothing to benchmark with 0 iterations");
+        return;
+    }
+    let src = &sources[entry];
+    let source_len = project.files[entry].text.len();
+    let tokens = Lexer::new(src).count();
+    let stages = if args.stage.is_empty() {
+        BenchStage::value_variants()
+    } else {
+        &args.stage
+    };
+    println!(
+        "{} bytes, {tokens} tokens, {} iterations after {} warmup iterations",
+        source_len, args.iterations, args.warmup
+    );
+    for stage in stages.iter().filter(|x| !x.checked()) {
+        let measurement = match stage {
+            BenchStage::Lex => measure(args, || {
+                for token in Lexer::new(src) {
+                    black_box(token);
+                }
+            }),
+            BenchStage::Parse => measure(args, || {
+                black_box(Parser::new(src).parse());
+            }),
+            _ => unreachable!("only stages on the source are measured here"),
+        };
+        report(*stage, &measurement, source_len, tokens, args.iterations);
+    }
+    if !stages.iter().any(|x| x.checked()) {
+        return;
+    }
+
+    let modules = project.parse(sources);
+    let graph = ModuleGraph::build(&modules);
+    let res = resolve::resolve(&modules, &graph);
+    let typeck = check::check(&modules, &graph, &res);
+    let borrows = borrow::infer(&modules, &graph, &res, &typeck);
+    let ownership = ownership::analyze(&modules, &graph, &res, &typeck, &borrows);
+    let info = HirInfo {
+        graph: &graph,
+        res: &res,
+        typeck: &typeck,
+        borrows: &borrows,
+        ownership: &ownership,
+    };
+    let program = match diag::error() {
+        false => compile(&modules, info, ModuleId(entry)),
+        true => None,
+    };
+    let Some(program) = program else {
+        println!("can't benchmark running the program, because of the errors above");
+        return;
+    };
+    for stage in stages.iter().filter(|x| x.checked()) {
+        let measurement = match stage {
+            BenchStage::Compile => measure(args, || {
+                black_box(compile(&modules, info, ModuleId(entry)));
+            }),
+            // Every iteration runs on the same thread, as starting the interpreter's thread with
+            // its big stack would be measured too
+            BenchStage::Interp => std::thread::scope(|scope| {
+                std::thread::Builder::new()
+                    .stack_size(interp::STACK_SIZE)
+                    .spawn_scoped(scope, || {
+                        measure(args, || {
+                            let result = interp::run_on_thread(
+                                &modules,
+                                info,
+                                ModuleId(entry),
+                                &mut io::sink(),
+                            );
+                            black_box(result.is_ok());
+                        })
+                    })
+                    .expect("the benchmark thread can be started")
+                    .join()
+                    .unwrap_or_else(|panic| std::panic::resume_unwind(panic))
+            }),
+            BenchStage::Vm => measure(args, || {
+                black_box(vm::run(&program, &mut io::sink()).is_ok());
+            }),
+            _ => unreachable!("only stages on the checked program are measured here"),
+        };
+        report(*stage, &measurement, source_len, tokens, args.iterations);
+    }
+}
+
+/// Compiles the checked program to bytecode, as `esci run` does at `-O0`.
+fn compile(modules: &[Module], info: HirInfo, entry: ModuleId) -> Option<bytecode::Program> {
+    let program = mir::lower::lower(modules, info, entry)?;
+    Some(from_mir::compile(&program, modules))
+}
+
+fn report(
+    stage: BenchStage,
+    measurement: &Measurement,
+    bytes: usize,
+    tokens: usize,
+    iterations: usize,
+) {
+    let mean = measurement.mean().as_secs_f64();
+    println!("{stage:?}:");
+    println!(
+        "  mean {:?}, median {:?}, p99 {:?}",
+        measurement.mean(),
+        measurement.percentile(50),
+        measurement.percentile(99)
+    );
+    // Running a program takes as long as the program does, not as long as its source is
+    if !stage.checked() {
+        println!(
+            "  {:.2} MB/s, {:.0} tokens/s",
+            bytes as f64 / mean / 1_000_000.0,
+            tokens as f64 / mean
+        );
+    }
+    println!(
+        "  {:.1} allocations per iteration",
+        measurement.allocations as f64 / iterations as f64
+    );
+}
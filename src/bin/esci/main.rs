@@ -1,21 +1,240 @@
-use std::{fs, path::PathBuf};
+use std::{
+    env,
+    fs::File,
+    io::{self, Write},
+    path::{Path, PathBuf},
+    time::Instant,
+};
 
-use clap::{Parser as ClapParser, ValueEnum};
-use escoop::{Source, lexer::Lexer, parser::Parser};
+use clap::{Parser as ClapParser, Subcommand, ValueEnum};
+use escoop::{
+    borrow,
+    bytecode::{self, container, from_mir, vm},
+    codegen::{c, llvm, wat},
+    diag::{self, Diag},
+    emit::{self, HirInfo},
+    escape, interp,
+    lexer::Lexer,
+    manifest,
+    mir::{
+        self,
+        opt::{self, Pass},
+    },
+    modules::{ModuleGraph, ModuleId},
+    ownership,
+    project::{Project, ProjectError},
+    resolve,
+    ty::check,
+};
+
+mod bench;
 
 #[derive(ClapParser)]
 struct Args {
-    #[arg(short, long, value_name = "FILE")]
-    file: Option<PathBuf>,
+    #[command(subcommand)]
+    command: Option<Command>,
 
-    #[arg(short, long)]
-    profiling: bool,
+    #[arg(short, long, value_name = "FILE", global = true)]
+    file: Option<PathBuf>,
 
-    #[arg(short, long)]
+    #[arg(short, long, global = true)]
     verbose: bool,
 
-    #[arg(short, long)]
+    /// Unstable compiler options (e.g. `-Z time-passes`)
+    #[arg(short = 'Z', value_name = "FLAG", global = true)]
+    unstable: Vec<UnstableFlag>,
+
+    /// Enable optional warnings (e.g. `-W rc-fallback`)
+    #[arg(short = 'W', value_name = "LINT", global = true)]
+    warn: Vec<Lint>,
+
+    #[arg(short, long, global = true)]
     debug: Option<DebugMode>,
+
+    /// Optimization level (0-3) of the IR
+    #[arg(
+        short = 'O',
+        value_name = "LEVEL",
+        default_value_t = 0,
+        global = true,
+        value_parser = clap::value_parser!(u8).range(0..=3)
+    )]
+    opt_level: u8,
+
+    /// Enable or disable IR passes on top of the optimization level (e.g. `+inline,-fold`)
+    #[arg(
+        long,
+        value_name = "[+|-]PASS",
+        value_delimiter = ',',
+        global = true,
+        allow_hyphen_values = true,
+        value_parser = parse_pass
+    )]
+    passes: Vec<(bool, Pass)>,
+
+    /// Dump stages of the pipeline, optionally into a file (e.g. `tokens,ast=out.ast`)
+    #[arg(
+        long,
+        value_name = "STAGE[=PATH]",
+        value_delimiter = ',',
+        global = true,
+        value_parser = parse_emit
+    )]
+    emit: Vec<Emit>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Benchmark every stage of the pipeline on a file
+    Bench(bench::BenchArgs),
+    /// Run a program, starting at the `start` function of its entry module
+    Run(RunArgs),
+    /// Compile a program into a file that can be run without its sources
+    Build(BuildArgs),
+}
+
+#[derive(clap::Args)]
+struct BuildArgs {
+    /// File to compile, instead of the project in the current directory
+    #[arg(value_name = "FILE")]
+    file: Option<PathBuf>,
+
+    /// What to compile the program to
+    #[arg(long, default_value = "bytecode")]
+    target: BuildTarget,
+
+    /// Where to write the output, named after the project or file by default
+    #[arg(short, long, value_name = "PATH")]
+    output: Option<PathBuf>,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, ValueEnum)]
+enum BuildTarget {
+    /// A `.scpc` file holding bytecode, run with `esci run app.scpc`
+    Bytecode,
+    /// C99 source code, compiled with `cc app.c`. The `escoop.h` runtime it includes is written
+    /// next to it
+    C,
+    /// A WebAssembly text module, exporting the `start` function and importing `print` from the
+    /// host
+    Wat,
+    /// LLVM IR with line debug info, compiled with `clang app.ll escoop.c`. The runtime and
+    /// the `escoop.h` header it includes are written next to it
+    LlvmIr,
+}
+
+impl BuildTarget {
+    fn extension(self) -> &'static str {
+        match self {
+            BuildTarget::Bytecode => container::EXTENSION,
+            BuildTarget::C => "c",
+            BuildTarget::Wat => "wat",
+            BuildTarget::LlvmIr => "ll",
+        }
+    }
+}
+
+#[derive(clap::Args)]
+struct RunArgs {
+    /// File to run, instead of the project in the current directory. Files compiled by
+    /// `esci build --target bytecode` are run in the virtual machine
+    #[arg(value_name = "FILE")]
+    file: Option<PathBuf>,
+
+    /// How to run the program
+    #[arg(long, default_value = "vm")]
+    engine: Engine,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, ValueEnum)]
+enum Engine {
+    /// Compile to bytecode and run it in the virtual machine
+    Vm,
+    /// Walk the checked AST
+    Interp,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, ValueEnum)]
+enum UnstableFlag {
+    /// Print how long each pass of the compiler took
+    TimePasses,
+    /// Print how many allocations are reference counted and how many are managed statically
+    RcReport,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, ValueEnum)]
+enum Lint {
+    /// Explain why values fall back to reference counting
+    RcFallback,
+}
+
+/// Runs a compiler pass, printing how long it took if `-Z time-passes` is enabled.
+fn time_pass<T>(args: &Args, name: &str, pass: impl FnOnce() -> T) -> T {
+    let start = Instant::now();
+    let result = pass();
+    report_time(args, name, start);
+    result
+}
+
+fn report_time(args: &Args, name: &str, start: Instant) {
+    if args.unstable.contains(&UnstableFlag::TimePasses) {
+        eprintln!("time: {:>10.3?}\t{name}", start.elapsed());
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, ValueEnum)]
+enum EmitStage {
+    Tokens,
+    Ast,
+    Hir,
+    Escape,
+    Ir,
+    Bytecode,
+}
+
+#[derive(Debug, Clone)]
+struct Emit {
+    stage: EmitStage,
+    path: Option<PathBuf>,
+}
+
+fn parse_emit(arg: &str) -> Result<Emit, String> {
+    let (stage, path) = match arg.split_once('=') {
+        Some((stage, path)) => (stage, Some(PathBuf::from(path))),
+        None => (arg, None),
+    };
+    Ok(Emit {
+        stage: EmitStage::from_str(stage, true)?,
+        path,
+    })
+}
+
+fn write_emit(emit: &Emit, verbose: bool, dump: impl FnOnce(&mut dyn Write) -> io::Result<()>) {
+    let result = match &emit.path {
+        Some(path) => File::create(path).and_then(|mut file| dump(&mut file)),
+        None => dump(&mut io::stdout().lock()),
+    };
+    if let Err(err) = result {
+        let mut msg = format!("could not emit `{:?}`: {}", emit.stage, err);
+        if verbose {
+            msg += format!(" ({:?})", err.kind()).as_str();
+        }
+        println!("{msg}");
+    }
+}
+
+fn parse_pass(arg: &str) -> Result<(bool, Pass), String> {
+    let (enable, name) = match arg.strip_prefix('-') {
+        Some(name) => (false, name),
+        None => (true, arg.strip_prefix('+').unwrap_or(arg)),
+    };
+    match Pass::from_name(name) {
+        Some(pass) => Ok((enable, pass)),
+        None => {
+            let names: Vec<_> = Pass::ALL.iter().map(|x| x.name()).collect();
+            Err(format!("expected one of: {}", names.join(", ")))
+        }
+    }
 }
 
 #[derive(Debug, Copy, Clone, PartialEq, Eq, ValueEnum)]
@@ -26,52 +245,299 @@ enum DebugMode {
 
 fn main() {
     let args = Args::parse();
-    let path = args
-        .file
-        .unwrap_or(PathBuf::from("escoop-tests/hello-world/entrypoint.scp"));
-    let file = match fs::read_to_string(&path) {
-        Ok(file) => file,
+    let file = match &args.command {
+        Some(Command::Run(run)) => run.file.as_ref().or(args.file.as_ref()),
+        Some(Command::Build(build)) => build.file.as_ref().or(args.file.as_ref()),
+        Some(Command::Bench(bench)) => bench.file.as_ref().or(args.file.as_ref()),
+        _ => args.file.as_ref(),
+    };
+    if let Some(Command::Run(_)) = &args.command
+        && let Some(file) = file
+        && file.extension().is_some_and(|x| x == container::EXTENSION)
+    {
+        run_compiled(&args, file);
+        return;
+    }
+    let project = match file {
+        Some(path) => Project::single_file(path),
+        None => match env::current_dir().ok().and_then(|dir| manifest::find(&dir)) {
+            Some(manifest) => Project::load(&manifest),
+            None => Project::single_file("escoop-tests/hello-world/entrypoint.scp"),
+        },
+    };
+    let project = match project {
+        Ok(project) => project,
         Err(err) => {
-            let mut msg = format!("could not open `{}`: {}", path.to_string_lossy(), err);
-            if args.verbose {
+            let mut msg = err.to_string();
+            if args.verbose
+                && let ProjectError::Io(_, err) = &err
+            {
                 msg += format!(" ({:?})", err.kind()).as_str();
             }
             println!("{msg}");
             return;
         }
     };
+    let sources = project.sources();
+    let entry = project
+        .files
+        .iter()
+        .position(|x| x.module.is_some() && x.module == project.entry)
+        .unwrap_or(0);
+
+    if let Some(Command::Bench(bench)) = &args.command {
+        bench::run(&project, &sources, entry, bench);
+        return;
+    }
 
-    let src = Source::new(file.as_str(), path);
+    let total = Instant::now();
+    let modules = time_pass(&args, "parse", || project.parse(&sources));
+    let graph = time_pass(&args, "module graph", || ModuleGraph::build(&modules));
+    let res = time_pass(&args, "resolve", || resolve::resolve(&modules, &graph));
+    let typeck = time_pass(&args, "type check", || check::check(&modules, &graph, &res));
+    let borrows = time_pass(&args, "borrow inference", || {
+        borrow::infer(&modules, &graph, &res, &typeck)
+    });
+    let ownership = time_pass(&args, "ownership", || {
+        ownership::analyze(&modules, &graph, &res, &typeck, &borrows)
+    });
+    // Only used for diagnostics, the backends don't read it
+    let escapes = time_pass(&args, "escape analysis", || {
+        escape::analyze(&modules, &graph, &res, &typeck, &borrows, &ownership)
+    });
+    if args.warn.contains(&Lint::RcFallback) {
+        ownership::lint_rc(&ownership, &graph, &typeck);
+    }
+    if args.unstable.contains(&UnstableFlag::RcReport) {
+        let total = escapes.allocs.len();
+        let rc = escapes.rc.len();
+        eprintln!(
+            "rc: {rc} of {total} allocations are reference counted, {} are managed statically",
+            total - rc
+        );
+    }
 
-    if matches!(args.debug, Some(DebugMode::Lexer)) {
-        if args.profiling {
-            for _ in 0..1000000 {
-                let lexer = Lexer::new(&src);
-                for _ in lexer {
-                    //println!("{:?}", i);
-                }
+    let info = HirInfo {
+        graph: &graph,
+        res: &res,
+        typeck: &typeck,
+        borrows: &borrows,
+        ownership: &ownership,
+    };
+    let run = match &args.command {
+        Some(Command::Run(run)) => Some(run.engine),
+        _ => None,
+    };
+    let build = match &args.command {
+        Some(Command::Build(build)) => Some(build),
+        _ => None,
+    };
+    let needs_program = run == Some(Engine::Vm)
+        || build.is_some_and(|x| x.target == BuildTarget::Bytecode)
+        || args.emit.iter().any(|x| x.stage == EmitStage::Bytecode);
+    let mut opt = opt::Options::level(args.opt_level);
+    for (enable, pass) in &args.passes {
+        match enable {
+            true => opt.enable(*pass),
+            false => opt.disable(*pass),
+        }
+    }
+    if run == Some(Engine::Interp) && (args.opt_level > 0 || !args.passes.is_empty()) {
+        Diag::warn(&sources[entry])
+            .with_message(
+                "`-O` and `--passes` only optimize the IR, which the interpreter doesn't use",
+            )
+            .finish()
+            .emit();
+    }
+    let needs_ir =
+        needs_program || build.is_some() || args.emit.iter().any(|x| x.stage == EmitStage::Ir);
+    let mut ir = match needs_ir && !diag::error() {
+        true => time_pass(&args, "lower to ir", || {
+            mir::lower::lower(&modules, info, ModuleId(entry))
+        }),
+        false => None,
+    };
+    if let Some(ir) = &mut ir {
+        if let Err(errors) = mir::validate::validate(ir) {
+            Diag::bug(&sources[entry])
+                .with_message("internal compiler error: the lowering produced invalid IR")
+                .with_notes_iter(errors.iter().map(|x| x.to_string()))
+                .finish()
+                .emit();
+            std::process::exit(1);
+        }
+        time_pass(&args, "optimize ir", || opt::optimize(ir, &opt));
+    }
+    let program = match (needs_program, &ir) {
+        (true, Some(ir)) => Some(time_pass(&args, "bytecode", || {
+            from_mir::compile(ir, &modules)
+        })),
+        _ => None,
+    };
+    for stage in &args.emit {
+        match stage.stage {
+            EmitStage::Tokens => write_emit(stage, args.verbose, |out| {
+                sources.iter().try_for_each(|src| emit::tokens(src, out))
+            }),
+            EmitStage::Ast => write_emit(stage, args.verbose, |out| {
+                modules.iter().try_for_each(|x| emit::ast(&x.decls, out))
+            }),
+            EmitStage::Hir => write_emit(stage, args.verbose, |out| {
+                modules
+                    .iter()
+                    .try_for_each(|x| emit::hir(&x.decls, info, out))
+            }),
+            EmitStage::Escape => write_emit(stage, args.verbose, |out| {
+                modules
+                    .iter()
+                    .try_for_each(|x| emit::escape(&x.decls, info, &escapes, out))
+            }),
+            EmitStage::Ir => match &ir {
+                Some(ir) => write_emit(stage, args.verbose, |out| mir::pretty(ir, out)),
+                None => println!("could not emit `Ir`, because of the errors above"),
+            },
+            EmitStage::Bytecode => match &program {
+                Some(program) => write_emit(stage, args.verbose, |out| {
+                    bytecode::disassemble(program, out)
+                }),
+                None => println!("could not emit `Bytecode`, because of the errors above"),
+            },
+        }
+    }
+
+    match args.debug {
+        Some(DebugMode::Lexer) => {
+            for i in sources.iter().flat_map(Lexer::new) {
+                println!("{i:?}");
             }
-        } else {
-            let lexer = Lexer::new(&src);
-            for i in lexer {
+        }
+        Some(DebugMode::Parser) => {
+            for i in modules.iter().flat_map(|x| &x.decls) {
                 println!("{i:?}");
             }
         }
+        None => {}
     }
 
-    if matches!(args.debug, Some(DebugMode::Parser)) {
-        if args.profiling {
-            for _ in 0..1000000 {
-                let mut parser = Parser::new(&src);
-                for _ in parser.parse() {
-                    //println!("{:?}", i);
+    if let Some(engine) = run {
+        if diag::error() {
+            println!("could not run the program, because of the errors above");
+            std::process::exit(1);
+        }
+        let mut out = io::BufWriter::new(io::stdout());
+        match engine {
+            Engine::Vm => {
+                let program = program.as_ref().expect("programs without errors compile");
+                match time_pass(&args, "run", || vm::run(program, &mut out)) {
+                    Ok(leaks) => vm::report_leaks(&leaks),
+                    Err(err) => {
+                        err.emit();
+                        std::process::exit(1);
+                    }
                 }
             }
-        } else {
-            let mut parser = Parser::new(&src);
-            for i in parser.parse() {
-                println!("{i:?}");
+            Engine::Interp => {
+                let result = time_pass(&args, "run", || {
+                    interp::run(&modules, info, ModuleId(entry), &mut out)
+                });
+                match result {
+                    Ok(leaks) => interp::report_leaks(&leaks, &graph),
+                    Err(err) => {
+                        err.emit();
+                        std::process::exit(1);
+                    }
+                }
+            }
+        }
+    }
+
+    if let Some(build) = build {
+        if diag::error() {
+            println!("could not build the program, because of the errors above");
+            std::process::exit(1);
+        }
+        let output = build.output.clone().unwrap_or_else(|| {
+            let name = match &project.manifest {
+                Some(manifest) => manifest.name.clone(),
+                None => project.files[entry]
+                    .path
+                    .file_stem()
+                    .map_or("main".into(), |x| x.to_string_lossy().into_owned()),
+            };
+            PathBuf::from(format!("{name}.{}", build.target.extension()))
+        });
+        let bytes = match build.target {
+            BuildTarget::Bytecode => {
+                let program = program.as_ref().expect("programs without errors compile");
+                time_pass(&args, "write bytecode", || container::write(program))
+            }
+            BuildTarget::C => {
+                let ir = ir.as_ref().expect("programs without errors are lowered");
+                let code = time_pass(&args, "generate c", || c::generate(ir));
+                write_output(&args, &output.with_file_name(c::HEADER_NAME), c::HEADER);
+                code.into_bytes()
+            }
+            BuildTarget::Wat => {
+                let ir = ir.as_ref().expect("programs without errors are lowered");
+                time_pass(&args, "generate wat", || wat::generate(ir)).into_bytes()
+            }
+            BuildTarget::LlvmIr => {
+                let ir = ir.as_ref().expect("programs without errors are lowered");
+                let code = time_pass(&args, "generate llvm ir", || llvm::generate(ir));
+                write_output(&args, &output.with_file_name(c::HEADER_NAME), c::HEADER);
+                write_output(
+                    &args,
+                    &output.with_file_name(llvm::RUNTIME_NAME),
+                    llvm::RUNTIME,
+                );
+                code.into_bytes()
+            }
+        };
+        write_output(&args, &output, bytes);
+    }
+
+    report_time(&args, "total", total);
+}
+
+/// Writes a file built by `esci build`, stopping if it can't be written.
+fn write_output(args: &Args, path: &Path, contents: impl AsRef<[u8]>) {
+    if let Err(err) = std::fs::write(path, contents) {
+        let mut msg = format!("could not write `{}`: {err}", path.to_string_lossy());
+        if args.verbose {
+            msg += format!(" ({:?})", err.kind()).as_str();
+        }
+        println!("{msg}");
+        std::process::exit(1);
+    }
+}
+
+/// Runs a file compiled by `esci build --target bytecode`.
+fn run_compiled(args: &Args, path: &Path) {
+    let bytes = match std::fs::read(path) {
+        Ok(bytes) => bytes,
+        Err(err) => {
+            let mut msg = format!("could not open `{}`: {err}", path.to_string_lossy());
+            if args.verbose {
+                msg += format!(" ({:?})", err.kind()).as_str();
             }
+            println!("{msg}");
+            std::process::exit(1);
+        }
+    };
+    let program = match time_pass(args, "load bytecode", || container::read(&bytes)) {
+        Ok(program) => program,
+        Err(err) => {
+            println!("could not load `{}`: {err}", path.to_string_lossy());
+            std::process::exit(1);
+        }
+    };
+    let mut out = io::BufWriter::new(io::stdout().lock());
+    match time_pass(args, "run", || vm::run(&program, &mut out)) {
+        Ok(leaks) => vm::report_leaks(&leaks),
+        Err(err) => {
+            err.emit();
+            std::process::exit(1);
         }
     }
 }
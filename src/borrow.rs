@@ -0,0 +1,573 @@
+#![deny(missing_docs)]
+//! Module for borrow inference: working out how every function takes its parameters, without
+//! the user writing it down.
+//!
+//! Each parameter and `self` receiver owning memory (see [`TyInterner::needs_drop`]) is
+//! classified from the function body:
+//! - [`Owned`](PassMode::Owned) if the body moves it away (into a variable, an array, a field,
+//!   a return value or another owned parameter) or drops it.
+//! - [`Mutable`](PassMode::Mutable) if the body assigns to it or its fields, or passes it on
+//!   mutably (like calling `push` on `self.items`).
+//! - [`Shared`](PassMode::Shared) otherwise.
+//!
+//! Functions calling each other depend on each other's modes, so the modes start out shared and
+//! are raised until nothing changes. Parameters of copied types and of `extern` functions are
+//! never inferred: copies are passed by value and `extern` functions are assumed to only read
+//! their arguments.
+//!
+//! Callers are then checked for aliasing: a call can't borrow the same variable mutably twice, or
+//! mutably and shared at once, or move it while it is borrowed by an earlier argument.
+
+use std::{
+    collections::{HashMap, HashSet},
+    slice,
+};
+
+use codespan_reporting::diagnostic::Label;
+
+use crate::{
+    ast::{Block, Declaration, Expr, ExprKind, Func, NodeId, Stmt, StmtKind},
+    builtins::{Builtin, BuiltinMethod},
+    diag::Diag,
+    modules::ModuleGraph,
+    project::Module,
+    resolve::{Res, Resolutions},
+    span::Span,
+    ty::{
+        TyInterner, TyKind,
+        check::{Method, TypeckResults},
+    },
+};
+
+/// How a function takes one of its parameters.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum PassMode {
+    /// The function only reads the argument.
+    Shared,
+    /// The function changes the argument, which stays with the caller.
+    Mutable,
+    /// The function takes the argument away from the caller.
+    Owned,
+}
+
+impl PassMode {
+    /// Gets the name of the mode, as used in diagnostics and dumps.
+    pub fn name(self) -> &'static str {
+        match self {
+            PassMode::Shared => "shared",
+            PassMode::Mutable => "mutable",
+            PassMode::Owned => "owned",
+        }
+    }
+}
+
+/// How a call passes one of its arguments.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Passing {
+    /// How the argument is passed.
+    pub mode: PassMode,
+    /// The parameter binding receiving it, `None` for builtins.
+    pub param: Option<NodeId>,
+}
+
+/// How a call passes its receiver and arguments.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CallModes {
+    /// How the receiver is passed, for method calls.
+    pub receiver: Option<Passing>,
+    /// How each argument is passed. Arguments without a matching parameter are left out.
+    pub args: Vec<Passing>,
+}
+
+/// The result of borrow inference.
+#[derive(Debug, Default)]
+pub struct Borrows<'src> {
+    /// The mode of every parameter, keyed by the id of the parameter, and of every `self`
+    /// receiver, keyed by the id of the method.
+    pub bindings: HashMap<NodeId, PassMode>,
+    /// The expression that made each inferred parameter owned or mutable.
+    pub reasons: HashMap<NodeId, Span<'src>>,
+    /// How every call passes its arguments, keyed by the id of the call expression.
+    pub calls: HashMap<NodeId, CallModes>,
+}
+
+/// Infers the mode of every parameter of `modules` and checks their callers, reporting aliasing
+/// conflicts through [`Diag`].
+pub fn infer<'src>(
+    modules: &[Module<'src>],
+    graph: &ModuleGraph<'src>,
+    res: &Resolutions<'src>,
+    typeck: &TypeckResults<'src>,
+) -> Borrows<'src> {
+    let mut inferrer = Inferrer {
+        modules,
+        graph,
+        res,
+        typeck,
+        inferred: HashSet::new(),
+        changed: false,
+        report: false,
+        out: Borrows::default(),
+    };
+    let funcs = inferrer.funcs();
+    for (func, method) in &funcs {
+        inferrer.declare(func, *method);
+    }
+    for func in modules.iter().flat_map(|x| &x.decls) {
+        if let Declaration::Extern(func) = func {
+            for param in &func.sig.params {
+                inferrer.declare_param(param.id, PassMode::Shared);
+            }
+        }
+    }
+
+    inferrer.changed = true;
+    while inferrer.changed {
+        inferrer.changed = false;
+        funcs.iter().for_each(|(func, _)| inferrer.func(func));
+    }
+    inferrer.report = true;
+    funcs.iter().for_each(|(func, _)| inferrer.func(func));
+    inferrer.out
+}
+
+struct Inferrer<'a, 'src> {
+    modules: &'a [Module<'src>],
+    graph: &'a ModuleGraph<'src>,
+    res: &'a Resolutions<'src>,
+    typeck: &'a TypeckResults<'src>,
+    /// The bindings whose mode is inferred from the body.
+    inferred: HashSet<NodeId>,
+    /// Whether a mode was raised since this was last reset.
+    changed: bool,
+    /// Whether to check calls for aliasing. Only done once the modes are final.
+    report: bool,
+    out: Borrows<'src>,
+}
+
+impl<'a, 'src> Inferrer<'a, 'src> {
+    fn tys(&self) -> &'a TyInterner {
+        &self.typeck.tys
+    }
+
+    /// Gets every function and method with a body, with whether it is a method.
+    fn funcs(&self) -> Vec<(&'a Func<'src>, bool)> {
+        let mut funcs = Vec::new();
+        for decl in self.modules.iter().flat_map(|x| &x.decls) {
+            match decl {
+                Declaration::Func(func) => funcs.push((func, false)),
+                Declaration::Impl(imp) => funcs.extend(imp.methods.iter().map(|x| (x, true))),
+                _ => {}
+            }
+        }
+        funcs
+    }
+
+    fn declare(&mut self, func: &Func, method: bool) {
+        if method {
+            // The receiver is always an object, which owns its memory
+            self.out.bindings.insert(func.id, PassMode::Shared);
+            self.inferred.insert(func.id);
+        }
+        for param in &func.sig.params {
+            self.declare_param(param.id, PassMode::Shared);
+            if self.out.bindings[&param.id] == PassMode::Shared {
+                self.inferred.insert(param.id);
+            }
+        }
+    }
+
+    /// Gives a parameter its starting mode, or [`PassMode::Owned`] if it is copied.
+    fn declare_param(&mut self, id: NodeId, mode: PassMode) {
+        let copied = self
+            .typeck
+            .bindings
+            .get(&id)
+            .is_none_or(|x| !self.tys().needs_drop(*x));
+        let mode = if copied { PassMode::Owned } else { mode };
+        self.out.bindings.insert(id, mode);
+    }
+
+    /// Raises the mode of the parameter used by `expr` to at least `mode`.
+    fn raise(&mut self, expr: &Expr<'src>, mode: PassMode) {
+        let Some(Res::Local(binding)) = self.res.names.get(&expr.id) else {
+            return;
+        };
+        if !self.inferred.contains(binding) || self.out.bindings[binding] >= mode {
+            return;
+        }
+        self.out.bindings.insert(*binding, mode);
+        self.out.reasons.insert(*binding, expr.span);
+        self.changed = true;
+    }
+
+    fn func(&mut self, func: &Func<'src>) {
+        self.block(&func.body);
+    }
+
+    fn block(&mut self, block: &Block<'src>) {
+        block.stmts.iter().for_each(|x| self.stmt(x));
+    }
+
+    fn stmt(&mut self, stmt: &Stmt<'src>) {
+        match &stmt.kind {
+            StmtKind::Local { init, .. } => self.expr(init, PassMode::Owned),
+            StmtKind::Assign { target, value } => {
+                self.expr(value, PassMode::Owned);
+                self.expr(target, PassMode::Mutable);
+            }
+            StmtKind::Increment(target) => self.expr(target, PassMode::Mutable),
+            StmtKind::Expr(expr) | StmtKind::Bulk(expr) => self.expr(expr, PassMode::Shared),
+            StmtKind::Return(value) => {
+                if let Some(value) = value {
+                    self.expr(value, PassMode::Owned);
+                }
+            }
+            StmtKind::If { cond, then, els } => {
+                self.expr(cond, PassMode::Shared);
+                self.block(then);
+                if let Some(els) = els {
+                    self.block(els);
+                }
+            }
+            StmtKind::While { cond, body } => {
+                self.expr(cond, PassMode::Shared);
+                self.block(body);
+            }
+            // The loop variable borrows each item of the array in turn
+            StmtKind::For { iter, body, .. } => {
+                self.expr(iter, PassMode::Shared);
+                self.block(body);
+            }
+            StmtKind::Break | StmtKind::Continue => {}
+        }
+    }
+
+    /// Walks an expression whose value is used as `mode`.
+    fn expr(&mut self, expr: &Expr<'src>, mode: PassMode) {
+        match &expr.kind {
+            ExprKind::Name(_) => self.raise(expr, mode),
+            ExprKind::Str(_) | ExprKind::Int(_) | ExprKind::Float(_) | ExprKind::Bool(_) => {}
+            ExprKind::Array(items) => items.iter().for_each(|x| self.expr(x, PassMode::Owned)),
+            // Moving a value out of a field only borrows the object it is in
+            ExprKind::Field { base, .. } => {
+                let mode = if mode == PassMode::Mutable {
+                    PassMode::Mutable
+                } else {
+                    PassMode::Shared
+                };
+                self.expr(base, mode);
+            }
+            ExprKind::Call { callee, args } => self.call(expr, callee, args),
+            ExprKind::Construct { fields, .. } => {
+                for field in fields {
+                    self.expr(&field.value, PassMode::Owned);
+                }
+            }
+            // Overloaded operators call the method with the right hand side as the argument
+            ExprKind::Binary { lhs, rhs, .. } if self.typeck.methods.contains_key(&expr.id) => {
+                self.call(expr, lhs, slice::from_ref(rhs));
+            }
+            ExprKind::Binary { lhs, rhs, .. } => {
+                self.expr(lhs, PassMode::Shared);
+                self.expr(rhs, PassMode::Shared);
+            }
+            ExprKind::Unary { operand, .. } => self.expr(operand, PassMode::Shared),
+        }
+    }
+
+    /// Walks a call or an overloaded operator.
+    fn call(&mut self, expr: &Expr<'src>, callee: &Expr<'src>, args: &[Expr<'src>]) {
+        let modes = self.call_modes(expr, callee, args.len());
+        let receiver = match (self.typeck.receiver(expr), modes.receiver) {
+            (Some(base), Some(receiver)) => {
+                self.expr(base, receiver.mode);
+                Some(base)
+            }
+            _ => {
+                self.expr(callee, PassMode::Shared);
+                None
+            }
+        };
+        for (index, arg) in args.iter().enumerate() {
+            let mode = modes.args.get(index).map_or(PassMode::Shared, |x| x.mode);
+            self.expr(arg, mode);
+        }
+        if self.report {
+            self.check_aliasing(receiver, args, &modes);
+            self.out.calls.insert(expr.id, modes);
+        }
+    }
+
+    /// Gets how the call `call` passes its receiver and `count` arguments.
+    fn call_modes(&self, call: &Expr, callee: &Expr, count: usize) -> CallModes {
+        let passing = |id: NodeId| Passing {
+            mode: self.out.bindings[&id],
+            param: Some(id),
+        };
+        let builtin = |mode| Passing { mode, param: None };
+        let func = match self.typeck.methods.get(&call.id) {
+            Some(Method::User(method)) => {
+                let Declaration::Impl(imp) = &self.modules[method.module.0].decls[method.decl]
+                else {
+                    unreachable!("methods always come from impl blocks");
+                };
+                let func = &imp.methods[method.method];
+                return CallModes {
+                    receiver: Some(passing(func.id)),
+                    args: func.sig.params.iter().map(|x| passing(x.id)).collect(),
+                };
+            }
+            Some(Method::Builtin(method)) => {
+                return match method {
+                    BuiltinMethod::Push => CallModes {
+                        receiver: Some(builtin(PassMode::Mutable)),
+                        args: vec![builtin(PassMode::Owned)],
+                    },
+                    BuiltinMethod::ToString | BuiltinMethod::Len => CallModes {
+                        receiver: Some(builtin(PassMode::Shared)),
+                        args: Vec::new(),
+                    },
+                };
+            }
+            None => match self.tys().kind(self.typeck.expr_ty(callee.id)) {
+                TyKind::FnItem(item) => {
+                    let item = self.graph.item(*item);
+                    match &self.modules[item.module.0].decls[item.decl] {
+                        Declaration::Func(func) => &func.sig,
+                        Declaration::Extern(func) => &func.sig,
+                        _ => return CallModes::default(),
+                    }
+                }
+                TyKind::Builtin(Builtin::Drop) => {
+                    return CallModes {
+                        receiver: None,
+                        args: vec![builtin(PassMode::Owned); count.min(1)],
+                    };
+                }
+                TyKind::Builtin(Builtin::Print | Builtin::Println) => {
+                    return CallModes {
+                        receiver: None,
+                        args: vec![builtin(PassMode::Shared); count.min(1)],
+                    };
+                }
+                _ => return CallModes::default(),
+            },
+        };
+        CallModes {
+            receiver: None,
+            args: func.params.iter().map(|x| passing(x.id)).collect(),
+        }
+    }
+
+    /// Finds the local variable an argument is a part of, if it owns its memory.
+    fn root(&self, expr: &Expr) -> Option<NodeId> {
+        match &expr.kind {
+            ExprKind::Name(_) => match self.res.names.get(&expr.id) {
+                Some(Res::Local(binding)) => {
+                    let ty = self.typeck.bindings.get(binding)?;
+                    self.tys().needs_drop(*ty).then_some(*binding)
+                }
+                _ => None,
+            },
+            ExprKind::Field { base, .. } => self.root(base),
+            _ => None,
+        }
+    }
+
+    /// Reports arguments of one call that borrow the same variable in conflicting ways.
+    fn check_aliasing(
+        &self,
+        receiver: Option<&Expr<'src>>,
+        args: &[Expr<'src>],
+        modes: &CallModes,
+    ) {
+        let mut borrows: Vec<(NodeId, Passing, &Expr<'src>)> = Vec::new();
+        let passed = receiver.zip(modes.receiver).into_iter();
+        let passed = passed.chain(args.iter().zip(modes.args.iter().copied()));
+        for (expr, passing) in passed {
+            // Copies don't borrow anything
+            if !self.tys().needs_drop(self.typeck.expr_ty(expr.id)) {
+                continue;
+            }
+            let Some(root) = self.root(expr) else {
+                continue;
+            };
+            let earlier = borrows.iter().find(|(x, earlier, _)| {
+                *x == root
+                    && earlier.mode != PassMode::Owned
+                    && conflicts(earlier.mode, passing.mode)
+            });
+            if let Some((_, earlier, first)) = earlier {
+                self.aliasing_error(root, (first, *earlier), (expr, passing));
+            }
+            borrows.push((root, passing, expr));
+        }
+    }
+
+    fn aliasing_error(
+        &self,
+        root: NodeId,
+        (first, first_passing): (&Expr<'src>, Passing),
+        (second, second_passing): (&Expr<'src>, Passing),
+    ) {
+        let name = self.res.bindings[&root].name;
+        let (message, first_label, second_label) = match (first_passing.mode, second_passing.mode) {
+            (PassMode::Mutable, PassMode::Mutable) => (
+                format!("cannot borrow `{name}` as mutable more than once at a time"),
+                "first mutable borrow here",
+                "second mutable borrow here",
+            ),
+            (PassMode::Mutable, PassMode::Shared) => (
+                format!("cannot borrow `{name}` as shared because it is also borrowed as mutable"),
+                "mutable borrow here",
+                "shared borrow here",
+            ),
+            (_, PassMode::Owned) => (
+                format!("cannot move out of `{name}` because it is borrowed"),
+                "borrow here",
+                "move out of `{name}` here",
+            ),
+            _ => (
+                format!("cannot borrow `{name}` as mutable because it is also borrowed as shared"),
+                "shared borrow here",
+                "mutable borrow here",
+            ),
+        };
+        let mut diag = Diag::error(second.span.source())
+            .with_message(message)
+            .with_label(
+                Label::primary((), second.span).with_message(second_label.replace("{name}", name)),
+            )
+            .with_secondary(first.span, first_label);
+        // Explain the inferred modes that caused the conflict
+        for passing in [first_passing, second_passing] {
+            let Some(param) = passing.param else {
+                continue;
+            };
+            let Some(reason) = self.out.reasons.get(&param) else {
+                continue;
+            };
+            let param = self.res.bindings[&param].name;
+            let why = match passing.mode {
+                PassMode::Owned => "moved",
+                _ => "changed",
+            };
+            diag = diag.with_secondary(
+                *reason,
+                format!(
+                    "`{param}` is {} because it is {why} here",
+                    passing.mode.name()
+                ),
+            );
+        }
+        diag.finish().emit();
+    }
+}
+
+/// Checks if a variable can't be passed as both `first` and `second` in the same call.
+fn conflicts(first: PassMode, second: PassMode) -> bool {
+    first == PassMode::Mutable || second != PassMode::Shared
+}
+
+#[cfg(test)]
+fn infer_source(text: &str) -> (usize, Vec<(String, &'static str)>) {
+    let sources = [crate::Source::new(text, "main.scp")];
+    let modules = crate::project::test_modules(&sources);
+    let graph = ModuleGraph::build(&modules);
+    let res = crate::resolve::resolve(&modules, &graph);
+    let typeck = crate::ty::check::check(&modules, &graph, &res);
+    let errors = crate::diag::error_count();
+    let borrows = infer(&modules, &graph, &res, &typeck);
+    let errors = crate::diag::error_count() - errors;
+
+    // The mode of every parameter, as `func.param`
+    let mut modes = Vec::new();
+    for decl in &modules[0].decls {
+        let funcs = match decl {
+            Declaration::Func(func) => std::slice::from_ref(func),
+            Declaration::Impl(imp) => &imp.methods[..],
+            _ => continue,
+        };
+        for func in funcs {
+            if let Some(mode) = borrows.bindings.get(&func.id) {
+                modes.push((format!("{}.self", func.sig.name.name), mode.name()));
+            }
+            for param in &func.sig.params {
+                let name = format!("{}.{}", func.sig.name.name, param.name.name);
+                modes.push((name, borrows.bindings[&param.id].name()));
+            }
+        }
+    }
+    (errors, modes)
+}
+
+#[test]
+fn infer_test() {
+    let (errors, modes) = infer_source(
+        r"identifier app.main
+obj list is
+    items: string[] = [],
+end
+impl list is
+    func add(string item) is
+        self.items.push(item)
+    end
+    func i32 size() is
+        return self.items.len()
+    end
+end
+func void fill(list l, string item) is
+    l.add(item)
+end
+func list keep(list l, i32 n) is
+    return l
+end
+func void start() is
+end",
+    );
+    assert_eq!(errors, 0);
+    let expected = [
+        ("add.self", "mutable"),
+        ("add.item", "owned"),
+        ("size.self", "shared"),
+        ("fill.l", "mutable"),
+        ("fill.item", "owned"),
+        ("keep.l", "owned"),
+        ("keep.n", "owned"),
+    ];
+    let expected: Vec<_> = expected.iter().map(|(x, y)| (x.to_string(), *y)).collect();
+    assert_eq!(modes, expected);
+}
+
+#[test]
+fn aliasing_test() {
+    let (errors, _) = infer_source(
+        r"identifier app.main
+obj list is
+    items: string[] = [],
+end
+impl list is
+    func merge(list other) is
+        self.items.push('x')
+    end
+    func i32 size() is
+        return self.items.len()
+    end
+end
+func void both(list a, list b) is
+    a.merge(b)
+    b.merge(a)
+end
+func void start() is
+    list l = list
+    l.merge(l)
+    both(l, l)
+    list m = list
+    both(m, list)
+    l.size()
+end",
+    );
+    // `l.merge(l)` and `both(l, l)`
+    assert_eq!(errors, 2);
+}
@@ -0,0 +1,111 @@
+#![deny(missing_docs)]
+//! Module for the functions and types every module can use without declaring or importing them.
+
+/// Name of the method that makes values of an object type callable: calling `value(args)` calls
+/// `value.op-call(args)`.
+pub const CALL_OPERATOR: &str = "op-call";
+
+/// Functions built into the language. Items declared in a module shadow these.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Builtin {
+    /// `print(str msg)`, writes `msg` to standard output.
+    Print,
+    /// `println()`, ends the current line of standard output.
+    Println,
+    /// `drop(value)`, ends the lifetime of `value`.
+    Drop,
+}
+
+impl Builtin {
+    /// Every builtin function.
+    pub const ALL: [Builtin; 3] = [Builtin::Print, Builtin::Println, Builtin::Drop];
+
+    /// Finds the builtin function called `name`.
+    pub fn from_name(name: &str) -> Option<Builtin> {
+        Self::ALL.into_iter().find(|x| x.name() == name)
+    }
+
+    /// Gets the name of the builtin function.
+    pub fn name(self) -> &'static str {
+        match self {
+            Builtin::Print => "print",
+            Builtin::Println => "println",
+            Builtin::Drop => "drop",
+        }
+    }
+}
+
+/// Types built into the language.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum PrimType {
+    /// 32-bit signed integer.
+    I32,
+    /// 32-bit floating point number.
+    F32,
+    /// Boolean, `true` or `false`.
+    Bool,
+    /// Borrowed, immutable string. The type of string literals.
+    Str,
+    /// Owned, growable string.
+    String,
+    /// Growable array, with an element type inferred from its uses.
+    Array,
+}
+
+impl PrimType {
+    /// Every built in type.
+    pub const ALL: [PrimType; 6] = [
+        PrimType::I32,
+        PrimType::F32,
+        PrimType::Bool,
+        PrimType::Str,
+        PrimType::String,
+        PrimType::Array,
+    ];
+
+    /// Finds the built in type called `name`.
+    pub fn from_name(name: &str) -> Option<PrimType> {
+        Self::ALL.into_iter().find(|x| x.name() == name)
+    }
+
+    /// Gets the name of the built in type.
+    pub fn name(self) -> &'static str {
+        match self {
+            PrimType::I32 => "i32",
+            PrimType::F32 => "f32",
+            PrimType::Bool => "bool",
+            PrimType::Str => "str",
+            PrimType::String => "string",
+            PrimType::Array => "array",
+        }
+    }
+}
+
+/// Methods built into the primitive types.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum BuiltinMethod {
+    /// `value.to-string()` on numbers and strings, converts the value to an owned `string`.
+    ToString,
+    /// `value.len()` on strings and arrays, gets the number of bytes or elements.
+    Len,
+    /// `array.push(item)`, appends `item` to the end of the array.
+    Push,
+}
+
+impl BuiltinMethod {
+    /// Every builtin method.
+    pub const ALL: [BuiltinMethod; 3] = [
+        BuiltinMethod::ToString,
+        BuiltinMethod::Len,
+        BuiltinMethod::Push,
+    ];
+
+    /// Gets the name of the builtin method.
+    pub fn name(self) -> &'static str {
+        match self {
+            BuiltinMethod::ToString => "to-string",
+            BuiltinMethod::Len => "len",
+            BuiltinMethod::Push => "push",
+        }
+    }
+}
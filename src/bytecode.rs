@@ -0,0 +1,332 @@
+#![deny(missing_docs)]
+//! Module for the bytecode run by the [virtual machine](vm).
+//!
+//! A [`Program`] holds a constant pool, the layout of every `obj` type and a table of functions,
+//! and is [compiled](from_mir) from the [IR](crate::mir) of a project, optimized or not.
+//! Functions are register based: every instruction names the registers it reads and writes, and
+//! every call gets a fresh set of registers, starting with its arguments (and the receiver of
+//! methods, before them).
+//!
+//! Instructions that consume a value ([`Call`](Instr::Call), [`New`](Instr::New),
+//! [`Array`](Instr::Array), [`Push`](Instr::Push), [`SetField`](Instr::SetField),
+//! [`Replace`](Instr::Replace) and [`Return`](Instr::Return)) move it out of its register, leaving
+//! the register empty. The others copy it, sharing strings, arrays and objects. The
+//! [lowering](crate::mir::lower) places [`Drop`](Instr::Drop)s where
+//! [ownership analysis](crate::ownership) ends the lifetime of variables, so values are freed at
+//! the same points in every run.
+
+use std::{
+    fmt::{self, Display},
+    io::{self, Write},
+    rc::Rc,
+};
+
+use crate::ast::BinOp;
+
+pub mod container;
+pub mod from_mir;
+pub mod vm;
+
+/// Index of a register in the registers of a call.
+pub type Reg = u32;
+
+/// A compiled program.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Program {
+    /// Values too big to fit in an instruction, used by [`Const`](Instr::Const).
+    pub constants: Vec<Constant>,
+    /// The `obj` types, used by [`New`](Instr::New).
+    pub types: Vec<TypeInfo>,
+    /// Every function and method, used by [`Call`](Instr::Call).
+    pub functions: Vec<Function>,
+    /// The source files, used by [`Function::file`].
+    pub files: Vec<SourceFile>,
+    /// Index of the `start` function.
+    pub entry: u32,
+}
+
+/// A source file a program was compiled from.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SourceFile {
+    /// Path of the file.
+    pub path: String,
+    /// CRC-32 checksum of the text the program was compiled from, to tell if the file changed
+    /// since.
+    pub checksum: u32,
+}
+
+/// A value in the constant pool.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Constant {
+    /// An `i32`.
+    Int(i32),
+    /// An `f32`.
+    Float(f32),
+    /// A string literal.
+    Str(Rc<str>),
+}
+
+/// The layout of an `obj` type.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TypeInfo {
+    /// Name of the type.
+    pub name: String,
+    /// Number of fields of the type.
+    pub fields: u32,
+}
+
+/// A compiled function or method.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Function {
+    /// Name of the function, `type.method` for methods.
+    pub name: String,
+    /// Number of parameters, counting the receiver of methods. They come in the first registers.
+    pub params: u32,
+    /// Number of registers a call needs.
+    pub registers: u32,
+    /// The instructions.
+    pub code: Vec<Instr>,
+    /// Where in the source each instruction was compiled from.
+    pub debug: Vec<DebugSpan>,
+    /// Index of the source file in [`Program::files`].
+    pub file: u32,
+}
+
+/// The area of the source an instruction was compiled from, so errors can point at the
+/// original [`Span`](crate::span::Span) even without the source at hand.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct DebugSpan {
+    /// Byte offset of the start of the span.
+    pub start: u32,
+    /// Byte offset of the end of the span.
+    pub end: u32,
+    /// Line of the start of the span, starting at 1.
+    pub line: u32,
+}
+
+/// A binary operator, applied by [`Binary`](Instr::Binary).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Op {
+    /// Addition of numbers, or concatenation of strings.
+    Add,
+    /// Subtraction.
+    Sub,
+    /// Multiplication.
+    Mul,
+    /// Division, which fails when dividing integers by zero.
+    Div,
+    /// Equality.
+    Eq,
+    /// Inequality.
+    Ne,
+    /// Less than.
+    Lt,
+    /// Less than or equal.
+    Le,
+    /// Greater than.
+    Gt,
+    /// Greater than or equal.
+    Ge,
+}
+
+impl Op {
+    /// Every operator.
+    pub const ALL: [Op; 10] = [
+        Op::Add,
+        Op::Sub,
+        Op::Mul,
+        Op::Div,
+        Op::Eq,
+        Op::Ne,
+        Op::Lt,
+        Op::Le,
+        Op::Gt,
+        Op::Ge,
+    ];
+
+    /// Gets the name of the operator, as shown by the disassembler.
+    pub fn name(self) -> &'static str {
+        match self {
+            Op::Add => "add",
+            Op::Sub => "sub",
+            Op::Mul => "mul",
+            Op::Div => "div",
+            Op::Eq => "eq",
+            Op::Ne => "ne",
+            Op::Lt => "lt",
+            Op::Le => "le",
+            Op::Gt => "gt",
+            Op::Ge => "ge",
+        }
+    }
+}
+
+/// An instruction. Jump targets are indices into [`Function::code`].
+// The operands are described by the documentation of their instruction
+#[allow(missing_docs)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Instr {
+    /// Loads the constant at `index` into `dst`.
+    Const { dst: Reg, index: u32 },
+    /// Loads a boolean into `dst`.
+    Bool { dst: Reg, value: bool },
+    /// Copies `src` into `dst`.
+    Copy { dst: Reg, src: Reg },
+    /// Moves `src` into `dst`.
+    Move { dst: Reg, src: Reg },
+    /// Drops the value in `reg`, if any.
+    Drop { reg: Reg },
+    /// Moves `src` into the array or object in `dst`, replacing its contents in place so the
+    /// caller sees the change, or into `dst` itself for other values.
+    Replace { dst: Reg, src: Reg },
+    /// Applies `op` to `lhs` and `rhs`, into `dst`.
+    Binary {
+        op: Op,
+        dst: Reg,
+        lhs: Reg,
+        rhs: Reg,
+    },
+    /// Negates the number in `src`, into `dst`.
+    Neg { dst: Reg, src: Reg },
+    /// Negates the boolean in `src`, into `dst`.
+    Not { dst: Reg, src: Reg },
+    /// Adds one to the number in `reg`.
+    Inc { reg: Reg },
+    /// Continues at `target`.
+    Jump { target: u32 },
+    /// Continues at `target` if `cond` is true.
+    JumpIf { cond: Reg, target: u32 },
+    /// Continues at `target` if `cond` is false.
+    JumpIfNot { cond: Reg, target: u32 },
+    /// Calls the function at `func` with the `argc` arguments starting at `args`, moving them
+    /// into the call. The result goes into `dst`.
+    Call {
+        func: u32,
+        args: Reg,
+        argc: u32,
+        dst: Reg,
+    },
+    /// Returns the value in `src`.
+    Return { src: Reg },
+    /// Returns from a `void` function.
+    ReturnVoid,
+    /// Writes the string in `src` to the output.
+    Print { src: Reg },
+    /// Ends the current line of the output.
+    Println,
+    /// Converts the value in `src` to a string, into `dst`.
+    ToString { dst: Reg, src: Reg },
+    /// Gets the length of the string or array in `src`, into `dst`.
+    Len { dst: Reg, src: Reg },
+    /// Moves `src` to the end of the array in `array`.
+    Push { array: Reg, src: Reg },
+    /// Makes an array of the `len` values starting at `start`, into `dst`.
+    Array { dst: Reg, start: Reg, len: u32 },
+    /// Gets the item at the integer `index` of the array in `array`, into `dst`.
+    Index { dst: Reg, array: Reg, index: Reg },
+    /// Makes an object of the type at `ty`, moving its fields from the registers starting at
+    /// `fields`, into `dst`.
+    New { dst: Reg, ty: u32, fields: Reg },
+    /// Gets a field of the object in `obj`, into `dst`.
+    GetField { dst: Reg, obj: Reg, field: u32 },
+    /// Moves `src` into a field of the object in `obj`.
+    SetField { obj: Reg, field: u32, src: Reg },
+    /// Stops the program, with the constant string at `message` as error.
+    Fail { message: u32 },
+}
+
+impl From<Op> for BinOp {
+    fn from(op: Op) -> Self {
+        match op {
+            Op::Add => BinOp::Add,
+            Op::Sub => BinOp::Sub,
+            Op::Mul => BinOp::Mul,
+            Op::Div => BinOp::Div,
+            Op::Eq => BinOp::Eq,
+            Op::Ne => BinOp::Ne,
+            Op::Lt => BinOp::Lt,
+            Op::Le => BinOp::Le,
+            Op::Gt => BinOp::Gt,
+            Op::Ge => BinOp::Ge,
+        }
+    }
+}
+
+impl Display for Instr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            Instr::Const { dst, index } => write!(f, "const r{dst}, #{index}"),
+            Instr::Bool { dst, value } => write!(f, "bool r{dst}, {value}"),
+            Instr::Copy { dst, src } => write!(f, "copy r{dst}, r{src}"),
+            Instr::Move { dst, src } => write!(f, "move r{dst}, r{src}"),
+            Instr::Drop { reg } => write!(f, "drop r{reg}"),
+            Instr::Replace { dst, src } => write!(f, "replace r{dst}, r{src}"),
+            Instr::Binary { op, dst, lhs, rhs } => {
+                write!(f, "{} r{dst}, r{lhs}, r{rhs}", op.name())
+            }
+            Instr::Neg { dst, src } => write!(f, "neg r{dst}, r{src}"),
+            Instr::Not { dst, src } => write!(f, "not r{dst}, r{src}"),
+            Instr::Inc { reg } => write!(f, "inc r{reg}"),
+            Instr::Jump { target } => write!(f, "jump @{target}"),
+            Instr::JumpIf { cond, target } => write!(f, "jump-if r{cond}, @{target}"),
+            Instr::JumpIfNot { cond, target } => write!(f, "jump-if-not r{cond}, @{target}"),
+            Instr::Call {
+                func,
+                args,
+                argc,
+                dst,
+            } => write!(f, "call r{dst}, f{func}, r{args}, {argc}"),
+            Instr::Return { src } => write!(f, "return r{src}"),
+            Instr::ReturnVoid => write!(f, "return"),
+            Instr::Print { src } => write!(f, "print r{src}"),
+            Instr::Println => write!(f, "println"),
+            Instr::ToString { dst, src } => write!(f, "to-string r{dst}, r{src}"),
+            Instr::Len { dst, src } => write!(f, "len r{dst}, r{src}"),
+            Instr::Push { array, src } => write!(f, "push r{array}, r{src}"),
+            Instr::Array { dst, start, len } => write!(f, "array r{dst}, r{start}, {len}"),
+            Instr::Index { dst, array, index } => write!(f, "index r{dst}, r{array}, r{index}"),
+            Instr::New { dst, ty, fields } => write!(f, "new r{dst}, t{ty}, r{fields}"),
+            Instr::GetField { dst, obj, field } => write!(f, "get-field r{dst}, r{obj}, {field}"),
+            Instr::SetField { obj, field, src } => write!(f, "set-field r{obj}, {field}, r{src}"),
+            Instr::Fail { message } => write!(f, "fail #{message}"),
+        }
+    }
+}
+
+impl Display for Constant {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Constant::Int(int) => write!(f, "i32 {int}"),
+            Constant::Float(float) => write!(f, "f32 {float:?}"),
+            Constant::Str(string) => write!(f, "str {string:?}"),
+        }
+    }
+}
+
+/// Writes a readable listing of `program`: its constants, types and the instructions of every
+/// function, with the line each instruction comes from.
+pub fn disassemble(program: &Program, out: &mut dyn Write) -> io::Result<()> {
+    for (index, constant) in program.constants.iter().enumerate() {
+        writeln!(out, "#{index} = {constant}")?;
+    }
+    for (index, ty) in program.types.iter().enumerate() {
+        writeln!(out, "t{index} = obj {} ({} fields)", ty.name, ty.fields)?;
+    }
+    for (index, func) in program.functions.iter().enumerate() {
+        let entry = if index as u32 == program.entry {
+            ", entry"
+        } else {
+            ""
+        };
+        writeln!(out)?;
+        writeln!(
+            out,
+            "f{index} = func {} ({} params, {} registers{entry}) in {}",
+            func.name, func.params, func.registers, program.files[func.file as usize].path
+        )?;
+        for (pc, (instr, debug)) in func.code.iter().zip(&func.debug).enumerate() {
+            writeln!(out, "  {pc:>4}  {:>4} | {instr}", debug.line)?;
+        }
+    }
+    Ok(())
+}
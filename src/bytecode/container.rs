@@ -0,0 +1,657 @@
+#![deny(missing_docs)]
+//! Module for the `.scpc` files holding compiled [bytecode](super), so a program can be compiled
+//! once and run many times.
+//!
+//! A file starts with the [`MAGIC`] bytes and the [`VERSION`] of the format, followed by the
+//! entry function, the source paths with their checksums, the constant pool, the types and the function table, with
+//! the debug span of every instruction. It ends with a CRC-32 checksum of everything before it.
+//! Numbers are little endian, and strings and lists are prefixed with their length as a `u32`.
+//!
+//! Loading a file checks the version before anything else, so files of other versions get a
+//! clear error instead of a confusing one, and then validates every index in the program, so
+//! a damaged file can't make the [virtual machine](super::vm) misbehave.
+
+use std::{fmt::Display, rc::Rc};
+
+use super::{Constant, DebugSpan, Function, Instr, Op, Program, Reg, SourceFile, TypeInfo};
+
+/// The bytes every `.scpc` file starts with.
+pub const MAGIC: [u8; 4] = *b"SCPC";
+
+/// The version of the format written by [`write`]. Only files of this version can be read.
+pub const VERSION: u16 = 3;
+
+/// The extension of compiled files.
+pub const EXTENSION: &str = "scpc";
+
+/// Errors that stop a `.scpc` file from being loaded.
+#[derive(Debug, Clone, PartialEq)]
+pub enum LoadError {
+    /// The file doesn't start with [`MAGIC`].
+    NotBytecode,
+    /// The file was written for another version of the format.
+    Version(u16),
+    /// The checksum doesn't match the contents.
+    Checksum,
+    /// The file ends in the middle of the program.
+    Truncated,
+    /// The file holds something no compiler writes, described by the message.
+    Invalid(String),
+}
+
+impl Display for LoadError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LoadError::NotBytecode => write!(f, "not a compiled Escoop program"),
+            LoadError::Version(version) => write!(
+                f,
+                "compiled for bytecode version {version}, but this version of esci runs version \
+                 {VERSION}; rebuild it with `esci build --target bytecode`"
+            ),
+            LoadError::Checksum => write!(f, "the file is corrupted, its checksum doesn't match"),
+            LoadError::Truncated => write!(f, "the file is truncated"),
+            LoadError::Invalid(msg) => write!(f, "invalid bytecode: {msg}"),
+        }
+    }
+}
+
+/// Serializes `program` to the `.scpc` format.
+pub fn write(program: &Program) -> Vec<u8> {
+    let mut out = Writer(Vec::new());
+    out.0.extend(MAGIC);
+    out.u16(VERSION);
+    out.u32(program.entry);
+    out.len(program.files.len());
+    for file in &program.files {
+        out.str(&file.path);
+        out.u32(file.checksum);
+    }
+    out.len(program.constants.len());
+    for constant in &program.constants {
+        match constant {
+            Constant::Int(int) => {
+                out.u8(0);
+                out.u32(*int as u32);
+            }
+            Constant::Float(float) => {
+                out.u8(1);
+                out.u32(float.to_bits());
+            }
+            Constant::Str(string) => {
+                out.u8(2);
+                out.str(string);
+            }
+        }
+    }
+    out.len(program.types.len());
+    for ty in &program.types {
+        out.str(&ty.name);
+        out.u32(ty.fields);
+    }
+    out.len(program.functions.len());
+    for func in &program.functions {
+        out.str(&func.name);
+        out.u32(func.params);
+        out.u32(func.registers);
+        out.u32(func.file);
+        out.len(func.code.len());
+        for instr in &func.code {
+            out.instr(*instr);
+        }
+        for debug in &func.debug {
+            out.u32(debug.start);
+            out.u32(debug.end);
+            out.u32(debug.line);
+        }
+    }
+    let checksum = crc32(&out.0);
+    out.u32(checksum);
+    out.0
+}
+
+/// Loads a program from the contents of a `.scpc` file.
+pub fn read(bytes: &[u8]) -> Result<Program, LoadError> {
+    if !bytes.starts_with(&MAGIC) {
+        return Err(LoadError::NotBytecode);
+    }
+    let mut input = Reader { bytes, pos: 4 };
+    let version = input.u16()?;
+    if version != VERSION {
+        return Err(LoadError::Version(version));
+    }
+    let Some(body) = bytes.len().checked_sub(4).filter(|x| *x >= input.pos) else {
+        return Err(LoadError::Truncated);
+    };
+    let checksum = u32::from_le_bytes(bytes[body..].try_into().unwrap());
+    if crc32(&bytes[..body]) != checksum {
+        return Err(LoadError::Checksum);
+    }
+    input.bytes = &bytes[..body];
+
+    let entry = input.u32()?;
+    let files = input.list(|x| {
+        Ok(SourceFile {
+            path: x.str()?,
+            checksum: x.u32()?,
+        })
+    })?;
+    let constants = input.list(|x| match x.u8()? {
+        0 => Ok(Constant::Int(x.u32()? as i32)),
+        1 => Ok(Constant::Float(f32::from_bits(x.u32()?))),
+        2 => Ok(Constant::Str(Rc::from(x.str()?))),
+        tag => Err(LoadError::Invalid(format!("unknown constant kind {tag}"))),
+    })?;
+    let types = input.list(|x| {
+        Ok(TypeInfo {
+            name: x.str()?,
+            fields: x.u32()?,
+        })
+    })?;
+    let functions = input.list(|x| {
+        let name = x.str()?;
+        let params = x.u32()?;
+        let registers = x.u32()?;
+        let file = x.u32()?;
+        let len = x.len()?;
+        let code = (0..len).map(|_| x.instr()).collect::<Result<_, _>>()?;
+        let debug = (0..len)
+            .map(|_| {
+                Ok(DebugSpan {
+                    start: x.u32()?,
+                    end: x.u32()?,
+                    line: x.u32()?,
+                })
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(Function {
+            name,
+            params,
+            registers,
+            code,
+            debug,
+            file,
+        })
+    })?;
+    if input.pos != input.bytes.len() {
+        return Err(LoadError::Invalid(
+            "unexpected data after the functions".to_owned(),
+        ));
+    }
+    let program = Program {
+        constants,
+        types,
+        functions,
+        files,
+        entry,
+    };
+    validate(&program)?;
+    Ok(program)
+}
+
+/// Checks that every index in `program` points at something, and that no function can run past
+/// its last instruction.
+fn validate(program: &Program) -> Result<(), LoadError> {
+    let invalid = |msg: String| Err(LoadError::Invalid(msg));
+    let Some(entry) = program.functions.get(program.entry as usize) else {
+        return invalid(format!("entry function {} doesn't exist", program.entry));
+    };
+    if entry.params != 0 {
+        return invalid("the entry function takes parameters".to_owned());
+    }
+    for func in &program.functions {
+        let name = &func.name;
+        if func.file as usize >= program.files.len() {
+            return invalid(format!(
+                "function `{name}` is in file {}, which doesn't exist",
+                func.file
+            ));
+        }
+        if func.params > func.registers {
+            return invalid(format!(
+                "function `{name}` has more parameters than registers"
+            ));
+        }
+        if !matches!(
+            func.code.last(),
+            Some(
+                Instr::Return { .. } | Instr::ReturnVoid | Instr::Jump { .. } | Instr::Fail { .. }
+            )
+        ) {
+            return invalid(format!(
+                "function `{name}` can run past its last instruction"
+            ));
+        }
+        for (pc, instr) in func.code.iter().enumerate() {
+            if let Err(msg) = check_instr(program, func, *instr) {
+                return invalid(format!("instruction {pc} of function `{name}` {msg}"));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Checks the operands of one instruction of `func`.
+fn check_instr(program: &Program, func: &Function, instr: Instr) -> Result<(), String> {
+    // Every instruction uses at most a few ranges of registers
+    let mut ranges: [(Reg, usize); 3] = [(0, 0); 3];
+    match instr {
+        Instr::Const { dst, index } => {
+            if index as usize >= program.constants.len() {
+                return Err(format!("loads constant {index}, which doesn't exist"));
+            }
+            ranges[0] = (dst, 1);
+        }
+        Instr::Fail { message } => {
+            if !matches!(
+                program.constants.get(message as usize),
+                Some(Constant::Str(_))
+            ) {
+                return Err(format!(
+                    "fails with constant {message}, which isn't a string"
+                ));
+            }
+        }
+        Instr::Bool { dst, .. } => ranges[0] = (dst, 1),
+        Instr::Drop { reg } | Instr::Inc { reg } => ranges[0] = (reg, 1),
+        Instr::Return { src } | Instr::Print { src } => ranges[0] = (src, 1),
+        Instr::ReturnVoid | Instr::Println => {}
+        Instr::Copy { dst, src }
+        | Instr::Move { dst, src }
+        | Instr::Replace { dst, src }
+        | Instr::Neg { dst, src }
+        | Instr::Not { dst, src }
+        | Instr::ToString { dst, src }
+        | Instr::Len { dst, src } => ranges[..2].copy_from_slice(&[(dst, 1), (src, 1)]),
+        Instr::Push { array, src } => ranges[..2].copy_from_slice(&[(array, 1), (src, 1)]),
+        Instr::Binary { dst, lhs, rhs, .. } => ranges = [(dst, 1), (lhs, 1), (rhs, 1)],
+        Instr::Index { dst, array, index } => ranges = [(dst, 1), (array, 1), (index, 1)],
+        Instr::GetField { dst, obj, .. } => ranges[..2].copy_from_slice(&[(dst, 1), (obj, 1)]),
+        Instr::SetField { obj, src, .. } => ranges[..2].copy_from_slice(&[(obj, 1), (src, 1)]),
+        Instr::Jump { target } | Instr::JumpIf { target, .. } | Instr::JumpIfNot { target, .. } => {
+            if target as usize >= func.code.len() {
+                return Err(format!("jumps to {target}, past the end of the function"));
+            }
+            if let Instr::JumpIf { cond, .. } | Instr::JumpIfNot { cond, .. } = instr {
+                ranges[0] = (cond, 1);
+            }
+        }
+        Instr::Call {
+            func: callee,
+            args,
+            argc,
+            dst,
+        } => {
+            if callee as usize >= program.functions.len() {
+                return Err(format!("calls function {callee}, which doesn't exist"));
+            }
+            ranges[..2].copy_from_slice(&[(args, argc as usize), (dst, 1)]);
+        }
+        Instr::Array { dst, start, len } => {
+            ranges[..2].copy_from_slice(&[(dst, 1), (start, len as usize)]);
+        }
+        Instr::New { dst, ty, fields } => {
+            let Some(info) = program.types.get(ty as usize) else {
+                return Err(format!("makes type {ty}, which doesn't exist"));
+            };
+            ranges[..2].copy_from_slice(&[(dst, 1), (fields, info.fields as usize)]);
+        }
+    }
+    for (start, len) in ranges {
+        if start as usize + len > func.registers as usize {
+            return Err(format!(
+                "uses register {}, past the registers of the function",
+                start as usize + len - 1
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// The CRC-32 lookup table, for the polynomial used by zlib and PNG.
+const CRC_TABLE: [u32; 256] = {
+    let mut table = [0; 256];
+    let mut index = 0;
+    while index < 256 {
+        let mut crc = index as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 == 1 {
+                0xEDB8_8320 ^ (crc >> 1)
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[index] = crc;
+        index += 1;
+    }
+    table
+};
+
+pub(crate) fn crc32(bytes: &[u8]) -> u32 {
+    let crc = bytes.iter().fold(!0, |crc: u32, byte| {
+        CRC_TABLE[((crc ^ *byte as u32) & 0xFF) as usize] ^ (crc >> 8)
+    });
+    !crc
+}
+
+struct Writer(Vec<u8>);
+
+impl Writer {
+    fn u8(&mut self, value: u8) {
+        self.0.push(value);
+    }
+
+    fn u16(&mut self, value: u16) {
+        self.0.extend(value.to_le_bytes());
+    }
+
+    fn u32(&mut self, value: u32) {
+        self.0.extend(value.to_le_bytes());
+    }
+
+    fn len(&mut self, len: usize) {
+        self.u32(len as u32);
+    }
+
+    fn str(&mut self, string: &str) {
+        self.len(string.len());
+        self.0.extend(string.as_bytes());
+    }
+
+    fn instr(&mut self, instr: Instr) {
+        match instr {
+            Instr::Const { dst, index } => {
+                self.u8(0);
+                self.u32(dst);
+                self.u32(index);
+            }
+            Instr::Bool { dst, value } => {
+                self.u8(1);
+                self.u32(dst);
+                self.u8(value as u8);
+            }
+            Instr::Copy { dst, src } => self.regs(2, &[dst, src]),
+            Instr::Move { dst, src } => self.regs(3, &[dst, src]),
+            Instr::Drop { reg } => self.regs(4, &[reg]),
+            Instr::Replace { dst, src } => self.regs(5, &[dst, src]),
+            Instr::Binary { op, dst, lhs, rhs } => {
+                self.u8(6);
+                self.u8(Op::ALL.iter().position(|x| *x == op).unwrap() as u8);
+                self.u32(dst);
+                self.u32(lhs);
+                self.u32(rhs);
+            }
+            Instr::Neg { dst, src } => self.regs(7, &[dst, src]),
+            Instr::Not { dst, src } => self.regs(8, &[dst, src]),
+            Instr::Inc { reg } => self.regs(9, &[reg]),
+            Instr::Jump { target } => {
+                self.u8(10);
+                self.u32(target);
+            }
+            Instr::JumpIf { cond, target } => {
+                self.regs(11, &[cond]);
+                self.u32(target);
+            }
+            Instr::JumpIfNot { cond, target } => {
+                self.regs(12, &[cond]);
+                self.u32(target);
+            }
+            Instr::Call {
+                func,
+                args,
+                argc,
+                dst,
+            } => {
+                self.u8(13);
+                self.u32(func);
+                self.u32(args);
+                self.u32(argc);
+                self.u32(dst);
+            }
+            Instr::Return { src } => self.regs(14, &[src]),
+            Instr::ReturnVoid => self.u8(15),
+            Instr::Print { src } => self.regs(16, &[src]),
+            Instr::Println => self.u8(17),
+            Instr::ToString { dst, src } => self.regs(18, &[dst, src]),
+            Instr::Len { dst, src } => self.regs(19, &[dst, src]),
+            Instr::Push { array, src } => self.regs(20, &[array, src]),
+            Instr::Array { dst, start, len } => self.regs(21, &[dst, start, len]),
+            Instr::Index { dst, array, index } => self.regs(22, &[dst, array, index]),
+            Instr::New { dst, ty, fields } => {
+                self.regs(23, &[dst]);
+                self.u32(ty);
+                self.u32(fields);
+            }
+            Instr::GetField { dst, obj, field } => self.regs(24, &[dst, obj, field]),
+            Instr::SetField { obj, field, src } => self.regs(25, &[obj, field, src]),
+            Instr::Fail { message } => {
+                self.u8(26);
+                self.u32(message);
+            }
+        }
+    }
+
+    /// Writes an opcode followed by `u32` operands.
+    fn regs(&mut self, opcode: u8, regs: &[u32]) {
+        self.u8(opcode);
+        for reg in regs {
+            self.u32(*reg);
+        }
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl Reader<'_> {
+    fn take<const N: usize>(&mut self) -> Result<[u8; N], LoadError> {
+        let bytes = self.bytes.get(self.pos..self.pos + N);
+        let bytes = bytes.ok_or(LoadError::Truncated)?;
+        self.pos += N;
+        Ok(bytes.try_into().unwrap())
+    }
+
+    fn u8(&mut self) -> Result<u8, LoadError> {
+        Ok(self.take::<1>()?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, LoadError> {
+        Ok(u16::from_le_bytes(self.take()?))
+    }
+
+    fn u32(&mut self) -> Result<u32, LoadError> {
+        Ok(u32::from_le_bytes(self.take()?))
+    }
+
+    fn len(&mut self) -> Result<usize, LoadError> {
+        let len = self.u32()? as usize;
+        // Every item takes at least a byte, so longer lengths can only come from damaged files
+        if len > self.bytes.len() - self.pos {
+            return Err(LoadError::Truncated);
+        }
+        Ok(len)
+    }
+
+    fn str(&mut self) -> Result<String, LoadError> {
+        let len = self.len()?;
+        let bytes = &self.bytes[self.pos..self.pos + len];
+        self.pos += len;
+        String::from_utf8(bytes.to_vec())
+            .map_err(|_| LoadError::Invalid("a string isn't valid UTF-8".to_owned()))
+    }
+
+    fn list<T>(
+        &mut self,
+        mut item: impl FnMut(&mut Self) -> Result<T, LoadError>,
+    ) -> Result<Vec<T>, LoadError> {
+        let len = self.len()?;
+        (0..len).map(|_| item(self)).collect()
+    }
+
+    fn instr(&mut self) -> Result<Instr, LoadError> {
+        let instr = match self.u8()? {
+            0 => Instr::Const {
+                dst: self.u32()?,
+                index: self.u32()?,
+            },
+            1 => Instr::Bool {
+                dst: self.u32()?,
+                value: self.u8()? != 0,
+            },
+            2 => Instr::Copy {
+                dst: self.u32()?,
+                src: self.u32()?,
+            },
+            3 => Instr::Move {
+                dst: self.u32()?,
+                src: self.u32()?,
+            },
+            4 => Instr::Drop { reg: self.u32()? },
+            5 => Instr::Replace {
+                dst: self.u32()?,
+                src: self.u32()?,
+            },
+            6 => {
+                let op = self.u8()?;
+                let Some(op) = Op::ALL.get(op as usize) else {
+                    return Err(LoadError::Invalid(format!("unknown operator {op}")));
+                };
+                Instr::Binary {
+                    op: *op,
+                    dst: self.u32()?,
+                    lhs: self.u32()?,
+                    rhs: self.u32()?,
+                }
+            }
+            7 => Instr::Neg {
+                dst: self.u32()?,
+                src: self.u32()?,
+            },
+            8 => Instr::Not {
+                dst: self.u32()?,
+                src: self.u32()?,
+            },
+            9 => Instr::Inc { reg: self.u32()? },
+            10 => Instr::Jump {
+                target: self.u32()?,
+            },
+            11 => Instr::JumpIf {
+                cond: self.u32()?,
+                target: self.u32()?,
+            },
+            12 => Instr::JumpIfNot {
+                cond: self.u32()?,
+                target: self.u32()?,
+            },
+            13 => Instr::Call {
+                func: self.u32()?,
+                args: self.u32()?,
+                argc: self.u32()?,
+                dst: self.u32()?,
+            },
+            14 => Instr::Return { src: self.u32()? },
+            15 => Instr::ReturnVoid,
+            16 => Instr::Print { src: self.u32()? },
+            17 => Instr::Println,
+            18 => Instr::ToString {
+                dst: self.u32()?,
+                src: self.u32()?,
+            },
+            19 => Instr::Len {
+                dst: self.u32()?,
+                src: self.u32()?,
+            },
+            20 => Instr::Push {
+                array: self.u32()?,
+                src: self.u32()?,
+            },
+            21 => Instr::Array {
+                dst: self.u32()?,
+                start: self.u32()?,
+                len: self.u32()?,
+            },
+            22 => Instr::Index {
+                dst: self.u32()?,
+                array: self.u32()?,
+                index: self.u32()?,
+            },
+            23 => Instr::New {
+                dst: self.u32()?,
+                ty: self.u32()?,
+                fields: self.u32()?,
+            },
+            24 => Instr::GetField {
+                dst: self.u32()?,
+                obj: self.u32()?,
+                field: self.u32()?,
+            },
+            25 => Instr::SetField {
+                obj: self.u32()?,
+                field: self.u32()?,
+                src: self.u32()?,
+            },
+            26 => Instr::Fail {
+                message: self.u32()?,
+            },
+            opcode => return Err(LoadError::Invalid(format!("unknown opcode {opcode}"))),
+        };
+        Ok(instr)
+    }
+}
+
+/// A program with a constant of every kind, a type and a call.
+#[cfg(test)]
+fn sample() -> Program {
+    let text = r"identifier app.main
+obj point is
+    x: f32 = 0.5,
+end
+func i32 twice(i32 n) is
+    return n * 2
+end
+func void start() is
+    point p = point
+    print(twice(21).to-string() + ' ' + p.x.to-string() + ' done')
+end";
+    super::from_mir::compile_source(text)
+}
+
+#[test]
+fn round_trip_test() {
+    let program = sample();
+    let bytes = write(&program);
+    assert_eq!(read(&bytes), Ok(program.clone()));
+
+    let mut out = Vec::new();
+    super::vm::run(&read(&bytes).unwrap(), &mut out).unwrap();
+    assert_eq!(out, b"42 0.5 done");
+}
+
+#[test]
+fn load_error_test() {
+    let bytes = write(&sample());
+    assert_eq!(read(b"\x7fELF"), Err(LoadError::NotBytecode));
+
+    let mut newer = bytes.clone();
+    newer[4..6].copy_from_slice(&(VERSION + 1).to_le_bytes());
+    assert_eq!(read(&newer), Err(LoadError::Version(VERSION + 1)));
+
+    let mut damaged = bytes.clone();
+    damaged[20] ^= 1;
+    assert_eq!(read(&damaged), Err(LoadError::Checksum));
+    assert_eq!(read(&bytes[..5]), Err(LoadError::Truncated));
+
+    // A jump out of the function, with a valid checksum
+    let mut program = sample();
+    program.functions[0]
+        .code
+        .insert(0, Instr::Jump { target: 9999 });
+    program.functions[0].debug.insert(0, DebugSpan::default());
+    let Err(LoadError::Invalid(msg)) = read(&write(&program)) else {
+        panic!("jumps out of functions are invalid");
+    };
+    assert!(msg.contains("jumps to 9999"), "{msg}");
+}
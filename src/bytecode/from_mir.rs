@@ -0,0 +1,470 @@
+#![deny(missing_docs)]
+//! Module for compiling the [mid-level IR](crate::mir) of a project to [bytecode](super), the
+//! only way programs are compiled for the [virtual machine](super::vm).
+//!
+//! Every local gets the register of the same index, and operands that aren't in a local of
+//! their own are loaded into scratch registers above them. Values consumed by an instruction
+//! are moved out of their local for [`Operand::Move`], and copied into a scratch register first
+//! for [`Operand::Copy`], so the local keeps its value. Registers count the references to their
+//! values, so [sharing](Rvalue::Share) a value copies it, and [taking](Rvalue::Take) an item
+//! shares it, leaving the array as it was. Blocks are laid out in order, falling through to the
+//! next block where they can.
+
+use std::{collections::HashMap, rc::Rc};
+
+use super::{Constant, DebugSpan, Function, Instr, Op, Program, Reg, SourceFile, TypeInfo};
+use crate::{
+    ast::{BinOp, UnOp},
+    mir::{self, Callee, Const, Operand, Rvalue, StatementKind, Terminator},
+    project::Module,
+    span::Span,
+};
+
+/// Compiles `program`, lowered from `modules`.
+pub fn compile(program: &mir::Program, modules: &[Module]) -> Program {
+    let mut compiler = Compiler {
+        program,
+        constants: Vec::new(),
+        interned: HashMap::new(),
+    };
+    let functions = program
+        .functions
+        .iter()
+        .map(|func| {
+            let file = modules
+                .iter()
+                .position(|x| std::ptr::eq(x.src, func.span.src))
+                .unwrap_or(0);
+            compiler.function(func, file as u32)
+        })
+        .collect();
+    Program {
+        constants: compiler.constants,
+        types: program
+            .types
+            .iter()
+            .map(|x| TypeInfo {
+                name: short_name(&x.name).to_owned(),
+                fields: x.fields.len() as u32,
+            })
+            .collect(),
+        functions,
+        files: modules
+            .iter()
+            .map(|x| SourceFile {
+                path: x.src.path().display().to_string(),
+                checksum: super::container::crc32(x.src.text().as_bytes()),
+            })
+            .collect(),
+        entry: program.entry.0 as u32,
+    }
+}
+
+/// Strips the module from the name of a function or type, as the bytecode names them.
+fn short_name(name: &str) -> &str {
+    name.rsplit_once('/').map_or(name, |x| x.1)
+}
+
+fn debug_span(span: Span) -> DebugSpan {
+    DebugSpan {
+        start: span.start,
+        end: span.end,
+        line: span.get_start_code_pos().0,
+    }
+}
+
+/// A constant, hashable so equal constants share their index.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum ConstKey {
+    Int(i32),
+    /// The bits of an `f32`.
+    Float(u32),
+    Str(Rc<str>),
+}
+
+struct Compiler<'a, 'src> {
+    program: &'a mir::Program<'src>,
+    constants: Vec<Constant>,
+    interned: HashMap<ConstKey, u32>,
+}
+
+/// The state of the function being compiled.
+struct FuncState<'c, 'a, 'src> {
+    compiler: &'c mut Compiler<'a, 'src>,
+    code: Vec<Instr>,
+    debug: Vec<DebugSpan>,
+    /// Where the instructions being emitted come from.
+    span: DebugSpan,
+    /// The first free scratch register of the current statement, after the locals.
+    next: Reg,
+    /// How many registers the function needs.
+    registers: Reg,
+}
+
+impl Compiler<'_, '_> {
+    fn constant(&mut self, key: ConstKey) -> u32 {
+        if let Some(index) = self.interned.get(&key) {
+            return *index;
+        }
+        let constant = match &key {
+            ConstKey::Int(int) => Constant::Int(*int),
+            ConstKey::Float(bits) => Constant::Float(f32::from_bits(*bits)),
+            ConstKey::Str(string) => Constant::Str(string.clone()),
+        };
+        let index = self.constants.len() as u32;
+        self.constants.push(constant);
+        self.interned.insert(key, index);
+        index
+    }
+
+    fn function(&mut self, func: &mir::Function, file: u32) -> Function {
+        let scratch = func.locals.len() as Reg;
+        let mut state = FuncState {
+            compiler: self,
+            code: Vec::new(),
+            debug: Vec::new(),
+            span: debug_span(func.span),
+            next: scratch,
+            registers: scratch,
+        };
+        let mut starts = Vec::with_capacity(func.blocks.len());
+        // Jumps are emitted with the index of their target block, then patched to its start
+        let mut jumps = Vec::new();
+        for (index, block) in func.blocks.iter().enumerate() {
+            starts.push(state.code.len() as u32);
+            for stmt in &block.stmts {
+                state.span = debug_span(stmt.span);
+                state.next = scratch;
+                state.statement(&stmt.kind);
+            }
+            state.next = scratch;
+            let next = mir::BlockId(index + 1);
+            match &block.terminator {
+                Terminator::Goto(target) => {
+                    if *target != next {
+                        jumps.push(state.emit(Instr::Jump {
+                            target: target.0 as u32,
+                        }));
+                    }
+                }
+                Terminator::Branch { cond, then, els } => {
+                    let cond = state.read(cond);
+                    jumps.push(state.emit(Instr::JumpIfNot {
+                        cond,
+                        target: els.0 as u32,
+                    }));
+                    if *then != next {
+                        jumps.push(state.emit(Instr::Jump {
+                            target: then.0 as u32,
+                        }));
+                    }
+                }
+                Terminator::Return(Some(value)) => {
+                    let src = state.consume(value);
+                    state.emit(Instr::Return { src });
+                }
+                Terminator::Return(None) => {
+                    state.emit(Instr::ReturnVoid);
+                }
+                Terminator::Unreachable => {
+                    let message = state.string("entered unreachable code".into());
+                    state.emit(Instr::Fail { message });
+                }
+            }
+        }
+        for at in jumps {
+            match &mut state.code[at] {
+                Instr::Jump { target } | Instr::JumpIfNot { target, .. } => {
+                    *target = starts[*target as usize]
+                }
+                _ => unreachable!("only jumps are patched"),
+            }
+        }
+        Function {
+            name: short_name(&func.name).to_owned(),
+            params: func.params as u32,
+            registers: state.registers,
+            code: state.code,
+            debug: state.debug,
+            file,
+        }
+    }
+}
+
+impl FuncState<'_, '_, '_> {
+    fn emit(&mut self, instr: Instr) -> usize {
+        self.code.push(instr);
+        self.debug.push(self.span);
+        self.code.len() - 1
+    }
+
+    /// Allocates `count` consecutive scratch registers.
+    fn alloc(&mut self, count: usize) -> Reg {
+        let reg = self.next;
+        self.next += count as Reg;
+        self.registers = self.registers.max(self.next);
+        reg
+    }
+
+    fn string(&mut self, string: Rc<str>) -> u32 {
+        self.compiler.constant(ConstKey::Str(string))
+    }
+
+    /// Loads a constant into `dst`.
+    fn load(&mut self, constant: &Const, dst: Reg) {
+        let index = match constant {
+            Const::Bool(value) => {
+                self.emit(Instr::Bool { dst, value: *value });
+                return;
+            }
+            Const::Int(int) => self.compiler.constant(ConstKey::Int(*int)),
+            Const::Float(float) => self.compiler.constant(ConstKey::Float(float.to_bits())),
+            Const::Str(string) => self.string(string.clone()),
+        };
+        self.emit(Instr::Const { dst, index });
+    }
+
+    /// Gets a register holding the operand, to borrow it.
+    fn read(&mut self, operand: &Operand) -> Reg {
+        match operand {
+            Operand::Copy(local) | Operand::Move(local) => local.0 as Reg,
+            Operand::Const(constant) => {
+                let reg = self.alloc(1);
+                self.load(constant, reg);
+                reg
+            }
+        }
+    }
+
+    /// Gets a register holding the operand, for an instruction moving it out.
+    fn consume(&mut self, operand: &Operand) -> Reg {
+        match operand {
+            Operand::Move(local) => local.0 as Reg,
+            _ => {
+                let reg = self.alloc(1);
+                self.consume_into(operand, reg);
+                reg
+            }
+        }
+    }
+
+    /// Puts the operand in `dst`, sharing copied values.
+    fn consume_into(&mut self, operand: &Operand, dst: Reg) {
+        match operand {
+            Operand::Copy(local) => {
+                self.emit(Instr::Copy {
+                    dst,
+                    src: local.0 as Reg,
+                });
+            }
+            Operand::Move(local) => {
+                self.emit(Instr::Move {
+                    dst,
+                    src: local.0 as Reg,
+                });
+            }
+            Operand::Const(constant) => self.load(constant, dst),
+        }
+    }
+
+    /// Puts the operands in consecutive registers, for an instruction moving them out.
+    fn consume_all(&mut self, operands: &[Operand]) -> Reg {
+        let start = self.alloc(operands.len());
+        for (index, operand) in operands.iter().enumerate() {
+            self.consume_into(operand, start + index as Reg);
+        }
+        start
+    }
+
+    fn statement(&mut self, stmt: &StatementKind) {
+        match stmt {
+            StatementKind::Assign(dst, rvalue) => self.rvalue(rvalue, dst.0 as Reg),
+            StatementKind::Call { dst, callee, args } => match *callee {
+                Callee::Func(func) => {
+                    let argc = args.len() as u32;
+                    let args = self.consume_all(args);
+                    let ret = &self.compiler.program.functions[func.0].ret;
+                    let (dst, ignored) = match dst {
+                        Some(dst) => (dst.0 as Reg, false),
+                        None => (self.alloc(1), ret.is_ref()),
+                    };
+                    self.emit(Instr::Call {
+                        func: func.0 as u32,
+                        args,
+                        argc,
+                        dst,
+                    });
+                    if ignored {
+                        self.emit(Instr::Drop { reg: dst });
+                    }
+                }
+                Callee::Extern(func) => {
+                    let message = format!(
+                        "extern function `{}` isn't available in the virtual machine",
+                        self.compiler.program.externs[func.0].name
+                    );
+                    let message = self.string(message.into());
+                    self.emit(Instr::Fail { message });
+                }
+            },
+            StatementKind::SetField { obj, field, value } => {
+                let src = self.consume(value);
+                self.emit(Instr::SetField {
+                    obj: obj.0 as Reg,
+                    field: *field as u32,
+                    src,
+                });
+            }
+            StatementKind::Replace { dst, value } => {
+                let src = self.consume(value);
+                self.emit(Instr::Replace {
+                    dst: dst.0 as Reg,
+                    src,
+                });
+            }
+            StatementKind::Push { array, value } => {
+                let src = self.consume(value);
+                self.emit(Instr::Push {
+                    array: array.0 as Reg,
+                    src,
+                });
+            }
+            StatementKind::Print(value) => {
+                let src = self.read(value);
+                self.emit(Instr::Print { src });
+            }
+            StatementKind::Println => {
+                self.emit(Instr::Println);
+            }
+            StatementKind::Drop(local) => {
+                self.emit(Instr::Drop {
+                    reg: local.0 as Reg,
+                });
+            }
+        }
+    }
+
+    fn rvalue(&mut self, rvalue: &Rvalue, dst: Reg) {
+        let instr = match rvalue {
+            Rvalue::Use(operand) | Rvalue::Share(operand) => {
+                return self.consume_into(operand, dst);
+            }
+            Rvalue::Binary(op, lhs, rhs) => Instr::Binary {
+                op: match op {
+                    BinOp::Add => Op::Add,
+                    BinOp::Sub => Op::Sub,
+                    BinOp::Mul => Op::Mul,
+                    BinOp::Div => Op::Div,
+                    BinOp::Eq => Op::Eq,
+                    BinOp::Ne => Op::Ne,
+                    BinOp::Lt => Op::Lt,
+                    BinOp::Le => Op::Le,
+                    BinOp::Gt => Op::Gt,
+                    BinOp::Ge => Op::Ge,
+                    BinOp::And | BinOp::Or => {
+                        unreachable!("`and` and `or` are lowered to branches")
+                    }
+                },
+                dst,
+                lhs: self.read(lhs),
+                rhs: self.read(rhs),
+            },
+            Rvalue::Unary(UnOp::Neg, operand) => Instr::Neg {
+                dst,
+                src: self.read(operand),
+            },
+            Rvalue::Unary(UnOp::Not, operand) => Instr::Not {
+                dst,
+                src: self.read(operand),
+            },
+            Rvalue::ToString(operand) => Instr::ToString {
+                dst,
+                src: self.read(operand),
+            },
+            Rvalue::Len(operand) => Instr::Len {
+                dst,
+                src: self.read(operand),
+            },
+            Rvalue::Array(_, items) => Instr::Array {
+                dst,
+                start: self.consume_all(items),
+                len: items.len() as u32,
+            },
+            Rvalue::New(ty, fields) => Instr::New {
+                dst,
+                ty: ty.0 as u32,
+                fields: self.consume_all(fields),
+            },
+            Rvalue::Field(obj, field) => Instr::GetField {
+                dst,
+                obj: self.read(obj),
+                field: *field as u32,
+            },
+            Rvalue::Index(array, index) | Rvalue::Take(array, index) => Instr::Index {
+                dst,
+                array: self.read(array),
+                index: self.read(index),
+            },
+        };
+        self.emit(instr);
+    }
+}
+
+#[cfg(test)]
+pub(crate) fn compile_source(text: &str) -> Program {
+    crate::interp::with_checked(text, |modules, info| {
+        let program = mir::lower::lower(modules, info, crate::modules::ModuleId(0)).unwrap();
+        compile(&program, modules)
+    })
+}
+
+#[test]
+fn drop_test() {
+    let program = compile_source(
+        r"identifier app.main
+obj log is
+    lines: string[] = [],
+end
+func void consume(log l) is
+end
+func void start() is
+    log a = log
+    log b = log
+    consume(b)
+    if true is
+        string[] c = ['x']
+        print('y')
+    end
+    print('z')
+end",
+    );
+    let start = &program.functions[program.entry as usize];
+    let code: Vec<_> = start.code.iter().map(|x| x.to_string()).collect();
+    // Unused variables are dropped right away, `b` after its last use, and neither again when
+    // the function ends
+    assert_eq!(
+        code,
+        [
+            "array r0, r5, 0",
+            "move r5, r0",
+            "new r1, t0, r5",
+            "drop r1",
+            "array r2, r5, 0",
+            "move r5, r2",
+            "new r3, t0, r5",
+            "copy r5, r3",
+            "call r6, f0, r5, 1",
+            "drop r3",
+            "bool r5, true",
+            "jump-if-not r5, @17",
+            "const r5, #0",
+            "array r4, r5, 1",
+            "drop r4",
+            "const r5, #1",
+            "print r5",
+            "const r5, #2",
+            "print r5",
+            "return",
+        ]
+    );
+}
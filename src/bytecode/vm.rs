@@ -0,0 +1,710 @@
+#![deny(missing_docs)]
+//! Module for running [bytecode](super) programs.
+//!
+//! The virtual machine keeps the registers of every running call on one stack, and runs the
+//! instructions of the innermost call in a dispatch loop. Returning truncates the stack to the
+//! registers of the caller, dropping whatever the call still held.
+//!
+//! # Cycle detection
+//! Like the [interpreter](crate::interp), the virtual machine remembers every object it allocates
+//! in debug builds. Once the entry function returns, its registers are dropped, so the objects
+//! still alive are kept by reference cycles: they are returned as [`Leak`]s, which
+//! [`report_leaks`] reports as warnings.
+
+use std::{
+    cell::RefCell,
+    fmt::{self, Display},
+    fs,
+    io::Write,
+    rc::{Rc, Weak},
+};
+
+use codespan_reporting::diagnostic::Label;
+
+use super::{Constant, DebugSpan, Instr, Op, Program, Reg, container::crc32};
+use crate::{
+    Source,
+    diag::{Diag, MAX_CALLS},
+    interp::{CYCLE_NOTE, compare},
+    span::Span,
+};
+
+/// How deep calls can nest before the program is stopped. Calls live on the heap rather than the
+/// native stack, so they can nest much deeper than in the [interpreter](crate::interp).
+const MAX_DEPTH: usize = 1 << 18;
+
+/// A value in a register.
+#[derive(Debug, Clone, Default)]
+enum Value {
+    /// An empty register, or the result of a `void` call.
+    #[default]
+    Void,
+    Int(i32),
+    Float(f32),
+    Bool(bool),
+    Str(Rc<str>),
+    Array(Rc<RefCell<Vec<Value>>>),
+    /// An object, with its fields in declaration order.
+    Obj(Rc<RefCell<Vec<Value>>>),
+}
+
+/// An error that stopped the program, like a division by zero.
+#[derive(Debug, Clone, PartialEq)]
+pub struct VmError {
+    /// What went wrong.
+    pub message: String,
+    /// Where each running call was, innermost first.
+    pub trace: Vec<Location>,
+}
+
+/// An instruction of a running call.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Location {
+    /// Name of the function.
+    pub function: String,
+    /// Path of its source file.
+    pub file: String,
+    /// Checksum of the source file the program was compiled from.
+    pub checksum: u32,
+    /// Where in the source the instruction comes from.
+    pub span: DebugSpan,
+}
+
+impl VmError {
+    /// Reports the error through [`Diag`], pointing at the source of the failing instruction if
+    /// it can still be read and is unchanged, or prints it with the lines of the calls otherwise.
+    pub fn emit(&self) {
+        let Some(location) = self.trace.first() else {
+            eprintln!("error: {self}");
+            return;
+        };
+        let Ok(text) = fs::read_to_string(&location.file) else {
+            eprintln!("error: {self}");
+            return;
+        };
+        // The spans only fit the text the program was compiled from
+        if crc32(text.as_bytes()) != location.checksum {
+            eprintln!("error: {self}");
+            eprintln!(
+                "note: `{}` changed since the program was compiled, so its code isn't shown",
+                location.file
+            );
+            return;
+        }
+        let src = Source::new(&text, &location.file);
+        let len = text.len() as u32;
+        let span = |x: &Location| Span::new_from(&src, x.span.start.min(len), x.span.end.min(len));
+        // Labels can only point into the file of the diagnostic
+        let calls = self.trace[1..].iter().filter(|x| x.file == location.file);
+        Diag::error(&src)
+            .with_message(&self.message)
+            .with_label(Label::primary((), span(location)))
+            .with_calls(calls.map(span))
+            .finish()
+            .emit();
+    }
+}
+
+impl Display for VmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message)?;
+        // Recursive calls are written once, with how many times they repeat
+        let mut lines = 0;
+        let mut index = 0;
+        while index < self.trace.len() && lines < MAX_CALLS {
+            let location = &self.trace[index];
+            let count = self.trace[index..]
+                .iter()
+                .take_while(|x| *x == location)
+                .count();
+            write!(
+                f,
+                "\n  in {} at {}:{}",
+                location.function, location.file, location.span.line
+            )?;
+            if count > 1 {
+                write!(f, " ({count} times)")?;
+            }
+            lines += 1;
+            index += count;
+        }
+        if index < self.trace.len() {
+            write!(f, "\n  ... and {} more calls", self.trace.len() - index)?;
+        }
+        Ok(())
+    }
+}
+
+/// Objects allocated by the same instruction, which a reference cycle kept alive after the
+/// program was done.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Leak {
+    /// Name of the type of the objects.
+    pub ty: String,
+    /// The instruction that allocated them.
+    pub location: Location,
+    /// How many objects leaked.
+    pub count: usize,
+}
+
+/// Reports objects leaked by reference cycles, as warnings pointing at where they were allocated
+/// if the source can still be read and is unchanged.
+pub fn report_leaks(leaks: &[Leak]) {
+    for leak in leaks {
+        let what = match leak.count {
+            1 => format!("a value of type `{}` was", leak.ty),
+            count => format!("{count} values of type `{}` were", leak.ty),
+        };
+        let message = format!("{what} leaked by a reference cycle");
+        let location = &leak.location;
+        let text = fs::read_to_string(&location.file)
+            .ok()
+            .filter(|x| crc32(x.as_bytes()) == location.checksum);
+        let Some(text) = text else {
+            eprintln!(
+                "warning[rc-cycle]: {message}\n  allocated in {} at {}:{}",
+                location.function, location.file, location.span.line
+            );
+            continue;
+        };
+        let src = Source::new(&text, &location.file);
+        let len = text.len() as u32;
+        let span = Span::new_from(
+            &src,
+            location.span.start.min(len),
+            location.span.end.min(len),
+        );
+        Diag::warn(&src)
+            .with_code("rc-cycle")
+            .with_message(message)
+            .with_label(Label::primary((), span).with_message("allocated here"))
+            .with_note(CYCLE_NOTE)
+            .finish()
+            .emit();
+    }
+}
+
+/// A running call.
+struct Frame {
+    func: usize,
+    /// Index of the next instruction.
+    pc: usize,
+    /// Index of the first register of the call on the stack.
+    base: usize,
+    /// Register of the caller receiving the result.
+    dst: usize,
+}
+
+/// Runs `program` from its entry function, writing what it prints to `out`, and returns the
+/// objects leaked by reference cycles if they are looked for.
+pub fn run(program: &Program, out: &mut dyn Write) -> Result<Vec<Leak>, VmError> {
+    let constants: Vec<_> = program
+        .constants
+        .iter()
+        .map(|x| match x {
+            Constant::Int(int) => Value::Int(*int),
+            Constant::Float(float) => Value::Float(*float),
+            Constant::Str(string) => Value::Str(string.clone()),
+        })
+        .collect();
+    let mut vm = Vm {
+        program,
+        constants,
+        out,
+        regs: Vec::new(),
+        frames: Vec::new(),
+        allocs: cfg!(debug_assertions).then(Vec::new),
+    };
+    let result = vm.run();
+    let flushed = vm.out.flush();
+    result?;
+    flushed.map_err(|err| vm.error(format!("could not write the output: {err}")))?;
+    Ok(vm.leaks())
+}
+
+struct Vm<'a> {
+    program: &'a Program,
+    constants: Vec<Value>,
+    out: &'a mut dyn Write,
+    regs: Vec<Value>,
+    /// The running calls, innermost last.
+    frames: Vec<Frame>,
+    /// Every object allocated so far, if cycles are looked for.
+    allocs: Option<Vec<Alloc>>,
+}
+
+/// An object allocated by the program, remembered to look for reference cycles.
+struct Alloc {
+    object: Weak<RefCell<Vec<Value>>>,
+    ty: u32,
+    /// The function and instruction that allocated the object.
+    func: usize,
+    pc: usize,
+}
+
+impl Vm<'_> {
+    fn error(&self, message: impl Into<String>) -> VmError {
+        let trace = self
+            .frames
+            .iter()
+            .rev()
+            .map(|frame| self.location(frame.func, frame.pc.saturating_sub(1)))
+            .collect();
+        VmError {
+            message: message.into(),
+            trace,
+        }
+    }
+
+    /// Gets where the instruction at `pc` of the function at `func` comes from.
+    fn location(&self, func: usize, pc: usize) -> Location {
+        let func = &self.program.functions[func];
+        let file = &self.program.files[func.file as usize];
+        Location {
+            function: func.name.clone(),
+            file: file.path.clone(),
+            checksum: file.checksum,
+            span: func.debug.get(pc).copied().unwrap_or_default(),
+        }
+    }
+
+    /// Starts a call to the function at `func`, moving its arguments from the registers starting
+    /// at `args`.
+    fn enter(&mut self, func: usize, args: usize, argc: usize, dst: usize) -> Result<(), VmError> {
+        if self.frames.len() >= MAX_DEPTH {
+            return Err(self.error(format!(
+                "stack overflow: calls are nested more than {MAX_DEPTH} deep"
+            )));
+        }
+        let base = self.regs.len();
+        let registers = self.program.functions[func].registers as usize;
+        self.regs.resize(base + registers.max(argc), Value::Void);
+        for index in 0..argc {
+            self.regs[base + index] = std::mem::take(&mut self.regs[args + index]);
+        }
+        self.frames.push(Frame {
+            func,
+            pc: 0,
+            base,
+            dst,
+        });
+        Ok(())
+    }
+
+    fn run(&mut self) -> Result<(), VmError> {
+        self.enter(self.program.entry as usize, 0, 0, 0)?;
+        let program = self.program;
+        loop {
+            let frame = self.frames.last_mut().expect("the entry call is running");
+            let base = frame.base;
+            let instr = program.functions[frame.func].code[frame.pc];
+            frame.pc += 1;
+            let r = |reg: Reg| base + reg as usize;
+            match instr {
+                Instr::Const { dst, index } => {
+                    self.regs[r(dst)] = self.constants[index as usize].clone();
+                }
+                Instr::Bool { dst, value } => self.regs[r(dst)] = Value::Bool(value),
+                Instr::Copy { dst, src } => self.regs[r(dst)] = self.regs[r(src)].clone(),
+                Instr::Move { dst, src } => {
+                    self.regs[r(dst)] = std::mem::take(&mut self.regs[r(src)]);
+                }
+                Instr::Drop { reg } => self.regs[r(reg)] = Value::Void,
+                Instr::Replace { dst, src } => {
+                    let value = std::mem::take(&mut self.regs[r(src)]);
+                    match (&self.regs[r(dst)], value) {
+                        (Value::Array(old), Value::Array(new))
+                        | (Value::Obj(old), Value::Obj(new))
+                            if !Rc::ptr_eq(old, &new) =>
+                        {
+                            let contents = new.take();
+                            *old.borrow_mut() = contents;
+                        }
+                        (_, value) => self.regs[r(dst)] = value,
+                    }
+                }
+                Instr::Binary { op, dst, lhs, rhs } => {
+                    let value = self.binary(op, &self.regs[r(lhs)], &self.regs[r(rhs)])?;
+                    self.regs[r(dst)] = value;
+                }
+                Instr::Neg { dst, src } => {
+                    self.regs[r(dst)] = match self.regs[r(src)] {
+                        Value::Int(int) => Value::Int(int.wrapping_neg()),
+                        Value::Float(float) => Value::Float(-float),
+                        _ => return Err(self.error("`neg` needs a number")),
+                    };
+                }
+                Instr::Not { dst, src } => {
+                    self.regs[r(dst)] = match self.regs[r(src)] {
+                        Value::Bool(bool) => Value::Bool(!bool),
+                        _ => return Err(self.error("`not` needs a boolean")),
+                    };
+                }
+                Instr::Inc { reg } => match &mut self.regs[r(reg)] {
+                    Value::Int(int) => *int = int.wrapping_add(1),
+                    Value::Float(float) => *float += 1.0,
+                    _ => return Err(self.error("`inc` needs a number")),
+                },
+                Instr::Jump { target } => self.jump(target),
+                Instr::JumpIf { cond, target } => {
+                    if self.truth(r(cond))? {
+                        self.jump(target);
+                    }
+                }
+                Instr::JumpIfNot { cond, target } => {
+                    if !self.truth(r(cond))? {
+                        self.jump(target);
+                    }
+                }
+                Instr::Call {
+                    func,
+                    args,
+                    argc,
+                    dst,
+                } => self.enter(func as usize, r(args), argc as usize, r(dst))?,
+                Instr::Return { src } => {
+                    let value = std::mem::take(&mut self.regs[r(src)]);
+                    if self.ret(value) {
+                        return Ok(());
+                    }
+                }
+                Instr::ReturnVoid => {
+                    if self.ret(Value::Void) {
+                        return Ok(());
+                    }
+                }
+                Instr::Print { src } => {
+                    let Value::Str(string) = &self.regs[r(src)] else {
+                        return Err(self.error("`print` needs a string"));
+                    };
+                    let written = self.out.write_all(string.as_bytes());
+                    written
+                        .map_err(|err| self.error(format!("could not write the output: {err}")))?;
+                }
+                Instr::Println => {
+                    let written = self.out.write_all(b"\n");
+                    written
+                        .map_err(|err| self.error(format!("could not write the output: {err}")))?;
+                }
+                Instr::ToString { dst, src } => {
+                    let string: Rc<str> = match &self.regs[r(src)] {
+                        Value::Int(int) => int.to_string().into(),
+                        Value::Float(float) => float.to_string().into(),
+                        Value::Bool(bool) => bool.to_string().into(),
+                        Value::Str(string) => string.clone(),
+                        _ => return Err(self.error("`to-string` needs a number or a string")),
+                    };
+                    self.regs[r(dst)] = Value::Str(string);
+                }
+                Instr::Len { dst, src } => {
+                    let len = match &self.regs[r(src)] {
+                        Value::Str(string) => string.len(),
+                        Value::Array(items) => items.borrow().len(),
+                        _ => return Err(self.error("`len` needs a string or an array")),
+                    };
+                    self.regs[r(dst)] = Value::Int(len as i32);
+                }
+                Instr::Push { array, src } => {
+                    let value = std::mem::take(&mut self.regs[r(src)]);
+                    let Value::Array(items) = &self.regs[r(array)] else {
+                        return Err(self.error("`push` needs an array"));
+                    };
+                    items.borrow_mut().push(value);
+                }
+                Instr::Array { dst, start, len } => {
+                    let start = r(start);
+                    let items = self.regs[start..start + len as usize]
+                        .iter_mut()
+                        .map(std::mem::take)
+                        .collect();
+                    self.regs[r(dst)] = Value::Array(Rc::new(RefCell::new(items)));
+                }
+                Instr::Index { dst, array, index } => {
+                    let (Value::Array(items), Value::Int(index)) =
+                        (&self.regs[r(array)], &self.regs[r(index)])
+                    else {
+                        return Err(self.error("`index` needs an array and an integer"));
+                    };
+                    let item = items.borrow().get(*index as usize).cloned();
+                    let Some(item) = item else {
+                        return Err(self.error(format!("index {index} is out of bounds")));
+                    };
+                    self.regs[r(dst)] = item;
+                }
+                Instr::New { dst, ty, fields } => {
+                    let count = program.types[ty as usize].fields as usize;
+                    let start = r(fields);
+                    let fields = self.regs[start..start + count]
+                        .iter_mut()
+                        .map(std::mem::take)
+                        .collect();
+                    let object = Rc::new(RefCell::new(fields));
+                    if let Some(allocs) = &mut self.allocs {
+                        // Forget freed objects before growing, so long programs don't pile them up
+                        if allocs.len() == allocs.capacity() {
+                            allocs.retain(|x| x.object.strong_count() > 0);
+                        }
+                        let frame = self.frames.last().expect("the entry call is running");
+                        allocs.push(Alloc {
+                            object: Rc::downgrade(&object),
+                            ty,
+                            func: frame.func,
+                            pc: frame.pc - 1,
+                        });
+                    }
+                    self.regs[r(dst)] = Value::Obj(object);
+                }
+                Instr::GetField { dst, obj, field } => {
+                    let Value::Obj(fields) = &self.regs[r(obj)] else {
+                        return Err(self.error("`get-field` needs an object"));
+                    };
+                    let value = fields.borrow().get(field as usize).cloned();
+                    let Some(value) = value else {
+                        return Err(self.error(format!("the object has no field {field}")));
+                    };
+                    self.regs[r(dst)] = value;
+                }
+                Instr::SetField { obj, field, src } => {
+                    let value = std::mem::take(&mut self.regs[r(src)]);
+                    let Value::Obj(fields) = &self.regs[r(obj)] else {
+                        return Err(self.error("`set-field` needs an object"));
+                    };
+                    // The old value is dropped after the borrow ends, in case it owns the object
+                    let old = match fields.borrow_mut().get_mut(field as usize) {
+                        Some(slot) => std::mem::replace(slot, value),
+                        None => return Err(self.error(format!("the object has no field {field}"))),
+                    };
+                    drop(old);
+                }
+                Instr::Fail { message } => {
+                    let message = match &self.constants[message as usize] {
+                        Value::Str(message) => message.to_string(),
+                        _ => "the program failed".to_owned(),
+                    };
+                    return Err(self.error(message));
+                }
+            }
+        }
+    }
+
+    fn jump(&mut self, target: u32) {
+        let frame = self.frames.last_mut().expect("jumps happen in calls");
+        frame.pc = target as usize;
+    }
+
+    fn truth(&self, reg: usize) -> Result<bool, VmError> {
+        match self.regs[reg] {
+            Value::Bool(bool) => Ok(bool),
+            _ => Err(self.error("conditions need a boolean")),
+        }
+    }
+
+    /// Ends the innermost call, giving its result to the caller. Gives whether the program is
+    /// done.
+    fn ret(&mut self, value: Value) -> bool {
+        let frame = self.frames.pop().expect("returns happen in calls");
+        self.regs.truncate(frame.base);
+        if self.frames.is_empty() {
+            return true;
+        }
+        self.regs[frame.dst] = value;
+        false
+    }
+
+    /// Finds the objects still alive, which only reference cycles can keep alive once the
+    /// program is done.
+    fn leaks(&self) -> Vec<Leak> {
+        let mut leaks: Vec<(&Alloc, usize)> = Vec::new();
+        let alive = self.allocs.iter().flatten();
+        for alloc in alive.filter(|x| x.object.strong_count() > 0) {
+            match leaks
+                .iter_mut()
+                .find(|x| (x.0.func, x.0.pc) == (alloc.func, alloc.pc))
+            {
+                Some(leak) => leak.1 += 1,
+                None => leaks.push((alloc, 1)),
+            }
+        }
+        leaks
+            .into_iter()
+            .map(|(alloc, count)| Leak {
+                ty: self.program.types[alloc.ty as usize].name.clone(),
+                location: self.location(alloc.func, alloc.pc),
+                count,
+            })
+            .collect()
+    }
+
+    fn binary(&self, op: Op, lhs: &Value, rhs: &Value) -> Result<Value, VmError> {
+        let value = match (lhs, rhs) {
+            (Value::Int(_), Value::Int(0)) if op == Op::Div => {
+                return Err(self.error("attempt to divide by zero"));
+            }
+            (Value::Int(a), Value::Int(b)) => match op {
+                Op::Add => Value::Int(a.wrapping_add(*b)),
+                Op::Sub => Value::Int(a.wrapping_sub(*b)),
+                Op::Mul => Value::Int(a.wrapping_mul(*b)),
+                Op::Div => Value::Int(a.wrapping_div(*b)),
+                _ => Value::Bool(compare(op.into(), a, b)),
+            },
+            (Value::Float(a), Value::Float(b)) => match op {
+                Op::Add => Value::Float(a + b),
+                Op::Sub => Value::Float(a - b),
+                Op::Mul => Value::Float(a * b),
+                Op::Div => Value::Float(a / b),
+                _ => Value::Bool(compare(op.into(), a, b)),
+            },
+            (Value::Str(a), Value::Str(b)) if op == Op::Add => Value::Str(format!("{a}{b}").into()),
+            (Value::Str(a), Value::Str(b)) if op != Op::Sub && op != Op::Mul && op != Op::Div => {
+                Value::Bool(compare(op.into(), a, b))
+            }
+            (Value::Bool(a), Value::Bool(b)) if op == Op::Eq || op == Op::Ne => {
+                Value::Bool(compare(op.into(), a, b))
+            }
+            _ => {
+                return Err(self.error(format!("`{}` can't be applied to these values", op.name())));
+            }
+        };
+        Ok(value)
+    }
+}
+
+#[cfg(test)]
+fn run_source(text: &str) -> Result<String, VmError> {
+    let program = super::from_mir::compile_source(text);
+    let mut out = Vec::new();
+    let leaks = run(&program, &mut out)?;
+    assert_eq!(leaks, [], "the program leaks");
+    Ok(String::from_utf8(out).unwrap())
+}
+
+#[test]
+fn run_test() {
+    let out = run_source(
+        r"identifier app.main
+obj counter is
+    count: i32 = 0,
+    names: string[] = [],
+end
+impl counter is
+    func add(string name, i32 n) is
+        self.names.push(name)
+        self.count = self.count + n
+    end
+end
+func i32 fib(i32 n) is
+    if n < 2 is
+        return n
+    end
+    return fib(n - 1) + fib(n - 2)
+end
+func void start() is
+    counter c = counter
+    c.add('a', 1)
+    c.add('b' + 'c', 2)
+    for name in c.names is
+        print(name)
+    end
+    print(' ' + fib(10).to-string() + ' ' + c.count.to-string() + ' ' + (7 / 2).to-string())
+end",
+    )
+    .unwrap();
+    assert_eq!(out, "abc 55 3 3");
+}
+
+#[test]
+fn error_test() {
+    let text = r"identifier app.main
+obj log is
+    lines: string[] = [],
+end
+func void keep(log l, string line) is
+    l.lines.push(line)
+end
+func i32 div(i32 a, i32 b) is
+    return a / b
+end
+func void start() is
+    log a = log
+    keep(a, 'x')
+    for line in a.lines is
+        print(line)
+    end
+    i32 i = 0
+    while i < 3 is
+        log b = log(lines: ['y'])
+        i++
+        if i == 2 is
+            break
+        end
+    end
+    drop(a)
+    print(div(1, 0).to-string())
+end";
+    let err = run_source(text).unwrap_err();
+    assert_eq!(err.message, "attempt to divide by zero");
+    let lines: Vec<_> = err
+        .trace
+        .iter()
+        .map(|x| (x.function.as_str(), x.span.line))
+        .collect();
+    assert_eq!(lines, [("div", 9), ("start", 26)]);
+    // Errors point at the expression that failed, not at its whole statement
+    let spans: Vec<_> = err
+        .trace
+        .iter()
+        .map(|x| &text[x.span.start as usize..x.span.end as usize])
+        .collect();
+    assert_eq!(spans, ["a / b", "div(1, 0)"]);
+}
+
+#[test]
+fn registers_test() {
+    // More items, each lowered into a temporary of its own, than a 16-bit register index could
+    // reach
+    let items = vec!["1"; 70_000].join(", ");
+    let text = format!(
+        r"identifier app.main
+func void start() is
+    i32[] a = [{items}]
+    print(a.len().to-string())
+end"
+    );
+    assert_eq!(run_source(&text).unwrap(), "70000");
+}
+
+// Cycles are only looked for in debug builds
+#[cfg(debug_assertions)]
+#[test]
+fn cycle_test() {
+    let text = r"identifier app.main
+obj node is
+    children: node[] = [],
+end
+obj holder is
+    first: node = node,
+end
+func void start() is
+    holder h = holder
+    node a = h.first
+    node child = node
+    child.children.push(h.first)
+    a.children.push(child)
+    node lonely = node
+    lonely.children.push(node)
+end";
+    let program = super::from_mir::compile_source(text);
+    let leaks = run(&program, &mut Vec::new()).unwrap();
+    // `a` and its child own each other, `lonely` and its child are freed
+    let leaks: Vec<_> = leaks
+        .iter()
+        .map(|x| {
+            (
+                x.ty.as_str(),
+                &text[x.location.span.start as usize..x.location.span.end as usize],
+                x.count,
+            )
+        })
+        .collect();
+    assert_eq!(leaks, [("node", "node", 1), ("node", "node", 1)]);
+}
@@ -0,0 +1,11 @@
+#![deny(missing_docs)]
+//! Module for compiling the [MIR](crate::mir) of a project to the source code of other languages,
+//! to be compiled by their own tools.
+//!
+//! Every backend generates code for an optimized [`Program`](crate::mir::Program), starting at
+//! its entry function and freeing values where the IR drops them, so programs behave the same in
+//! every engine.
+
+pub mod c;
+pub mod llvm;
+pub mod wat;
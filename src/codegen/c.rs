@@ -0,0 +1,935 @@
+#![deny(missing_docs)]
+//! Module for compiling the [mid-level IR](crate::mir) of a project to portable C99.
+//!
+//! The generated file includes the runtime [`HEADER`], which has to be written next to it as
+//! [`HEADER_NAME`]. Strings, arrays and objects have a single owner: values are moved into owned
+//! parameters, objects and arrays, borrowing locals hold a pointer to a value owned elsewhere,
+//! and values are freed where the IR drops them. Only values of the fields and types ownership
+//! analysis demoted are reference counted, and released instead. Strings are never shared:
+//! [sharing](Rvalue::Share) one copies it.
+//!
+//! Every local becomes a variable declared at the top of its function, and every block a label
+//! jumped to with `goto`, unless it follows the only block going to it. Blocks nothing goes to
+//! are left out. Moving a value out of a local that is dropped somewhere sets it to `NULL`, so
+//! dropping it does nothing.
+//!
+//! Names are mangled into C identifiers: functions become `f_<module>__<name>`, methods
+//! `m_<module>__<type>__<method>` and `obj` types `struct t_<module>__<name>`, with `.` and `-`
+//! replaced by `_`, and a number appended when that makes two names equal. Locals are named
+//! after their number, so they never shadow each other.
+//!
+//! `extern func`s become C prototypes of the same name, with `-` replaced by `_`, to be linked
+//! with C code. `i32`, `f32` and `bool` are `int32_t`, `float` and `bool`, strings are passed as
+//! zero terminated `const char *`, and arrays and objects as pointers borrowed for the call.
+
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::Write,
+};
+
+use crate::{
+    ast::{BinOp, UnOp},
+    mir::{
+        BlockId, Callee, Const, Function, Local, Operand, Program, Rvalue, StatementKind,
+        Terminator, Ty, TypeId,
+    },
+};
+
+/// The runtime the generated code includes.
+pub const HEADER: &str = include_str!("escoop.h");
+
+/// The file name the generated code includes the runtime as.
+pub const HEADER_NAME: &str = "escoop.h";
+
+/// Generates C source code for every function of `program`, with a `main` function calling its
+/// entry function.
+pub fn generate(program: &Program) -> String {
+    let mut generator = Generator {
+        program,
+        used: HashSet::new(),
+        funcs: Vec::new(),
+        types: Vec::new(),
+        externs: program.externs.iter().map(|x| sanitize(&x.name)).collect(),
+        constructed: HashSet::new(),
+        replaced: HashSet::new(),
+        strings: HashMap::new(),
+        literals: String::new(),
+    };
+
+    // Every name is chosen first, so code can refer to items generated after it
+    for ty in &program.types {
+        let (module, name) = split_name(&ty.name);
+        let name = generator.global("t_", &[module, name]);
+        generator.types.push(name["t_".len()..].to_owned());
+    }
+    for func in &program.functions {
+        let (module, name) = split_name(&func.name);
+        let name = match name.split_once('.') {
+            Some((ty, method)) => generator.global("m_", &[module, ty, method]),
+            None => generator.global("f_", &[module, name]),
+        };
+        generator.funcs.push(name);
+    }
+
+    let mut prototypes = String::new();
+    let mut definitions = String::new();
+    for (index, func) in program.functions.iter().enumerate() {
+        let (prototype, definition) = generator.function(index, func);
+        writeln!(prototypes, "{prototype};").unwrap();
+        writeln!(definitions, "\n{definition}").unwrap();
+    }
+
+    let mut out = String::new();
+    let (module, _) = split_name(&program.functions[program.entry.0].name);
+    writeln!(out, "/* Generated by esci from `{module}` */").unwrap();
+    writeln!(out, "#include \"{HEADER_NAME}\"").unwrap();
+    if !program.types.is_empty() {
+        out.push('\n');
+    }
+    for name in &generator.types {
+        writeln!(out, "struct t_{name};").unwrap();
+    }
+    for index in 0..program.types.len() {
+        generator.type_definition(TypeId(index), &mut out);
+    }
+    if !generator.literals.is_empty() {
+        out.push('\n');
+        out.push_str(&generator.literals);
+    }
+    if !program.externs.is_empty() {
+        out.push('\n');
+    }
+    for (func, name) in program.externs.iter().zip(&generator.externs) {
+        let params: Vec<_> = func
+            .params
+            .iter()
+            .enumerate()
+            .map(|(index, ty)| declare(&generator.extern_type(ty), &format!("arg{index}")))
+            .collect();
+        let params = match params.is_empty() {
+            true => "void".to_owned(),
+            false => params.join(", "),
+        };
+        let ret = generator.extern_type(&func.ret);
+        writeln!(out, "{};", declare(&ret, &format!("{name}({params})"))).unwrap();
+    }
+    out.push('\n');
+    out.push_str(&prototypes);
+    for index in 0..program.types.len() {
+        generator.type_functions(TypeId(index), &mut out);
+    }
+    out.push_str(&definitions);
+    writeln!(
+        out,
+        "\nint main(void) {{\n    {}();\n    return 0;\n}}",
+        generator.funcs[program.entry.0]
+    )
+    .unwrap();
+    out
+}
+
+/// Splits the name of a function or type of the IR into its module and the rest.
+fn split_name(name: &str) -> (&str, &str) {
+    name.split_once('/').unwrap_or(("", name))
+}
+
+/// Replaces the characters of escoop names that can't be in C identifiers.
+fn sanitize(name: &str) -> String {
+    name.replace(['-', '.'], "_")
+}
+
+/// Declares `name` with the C type `ty`, like `int32_t x` or `esc_string *x`.
+fn declare(ty: &str, name: &str) -> String {
+    match ty.ends_with('*') {
+        true => format!("{ty}{name}"),
+        false => format!("{ty} {name}"),
+    }
+}
+
+/// Escapes `text` into a C string literal. `?` is escaped too, as `??` starts a trigraph.
+fn string_literal(text: &str) -> String {
+    let mut literal = String::from("\"");
+    for byte in text.bytes() {
+        match byte {
+            b'"' | b'\\' | b'?' => {
+                literal.push('\\');
+                literal.push(byte as char);
+            }
+            b' '..=b'~' => literal.push(byte as char),
+            _ => write!(literal, "\\{byte:03o}").unwrap(),
+        }
+    }
+    literal.push('"');
+    literal
+}
+
+fn float_literal(float: f32) -> String {
+    match float.is_infinite() {
+        true => "INFINITY".to_owned(),
+        false => format!("{float:?}f"),
+    }
+}
+
+fn int_literal(int: i32) -> String {
+    if int == i32::MIN {
+        "INT32_MIN".to_owned()
+    } else if int < 0 {
+        format!("({int})")
+    } else {
+        int.to_string()
+    }
+}
+
+struct Generator<'p, 'src> {
+    program: &'p Program<'src>,
+    /// Every global name given out.
+    used: HashSet<String>,
+    funcs: Vec<String>,
+    /// The mangled name of every `obj` type, without the prefix of the struct or its functions.
+    types: Vec<String>,
+    externs: Vec<String>,
+    /// The types that are constructed, needing a function freeing their values.
+    constructed: HashSet<TypeId>,
+    /// The types whose values are replaced through mutable parameters, needing a function doing
+    /// so.
+    replaced: HashSet<TypeId>,
+    /// The static variable holding every string literal.
+    strings: HashMap<String, String>,
+    /// Definitions of the string literals.
+    literals: String,
+}
+
+impl<'p, 'src> Generator<'p, 'src> {
+    /// Gives out a global name made of `prefix` and `parts`, unique among the names given out.
+    fn global(&mut self, prefix: &str, parts: &[&str]) -> String {
+        let parts: Vec<_> = parts.iter().map(|x| sanitize(x)).collect();
+        let base = format!("{prefix}{}", parts.join("__"));
+        let mut name = base.clone();
+        let mut count = 1;
+        while self.used.contains(&name) {
+            count += 1;
+            name = format!("{base}_{count}");
+        }
+        self.used.insert(name.clone());
+        name
+    }
+
+    /// The runtime function dropping values of the reference type `ty`.
+    fn drop_fn(&self, ty: &Ty) -> &'static str {
+        match self.program.is_counted(ty) {
+            true => "esc_release",
+            false => "esc_free",
+        }
+    }
+
+    fn c_type(&self, ty: &Ty) -> String {
+        match ty {
+            Ty::I32 => "int32_t".to_owned(),
+            Ty::F32 => "float".to_owned(),
+            Ty::Bool => "bool".to_owned(),
+            Ty::Str | Ty::String => "esc_string *".to_owned(),
+            Ty::Array(_) => "esc_array *".to_owned(),
+            Ty::Obj(obj) => format!("struct t_{} *", self.types[obj.0]),
+            Ty::Void => "void".to_owned(),
+        }
+    }
+
+    /// The type of `ty` in the prototype of an `extern` function.
+    fn extern_type(&self, ty: &Ty) -> String {
+        match ty {
+            Ty::Str | Ty::String => "const char *".to_owned(),
+            _ => self.c_type(ty),
+        }
+    }
+
+    /// Gets the static variable holding the string literal `text`.
+    fn string(&mut self, text: &str) -> String {
+        if let Some(name) = self.strings.get(text) {
+            return format!("(&{name})");
+        }
+        let name = format!("s{}", self.strings.len());
+        writeln!(
+            self.literals,
+            "static esc_string {name} = {{{{-1, NULL}}, {}, {}}};",
+            text.len(),
+            string_literal(text)
+        )
+        .unwrap();
+        self.strings.insert(text.to_owned(), name.clone());
+        format!("(&{name})")
+    }
+
+    fn constant(&mut self, constant: &Const) -> String {
+        match constant {
+            Const::Int(int) => int_literal(*int),
+            Const::Float(float) => float_literal(*float),
+            Const::Bool(value) => value.to_string(),
+            Const::Str(string) => self.string(string),
+        }
+    }
+
+    fn field_name(&self, obj: TypeId, index: usize) -> String {
+        let field = &self.program.types[obj.0].fields[index];
+        format!("f{index}_{}", sanitize(&field.name))
+    }
+
+    /// Writes the struct of the `obj` type `obj`.
+    fn type_definition(&self, obj: TypeId, out: &mut String) {
+        writeln!(
+            out,
+            "\nstruct t_{} {{\n    esc_header header;",
+            self.types[obj.0]
+        )
+        .unwrap();
+        for (index, field) in self.program.types[obj.0].fields.iter().enumerate() {
+            let ty = self.c_type(&field.ty);
+            writeln!(out, "    {};", declare(&ty, &self.field_name(obj, index))).unwrap();
+        }
+        out.push_str("};\n");
+    }
+
+    /// Writes the functions freeing values of the `obj` type `obj` and replacing their fields,
+    /// if they are needed.
+    fn type_functions(&self, obj: TypeId, out: &mut String) {
+        let name = &self.types[obj.0];
+        let def = &self.program.types[obj.0];
+        let refs: Vec<_> = (0..def.fields.len())
+            .filter(|x| def.fields[*x].ty.is_ref())
+            .map(|x| (self.field_name(obj, x), self.drop_fn(&def.fields[x].ty)))
+            .collect();
+        if self.constructed.contains(&obj) {
+            writeln!(out, "\nstatic void d_{name}(void *value) {{").unwrap();
+            if !refs.is_empty() {
+                writeln!(out, "    struct t_{name} *object = value;").unwrap();
+            }
+            for (field, drop) in &refs {
+                writeln!(out, "    {drop}(object->{field});").unwrap();
+            }
+            out.push_str("    free(value);\n}\n");
+        }
+        if !self.replaced.contains(&obj) {
+            return;
+        }
+        writeln!(
+            out,
+            "\nstatic void r_{name}(struct t_{name} *dst, struct t_{name} *src) {{"
+        )
+        .unwrap();
+        out.push_str("    if (dst != src) {\n");
+        for (field, drop) in &refs {
+            writeln!(out, "        {drop}(dst->{field});").unwrap();
+        }
+        for index in 0..def.fields.len() {
+            let field = self.field_name(obj, index);
+            writeln!(out, "        dst->{field} = src->{field};").unwrap();
+        }
+        for (field, _) in &refs {
+            writeln!(out, "        src->{field} = NULL;").unwrap();
+        }
+        let drop = self.drop_fn(&Ty::Obj(obj));
+        writeln!(out, "    }}\n    {drop}(src);\n}}").unwrap();
+    }
+
+    /// Generates the function `index`, giving its prototype and its definition.
+    fn function(&mut self, index: usize, func: &'p Function<'src>) -> (String, String) {
+        let names = func
+            .locals
+            .iter()
+            .enumerate()
+            .map(|(index, decl)| match &decl.name {
+                Some(name) => format!("l{index}_{}", sanitize(name)),
+                None => format!("t{index}"),
+            })
+            .collect();
+        let reachable = func.reachable();
+        let mut mentioned = vec![false; func.locals.len()];
+        let mut read = vec![false; func.locals.len()];
+        let mut dropped = vec![false; func.locals.len()];
+        let blocks = func.blocks.iter().zip(&reachable).filter(|(_, x)| **x);
+        for (block, _) in blocks {
+            for stmt in &block.stmts {
+                for local in stmt.kind.operands().into_iter().filter_map(Operand::local) {
+                    read[local.0] = true;
+                }
+                if let Some(local) = stmt.kind.changes() {
+                    read[local.0] = true;
+                }
+                if let StatementKind::Drop(local) = stmt.kind {
+                    read[local.0] = true;
+                    dropped[local.0] = true;
+                }
+                if let Some(local) = stmt.kind.defines() {
+                    mentioned[local.0] = true;
+                }
+            }
+            if let Some(local) = block.terminator.operand().and_then(Operand::local) {
+                read[local.0] = true;
+            }
+        }
+        let mut state = FuncState {
+            generator: self,
+            func,
+            names,
+            dropped,
+            body: String::new(),
+        };
+
+        let order: Vec<_> = (0..func.blocks.len()).filter(|x| reachable[*x]).collect();
+        let mut targets = HashSet::new();
+        let mut code = Vec::new();
+        for (position, index) in order.iter().enumerate() {
+            let block = &func.blocks[*index];
+            for stmt in &block.stmts {
+                state.statement(&stmt.kind);
+            }
+            let next = order.get(position + 1).map(|x| BlockId(*x));
+            state.terminator(&block.terminator, next, &mut targets);
+            code.push(std::mem::take(&mut state.body));
+        }
+
+        let params: Vec<_> = (0..func.params)
+            .map(|x| declare(&state.generator.c_type(&func.locals[x].ty), &state.names[x]))
+            .collect();
+        let params = match params.is_empty() {
+            true => "void".to_owned(),
+            false => params.join(", "),
+        };
+        let prototype = declare(
+            &state.generator.c_type(&func.ret),
+            &format!("{}({params})", state.generator.funcs[index]),
+        );
+        let mut definition = format!("{prototype} {{\n");
+        let locals = (func.params..func.locals.len()).filter(|x| mentioned[*x] || read[*x]);
+        let mut declared = false;
+        for local in locals {
+            let ty = &func.locals[local].ty;
+            let zero = match ty {
+                Ty::I32 => "0",
+                Ty::F32 => "0.0f",
+                Ty::Bool => "false",
+                _ => "NULL",
+            };
+            let var = declare(&state.generator.c_type(ty), &state.names[local]);
+            writeln!(definition, "    {var} = {zero};").unwrap();
+            // Locals that are only assigned still count as used
+            if !read[local] {
+                writeln!(definition, "    (void){};", state.names[local]).unwrap();
+            }
+            declared = true;
+        }
+        if declared {
+            definition.push('\n');
+        }
+        for (index, code) in order.iter().zip(code) {
+            if targets.contains(&BlockId(*index)) {
+                writeln!(definition, "bb{index}:").unwrap();
+            }
+            definition.push_str(&code);
+        }
+        definition.push('}');
+        (prototype, definition)
+    }
+}
+
+/// The state of the function being generated.
+struct FuncState<'g, 'p, 'src> {
+    generator: &'g mut Generator<'p, 'src>,
+    func: &'p Function<'src>,
+    /// The C variable of every local.
+    names: Vec<String>,
+    /// Whether every local is dropped somewhere, so moving its value out has to empty it.
+    dropped: Vec<bool>,
+    /// The code of the block being generated.
+    body: String,
+}
+
+impl FuncState<'_, '_, '_> {
+    fn line(&mut self, line: impl AsRef<str>) {
+        self.body.push_str("    ");
+        self.body.push_str(line.as_ref());
+        self.body.push('\n');
+    }
+
+    fn ty(&self, operand: &Operand) -> Ty {
+        self.func.operand_ty(operand)
+    }
+
+    fn operand(&mut self, operand: &Operand) -> String {
+        match operand {
+            Operand::Copy(local) | Operand::Move(local) => self.names[local.0].clone(),
+            Operand::Const(constant) => self.generator.constant(constant),
+        }
+    }
+
+    fn operands(&mut self, operands: &[Operand]) -> Vec<String> {
+        operands.iter().map(|x| self.operand(x)).collect()
+    }
+
+    fn statement(&mut self, stmt: &StatementKind) {
+        match stmt {
+            StatementKind::Assign(dst, rvalue) => self.assign(*dst, rvalue),
+            StatementKind::Call { dst, callee, args } => self.call(*dst, *callee, args),
+            StatementKind::SetField { obj, field, value } => {
+                let Ty::Obj(ty) = self.func.locals[obj.0].ty else {
+                    unreachable!("fields are set on objects")
+                };
+                let field_ty = &self.generator.program.types[ty.0].fields[*field].ty;
+                let place = format!(
+                    "{}->{}",
+                    self.names[obj.0],
+                    self.generator.field_name(ty, *field)
+                );
+                if field_ty.is_ref() {
+                    let drop = self.generator.drop_fn(field_ty);
+                    self.line(format!("{drop}({place});"));
+                }
+                let value = self.operand(value);
+                self.line(format!("{place} = {value};"));
+            }
+            StatementKind::Replace { dst, value } => {
+                let value = self.operand(value);
+                let dst_name = &self.names[dst.0];
+                match self.func.locals[dst.0].ty {
+                    Ty::Obj(ty) => {
+                        self.generator.replaced.insert(ty);
+                        let name = &self.generator.types[ty.0];
+                        let line = format!("r_{name}({dst_name}, {value});");
+                        self.line(line);
+                    }
+                    _ => {
+                        let line = format!("esc_array_replace({dst_name}, {value});");
+                        self.line(line);
+                    }
+                }
+            }
+            StatementKind::Push { array, value } => {
+                let slot = slot(&self.ty(value));
+                let value = self.operand(value);
+                let array = &self.names[array.0];
+                let line = format!("esc_array_push({array}, (esc_slot){{.{slot} = {value}}});");
+                self.line(line);
+            }
+            StatementKind::Print(value) => {
+                let value = self.operand(value);
+                self.line(format!("esc_print({value});"));
+            }
+            StatementKind::Println => self.line("esc_println();"),
+            StatementKind::Drop(local) => {
+                let ty = &self.func.locals[local.0].ty;
+                if ty.is_ref() {
+                    let drop = self.generator.drop_fn(ty);
+                    let line = format!("ESC_DROP({drop}, {});", self.names[local.0]);
+                    self.line(line);
+                }
+            }
+        }
+        // A moved value belongs to something else now, so dropping the local does nothing
+        for operand in stmt.operands() {
+            if let Operand::Move(local) = operand
+                && self.dropped[local.0]
+                && self.func.locals[local.0].ty.is_ref()
+                && stmt.defines() != Some(*local)
+            {
+                let line = format!("{} = NULL;", self.names[local.0]);
+                self.line(line);
+            }
+        }
+    }
+
+    fn assign(&mut self, dst: Local, rvalue: &Rvalue) {
+        let dst = self.names[dst.0].clone();
+        let value = match rvalue {
+            Rvalue::Use(operand) => self.operand(operand),
+            Rvalue::Share(operand) => {
+                let ty = self.ty(operand);
+                let value = self.operand(operand);
+                match (ty, operand) {
+                    // String literals are never freed, so they have no owner
+                    (_, Operand::Const(_)) => value,
+                    (Ty::Str | Ty::String, _) => format!("esc_string_copy({value})"),
+                    _ => format!("esc_retain({value})"),
+                }
+            }
+            Rvalue::Binary(op, lhs, rhs) => {
+                let ty = self.ty(lhs);
+                let lhs = self.operand(lhs);
+                let rhs = self.operand(rhs);
+                binary(*op, &ty, &lhs, &rhs)
+            }
+            Rvalue::Unary(op, operand) => {
+                let ty = self.ty(operand);
+                let value = self.operand(operand);
+                match op {
+                    UnOp::Neg if ty == Ty::I32 => format!("esc_neg({value})"),
+                    UnOp::Neg => format!("(-{value})"),
+                    UnOp::Not => format!("(!{value})"),
+                }
+            }
+            Rvalue::ToString(operand) => {
+                let ty = self.ty(operand);
+                let value = self.operand(operand);
+                match ty {
+                    Ty::I32 => format!("esc_i32_to_string({value})"),
+                    Ty::F32 => format!("esc_f32_to_string({value})"),
+                    Ty::Bool => format!("esc_bool_to_string({value})"),
+                    _ => format!("esc_string_copy({value})"),
+                }
+            }
+            Rvalue::Len(operand) => format!("{}->len", self.operand(operand)),
+            Rvalue::Array(item, items) => {
+                let drop = match item.is_ref() {
+                    true => self.generator.drop_fn(item),
+                    false => "NULL",
+                };
+                self.line(format!("{dst} = esc_array_new({drop});"));
+                let slot = slot(item);
+                for item in self.operands(items) {
+                    self.line(format!(
+                        "esc_array_push({dst}, (esc_slot){{.{slot} = {item}}});"
+                    ));
+                }
+                return;
+            }
+            Rvalue::New(ty, fields) => {
+                self.generator.constructed.insert(*ty);
+                let name = &self.generator.types[ty.0];
+                let line = format!("{dst} = esc_alloc(sizeof(struct t_{name}), d_{name});");
+                self.line(line);
+                for (index, value) in self.operands(fields).into_iter().enumerate() {
+                    let field = self.generator.field_name(*ty, index);
+                    self.line(format!("{dst}->{field} = {value};"));
+                }
+                return;
+            }
+            Rvalue::Field(obj, field) => {
+                let Ty::Obj(ty) = self.ty(obj) else {
+                    unreachable!("fields are read from objects")
+                };
+                let obj = self.operand(obj);
+                format!("{obj}->{}", self.generator.field_name(ty, *field))
+            }
+            Rvalue::Index(array, index) => self.item(array, index),
+            Rvalue::Take(array, index) => {
+                // The array keeps an empty slot, which dropping it skips
+                let item = self.item(array, index);
+                self.line(format!("{dst} = {item};"));
+                self.line(format!("{item} = NULL;"));
+                return;
+            }
+        };
+        self.line(format!("{dst} = {value};"));
+    }
+
+    /// Gives C code for the item at `index` of `array`.
+    fn item(&mut self, array: &Operand, index: &Operand) -> String {
+        let Ty::Array(item) = self.ty(array) else {
+            unreachable!("items are read from arrays")
+        };
+        let array = self.operand(array);
+        let index = self.operand(index);
+        format!("{array}->items[{index}].{}", slot(&item))
+    }
+
+    fn call(&mut self, dst: Option<Local>, callee: Callee, args: &[Operand]) {
+        let program = self.generator.program;
+        let dst = dst.map(|x| self.names[x.0].clone());
+        match callee {
+            Callee::Func(func) => {
+                let args = self.operands(args).join(", ");
+                let call = format!("{}({args})", self.generator.funcs[func.0]);
+                let ret = &program.functions[func.0].ret;
+                match dst {
+                    Some(dst) => self.line(format!("{dst} = {call};")),
+                    // An ignored result is still owned by the caller
+                    None if ret.is_ref() => {
+                        let drop = self.generator.drop_fn(ret);
+                        self.line(format!("{drop}({call});"));
+                    }
+                    None => self.line(format!("{call};")),
+                }
+            }
+            Callee::Extern(func) => {
+                let args: Vec<_> = args
+                    .iter()
+                    .map(|arg| match self.ty(arg) {
+                        Ty::Str | Ty::String => format!("{}->data", self.operand(arg)),
+                        _ => self.operand(arg),
+                    })
+                    .collect();
+                let call = format!("{}({})", self.generator.externs[func.0], args.join(", "));
+                match (dst, &program.externs[func.0].ret) {
+                    (Some(dst), Ty::Str | Ty::String) => {
+                        self.line(format!("{dst} = esc_from_cstr({call});"));
+                    }
+                    (Some(dst), _) => self.line(format!("{dst} = {call};")),
+                    (None, _) => self.line(format!("{call};")),
+                }
+            }
+        }
+    }
+
+    /// Generates `terminator`, falling through to the block `next` generated after it, and
+    /// adding the blocks it jumps to to `targets`.
+    fn terminator(
+        &mut self,
+        terminator: &Terminator,
+        next: Option<BlockId>,
+        targets: &mut HashSet<BlockId>,
+    ) {
+        let mut goto = |target: BlockId| {
+            targets.insert(target);
+            format!("goto bb{};", target.0)
+        };
+        match terminator {
+            Terminator::Goto(target) => {
+                if Some(*target) != next {
+                    self.line(goto(*target));
+                }
+            }
+            Terminator::Branch { cond, then, els } => {
+                let cond = self.operand(cond);
+                if Some(*then) == next {
+                    self.line(format!("if (!{cond}) {}", goto(*els)));
+                    return;
+                }
+                self.line(format!("if ({cond}) {}", goto(*then)));
+                if Some(*els) != next {
+                    self.line(goto(*els));
+                }
+            }
+            Terminator::Return(Some(value)) => {
+                let value = self.operand(value);
+                self.line(format!("return {value};"));
+            }
+            Terminator::Return(None) => self.line("return;"),
+            Terminator::Unreachable => self.line("esc_fail(\"entered unreachable code\");"),
+        }
+    }
+}
+
+/// The member of `esc_slot` holding array items of type `ty`.
+fn slot(ty: &Ty) -> char {
+    match ty {
+        Ty::I32 => 'i',
+        Ty::F32 => 'f',
+        Ty::Bool => 'b',
+        _ => 'p',
+    }
+}
+
+/// Gives C code applying `op` to `lhs` and `rhs`, whose type is `ty`.
+fn binary(op: BinOp, ty: &Ty, lhs: &str, rhs: &str) -> String {
+    let symbol = match op {
+        BinOp::Add => "+",
+        BinOp::Sub => "-",
+        BinOp::Mul => "*",
+        BinOp::Div => "/",
+        BinOp::Eq => "==",
+        BinOp::Ne => "!=",
+        BinOp::Lt => "<",
+        BinOp::Le => "<=",
+        BinOp::Gt => ">",
+        BinOp::Ge => ">=",
+        BinOp::And | BinOp::Or => unreachable!("`and` and `or` are lowered to branches"),
+    };
+    match (ty, op) {
+        (Ty::I32, BinOp::Div) => format!("esc_div({lhs}, {rhs})"),
+        (Ty::I32, BinOp::Add | BinOp::Sub | BinOp::Mul) => {
+            let func = match op {
+                BinOp::Add => "esc_add",
+                BinOp::Sub => "esc_sub",
+                _ => "esc_mul",
+            };
+            format!("{func}({lhs}, {rhs})")
+        }
+        (Ty::Str | Ty::String, BinOp::Add) => format!("esc_concat({lhs}, {rhs})"),
+        (Ty::Str | Ty::String, BinOp::Eq) => format!("esc_str_eq({lhs}, {rhs})"),
+        (Ty::Str | Ty::String, BinOp::Ne) => format!("(!esc_str_eq({lhs}, {rhs}))"),
+        _ => format!("({lhs} {symbol} {rhs})"),
+    }
+}
+
+#[cfg(test)]
+fn run_c(name: &str, text: &str, level: u8) -> String {
+    use std::process::Command;
+
+    let code = generate_source(text, level);
+    let dir = std::env::temp_dir().join(format!("escoop-c-{name}-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(dir.join(HEADER_NAME), HEADER).unwrap();
+    std::fs::write(dir.join("main.c"), code).unwrap();
+    let exe = dir.join("main");
+    let cc = Command::new("cc")
+        .args(["-std=c99", "-pedantic", "-Wall", "-Werror", "-o"])
+        .arg(&exe)
+        .arg(dir.join("main.c"))
+        .output()
+        .unwrap();
+    assert!(
+        cc.status.success(),
+        "{}",
+        String::from_utf8_lossy(&cc.stderr)
+    );
+    let run = Command::new(&exe).output().unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+    assert!(run.status.success());
+    String::from_utf8(run.stdout).unwrap()
+}
+
+#[cfg(test)]
+fn generate_source(text: &str, level: u8) -> String {
+    crate::interp::with_checked(text, |modules, info| {
+        let mut program =
+            crate::mir::lower::lower(modules, info, crate::modules::ModuleId(0)).unwrap();
+        crate::mir::opt::optimize(&mut program, &crate::mir::opt::Options::level(level));
+        generate(&program)
+    })
+}
+
+#[cfg(test)]
+pub(super) fn run_interp(text: &str) -> String {
+    crate::interp::with_checked(text, |modules, info| {
+        let mut out = Vec::new();
+        crate::interp::run(modules, info, crate::modules::ModuleId(0), &mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    })
+}
+
+#[test]
+fn cc_test() {
+    // Only checked where a C compiler is installed
+    if std::process::Command::new("cc")
+        .arg("--version")
+        .output()
+        .is_err()
+    {
+        return;
+    }
+    let samples = [
+        (
+            "ownership",
+            r"identifier app.main
+obj box is
+    name: string = 'box',
+end
+obj shelf is
+    boxes: box[] = [],
+    labels: string[] = [],
+    best: box = box,
+end
+impl shelf is
+    func void add(box b) is
+        self.boxes.push(b)
+    end
+end
+func void take(box b) is
+    box kept = b
+    print(kept.name + ',')
+end
+func void rename(string s) is
+    s = s + '!'
+    print(s)
+end
+func void start() is
+    shelf s = shelf
+    s.add(box(name: 'a'))
+    s.add(box(name: 'b'))
+    s.labels.push('c')
+    bulk take(s.boxes)
+    bulk take([box(name: 'd')])
+    box best = s.best
+    string label = s.labels.len().to-string()
+    rename(label)
+    bulk rename(s.labels)
+    for b in s.boxes is
+        print(b.name + best.name + label)
+    end
+end",
+        ),
+        (
+            "values",
+            r"identifier app.main
+obj box is
+    items: string[] = [],
+    size: f32 = 1,
+end
+func void fill(box b, string[] items) is
+    b = box(items: ['x', 'y?'], size: 0.1)
+    items = ['z']
+end
+func void show(string s) is
+    print(s + ';')
+end
+func void start() is
+    box b = box
+    string[] items = ['a']
+    fill(b, items)
+    bulk show(b.items)
+    bulk show(items)
+    print((b.size * 3).to-string() + ' ' + (100000000000000000000.0 / 1).to-string() + ' ' + (-0.5).to-string() + ' ' + (214833.625).to-string() + ' ' + (2147483647 + 1).to-string())
+    if 'ab' == 'a' + 'b' and not ('b' == 'ab') and 'a' != 'b' is
+        print(' ordered')
+    end
+    for item in b.items is
+        for other in items is
+            break
+        end
+        if item == 'x' is
+            continue
+        end
+        return
+    end
+end",
+        ),
+    ];
+    for (name, text) in samples {
+        let expected = run_interp(text);
+        for level in [0, 3] {
+            assert_eq!(run_c(name, text, level), expected, "{name} -O{level}");
+        }
+    }
+}
+
+#[test]
+fn ownership_test() {
+    let code = generate_source(
+        r"identifier app.main
+obj item is
+    name: string = 'item',
+end
+obj holder is
+    kept: item = item,
+    items: item[] = [],
+end
+func void start() is
+    holder h = holder
+    h.items.push(item)
+    item taken = h.kept
+    string name = taken.name
+end",
+        0,
+    );
+    // Items are reference counted, as one is moved out of `kept`, but their arrays are not
+    assert!(
+        code.contains("\n    esc_release(object->f0_kept);\n    esc_free(object->f1_items);\n")
+    );
+    assert!(code.contains("esc_array_new(esc_release)"));
+    assert_eq!(code.matches("esc_retain(").count(), 1);
+    assert!(code.contains("ESC_DROP(esc_free, l2_h);"));
+}
+
+#[test]
+fn extern_test() {
+    let code = generate_source(
+        r"identifier app.main
+extern func void print(str s)
+extern func f32 to-float(str s, i32 base)
+func void start() is
+    print(to-float('1', 10).to-string())
+end",
+        0,
+    );
+    assert!(code.contains("\nfloat to_float(const char *arg0, int32_t arg1);\n"));
+    assert!(code.contains("to_float((&s0)->data, 10)"));
+    assert!(!code.contains("void print("));
+}
@@ -0,0 +1,31 @@
+/* Runtime of the LLVM IR written by `esci build --target llvm-ir`: the functions of `escoop.h`,
+ * compiled once and linked with the program, like `clang -O2 app.ll escoop.c`. */
+#define ESC_API
+#include "escoop.h"
+
+/* `esc_array_push` for every type of item, as unions are passed differently on every
+ * platform */
+
+void esc_array_push_i32(esc_array *array, int32_t item) {
+    esc_slot slot;
+    slot.i = item;
+    esc_array_push(array, slot);
+}
+
+void esc_array_push_f32(esc_array *array, float item) {
+    esc_slot slot;
+    slot.f = item;
+    esc_array_push(array, slot);
+}
+
+void esc_array_push_bool(esc_array *array, bool item) {
+    esc_slot slot;
+    slot.b = item;
+    esc_array_push(array, slot);
+}
+
+void esc_array_push_ptr(esc_array *array, void *item) {
+    esc_slot slot;
+    slot.p = item;
+    esc_array_push(array, slot);
+}
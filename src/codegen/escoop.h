@@ -0,0 +1,319 @@
+/* Runtime of the C code written by `esci build --target c`, and of the LLVM IR written by
+ * `esci build --target llvm-ir`.
+ *
+ * Strings, arrays and objects have a single owner, which frees them with `esc_free`. Values of
+ * the types ownership analysis demoted to reference counting are shared instead, and released
+ * with `esc_release`. Each value starts with an `esc_header`, holding its count and the function
+ * freeing it. String literals are static, with a negative count, and are never freed. Numbers
+ * wrap around on overflow like in the other engines, so integer arithmetic goes through the
+ * functions below. */
+#ifndef ESCOOP_H
+#define ESCOOP_H
+
+#include <math.h>
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+
+/* How the functions below are declared: `static inline` for C code including them, or with
+ * external linkage when `escoop.c` compiles them once, for the LLVM IR backend. */
+#ifndef ESC_API
+#define ESC_API static inline
+#endif
+
+typedef struct esc_header {
+    int32_t rc;
+    void (*free)(void *value);
+} esc_header;
+
+/* Stops the program with an error. */
+ESC_API void esc_fail(const char *message) {
+    fflush(stdout);
+    fprintf(stderr, "error: %s\n", message);
+    exit(1);
+}
+
+/* Allocates a value of `size` bytes with one reference. */
+ESC_API void *esc_alloc(size_t size, void (*free_fn)(void *value)) {
+    esc_header *header = malloc(size);
+    if (!header) {
+        esc_fail("out of memory");
+    }
+    header->rc = 1;
+    header->free = free_fn;
+    return header;
+}
+
+ESC_API void *esc_retain(void *value) {
+    esc_header *header = value;
+    if (header && header->rc >= 0) {
+        header->rc++;
+    }
+    return value;
+}
+
+ESC_API void esc_release(void *value) {
+    esc_header *header = value;
+    if (header && header->rc > 0 && --header->rc == 0) {
+        header->free(value);
+    }
+}
+
+/* Frees a value with a single owner, which is never shared so its count is not looked at. */
+ESC_API void esc_free(void *value) {
+    esc_header *header = value;
+    if (header && header->rc >= 0) {
+        header->free(value);
+    }
+}
+
+/* Drops the value of a variable with `drop`, `esc_free` or `esc_release`, leaving it empty. */
+#define ESC_DROP(drop, var) (drop(var), (var) = NULL)
+
+ESC_API void esc_free_plain(void *value) {
+    free(value);
+}
+
+/* Integers */
+
+ESC_API int32_t esc_add(int32_t a, int32_t b) {
+    return (int32_t)((uint32_t)a + (uint32_t)b);
+}
+
+ESC_API int32_t esc_sub(int32_t a, int32_t b) {
+    return (int32_t)((uint32_t)a - (uint32_t)b);
+}
+
+ESC_API int32_t esc_mul(int32_t a, int32_t b) {
+    return (int32_t)((uint32_t)a * (uint32_t)b);
+}
+
+ESC_API int32_t esc_div(int32_t a, int32_t b) {
+    if (b == 0) {
+        esc_fail("attempt to divide by zero");
+    }
+    if (a == INT32_MIN && b == -1) {
+        return a;
+    }
+    return a / b;
+}
+
+ESC_API int32_t esc_neg(int32_t a) {
+    return (int32_t)(0u - (uint32_t)a);
+}
+
+/* Strings, always followed by a zero byte so `data` can be passed to C functions */
+
+typedef struct esc_string {
+    esc_header header;
+    int32_t len;
+    const char *data;
+} esc_string;
+
+ESC_API esc_string *esc_string_new(const char *data, size_t len) {
+    esc_string *string = esc_alloc(sizeof(esc_string) + len + 1, esc_free_plain);
+    char *bytes = (char *)(string + 1);
+    memcpy(bytes, data, len);
+    bytes[len] = '\0';
+    string->len = (int32_t)len;
+    string->data = bytes;
+    return string;
+}
+
+ESC_API esc_string *esc_from_cstr(const char *data) {
+    return esc_string_new(data, strlen(data));
+}
+
+/* Copies a string for a new owner. String literals are static, so they are shared. */
+ESC_API esc_string *esc_string_copy(const esc_string *string) {
+    if (string->header.rc < 0) {
+        return (esc_string *)string;
+    }
+    return esc_string_new(string->data, (size_t)string->len);
+}
+
+ESC_API esc_string *esc_concat(const esc_string *a, const esc_string *b) {
+    size_t len = (size_t)a->len + (size_t)b->len;
+    esc_string *string = esc_alloc(sizeof(esc_string) + len + 1, esc_free_plain);
+    char *bytes = (char *)(string + 1);
+    memcpy(bytes, a->data, (size_t)a->len);
+    memcpy(bytes + a->len, b->data, (size_t)b->len);
+    bytes[len] = '\0';
+    string->len = (int32_t)len;
+    string->data = bytes;
+    return string;
+}
+
+ESC_API bool esc_str_eq(const esc_string *a, const esc_string *b) {
+    return a->len == b->len && memcmp(a->data, b->data, (size_t)a->len) == 0;
+}
+
+ESC_API esc_string *esc_i32_to_string(int32_t value) {
+    char buffer[16];
+    snprintf(buffer, sizeof buffer, "%ld", (long)value);
+    return esc_from_cstr(buffer);
+}
+
+ESC_API esc_string *esc_bool_to_string(bool value) {
+    return esc_from_cstr(value ? "true" : "false");
+}
+
+/* Multiplies `x` by 10^`shift`. Powers of ten up to 10^22 are exact in `double`, so this is one
+ * correctly rounded operation for them. */
+ESC_API double esc_scale(double x, int shift) {
+    double pow = 1.0;
+    int i;
+    for (i = 0; i < (shift < 0 ? -shift : shift); i++) {
+        pow *= 10.0;
+    }
+    return shift < 0 ? x / pow : x * pow;
+}
+
+/* Writes the shortest digits reading back as `value`, without an exponent, rounding halfway
+ * cases up like Rust does. */
+ESC_API esc_string *esc_f32_to_string(float value) {
+    char digits[32], out[96];
+    double x = value < 0 ? -(double)value : (double)value, scale = 1.0, rounded = 0.0;
+    int exponent = 0, len = 0, count = 0, i;
+    if (isnan(value)) {
+        return esc_from_cstr("NaN");
+    }
+    if (signbit(value)) {
+        out[len++] = '-';
+    }
+    if (isinf(value)) {
+        memcpy(out + len, "inf", 3);
+        return esc_string_new(out, (size_t)len + 3);
+    }
+    if (x == 0.0) {
+        out[len++] = '0';
+        return esc_string_new(out, (size_t)len);
+    }
+    /* 10^exponent <= x < 10^(exponent + 1) */
+    while (x >= scale * 10.0) {
+        scale *= 10.0;
+        exponent++;
+    }
+    while (x < scale) {
+        scale /= 10.0;
+        exponent--;
+    }
+    do {
+        count++;
+        rounded = (double)(int64_t)(esc_scale(x, count - 1 - exponent) + 0.5);
+    } while (count < 9 && (float)esc_scale(rounded, exponent + 1 - count) != (float)x);
+    /* Rounding up can give one digit more, like 9.99 giving 10.0 */
+    if (rounded >= esc_scale(1.0, count)) {
+        rounded /= 10.0;
+        exponent++;
+    }
+    snprintf(digits, sizeof digits, "%.0f", rounded);
+    while (count > 1 && digits[count - 1] == '0') {
+        count--;
+    }
+    if (exponent < 0) {
+        out[len++] = '0';
+        out[len++] = '.';
+        for (i = 0; i < -exponent - 1; i++) {
+            out[len++] = '0';
+        }
+        for (i = 0; i < count; i++) {
+            out[len++] = digits[i];
+        }
+    } else {
+        for (i = 0; i < count || i <= exponent; i++) {
+            if (i == exponent + 1) {
+                out[len++] = '.';
+            }
+            out[len++] = i < count ? digits[i] : '0';
+        }
+    }
+    return esc_string_new(out, (size_t)len);
+}
+
+ESC_API void esc_print(const esc_string *string) {
+    fwrite(string->data, 1, (size_t)string->len, stdout);
+}
+
+ESC_API void esc_println(void) {
+    putchar('\n');
+}
+
+/* Arrays, holding numbers, booleans, or references to strings, arrays or objects */
+
+typedef union esc_slot {
+    int32_t i;
+    float f;
+    bool b;
+    void *p;
+} esc_slot;
+
+typedef struct esc_array {
+    esc_header header;
+    int32_t len;
+    int32_t cap;
+    /* Drops the items with the array, `NULL` if they are not references */
+    void (*drop)(void *item);
+    esc_slot *items;
+} esc_array;
+
+ESC_API void esc_array_free(void *value) {
+    esc_array *array = value;
+    int32_t i;
+    if (array->drop) {
+        for (i = 0; i < array->len; i++) {
+            array->drop(array->items[i].p);
+        }
+    }
+    free(array->items);
+    free(array);
+}
+
+ESC_API esc_array *esc_array_new(void (*drop)(void *item)) {
+    esc_array *array = esc_alloc(sizeof(esc_array), esc_array_free);
+    array->len = 0;
+    array->cap = 0;
+    array->drop = drop;
+    array->items = NULL;
+    return array;
+}
+
+/* Moves `item` to the end of `array`. */
+ESC_API void esc_array_push(esc_array *array, esc_slot item) {
+    if (array->len == array->cap) {
+        int32_t cap = array->cap ? array->cap * 2 : 4;
+        esc_slot *items = realloc(array->items, (size_t)cap * sizeof(esc_slot));
+        if (!items) {
+            esc_fail("out of memory");
+        }
+        array->items = items;
+        array->cap = cap;
+    }
+    array->items[array->len++] = item;
+}
+
+/* Moves the items of `src` into `dst`, so its owner sees them, and drops `src`. */
+ESC_API void esc_array_replace(esc_array *dst, esc_array *src) {
+    esc_slot *items = dst->items;
+    int32_t len = dst->len, i;
+    if (dst != src) {
+        dst->items = src->items;
+        dst->len = src->len;
+        dst->cap = src->cap;
+        src->items = NULL;
+        src->len = 0;
+        src->cap = 0;
+        if (dst->drop) {
+            for (i = 0; i < len; i++) {
+                dst->drop(items[i].p);
+            }
+        }
+        free(items);
+    }
+    esc_release(src);
+}
+
+#endif
@@ -0,0 +1,1095 @@
+#![deny(missing_docs)]
+//! Module for compiling the [mid-level IR](crate::mir) of a project to textual LLVM IR, for
+//! `clang` or `llc` to optimize and compile to native code.
+//!
+//! The generated module calls the functions of the [C runtime](super::c::HEADER), which
+//! [`RUNTIME`] compiles once, so programs are built with `clang -O2 app.ll escoop.c`. Ownership
+//! works like in the [C backend](super::c): values are moved into owned parameters, objects and
+//! arrays, borrowing locals hold a pointer to a value owned elsewhere, and values are freed where
+//! the IR drops them. Only values of the fields and types ownership analysis demoted are
+//! reference counted, and released instead. Every local lives in an `alloca`, which LLVM turns
+//! into a register, and every block of the IR becomes a basic block named `bb<number>`.
+//!
+//! Functions are named `@"<module>/<name>"`, methods `@"<module>/<type>.<method>"` and `obj`
+//! types `%"<module>/<type>"`, a struct of the header of the runtime and every field. Every
+//! instruction has a `DILocation` pointing at the statement or call it comes from, so debuggers
+//! and profilers show the lines of the program.
+//!
+//! `extern func`s are declared like in the C backend, with the same names, to be linked with C
+//! code. Pointers are opaque, which LLVM 15 and later use by default, and sizes are 64 bit.
+
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::Write,
+    path::Path,
+};
+
+use crate::{
+    ast::{BinOp, UnOp},
+    mir::{
+        Callee, Const, Function, Local, Operand, Program, Rvalue, StatementKind, Terminator, Ty,
+        TypeId,
+    },
+    span::Span,
+};
+
+/// The runtime the generated code is linked with. It includes the runtime of the C backend, which
+/// has to be written next to it as [`HEADER_NAME`](super::c::HEADER_NAME).
+pub const RUNTIME: &str = include_str!("escoop.c");
+
+/// The file name the runtime is written as.
+pub const RUNTIME_NAME: &str = "escoop.c";
+
+/// Types of the runtime, and the functions generated code calls.
+const PRELUDE: &str = "%esc_header = type { i32, ptr }
+%esc_string = type { %esc_header, i32, ptr }
+%esc_array = type { %esc_header, i32, i32, ptr, ptr }
+%esc_slot = type { i64 }
+
+declare ptr @esc_alloc(i64, ptr)
+declare ptr @esc_retain(ptr)
+declare void @esc_release(ptr)
+declare void @esc_free(ptr)
+declare i32 @esc_div(i32, i32)
+declare ptr @esc_from_cstr(ptr)
+declare ptr @esc_string_copy(ptr)
+declare ptr @esc_concat(ptr, ptr)
+declare zeroext i1 @esc_str_eq(ptr, ptr)
+declare ptr @esc_i32_to_string(i32)
+declare ptr @esc_f32_to_string(float)
+declare ptr @esc_bool_to_string(i1 zeroext)
+declare void @esc_print(ptr)
+declare void @esc_println()
+declare ptr @esc_array_new(ptr)
+declare void @esc_array_push_i32(ptr, i32)
+declare void @esc_array_push_f32(ptr, float)
+declare void @esc_array_push_bool(ptr, i1 zeroext)
+declare void @esc_array_push_ptr(ptr, ptr)
+declare void @esc_array_replace(ptr, ptr)
+declare void @free(ptr)
+";
+
+/// Generates LLVM IR for every function of `program`, with a `main` function calling its entry
+/// function.
+pub fn generate(program: &Program) -> String {
+    let mut generator = Generator {
+        program,
+        funcs: program
+            .functions
+            .iter()
+            .map(|x| format!("@\"{}\"", x.name))
+            .collect(),
+        externs: program
+            .externs
+            .iter()
+            .map(|x| format!("@{}", x.name.replace('-', "_")))
+            .collect(),
+        constructed: HashSet::new(),
+        replaced: HashSet::new(),
+        strings: HashMap::new(),
+        literals: String::new(),
+        meta: Vec::new(),
+        files: HashMap::new(),
+        locations: HashMap::new(),
+        unit: 0,
+        signature: 0,
+    };
+    let entry = &program.functions[program.entry.0];
+    let file = generator.file(entry.span);
+    generator.unit = generator.meta(format!(
+        "distinct !DICompileUnit(language: DW_LANG_C99, file: !{file}, producer: \"esci\", \
+         isOptimized: false, runtimeVersion: 0, emissionKind: LineTablesOnly)"
+    ));
+    generator.signature = generator.meta("!DISubroutineType(types: !{})".to_owned());
+
+    let mut definitions = String::new();
+    for (index, func) in program.functions.iter().enumerate() {
+        let definition = generator.function(index, func);
+        writeln!(definitions, "\n{definition}").unwrap();
+    }
+
+    let mut out = String::new();
+    let module = entry
+        .name
+        .split_once('/')
+        .map_or(entry.name.as_str(), |x| x.0);
+    writeln!(out, "; Generated by esci from `{module}`").unwrap();
+    writeln!(out, "source_filename = \"{module}\"\n").unwrap();
+    out.push_str(PRELUDE);
+    if !program.types.is_empty() {
+        out.push('\n');
+    }
+    for ty in &program.types {
+        let mut members = String::from("%esc_header");
+        for field in &ty.fields {
+            write!(members, ", {}", llvm_type(&field.ty)).unwrap();
+        }
+        writeln!(out, "%\"{}\" = type {{ {members} }}", ty.name).unwrap();
+    }
+    if !program.externs.is_empty() {
+        out.push('\n');
+    }
+    for (func, name) in program.externs.iter().zip(&generator.externs) {
+        let params: Vec<_> = func.params.iter().map(extern_type).collect();
+        let ret = extern_type(&func.ret);
+        writeln!(out, "declare {ret} {name}({})", params.join(", ")).unwrap();
+    }
+    if !generator.literals.is_empty() {
+        out.push('\n');
+        out.push_str(&generator.literals);
+    }
+    for index in 0..program.types.len() {
+        generator.type_functions(TypeId(index), &mut out);
+    }
+    out.push_str(&definitions);
+    writeln!(
+        out,
+        "\ndefine i32 @main() {{\n  call void {}()\n  ret i32 0\n}}",
+        generator.funcs[program.entry.0]
+    )
+    .unwrap();
+
+    writeln!(out, "\n!llvm.dbg.cu = !{{!{}}}", generator.unit).unwrap();
+    let version = generator.meta("!{i32 2, !\"Debug Info Version\", i32 3}".to_owned());
+    let dwarf = generator.meta("!{i32 7, !\"Dwarf Version\", i32 4}".to_owned());
+    writeln!(out, "!llvm.module.flags = !{{!{version}, !{dwarf}}}\n").unwrap();
+    for (index, node) in generator.meta.iter().enumerate() {
+        writeln!(out, "!{index} = {node}").unwrap();
+    }
+    out
+}
+
+/// Escapes `text` into the contents of an LLVM string, which escapes bytes as two hex digits.
+fn escape(text: &str) -> String {
+    let mut escaped = String::new();
+    for byte in text.bytes() {
+        match byte {
+            b' '..=b'~' if !matches!(byte, b'"' | b'\\') => escaped.push(byte as char),
+            _ => write!(escaped, "\\{byte:02X}").unwrap(),
+        }
+    }
+    escaped
+}
+
+/// Gives the LLVM constant of `float`, written as the bits of the `double` of the same value,
+/// like LLVM does for `float`s that aren't exactly decimal.
+fn float_literal(float: f32) -> String {
+    format!("0x{:016X}", (float as f64).to_bits())
+}
+
+fn llvm_type(ty: &Ty) -> &'static str {
+    match ty {
+        Ty::I32 => "i32",
+        Ty::F32 => "float",
+        Ty::Bool => "i1",
+        Ty::Str | Ty::String | Ty::Array(_) | Ty::Obj(_) => "ptr",
+        Ty::Void => "void",
+    }
+}
+
+/// The type of `ty` in the declaration of an `extern` function, passing `bool`s like C.
+fn extern_type(ty: &Ty) -> &'static str {
+    match ty {
+        Ty::Bool => "i1 zeroext",
+        _ => llvm_type(ty),
+    }
+}
+
+/// The value locals of `ty` start with.
+fn zero(ty: &Ty) -> &'static str {
+    match ty {
+        Ty::I32 => "0",
+        Ty::F32 => "0.0",
+        Ty::Bool => "false",
+        _ => "null",
+    }
+}
+
+/// The function pushing array items of type `ty`.
+fn push(ty: &Ty) -> &'static str {
+    match ty {
+        Ty::I32 => "@esc_array_push_i32",
+        Ty::F32 => "@esc_array_push_f32",
+        Ty::Bool => "@esc_array_push_bool",
+        _ => "@esc_array_push_ptr",
+    }
+}
+
+struct Generator<'p, 'src> {
+    program: &'p Program<'src>,
+    funcs: Vec<String>,
+    externs: Vec<String>,
+    /// The types that are constructed, needing a function freeing their values.
+    constructed: HashSet<TypeId>,
+    /// The types whose values are replaced through mutable parameters, needing a function doing
+    /// so.
+    replaced: HashSet<TypeId>,
+    /// The global holding every string literal.
+    strings: HashMap<String, String>,
+    /// Definitions of the string literals.
+    literals: String,
+    /// Every metadata node, numbered by its index.
+    meta: Vec<String>,
+    /// The `DIFile` of every source file, by its path.
+    files: HashMap<&'src Path, usize>,
+    /// Every `DILocation`, by its line, column and scope.
+    locations: HashMap<(u32, u32, usize), usize>,
+    /// The `DICompileUnit`.
+    unit: usize,
+    /// The `DISubroutineType` every function has, as only lines are described.
+    signature: usize,
+}
+
+impl<'p, 'src> Generator<'p, 'src> {
+    /// Adds a metadata node, giving its number.
+    fn meta(&mut self, node: String) -> usize {
+        self.meta.push(node);
+        self.meta.len() - 1
+    }
+
+    /// Gets the `DIFile` of the source `span` is in.
+    fn file(&mut self, span: Span<'src>) -> usize {
+        let path = span.source().path();
+        if let Some(file) = self.files.get(path.as_path()) {
+            return *file;
+        }
+        let name = path
+            .file_name()
+            .map_or(Default::default(), |x| x.to_string_lossy().into_owned());
+        let dir = path.parent().map_or(Path::new(""), |x| x);
+        let file = self.meta(format!(
+            "!DIFile(filename: \"{}\", directory: \"{}\")",
+            escape(&name),
+            escape(&dir.to_string_lossy())
+        ));
+        self.files.insert(path, file);
+        file
+    }
+
+    /// Gets the `DILocation` of the start of `span` in the function `scope`.
+    fn location(&mut self, span: Span, scope: usize) -> usize {
+        let (line, column) = span.get_start_code_pos();
+        if let Some(location) = self.locations.get(&(line, column, scope)) {
+            return *location;
+        }
+        let location = self.meta(format!(
+            "!DILocation(line: {line}, column: {column}, scope: !{scope})"
+        ));
+        self.locations.insert((line, column, scope), location);
+        location
+    }
+
+    /// The runtime function dropping values of the reference type `ty`.
+    fn drop_fn(&self, ty: &Ty) -> &'static str {
+        match self.program.is_counted(ty) {
+            true => "@esc_release",
+            false => "@esc_free",
+        }
+    }
+
+    /// Gets the global holding the string literal `text`.
+    fn string(&mut self, text: &str) -> String {
+        if let Some(name) = self.strings.get(text) {
+            return name.clone();
+        }
+        let name = format!("@s{}", self.strings.len());
+        let len = text.len();
+        writeln!(
+            self.literals,
+            "{name}.data = private unnamed_addr constant [{} x i8] c\"{}\\00\"",
+            len + 1,
+            escape(text)
+        )
+        .unwrap();
+        writeln!(
+            self.literals,
+            "{name} = private global %esc_string {{ %esc_header {{ i32 -1, ptr null }}, \
+             i32 {len}, ptr {name}.data }}"
+        )
+        .unwrap();
+        self.strings.insert(text.to_owned(), name.clone());
+        name
+    }
+
+    /// Writes the functions freeing values of the `obj` type `obj` and replacing their fields,
+    /// if they are needed.
+    fn type_functions(&self, obj: TypeId, out: &mut String) {
+        let def = &self.program.types[obj.0];
+        let name = &def.name;
+        let refs: Vec<_> = (0..def.fields.len())
+            .filter(|x| def.fields[*x].ty.is_ref())
+            .collect();
+        if self.constructed.contains(&obj) {
+            writeln!(
+                out,
+                "\ndefine internal void @\"drop:{name}\"(ptr %value) {{"
+            )
+            .unwrap();
+            for index in &refs {
+                let member = index + 1;
+                let drop = self.drop_fn(&def.fields[*index].ty);
+                writeln!(
+                    out,
+                    "  %p{index} = getelementptr inbounds %\"{name}\", ptr %value, i32 0, i32 {member}\n  \
+                     %f{index} = load ptr, ptr %p{index}\n  \
+                     call void {drop}(ptr %f{index})"
+                )
+                .unwrap();
+            }
+            out.push_str("  call void @free(ptr %value)\n  ret void\n}\n");
+        }
+        if !self.replaced.contains(&obj) {
+            return;
+        }
+        writeln!(
+            out,
+            "\ndefine internal void @\"replace:{name}\"(ptr %dst, ptr %src) {{\n  \
+             %same = icmp eq ptr %dst, %src\n  \
+             br i1 %same, label %done, label %replace\n\nreplace:"
+        )
+        .unwrap();
+        for (index, field) in def.fields.iter().enumerate() {
+            let member = index + 1;
+            let ty = llvm_type(&field.ty);
+            writeln!(
+                out,
+                "  %dst{index} = getelementptr inbounds %\"{name}\", ptr %dst, i32 0, i32 {member}\n  \
+                 %src{index} = getelementptr inbounds %\"{name}\", ptr %src, i32 0, i32 {member}\n  \
+                 %new{index} = load {ty}, ptr %src{index}"
+            )
+            .unwrap();
+            if refs.contains(&index) {
+                let drop = self.drop_fn(&field.ty);
+                writeln!(
+                    out,
+                    "  %old{index} = load ptr, ptr %dst{index}\n  \
+                     call void {drop}(ptr %old{index})\n  \
+                     store ptr null, ptr %src{index}"
+                )
+                .unwrap();
+            }
+            writeln!(out, "  store {ty} %new{index}, ptr %dst{index}").unwrap();
+        }
+        let drop = self.drop_fn(&Ty::Obj(obj));
+        writeln!(
+            out,
+            "  br label %done\n\ndone:\n  call void {drop}(ptr %src)\n  ret void\n}}"
+        )
+        .unwrap();
+    }
+
+    /// Generates the function `index`, giving its definition.
+    fn function(&mut self, index: usize, func: &'p Function<'src>) -> String {
+        let file = self.file(func.span);
+        let (line, _) = func.span.get_start_code_pos();
+        let short = func
+            .name
+            .split_once('/')
+            .map_or(func.name.as_str(), |x| x.1);
+        let scope = self.meta(format!(
+            "distinct !DISubprogram(name: \"{}\", linkageName: \"{}\", scope: !{file}, \
+             file: !{file}, line: {line}, type: !{}, scopeLine: {line}, \
+             spFlags: DISPFlagDefinition, unit: !{})",
+            escape(short),
+            escape(&func.name),
+            self.signature,
+            self.unit
+        ));
+        let location = self.location(func.span, scope);
+        let names: Vec<_> = func
+            .locals
+            .iter()
+            .enumerate()
+            .map(|(index, decl)| match &decl.name {
+                Some(name) => format!("%{name}.{index}"),
+                None => format!("%t{index}"),
+            })
+            .collect();
+        let reachable = func.reachable();
+        let mut dropped = vec![false; func.locals.len()];
+        let blocks = func.blocks.iter().zip(&reachable).filter(|(_, x)| **x);
+        for (block, _) in blocks {
+            for stmt in &block.stmts {
+                if let StatementKind::Drop(local) = stmt.kind {
+                    dropped[local.0] = true;
+                }
+            }
+        }
+        let mut state = FuncState {
+            generator: self,
+            func,
+            names,
+            dropped,
+            body: String::new(),
+            next: 0,
+            location,
+        };
+
+        for (index, block) in func.blocks.iter().enumerate() {
+            if !reachable[index] {
+                continue;
+            }
+            writeln!(state.body, "\nbb{index}:").unwrap();
+            // Terminators have the location of the last statement of their block
+            state.location = location;
+            for stmt in &block.stmts {
+                state.location = state.generator.location(stmt.span, scope);
+                state.statement(&stmt.kind);
+            }
+            state.terminator(&block.terminator);
+        }
+
+        let params: Vec<_> = (0..func.params)
+            .map(|x| format!("{} %arg.{x}", llvm_type(&func.locals[x].ty)))
+            .collect();
+        let mut definition = format!(
+            "define {} {}({}) !dbg !{scope} {{\n",
+            llvm_type(&func.ret),
+            state.generator.funcs[index],
+            params.join(", ")
+        );
+        for (local, decl) in func.locals.iter().enumerate() {
+            let ty = llvm_type(&decl.ty);
+            let var = &state.names[local];
+            let value = match local < func.params {
+                true => format!("%arg.{local}"),
+                false => zero(&decl.ty).to_owned(),
+            };
+            writeln!(
+                definition,
+                "  {var} = alloca {ty}\n  store {ty} {value}, ptr {var}"
+            )
+            .unwrap();
+        }
+        definition.push_str("  br label %bb0\n");
+        definition.push_str(&state.body);
+        definition.push('}');
+        definition
+    }
+}
+
+/// The state of the function being generated.
+struct FuncState<'g, 'p, 'src> {
+    generator: &'g mut Generator<'p, 'src>,
+    func: &'p Function<'src>,
+    /// The `alloca` of every local.
+    names: Vec<String>,
+    /// Whether every local is dropped somewhere, so moving its value out has to empty it.
+    dropped: Vec<bool>,
+    body: String,
+    /// The number of the next register.
+    next: usize,
+    /// The `DILocation` of the next instructions.
+    location: usize,
+}
+
+impl FuncState<'_, '_, '_> {
+    fn line(&mut self, line: impl AsRef<str>) {
+        writeln!(self.body, "  {}, !dbg !{}", line.as_ref(), self.location).unwrap();
+    }
+
+    /// Writes an instruction giving a value, and gives the register holding it.
+    fn emit(&mut self, instr: impl AsRef<str>) -> String {
+        let reg = format!("%v{}", self.next);
+        self.next += 1;
+        self.line(format!("{reg} = {}", instr.as_ref()));
+        reg
+    }
+
+    fn ty(&self, operand: &Operand) -> Ty {
+        self.func.operand_ty(operand)
+    }
+
+    /// Gives the value of `operand`, loading it from its local.
+    fn operand(&mut self, operand: &Operand) -> String {
+        match operand {
+            Operand::Copy(local) | Operand::Move(local) => self.load(*local),
+            Operand::Const(Const::Int(int)) => int.to_string(),
+            Operand::Const(Const::Float(float)) => float_literal(*float),
+            Operand::Const(Const::Bool(value)) => value.to_string(),
+            Operand::Const(Const::Str(string)) => self.generator.string(string),
+        }
+    }
+
+    fn load(&mut self, local: Local) -> String {
+        let ty = llvm_type(&self.func.locals[local.0].ty);
+        let var = &self.names[local.0];
+        self.emit(format!("load {ty}, ptr {var}"))
+    }
+
+    fn store(&mut self, local: Local, value: &str) {
+        let ty = llvm_type(&self.func.locals[local.0].ty);
+        let line = format!("store {ty} {value}, ptr {}", self.names[local.0]);
+        self.line(line);
+    }
+
+    fn statement(&mut self, stmt: &StatementKind) {
+        match stmt {
+            StatementKind::Assign(dst, rvalue) => self.assign(*dst, rvalue),
+            StatementKind::Call { dst, callee, args } => self.call(*dst, *callee, args),
+            StatementKind::SetField { obj, field, value } => {
+                let Ty::Obj(ty) = self.func.locals[obj.0].ty else {
+                    unreachable!("fields are set on objects")
+                };
+                let field_ty = &self.generator.program.types[ty.0].fields[*field].ty;
+                let obj = self.load(*obj);
+                let place = self.field(ty, &obj, *field);
+                if field_ty.is_ref() {
+                    let old = self.emit(format!("load ptr, ptr {place}"));
+                    let drop = self.generator.drop_fn(field_ty);
+                    self.line(format!("call void {drop}(ptr {old})"));
+                }
+                let value = self.operand(value);
+                self.line(format!(
+                    "store {} {value}, ptr {place}",
+                    llvm_type(field_ty)
+                ));
+            }
+            StatementKind::Replace { dst, value } => {
+                let value = self.operand(value);
+                let old = self.load(*dst);
+                match self.func.locals[dst.0].ty {
+                    Ty::Obj(ty) => {
+                        self.generator.replaced.insert(ty);
+                        let name = &self.generator.program.types[ty.0].name;
+                        let line = format!("call void @\"replace:{name}\"(ptr {old}, ptr {value})");
+                        self.line(line);
+                    }
+                    _ => self.line(format!(
+                        "call void @esc_array_replace(ptr {old}, ptr {value})"
+                    )),
+                }
+            }
+            StatementKind::Push { array, value } => {
+                let ty = self.ty(value);
+                let value = self.operand(value);
+                let array = self.load(*array);
+                self.line(format!(
+                    "call void {}(ptr {array}, {} {value})",
+                    push(&ty),
+                    extern_type(&ty)
+                ));
+            }
+            StatementKind::Print(value) => {
+                let value = self.operand(value);
+                self.line(format!("call void @esc_print(ptr {value})"));
+            }
+            StatementKind::Println => self.line("call void @esc_println()"),
+            StatementKind::Drop(local) => {
+                let ty = &self.func.locals[local.0].ty;
+                if ty.is_ref() {
+                    let drop = self.generator.drop_fn(ty);
+                    let value = self.load(*local);
+                    self.line(format!("call void {drop}(ptr {value})"));
+                    self.store(*local, "null");
+                }
+            }
+        }
+        // A moved value belongs to something else now, so dropping the local does nothing
+        for operand in stmt.operands() {
+            if let Operand::Move(local) = operand
+                && self.dropped[local.0]
+                && self.func.locals[local.0].ty.is_ref()
+                && stmt.defines() != Some(*local)
+            {
+                self.store(*local, "null");
+            }
+        }
+    }
+
+    /// Gives the address of the field `index` of the object `obj` of type `ty`.
+    fn field(&mut self, ty: TypeId, obj: &str, index: usize) -> String {
+        let name = &self.generator.program.types[ty.0].name;
+        let member = index + 1;
+        let instr = format!("getelementptr inbounds %\"{name}\", ptr {obj}, i32 0, i32 {member}");
+        self.emit(instr)
+    }
+
+    /// Gives the type of the items of `array` and the address of the one at `index`.
+    fn slot(&mut self, array: &Operand, index: &Operand) -> (Ty, String) {
+        let Ty::Array(item) = self.ty(array) else {
+            unreachable!("items are read from arrays")
+        };
+        let array = self.operand(array);
+        let index = self.operand(index);
+        let items = self.emit(format!(
+            "getelementptr inbounds %esc_array, ptr {array}, i32 0, i32 4"
+        ));
+        let items = self.emit(format!("load ptr, ptr {items}"));
+        let slot = self.emit(format!(
+            "getelementptr inbounds %esc_slot, ptr {items}, i32 {index}"
+        ));
+        (*item, slot)
+    }
+
+    fn assign(&mut self, dst: Local, rvalue: &Rvalue) {
+        let value = match rvalue {
+            Rvalue::Use(operand) => self.operand(operand),
+            Rvalue::Share(operand) => {
+                let ty = self.ty(operand);
+                let value = self.operand(operand);
+                match (ty, operand) {
+                    // String literals are never freed, so they have no owner
+                    (_, Operand::Const(_)) => value,
+                    (Ty::Str | Ty::String, _) => {
+                        self.emit(format!("call ptr @esc_string_copy(ptr {value})"))
+                    }
+                    _ => self.emit(format!("call ptr @esc_retain(ptr {value})")),
+                }
+            }
+            Rvalue::Binary(op, lhs, rhs) => {
+                let ty = self.ty(lhs);
+                let lhs = self.operand(lhs);
+                let rhs = self.operand(rhs);
+                self.binary(*op, &ty, &lhs, &rhs)
+            }
+            Rvalue::Unary(op, operand) => {
+                let ty = self.ty(operand);
+                let value = self.operand(operand);
+                match op {
+                    UnOp::Neg if ty == Ty::F32 => self.emit(format!("fneg float {value}")),
+                    UnOp::Neg => self.emit(format!("sub i32 0, {value}")),
+                    UnOp::Not => self.emit(format!("xor i1 {value}, true")),
+                }
+            }
+            Rvalue::ToString(operand) => {
+                let ty = self.ty(operand);
+                let value = self.operand(operand);
+                self.emit(match ty {
+                    Ty::I32 => format!("call ptr @esc_i32_to_string(i32 {value})"),
+                    Ty::F32 => format!("call ptr @esc_f32_to_string(float {value})"),
+                    Ty::Bool => format!("call ptr @esc_bool_to_string(i1 zeroext {value})"),
+                    _ => format!("call ptr @esc_string_copy(ptr {value})"),
+                })
+            }
+            Rvalue::Len(operand) => {
+                let len = match self.ty(operand) {
+                    Ty::Array(_) => "%esc_array",
+                    _ => "%esc_string",
+                };
+                let value = self.operand(operand);
+                let len = self.emit(format!(
+                    "getelementptr inbounds {len}, ptr {value}, i32 0, i32 1"
+                ));
+                self.emit(format!("load i32, ptr {len}"))
+            }
+            Rvalue::Array(item, items) => {
+                let drop = match item.is_ref() {
+                    true => self.generator.drop_fn(item),
+                    false => "null",
+                };
+                let array = self.emit(format!("call ptr @esc_array_new(ptr {drop})"));
+                let (push, llvm_type) = (push(item), extern_type(item));
+                for item in items {
+                    let item = self.operand(item);
+                    self.line(format!("call void {push}(ptr {array}, {llvm_type} {item})"));
+                }
+                array
+            }
+            Rvalue::New(ty, fields) => {
+                self.generator.constructed.insert(*ty);
+                let name = &self.generator.program.types[ty.0].name;
+                let object = self.emit(format!(
+                    "call ptr @esc_alloc(i64 ptrtoint (ptr getelementptr (%\"{name}\", ptr null, \
+                     i32 1) to i64), ptr @\"drop:{name}\")"
+                ));
+                let program = self.generator.program;
+                for (index, value) in fields.iter().enumerate() {
+                    let value = self.operand(value);
+                    let place = self.field(*ty, &object, index);
+                    let field_ty = llvm_type(&program.types[ty.0].fields[index].ty);
+                    self.line(format!("store {field_ty} {value}, ptr {place}"));
+                }
+                object
+            }
+            Rvalue::Field(obj, field) => {
+                let Ty::Obj(ty) = self.ty(obj) else {
+                    unreachable!("fields are read from objects")
+                };
+                let field_ty = llvm_type(&self.generator.program.types[ty.0].fields[*field].ty);
+                let obj = self.operand(obj);
+                let place = self.field(ty, &obj, *field);
+                self.emit(format!("load {field_ty}, ptr {place}"))
+            }
+            Rvalue::Index(array, index) => {
+                let (item, slot) = self.slot(array, index);
+                self.emit(format!("load {}, ptr {slot}", llvm_type(&item)))
+            }
+            Rvalue::Take(array, index) => {
+                // The array keeps an empty slot, which dropping it skips
+                let (item, slot) = self.slot(array, index);
+                let value = self.emit(format!("load {}, ptr {slot}", llvm_type(&item)));
+                self.line(format!("store ptr null, ptr {slot}"));
+                value
+            }
+        };
+        self.store(dst, &value);
+    }
+
+    fn call(&mut self, dst: Option<Local>, callee: Callee, args: &[Operand]) {
+        let program = self.generator.program;
+        match callee {
+            Callee::Func(func) => {
+                let args: Vec<_> = args
+                    .iter()
+                    .map(|arg| {
+                        let ty = llvm_type(&self.ty(arg));
+                        format!("{ty} {}", self.operand(arg))
+                    })
+                    .collect();
+                let ret = &program.functions[func.0].ret;
+                let call = format!(
+                    "call {} {}({})",
+                    llvm_type(ret),
+                    self.generator.funcs[func.0],
+                    args.join(", ")
+                );
+                match dst {
+                    Some(dst) => {
+                        let value = self.emit(call);
+                        self.store(dst, &value);
+                    }
+                    // An ignored result is still owned by the caller
+                    None if ret.is_ref() => {
+                        let value = self.emit(call);
+                        let drop = self.generator.drop_fn(ret);
+                        self.line(format!("call void {drop}(ptr {value})"));
+                    }
+                    None => self.line(call),
+                }
+            }
+            Callee::Extern(func) => {
+                let mut call_args = Vec::new();
+                for arg in args {
+                    let ty = self.ty(arg);
+                    let value = self.operand(arg);
+                    let arg = match ty {
+                        Ty::Str | Ty::String => {
+                            let data = self.emit(format!(
+                                "getelementptr inbounds %esc_string, ptr {value}, i32 0, i32 2"
+                            ));
+                            let data = self.emit(format!("load ptr, ptr {data}"));
+                            format!("ptr {data}")
+                        }
+                        _ => format!("{} {value}", extern_type(&ty)),
+                    };
+                    call_args.push(arg);
+                }
+                let ret = &program.externs[func.0].ret;
+                let call = format!(
+                    "call {} {}({})",
+                    extern_type(ret),
+                    self.generator.externs[func.0],
+                    call_args.join(", ")
+                );
+                match (dst, ret) {
+                    (Some(dst), Ty::Str | Ty::String) => {
+                        let data = self.emit(call);
+                        let string = self.emit(format!("call ptr @esc_from_cstr(ptr {data})"));
+                        self.store(dst, &string);
+                    }
+                    (Some(dst), _) => {
+                        let value = self.emit(call);
+                        self.store(dst, &value);
+                    }
+                    (None, _) => self.line(call),
+                }
+            }
+        }
+    }
+
+    fn terminator(&mut self, terminator: &Terminator) {
+        match terminator {
+            Terminator::Goto(target) => self.line(format!("br label %bb{}", target.0)),
+            Terminator::Branch { cond, then, els } => {
+                let cond = self.operand(cond);
+                self.line(format!(
+                    "br i1 {cond}, label %bb{}, label %bb{}",
+                    then.0, els.0
+                ));
+            }
+            Terminator::Return(Some(value)) => {
+                let ty = llvm_type(&self.ty(value));
+                let value = self.operand(value);
+                self.line(format!("ret {ty} {value}"));
+            }
+            Terminator::Return(None) => self.line("ret void"),
+            Terminator::Unreachable => self.line("unreachable"),
+        }
+    }
+
+    /// Generates `op` applied to `lhs` and `rhs`, whose type is `ty`, giving the result.
+    fn binary(&mut self, op: BinOp, ty: &Ty, lhs: &str, rhs: &str) -> String {
+        let instr = match (ty, op) {
+            (Ty::I32, BinOp::Div) => format!("call i32 @esc_div(i32 {lhs}, i32 {rhs})"),
+            (Ty::Str | Ty::String, BinOp::Add) => {
+                format!("call ptr @esc_concat(ptr {lhs}, ptr {rhs})")
+            }
+            (Ty::Str | Ty::String, BinOp::Eq | BinOp::Ne) => {
+                let eq = self.emit(format!("call zeroext i1 @esc_str_eq(ptr {lhs}, ptr {rhs})"));
+                return match op {
+                    BinOp::Ne => self.emit(format!("xor i1 {eq}, true")),
+                    _ => eq,
+                };
+            }
+            (Ty::F32, _) => {
+                let instr = match op {
+                    BinOp::Add => "fadd",
+                    BinOp::Sub => "fsub",
+                    BinOp::Mul => "fmul",
+                    BinOp::Div => "fdiv",
+                    BinOp::Eq => "fcmp oeq",
+                    BinOp::Ne => "fcmp une",
+                    BinOp::Lt => "fcmp olt",
+                    BinOp::Le => "fcmp ole",
+                    BinOp::Gt => "fcmp ogt",
+                    BinOp::Ge => "fcmp oge",
+                    BinOp::And | BinOp::Or => {
+                        unreachable!("`and` and `or` are lowered to branches")
+                    }
+                };
+                format!("{instr} float {lhs}, {rhs}")
+            }
+            _ => {
+                let instr = match op {
+                    BinOp::Add => "add",
+                    BinOp::Sub => "sub",
+                    BinOp::Mul => "mul",
+                    BinOp::Eq => "icmp eq",
+                    BinOp::Ne => "icmp ne",
+                    BinOp::Lt => "icmp slt",
+                    BinOp::Le => "icmp sle",
+                    BinOp::Gt => "icmp sgt",
+                    BinOp::Ge => "icmp sge",
+                    BinOp::Div => unreachable!("division is handled above"),
+                    BinOp::And | BinOp::Or => {
+                        unreachable!("`and` and `or` are lowered to branches")
+                    }
+                };
+                format!("{instr} {} {lhs}, {rhs}", llvm_type(ty))
+            }
+        };
+        self.emit(instr)
+    }
+}
+
+#[cfg(test)]
+fn generate_module(text: &str, level: u8) -> String {
+    crate::interp::with_checked(text, |modules, info| {
+        let mut program =
+            crate::mir::lower::lower(modules, info, crate::modules::ModuleId(0)).unwrap();
+        crate::mir::opt::optimize(&mut program, &crate::mir::opt::Options::level(level));
+        generate(&program)
+    })
+}
+
+#[test]
+fn llvm_test() {
+    let module = generate_module(
+        r"identifier app.main
+obj counter is
+    count: i32 = 0,
+    names: string[] = [],
+end
+func i32 fib(i32 n) is
+    if n < 2 is
+        return n
+    end
+    return fib(n - 1) + fib(n - 2)
+end
+func void start() is
+    counter c = counter
+    c.names.push(fib(10).to-string())
+end",
+        0,
+    );
+    assert!(module.starts_with("; Generated by esci from `app.main`\n"));
+    for definition in [
+        "%\"app.main/counter\" = type { %esc_header, i32, ptr }\n",
+        "\ndefine i32 @\"app.main/fib\"(i32 %arg.0) !dbg !",
+        "\ndefine internal void @\"drop:app.main/counter\"(ptr %value) {\n",
+        "\ndefine i32 @main() {\n  call void @\"app.main/start\"()\n",
+        "\n!llvm.dbg.cu = !{!1}\n",
+        "\n!0 = !DIFile(filename: \"main.scp\", directory: \"\")\n",
+        "distinct !DISubprogram(name: \"fib\", linkageName: \"app.main/fib\"",
+    ] {
+        assert!(module.contains(definition), "{definition}");
+    }
+    // Every instruction of a function points at its line
+    let body = module.split("define i32 @\"app.main/fib\"").nth(1).unwrap();
+    let body = &body[..body.find("\n}").unwrap()];
+    for line in body
+        .lines()
+        .filter(|x| x.starts_with("  ") && !x.contains("alloca"))
+    {
+        assert!(
+            line.contains(", !dbg !") || line.starts_with("  store ") || line == "  br label %bb0"
+        );
+    }
+}
+
+#[test]
+fn llvm_ownership_test() {
+    let module = generate_module(
+        r"identifier app.main
+obj item is
+    name: string = 'item',
+end
+obj holder is
+    kept: item = item,
+    items: item[] = [],
+end
+func void start() is
+    holder h = holder
+    h.items.push(item)
+    item taken = h.kept
+    string name = taken.name
+end",
+        0,
+    );
+    // Items are reference counted, as one is moved out of `kept`, but their arrays are not
+    assert!(module.contains("call void @esc_release(ptr %f0)"));
+    assert!(module.contains("call void @esc_free(ptr %f1)"));
+    assert!(module.contains("call ptr @esc_array_new(ptr @esc_release)"));
+    assert_eq!(module.matches("call ptr @esc_retain(").count(), 1);
+    assert!(module.contains("call ptr @esc_string_copy("));
+}
+
+#[test]
+fn llc_test() {
+    use std::process::Command;
+
+    // Only checked where LLVM and a C compiler to link with are installed
+    if ["llc", "cc"]
+        .iter()
+        .any(|x| Command::new(x).arg("--version").output().is_err())
+    {
+        return;
+    }
+    let samples = [
+        (
+            "ownership",
+            r"identifier app.main
+obj box is
+    name: string = 'box',
+end
+obj shelf is
+    boxes: box[] = [],
+    labels: string[] = [],
+    best: box = box,
+end
+impl shelf is
+    func void add(box b) is
+        self.boxes.push(b)
+    end
+end
+func void take(box b) is
+    box kept = b
+    print(kept.name + ',')
+end
+func void rename(string s) is
+    s = s + '!'
+    print(s)
+end
+func void start() is
+    shelf s = shelf
+    s.add(box(name: 'a'))
+    s.add(box(name: 'b'))
+    s.labels.push('c')
+    bulk take(s.boxes)
+    bulk take([box(name: 'd')])
+    box best = s.best
+    string label = s.labels.len().to-string()
+    rename(label)
+    bulk rename(s.labels)
+    for b in s.boxes is
+        print(b.name + best.name + label)
+    end
+end",
+        ),
+        (
+            "values",
+            r"identifier app.main
+obj box is
+    items: string[] = [],
+    size: f32 = 1,
+end
+func void fill(box b, string[] items) is
+    b = box(items: ['x', 'y?'], size: 0.1)
+    items = ['z']
+end
+func void show(string s) is
+    print(s + ';')
+end
+func void start() is
+    box b = box
+    string[] items = ['a']
+    fill(b, items)
+    bulk show(b.items)
+    bulk show(items)
+    print((b.size * 3).to-string() + ' ' + (-0.5).to-string() + ' ' + (2147483647 + 1).to-string())
+    if 'ab' == 'a' + 'b' and not ('b' == 'ab') is
+        print(' ordered')
+    end
+    i32 i = 0
+    while i < 2 is
+        i++
+        for item in b.items is
+            print(item)
+        end
+    end
+end",
+        ),
+    ];
+    for (name, text) in samples {
+        let expected = super::c::run_interp(text);
+        for level in [0, 3] {
+            let output = run_llvm(name, &generate_module(text, level));
+            assert_eq!(output, expected, "{name} -O{level}");
+        }
+    }
+}
+
+/// Compiles `code` with `llc`, links it with the runtime and runs it, giving its output.
+#[cfg(test)]
+fn run_llvm(name: &str, code: &str) -> String {
+    use std::process::Command;
+
+    let dir = std::env::temp_dir().join(format!("escoop-llvm-{name}-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(dir.join(super::c::HEADER_NAME), super::c::HEADER).unwrap();
+    std::fs::write(dir.join(RUNTIME_NAME), RUNTIME).unwrap();
+    std::fs::write(dir.join("main.ll"), code).unwrap();
+    // LLVM before 15 only reads opaque pointers when asked to
+    let llc = |flags: &[&str]| {
+        Command::new("llc")
+            .args(flags)
+            .args(["-relocation-model=pic", "-filetype=obj", "-o"])
+            .arg(dir.join("main.o"))
+            .arg(dir.join("main.ll"))
+            .output()
+            .unwrap()
+    };
+    let mut compiled = llc(&["-opaque-pointers"]);
+    if !compiled.status.success() {
+        compiled = llc(&[]);
+    }
+    assert!(
+        compiled.status.success(),
+        "{}",
+        String::from_utf8_lossy(&compiled.stderr)
+    );
+    let exe = dir.join("main");
+    let linked = Command::new("cc")
+        .arg("-o")
+        .arg(&exe)
+        .arg(dir.join("main.o"))
+        .arg(dir.join(RUNTIME_NAME))
+        .status()
+        .unwrap();
+    assert!(linked.success());
+    let run = Command::new(&exe).output().unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+    assert!(run.status.success());
+    String::from_utf8(run.stdout).unwrap()
+}
@@ -0,0 +1,437 @@
+  ;; Runtime of the WebAssembly written by `esci build --target wat`.
+  ;;
+  ;; Strings, arrays and objects live in linear memory. Each starts with its reference count
+  ;; and the index of the function freeing it in the `$esc_drops` table: 0 for strings, 1 for
+  ;; arrays and one for every `obj` type. Values with a single owner are freed with `$esc_free`,
+  ;; whatever their count, and the values of the types ownership analysis demoted are released
+  ;; with `$esc_release`. String literals have a negative count and are never freed.
+  ;;
+  ;; Memory layout:
+  ;;   8          head of the free list of big blocks
+  ;;   16..272    heads of the free lists of blocks of 8, 16, .. 504 bytes
+  ;;   272..400   scratch space for formatting numbers
+  ;;   400..512   messages of the runtime
+  ;;   1024..     string literals, then the heap
+  ;;
+  ;; Blocks of the heap are preceded by their size, and freed blocks are kept in a free list
+  ;; for their size to be reused.
+
+  (type $esc_drop (func (param i32)))
+
+  (data (i32.const 400) "attempt to divide by zero")
+  (data (i32.const 432) "out of memory")
+  (data (i32.const 448) "truefalse")
+
+  (func $esc_fail (param $message i32) (param $len i32)
+    (call $esc_host_fail (local.get $message) (local.get $len))
+    (unreachable))
+
+  ;; Allocates a block of `size` bytes.
+  (func $esc_malloc (param $size i32) (result i32)
+    (local $ptr i32) (local $class i32) (local $prev i32)
+    (local.set $size (i32.and (i32.add (local.get $size) (i32.const 7)) (i32.const -8)))
+    (local.set $class (i32.shr_u (local.get $size) (i32.const 3)))
+    (if (i32.lt_u (local.get $class) (i32.const 64))
+      (then
+        (local.set $ptr (i32.load offset=16 (i32.shl (local.get $class) (i32.const 2))))
+        (if (local.get $ptr)
+          (then
+            (i32.store offset=16
+              (i32.shl (local.get $class) (i32.const 2))
+              (i32.load (local.get $ptr))))))
+      (else
+        ;; The first big block that is big enough
+        (local.set $ptr (i32.load (i32.const 8)))
+        (block $found
+          (loop $next
+            (br_if $found (i32.eqz (local.get $ptr)))
+            (if (i32.ge_u (i32.load (i32.sub (local.get $ptr) (i32.const 8))) (local.get $size))
+              (then
+                (if (local.get $prev)
+                  (then (i32.store (local.get $prev) (i32.load (local.get $ptr))))
+                  (else (i32.store (i32.const 8) (i32.load (local.get $ptr)))))
+                (br $found)))
+            (local.set $prev (local.get $ptr))
+            (local.set $ptr (i32.load (local.get $ptr)))
+            (br $next)))))
+    (if (i32.eqz (local.get $ptr))
+      (then
+        (local.set $ptr (i32.add (global.get $esc_heap) (i32.const 8)))
+        (global.set $esc_heap (i32.add (local.get $ptr) (local.get $size)))
+        (if (i32.gt_u (global.get $esc_heap) (i32.shl (memory.size) (i32.const 16)))
+          (then
+            (if (i32.eq
+                  (memory.grow
+                    (i32.sub
+                      (i32.shr_u (i32.add (global.get $esc_heap) (i32.const 65535)) (i32.const 16))
+                      (memory.size)))
+                  (i32.const -1))
+              (then (call $esc_fail (i32.const 432) (i32.const 13))))))
+        (i32.store (i32.sub (local.get $ptr) (i32.const 8)) (local.get $size))))
+    (local.get $ptr))
+
+  (func $esc_free_block (param $ptr i32)
+    (local $head i32)
+    (local.set $head
+      (i32.shr_u (i32.load (i32.sub (local.get $ptr) (i32.const 8))) (i32.const 3)))
+    (local.set $head
+      (select
+        (i32.add (i32.shl (local.get $head) (i32.const 2)) (i32.const 16))
+        (i32.const 8)
+        (i32.lt_u (local.get $head) (i32.const 64))))
+    (i32.store (local.get $ptr) (i32.load (local.get $head)))
+    (i32.store (local.get $head) (local.get $ptr)))
+
+  ;; Allocates a value of `size` bytes with one reference, freed by `$esc_drops[kind]`.
+  (func $esc_alloc (param $size i32) (param $kind i32) (result i32)
+    (local $ptr i32)
+    (local.set $ptr (call $esc_malloc (local.get $size)))
+    (i32.store (local.get $ptr) (i32.const 1))
+    (i32.store offset=4 (local.get $ptr) (local.get $kind))
+    (local.get $ptr))
+
+  ;; Frees `value`, which has a single owner.
+  (func $esc_free (param $value i32)
+    (if (local.get $value)
+      (then
+        (if (i32.ge_s (i32.load (local.get $value)) (i32.const 0))
+          (then
+            (call_indirect $esc_drops (type $esc_drop)
+              (local.get $value)
+              (i32.load offset=4 (local.get $value))))))))
+
+  (func $esc_retain (param $value i32) (result i32)
+    (if (local.get $value)
+      (then
+        (if (i32.ge_s (i32.load (local.get $value)) (i32.const 0))
+          (then
+            (i32.store (local.get $value) (i32.add (i32.load (local.get $value)) (i32.const 1)))))))
+    (local.get $value))
+
+  (func $esc_release (param $value i32)
+    (local $rc i32)
+    (if (local.get $value)
+      (then
+        (local.set $rc (i32.load (local.get $value)))
+        (if (i32.gt_s (local.get $rc) (i32.const 0))
+          (then
+            (i32.store (local.get $value) (i32.sub (local.get $rc) (i32.const 1)))
+            (if (i32.eq (local.get $rc) (i32.const 1))
+              (then
+                (call_indirect $esc_drops (type $esc_drop)
+                  (local.get $value)
+                  (i32.load offset=4 (local.get $value))))))))))
+
+  (func $esc_div (param $a i32) (param $b i32) (result i32)
+    (if (i32.eqz (local.get $b))
+      (then (call $esc_fail (i32.const 400) (i32.const 25))))
+    (if (i32.and
+          (i32.eq (local.get $a) (i32.const 0x80000000))
+          (i32.eq (local.get $b) (i32.const -1)))
+      (then (return (local.get $a))))
+    (i32.div_s (local.get $a) (local.get $b)))
+
+  ;; Strings: the count, the drop function, the length and the bytes.
+
+  (func $esc_free_string (param $string i32)
+    (call $esc_free_block (local.get $string)))
+
+  (func $esc_string_new (param $len i32) (result i32)
+    (local $string i32)
+    (local.set $string (call $esc_alloc (i32.add (local.get $len) (i32.const 12)) (i32.const 0)))
+    (i32.store offset=8 (local.get $string) (local.get $len))
+    (local.get $string))
+
+  ;; Copies `len` bytes at `data` into a new string.
+  (func $esc_string_from (param $data i32) (param $len i32) (result i32)
+    (local $string i32)
+    (local.set $string (call $esc_string_new (local.get $len)))
+    (memory.copy
+      (i32.add (local.get $string) (i32.const 12))
+      (local.get $data)
+      (local.get $len))
+    (local.get $string))
+
+  ;; Copies `string` for a new owner. String literals are never freed, so they are shared.
+  (func $esc_string_copy (param $string i32) (result i32)
+    (if (i32.lt_s (i32.load (local.get $string)) (i32.const 0))
+      (then (return (local.get $string))))
+    (call $esc_string_from
+      (i32.add (local.get $string) (i32.const 12))
+      (i32.load offset=8 (local.get $string))))
+
+  (func $esc_concat (param $a i32) (param $b i32) (result i32)
+    (local $string i32)
+    (local.set $string
+      (call $esc_string_new
+        (i32.add (i32.load offset=8 (local.get $a)) (i32.load offset=8 (local.get $b)))))
+    (memory.copy
+      (i32.add (local.get $string) (i32.const 12))
+      (i32.add (local.get $a) (i32.const 12))
+      (i32.load offset=8 (local.get $a)))
+    (memory.copy
+      (i32.add (i32.add (local.get $string) (i32.const 12)) (i32.load offset=8 (local.get $a)))
+      (i32.add (local.get $b) (i32.const 12))
+      (i32.load offset=8 (local.get $b)))
+    (local.get $string))
+
+  (func $esc_str_eq (param $a i32) (param $b i32) (result i32)
+    (local $i i32)
+    (if (i32.ne (i32.load offset=8 (local.get $a)) (i32.load offset=8 (local.get $b)))
+      (then (return (i32.const 0))))
+    (block $done
+      (loop $next
+        (br_if $done (i32.ge_u (local.get $i) (i32.load offset=8 (local.get $a))))
+        (if (i32.ne
+              (i32.load8_u offset=12 (i32.add (local.get $a) (local.get $i)))
+              (i32.load8_u offset=12 (i32.add (local.get $b) (local.get $i))))
+          (then (return (i32.const 0))))
+        (local.set $i (i32.add (local.get $i) (i32.const 1)))
+        (br $next)))
+    (i32.const 1))
+
+  (func $esc_print (param $string i32)
+    (call $esc_host_print
+      (i32.add (local.get $string) (i32.const 12))
+      (i32.load offset=8 (local.get $string))))
+
+  ;; Writes the digits of `value` to the scratch space, ending at byte 400. Gives where they
+  ;; start.
+  (func $esc_digits (param $value i64) (result i32)
+    (local $at i32)
+    (local.set $at (i32.const 400))
+    (loop $next
+      (local.set $at (i32.sub (local.get $at) (i32.const 1)))
+      (i64.store8 (local.get $at)
+        (i64.add (i64.rem_u (local.get $value) (i64.const 10)) (i64.const 48)))
+      (local.set $value (i64.div_u (local.get $value) (i64.const 10)))
+      (br_if $next (i64.ne (local.get $value) (i64.const 0))))
+    (local.get $at))
+
+  (func $esc_i32_to_string (param $value i32) (result i32)
+    (local $at i32)
+    (local.set $at
+      (call $esc_digits
+        (select
+          (i64.sub (i64.const 0) (i64.extend_i32_s (local.get $value)))
+          (i64.extend_i32_s (local.get $value))
+          (i32.lt_s (local.get $value) (i32.const 0)))))
+    (if (i32.lt_s (local.get $value) (i32.const 0))
+      (then
+        (local.set $at (i32.sub (local.get $at) (i32.const 1)))
+        (i32.store8 (local.get $at) (i32.const 45))))
+    (call $esc_string_from (local.get $at) (i32.sub (i32.const 400) (local.get $at))))
+
+  (func $esc_bool_to_string (param $value i32) (result i32)
+    (if (result i32) (local.get $value)
+      (then (call $esc_string_from (i32.const 448) (i32.const 4)))
+      (else (call $esc_string_from (i32.const 452) (i32.const 5)))))
+
+  ;; Multiplies `x` by 10^`shift`. Powers of ten up to 10^22 are exact in `f64`, so this is
+  ;; one correctly rounded operation for them.
+  (func $esc_scale (param $x f64) (param $shift i32) (result f64)
+    (local $pow f64) (local $i i32)
+    (local.set $pow (f64.const 1))
+    (block $done
+      (loop $next
+        (br_if $done
+          (i32.ge_s
+            (local.get $i)
+            (select
+              (local.get $shift)
+              (i32.sub (i32.const 0) (local.get $shift))
+              (i32.ge_s (local.get $shift) (i32.const 0)))))
+        (local.set $pow (f64.mul (local.get $pow) (f64.const 10)))
+        (local.set $i (i32.add (local.get $i) (i32.const 1)))
+        (br $next)))
+    (if (result f64) (i32.ge_s (local.get $shift) (i32.const 0))
+      (then (f64.mul (local.get $x) (local.get $pow)))
+      (else (f64.div (local.get $x) (local.get $pow)))))
+
+  ;; Writes the shortest digits reading back as `value`, without an exponent. The digits are
+  ;; found with `f64` arithmetic, which is precise enough for every `f32`.
+  (func $esc_f32_to_string (param $value f32) (result i32)
+    (local $x f64) (local $scale f64) (local $digits f64) (local $pow f64)
+    (local $exponent i32) (local $count i32) (local $shift i32) (local $len i32) (local $at i32)
+    (local $i i32) (local $int i64)
+    (if (f32.ne (local.get $value) (local.get $value))
+      (then
+        (i32.store (i32.const 272) (i32.const 0x4e614e))
+        (return (call $esc_string_from (i32.const 272) (i32.const 3)))))
+    (if (i32.lt_s (i32.reinterpret_f32 (local.get $value)) (i32.const 0))
+      (then
+        (i32.store8 (i32.const 272) (i32.const 45))
+        (local.set $len (i32.const 1))))
+    (local.set $x (f64.promote_f32 (f32.abs (local.get $value))))
+    (if (f64.eq (local.get $x) (f64.const inf))
+      (then
+        (i32.store (i32.add (local.get $len) (i32.const 272)) (i32.const 0x666e69))
+        (return (call $esc_string_from (i32.const 272) (i32.add (local.get $len) (i32.const 3))))))
+    (if (f64.eq (local.get $x) (f64.const 0))
+      (then
+        (i32.store8 (i32.add (local.get $len) (i32.const 272)) (i32.const 48))
+        (return (call $esc_string_from (i32.const 272) (i32.add (local.get $len) (i32.const 1))))))
+    ;; 10^exponent <= x < 10^(exponent + 1)
+    (local.set $scale (f64.const 1))
+    (block $done
+      (loop $up
+        (br_if $done (f64.lt (local.get $x) (f64.mul (local.get $scale) (f64.const 10))))
+        (local.set $scale (f64.mul (local.get $scale) (f64.const 10)))
+        (local.set $exponent (i32.add (local.get $exponent) (i32.const 1)))
+        (br $up)))
+    (block $done
+      (loop $down
+        (br_if $done (f64.ge (local.get $x) (local.get $scale)))
+        (local.set $scale (f64.div (local.get $scale) (f64.const 10)))
+        (local.set $exponent (i32.sub (local.get $exponent) (i32.const 1)))
+        (br $down)))
+    ;; The fewest digits that round trip, rounding halfway cases up like Rust does
+    (local.set $pow (f64.const 1))
+    (block $done
+      (loop $more
+        (local.set $count (i32.add (local.get $count) (i32.const 1)))
+        (local.set $shift (i32.sub (i32.sub (local.get $count) (i32.const 1)) (local.get $exponent)))
+        (local.set $digits
+          (f64.floor
+            (f64.add (call $esc_scale (local.get $x) (local.get $shift)) (f64.const 0.5))))
+        (br_if $done (i32.ge_u (local.get $count) (i32.const 9)))
+        (br_if $done
+          (f32.eq
+            (f32.demote_f64
+              (call $esc_scale (local.get $digits) (i32.sub (i32.const 0) (local.get $shift))))
+            (f32.abs (local.get $value))))
+        (local.set $pow (f64.mul (local.get $pow) (f64.const 10)))
+        (br $more)))
+    ;; Rounding up can give one digit more, like 9.99 giving 10.0
+    (if (f64.ge (local.get $digits) (f64.mul (local.get $pow) (f64.const 10)))
+      (then
+        (local.set $digits (f64.div (local.get $digits) (f64.const 10)))
+        (local.set $exponent (i32.add (local.get $exponent) (i32.const 1)))))
+    (local.set $int (i64.trunc_f64_u (local.get $digits)))
+    (block $done
+      (loop $trim
+        (br_if $done (i32.le_u (local.get $count) (i32.const 1)))
+        (br_if $done (i64.ne (i64.rem_u (local.get $int) (i64.const 10)) (i64.const 0)))
+        (local.set $int (i64.div_u (local.get $int) (i64.const 10)))
+        (local.set $count (i32.sub (local.get $count) (i32.const 1)))
+        (br $trim)))
+    ;; The digits are at 400 - count, and the result is written from 272 after the sign
+    (local.set $at (call $esc_digits (local.get $int)))
+    (if (i32.lt_s (local.get $exponent) (i32.const 0))
+      (then
+        (i32.store16 (i32.add (local.get $len) (i32.const 272)) (i32.const 0x2e30))
+        (local.set $len (i32.add (local.get $len) (i32.const 2)))
+        (block $done
+          (loop $zeros
+            (br_if $done
+              (i32.ge_s (local.get $i) (i32.sub (i32.const -1) (local.get $exponent))))
+            (i32.store8 (i32.add (local.get $len) (i32.const 272)) (i32.const 48))
+            (local.set $len (i32.add (local.get $len) (i32.const 1)))
+            (local.set $i (i32.add (local.get $i) (i32.const 1)))
+            (br $zeros)))
+        (memory.copy
+          (i32.add (local.get $len) (i32.const 272))
+          (local.get $at)
+          (local.get $count))
+        (local.set $len (i32.add (local.get $len) (local.get $count))))
+      (else
+        (block $done
+          (loop $next
+            (br_if $done
+              (i32.and
+                (i32.ge_s (local.get $i) (local.get $count))
+                (i32.gt_s (local.get $i) (local.get $exponent))))
+            (if (i32.eq (local.get $i) (i32.add (local.get $exponent) (i32.const 1)))
+              (then
+                (i32.store8 (i32.add (local.get $len) (i32.const 272)) (i32.const 46))
+                (local.set $len (i32.add (local.get $len) (i32.const 1)))))
+            (i32.store8 (i32.add (local.get $len) (i32.const 272))
+              (select
+                (i32.load8_u (i32.add (local.get $at) (local.get $i)))
+                (i32.const 48)
+                (i32.lt_s (local.get $i) (local.get $count))))
+            (local.set $len (i32.add (local.get $len) (i32.const 1)))
+            (local.set $i (i32.add (local.get $i) (i32.const 1)))
+            (br $next)))))
+    (call $esc_string_from (i32.const 272) (local.get $len)))
+
+  ;; Arrays: the count, the drop function, the length, the capacity, the address of the items
+  ;; and how the items are dropped with the array: 0 if they aren't references, 1 if they are
+  ;; freed and 2 if they are released. Every item takes 4 bytes.
+
+  (func $esc_array_free (param $array i32)
+    (local $i i32) (local $item i32)
+    (if (i32.load offset=20 (local.get $array))
+      (then
+        (block $done
+          (loop $next
+            (br_if $done (i32.ge_u (local.get $i) (i32.load offset=8 (local.get $array))))
+            (local.set $item (call $esc_array_get (local.get $array) (local.get $i)))
+            (if (i32.eq (i32.load offset=20 (local.get $array)) (i32.const 2))
+              (then (call $esc_release (local.get $item)))
+              (else (call $esc_free (local.get $item))))
+            (local.set $i (i32.add (local.get $i) (i32.const 1)))
+            (br $next)))))
+    (if (i32.load offset=16 (local.get $array))
+      (then (call $esc_free_block (i32.load offset=16 (local.get $array)))))
+    (call $esc_free_block (local.get $array)))
+
+  (func $esc_array_new (param $drop i32) (result i32)
+    (local $array i32)
+    (local.set $array (call $esc_alloc (i32.const 24) (i32.const 1)))
+    (i64.store offset=8 (local.get $array) (i64.const 0))
+    (i32.store offset=16 (local.get $array) (i32.const 0))
+    (i32.store offset=20 (local.get $array) (local.get $drop))
+    (local.get $array))
+
+  ;; Gets the address of the item at `index`.
+  (func $esc_array_item (param $array i32) (param $index i32) (result i32)
+    (i32.add
+      (i32.load offset=16 (local.get $array))
+      (i32.shl (local.get $index) (i32.const 2))))
+
+  (func $esc_array_get (param $array i32) (param $index i32) (result i32)
+    (i32.load (call $esc_array_item (local.get $array) (local.get $index))))
+
+  ;; Moves `item` to the end of `array`.
+  (func $esc_array_push (param $array i32) (param $item i32)
+    (local $cap i32) (local $items i32)
+    (if (i32.eq (i32.load offset=8 (local.get $array)) (i32.load offset=12 (local.get $array)))
+      (then
+        (local.set $cap
+          (select
+            (i32.shl (i32.load offset=12 (local.get $array)) (i32.const 1))
+            (i32.const 4)
+            (i32.load offset=12 (local.get $array))))
+        (local.set $items (call $esc_malloc (i32.shl (local.get $cap) (i32.const 2))))
+        (if (i32.load offset=16 (local.get $array))
+          (then
+            (memory.copy
+              (local.get $items)
+              (i32.load offset=16 (local.get $array))
+              (i32.shl (i32.load offset=8 (local.get $array)) (i32.const 2)))
+            (call $esc_free_block (i32.load offset=16 (local.get $array)))))
+        (i32.store offset=12 (local.get $array) (local.get $cap))
+        (i32.store offset=16 (local.get $array) (local.get $items))))
+    (i32.store
+      (call $esc_array_item (local.get $array) (i32.load offset=8 (local.get $array)))
+      (local.get $item))
+    (i32.store offset=8
+      (local.get $array)
+      (i32.add (i32.load offset=8 (local.get $array)) (i32.const 1))))
+
+  ;; Moves the items of `src` into `dst`, so its owner sees them, and drops `src`.
+  (func $esc_array_replace (param $dst i32) (param $src i32)
+    (local $old i32)
+    (if (i32.ne (local.get $dst) (local.get $src))
+      (then
+        ;; The old items go to a new array, freed with it
+        (local.set $old (call $esc_array_new (i32.load offset=20 (local.get $dst))))
+        (i64.store offset=8 (local.get $old) (i64.load offset=8 (local.get $dst)))
+        (i32.store offset=16 (local.get $old) (i32.load offset=16 (local.get $dst)))
+        (i64.store offset=8 (local.get $dst) (i64.load offset=8 (local.get $src)))
+        (i32.store offset=16 (local.get $dst) (i32.load offset=16 (local.get $src)))
+        (i64.store offset=8 (local.get $src) (i64.const 0))
+        (i32.store offset=16 (local.get $src) (i32.const 0))
+        (call $esc_free (local.get $old))))
+    (call $esc_release (local.get $src)))
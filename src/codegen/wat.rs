@@ -0,0 +1,921 @@
+#![deny(missing_docs)]
+//! Module for compiling the [mid-level IR](crate::mir) of a project to the WebAssembly text
+//! format.
+//!
+//! The generated module includes the [runtime](RUNTIME), which allocates strings, arrays and
+//! objects in linear memory, with the same ownership rules as the [C backend](super::c): values
+//! are moved into owned parameters, objects and arrays, borrowing locals hold the address of a
+//! value owned elsewhere, and values are freed where the IR drops them. Only values of the fields
+//! and types ownership analysis demoted are reference counted, and released instead.
+//!
+//! Functions are named `$<module>/<name>` and methods `$<module>/<type>.<method>`, and locals
+//! `$<name>.<number>`, or `$t<number>` for temporaries. Objects hold their reference count, the
+//! index of the function freeing them, and then every field in 4 bytes. `i32`, `bool`, strings,
+//! arrays and objects are `i32` values, the last three being the address of their value in
+//! memory, and `f32` is `f32`.
+//!
+//! WebAssembly has no `goto`, so the blocks of a function that are jumped to are the targets of
+//! a `br_table` in a loop, dispatching on the local `$next`. Jumping sets `$next` to the number
+//! of the block and branches to the top of the loop, and the other blocks follow the only block
+//! going to them. Functions without jumps are written without the loop.
+//!
+//! The module exports its `memory` and the entry function as `start`, and imports the functions
+//! of the host:
+//!
+//! - `escoop.print`, taking the address and length of UTF-8 text to print,
+//! - `escoop.println`, printing a line break,
+//! - `escoop.fail`, taking the address and length of an error message, and never returning.
+//!
+//! `extern func`s are imported from the `env` module under their own name. Strings are passed as
+//! the address and length of their bytes, and returned the same way, to be copied into a new
+//! string. Arrays and objects are passed as their address, borrowed for the call.
+
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::Write,
+};
+
+use crate::{
+    ast::{BinOp, UnOp},
+    mir::{
+        BlockId, Callee, Const, Function, Local, Operand, Program, Rvalue, StatementKind,
+        Terminator, Ty, TypeId,
+    },
+};
+
+/// The runtime included in every generated module.
+pub const RUNTIME: &str = include_str!("runtime.wat");
+
+/// Where string literals start in memory, after the space the runtime uses.
+const DATA_START: usize = 1024;
+
+/// The size of the header of strings, arrays and objects: their count and drop function.
+const HEADER_SIZE: usize = 8;
+
+/// Offset of the length of strings and arrays.
+const LEN: usize = 8;
+
+/// Offset of the bytes of strings.
+const BYTES: usize = 12;
+
+/// Generates a WebAssembly module for every function of `program`, exporting its entry function
+/// as `start`.
+pub fn generate(program: &Program) -> String {
+    let mut generator = Generator {
+        program,
+        funcs: program
+            .functions
+            .iter()
+            .map(|x| format!("${}", x.name))
+            .collect(),
+        externs: program
+            .externs
+            .iter()
+            .map(|x| format!("$extern:{}", x.name))
+            .collect(),
+        constructed: Vec::new(),
+        replaced: HashSet::new(),
+        strings: HashMap::new(),
+        data: String::new(),
+        data_end: DATA_START,
+    };
+
+    let mut definitions = String::new();
+    for (index, func) in program.functions.iter().enumerate() {
+        let definition = generator.function(index, func);
+        writeln!(definitions, "\n{definition}").unwrap();
+    }
+
+    let mut out = String::new();
+    let entry = &program.functions[program.entry.0].name;
+    let module = entry.split_once('/').map_or(entry.as_str(), |x| x.0);
+    writeln!(out, "(module").unwrap();
+    writeln!(out, "  ;; Generated by esci from `{module}`\n").unwrap();
+    out.push_str("  (import \"escoop\" \"print\" (func $esc_host_print (param i32 i32)))\n");
+    out.push_str("  (import \"escoop\" \"println\" (func $esc_host_println))\n");
+    out.push_str("  (import \"escoop\" \"fail\" (func $esc_host_fail (param i32 i32)))\n");
+    for (func, name) in program.externs.iter().zip(&generator.externs) {
+        let mut import = name.clone();
+        let params: Vec<_> = func.params.iter().map(extern_type).collect();
+        if !params.is_empty() {
+            write!(import, " (param {})", params.join(" ")).unwrap();
+        }
+        if func.ret != Ty::Void {
+            write!(import, " (result {})", extern_type(&func.ret)).unwrap();
+        }
+        writeln!(out, "  (import \"env\" \"{}\" (func {import}))", func.name).unwrap();
+    }
+    out.push('\n');
+    out.push_str(RUNTIME);
+
+    // The heap starts after the literals, with space for the size of its first block
+    let heap = generator.data_end.next_multiple_of(8);
+    let pages = heap / 65536 + 1;
+    writeln!(out, "\n  (memory (export \"memory\") {pages})").unwrap();
+    writeln!(out, "  (global $esc_heap (mut i32) (i32.const {heap}))").unwrap();
+    let mut drops = vec!["$esc_free_string".to_owned(), "$esc_array_free".to_owned()];
+    drops.extend(
+        generator
+            .constructed
+            .iter()
+            .map(|obj| format!("$drop:{}", program.types[obj.0].name)),
+    );
+    writeln!(out, "  (table $esc_drops {} funcref)", drops.len()).unwrap();
+    writeln!(out, "  (elem (i32.const 0) {})", drops.join(" ")).unwrap();
+    if !generator.data.is_empty() {
+        out.push('\n');
+        out.push_str(&generator.data);
+    }
+    for index in 0..program.types.len() {
+        generator.type_functions(TypeId(index), &mut out);
+    }
+    out.push_str(&definitions);
+    writeln!(
+        out,
+        "\n  (export \"start\" (func {})))",
+        generator.funcs[program.entry.0]
+    )
+    .unwrap();
+    out
+}
+
+/// Escapes `bytes` into a WebAssembly string literal.
+fn string_literal(bytes: &[u8]) -> String {
+    let mut literal = String::from("\"");
+    for byte in bytes {
+        match byte {
+            b' '..=b'~' if !matches!(byte, b'"' | b'\\') => literal.push(*byte as char),
+            _ => write!(literal, "\\{byte:02x}").unwrap(),
+        }
+    }
+    literal.push('"');
+    literal
+}
+
+fn float_literal(float: f32) -> String {
+    match float.is_infinite() {
+        true => "(f32.const inf)".to_owned(),
+        false => format!("(f32.const {float:?})"),
+    }
+}
+
+fn int_literal(int: i32) -> String {
+    format!("(i32.const {int})")
+}
+
+/// Gives code reading the local `var`.
+fn get(var: &str) -> String {
+    format!("(local.get {var})")
+}
+
+/// Gives code calling the function `func` with `args`.
+fn call(func: &str, args: &[impl AsRef<str>]) -> String {
+    let mut call = format!("(call {func}");
+    for arg in args {
+        write!(call, " {}", arg.as_ref()).unwrap();
+    }
+    call.push(')');
+    call
+}
+
+fn wasm_type(ty: &Ty) -> &'static str {
+    match ty {
+        Ty::F32 => "f32",
+        _ => "i32",
+    }
+}
+
+/// The types of `ty` in the signature of an imported `extern` function.
+fn extern_type(ty: &Ty) -> &'static str {
+    match ty {
+        Ty::Str | Ty::String => "i32 i32",
+        _ => wasm_type(ty),
+    }
+}
+
+/// The offset of the field at `index` in objects.
+fn field_offset(index: usize) -> usize {
+    HEADER_SIZE + 4 * index
+}
+
+/// Gives code loading a value of type `ty` at `offset` from the address `address`.
+fn load(ty: &Ty, address: &str, offset: usize) -> String {
+    let wasm_type = wasm_type(ty);
+    match offset {
+        0 => format!("({wasm_type}.load {address})"),
+        _ => format!("({wasm_type}.load offset={offset} {address})"),
+    }
+}
+
+/// Gives code storing `value` of type `ty` at `offset` from the address `address`.
+fn store(ty: &Ty, address: &str, offset: usize, value: &str) -> String {
+    let wasm_type = wasm_type(ty);
+    match offset {
+        0 => format!("({wasm_type}.store {address} {value})"),
+        _ => format!("({wasm_type}.store offset={offset} {address} {value})"),
+    }
+}
+
+/// Gives code for the 4 bytes array items of type `ty` are stored as.
+fn slot(ty: &Ty, value: String) -> String {
+    match ty {
+        Ty::F32 => format!("(i32.reinterpret_f32 {value})"),
+        _ => value,
+    }
+}
+
+struct Generator<'p, 'src> {
+    program: &'p Program<'src>,
+    funcs: Vec<String>,
+    externs: Vec<String>,
+    /// The types that are constructed, in the order of their drop functions in the table after
+    /// the ones of strings and arrays.
+    constructed: Vec<TypeId>,
+    /// The types whose values are replaced through mutable parameters, needing a function doing
+    /// so.
+    replaced: HashSet<TypeId>,
+    /// The address of every string literal.
+    strings: HashMap<String, usize>,
+    /// The data segments of the string literals.
+    data: String,
+    /// Where the next string literal goes.
+    data_end: usize,
+}
+
+impl<'p, 'src> Generator<'p, 'src> {
+    /// The runtime function dropping values of the reference type `ty`.
+    fn drop_fn(&self, ty: &Ty) -> &'static str {
+        match self.program.is_counted(ty) {
+            true => "$esc_release",
+            false => "$esc_free",
+        }
+    }
+
+    /// Gets the address of the string literal `text`.
+    fn string(&mut self, text: &str) -> String {
+        if let Some(address) = self.strings.get(text) {
+            return int_literal(*address as i32);
+        }
+        let address = self.data_end;
+        // A negative count, the drop function of strings, and the length
+        let mut bytes = vec![0xff; 4];
+        bytes.extend(0u32.to_le_bytes());
+        bytes.extend((text.len() as u32).to_le_bytes());
+        bytes.extend(text.as_bytes());
+        writeln!(
+            self.data,
+            "  (data (i32.const {address}) {})",
+            string_literal(&bytes)
+        )
+        .unwrap();
+        self.data_end = (address + bytes.len()).next_multiple_of(4);
+        self.strings.insert(text.to_owned(), address);
+        int_literal(address as i32)
+    }
+
+    fn constant(&mut self, constant: &Const) -> String {
+        match constant {
+            Const::Int(int) => int_literal(*int),
+            Const::Float(float) => float_literal(*float),
+            Const::Bool(value) => int_literal(*value as i32),
+            Const::Str(string) => self.string(string),
+        }
+    }
+
+    /// Gets the index of the drop function of the `obj` type `obj` in the table.
+    fn drop_index(&mut self, obj: TypeId) -> usize {
+        let index = match self.constructed.iter().position(|x| *x == obj) {
+            Some(index) => index,
+            None => {
+                self.constructed.push(obj);
+                self.constructed.len() - 1
+            }
+        };
+        index + 2
+    }
+
+    /// Writes the functions freeing values of the `obj` type `obj` and replacing their fields,
+    /// if they are needed.
+    fn type_functions(&self, obj: TypeId, out: &mut String) {
+        let def = &self.program.types[obj.0];
+        let name = &def.name;
+        let refs: Vec<_> = (0..def.fields.len())
+            .filter(|x| def.fields[*x].ty.is_ref())
+            .map(|x| (field_offset(x), self.drop_fn(&def.fields[x].ty)))
+            .collect();
+        if self.constructed.contains(&obj) {
+            writeln!(out, "\n  (func $drop:{name} (param $value i32)").unwrap();
+            for (offset, drop) in &refs {
+                writeln!(
+                    out,
+                    "    (call {drop} (i32.load offset={offset} (local.get $value)))"
+                )
+                .unwrap();
+            }
+            out.push_str("    (call $esc_free_block (local.get $value)))\n");
+        }
+        if !self.replaced.contains(&obj) {
+            return;
+        }
+        writeln!(
+            out,
+            "\n  (func $replace:{name} (param $dst i32) (param $src i32)"
+        )
+        .unwrap();
+        out.push_str("    (if (i32.ne (local.get $dst) (local.get $src))\n      (then\n");
+        for (offset, drop) in &refs {
+            writeln!(
+                out,
+                "        (call {drop} (i32.load offset={offset} (local.get $dst)))"
+            )
+            .unwrap();
+        }
+        // Fields are copied as `i32`, whatever their type
+        for offset in (0..def.fields.len()).map(field_offset) {
+            writeln!(
+                out,
+                "        (i32.store offset={offset} (local.get $dst) \
+                 (i32.load offset={offset} (local.get $src)))"
+            )
+            .unwrap();
+        }
+        for (offset, _) in &refs {
+            writeln!(
+                out,
+                "        (i32.store offset={offset} (local.get $src) (i32.const 0))"
+            )
+            .unwrap();
+        }
+        let drop = self.drop_fn(&Ty::Obj(obj));
+        writeln!(out, "    ))\n    (call {drop} (local.get $src)))").unwrap();
+    }
+
+    /// Generates the function `index`, giving its definition.
+    fn function(&mut self, index: usize, func: &'p Function<'src>) -> String {
+        let names: Vec<_> = func
+            .locals
+            .iter()
+            .enumerate()
+            .map(|(index, decl)| match &decl.name {
+                Some(name) => format!("${name}.{index}"),
+                None => format!("$t{index}"),
+            })
+            .collect();
+        let reachable = func.reachable();
+        let mut dropped = vec![false; func.locals.len()];
+        let blocks = func.blocks.iter().zip(&reachable).filter(|(_, x)| **x);
+        for (block, _) in blocks {
+            for stmt in &block.stmts {
+                if let StatementKind::Drop(local) = stmt.kind {
+                    dropped[local.0] = true;
+                }
+            }
+        }
+        let mut state = FuncState {
+            generator: self,
+            func,
+            names,
+            dropped,
+            body: Vec::new(),
+        };
+
+        let order: Vec<_> = (0..func.blocks.len()).filter(|x| reachable[*x]).collect();
+        let mut targets = HashSet::new();
+        let mut code = Vec::new();
+        for (position, index) in order.iter().enumerate() {
+            let block = &func.blocks[*index];
+            for stmt in &block.stmts {
+                state.statement(&stmt.kind);
+            }
+            let next = order.get(position + 1).map(|x| BlockId(*x));
+            state.terminator(&block.terminator, next, &mut targets);
+            code.push(std::mem::take(&mut state.body));
+        }
+
+        let mut definition = format!("  (func {}", state.generator.funcs[index]);
+        for param in 0..func.params {
+            let ty = wasm_type(&func.locals[param].ty);
+            write!(definition, " (param {} {ty})", state.names[param]).unwrap();
+        }
+        if func.ret != Ty::Void {
+            write!(definition, " (result {})", wasm_type(&func.ret)).unwrap();
+        }
+        definition.push('\n');
+        for local in func.params..func.locals.len() {
+            let ty = wasm_type(&func.locals[local].ty);
+            writeln!(definition, "    (local {} {ty})", state.names[local]).unwrap();
+        }
+        if targets.is_empty() {
+            for line in code.iter().flatten() {
+                writeln!(definition, "    {line}").unwrap();
+            }
+            definition.push_str("  )");
+            return definition;
+        }
+
+        // Every block jumped to ends a `block` of its own, whose end it follows, and
+        // `br_table` leaves the one of the block `$next` holds
+        definition.push_str("    (local $next i32)\n    (loop $top\n");
+        let starts: Vec<_> = order
+            .iter()
+            .filter(|x| **x == 0 || targets.contains(&BlockId(**x)))
+            .collect();
+        for start in starts.iter().rev() {
+            writeln!(definition, "      (block $bb{start}").unwrap();
+        }
+        let last = targets.iter().map(|x| x.0).max().unwrap();
+        let labels: Vec<_> = (0..=last)
+            .map(|x| match targets.contains(&BlockId(x)) {
+                true => format!("$bb{x}"),
+                false => "$bb0".to_owned(),
+            })
+            .collect();
+        writeln!(
+            definition,
+            "      (br_table {} (local.get $next)))",
+            labels.join(" ")
+        )
+        .unwrap();
+        for (position, (index, code)) in order.iter().zip(&code).enumerate() {
+            if position > 0 && targets.contains(&BlockId(*index)) {
+                writeln!(definition, "      ) ;; bb{index}").unwrap();
+            }
+            for line in code {
+                writeln!(definition, "      {line}").unwrap();
+            }
+        }
+        definition.push_str("    )\n");
+        if func.ret != Ty::Void {
+            // Every block ends by jumping or returning, but validation can't tell
+            definition.push_str("    (unreachable)\n");
+        }
+        definition.push_str("  )");
+        definition
+    }
+}
+
+/// The state of the function being generated.
+struct FuncState<'g, 'p, 'src> {
+    generator: &'g mut Generator<'p, 'src>,
+    func: &'p Function<'src>,
+    /// The WebAssembly local of every local.
+    names: Vec<String>,
+    /// Whether every local is dropped somewhere, so moving its value out has to empty it.
+    dropped: Vec<bool>,
+    /// The lines of the block being generated.
+    body: Vec<String>,
+}
+
+impl FuncState<'_, '_, '_> {
+    fn line(&mut self, line: impl Into<String>) {
+        self.body.push(line.into());
+    }
+
+    fn ty(&self, operand: &Operand) -> Ty {
+        self.func.operand_ty(operand)
+    }
+
+    fn operand(&mut self, operand: &Operand) -> String {
+        match operand {
+            Operand::Copy(local) | Operand::Move(local) => get(&self.names[local.0]),
+            Operand::Const(constant) => self.generator.constant(constant),
+        }
+    }
+
+    fn operands(&mut self, operands: &[Operand]) -> Vec<String> {
+        operands.iter().map(|x| self.operand(x)).collect()
+    }
+
+    /// Gives code setting `dst` to `value`.
+    fn set(&self, dst: Local, value: &str) -> String {
+        format!("(local.set {} {value})", self.names[dst.0])
+    }
+
+    fn statement(&mut self, stmt: &StatementKind) {
+        match stmt {
+            StatementKind::Assign(dst, rvalue) => self.assign(*dst, rvalue),
+            StatementKind::Call { dst, callee, args } => self.call(*dst, *callee, args),
+            StatementKind::SetField { obj, field, value } => {
+                let Ty::Obj(ty) = self.func.locals[obj.0].ty else {
+                    unreachable!("fields are set on objects")
+                };
+                let field_ty = &self.generator.program.types[ty.0].fields[*field].ty;
+                let obj = get(&self.names[obj.0]);
+                let offset = field_offset(*field);
+                if field_ty.is_ref() {
+                    let drop = self.generator.drop_fn(field_ty);
+                    self.line(format!("(call {drop} {})", load(field_ty, &obj, offset)));
+                }
+                let value = self.operand(value);
+                self.line(store(field_ty, &obj, offset, &value));
+            }
+            StatementKind::Replace { dst, value } => {
+                let value = self.operand(value);
+                let dst_value = get(&self.names[dst.0]);
+                match self.func.locals[dst.0].ty {
+                    Ty::Obj(ty) => {
+                        self.generator.replaced.insert(ty);
+                        let name = &self.generator.program.types[ty.0].name;
+                        self.line(format!("(call $replace:{name} {dst_value} {value})"));
+                    }
+                    _ => self.line(format!("(call $esc_array_replace {dst_value} {value})")),
+                }
+            }
+            StatementKind::Push { array, value } => {
+                let ty = self.ty(value);
+                let value = slot(&ty, self.operand(value));
+                let array = get(&self.names[array.0]);
+                self.line(format!("(call $esc_array_push {array} {value})"));
+            }
+            StatementKind::Print(value) => {
+                let value = self.operand(value);
+                self.line(format!("(call $esc_print {value})"));
+            }
+            StatementKind::Println => self.line("(call $esc_host_println)"),
+            StatementKind::Drop(local) => {
+                let ty = &self.func.locals[local.0].ty;
+                if ty.is_ref() {
+                    let drop = self.generator.drop_fn(ty);
+                    self.line(format!("(call {drop} {})", get(&self.names[local.0])));
+                    self.line(self.set(*local, "(i32.const 0)"));
+                }
+            }
+        }
+        // A moved value belongs to something else now, so dropping the local does nothing
+        for operand in stmt.operands() {
+            if let Operand::Move(local) = operand
+                && self.dropped[local.0]
+                && self.func.locals[local.0].ty.is_ref()
+                && stmt.defines() != Some(*local)
+            {
+                self.line(self.set(*local, "(i32.const 0)"));
+            }
+        }
+    }
+
+    fn assign(&mut self, dst: Local, rvalue: &Rvalue) {
+        let value = match rvalue {
+            Rvalue::Use(operand) => self.operand(operand),
+            Rvalue::Share(operand) => {
+                let ty = self.ty(operand);
+                let value = self.operand(operand);
+                match (ty, operand) {
+                    // String literals are never freed, so they have no owner
+                    (_, Operand::Const(_)) => value,
+                    (Ty::Str | Ty::String, _) => format!("(call $esc_string_copy {value})"),
+                    _ => format!("(call $esc_retain {value})"),
+                }
+            }
+            Rvalue::Binary(op, lhs, rhs) => {
+                let ty = self.ty(lhs);
+                let lhs = self.operand(lhs);
+                let rhs = self.operand(rhs);
+                binary(*op, &ty, &lhs, &rhs)
+            }
+            Rvalue::Unary(op, operand) => {
+                let ty = self.ty(operand);
+                let value = self.operand(operand);
+                match op {
+                    UnOp::Neg if ty == Ty::F32 => format!("(f32.neg {value})"),
+                    UnOp::Neg => format!("(i32.sub (i32.const 0) {value})"),
+                    UnOp::Not => format!("(i32.eqz {value})"),
+                }
+            }
+            Rvalue::ToString(operand) => {
+                let ty = self.ty(operand);
+                let value = self.operand(operand);
+                match ty {
+                    Ty::I32 => format!("(call $esc_i32_to_string {value})"),
+                    Ty::F32 => format!("(call $esc_f32_to_string {value})"),
+                    Ty::Bool => format!("(call $esc_bool_to_string {value})"),
+                    _ => format!("(call $esc_string_copy {value})"),
+                }
+            }
+            Rvalue::Len(operand) => format!("(i32.load offset={LEN} {})", self.operand(operand)),
+            Rvalue::Array(item, items) => {
+                // How the runtime drops the items
+                let drop = match item.is_ref() {
+                    false => 0,
+                    true if !self.generator.program.is_counted(item) => 1,
+                    true => 2,
+                };
+                self.line(self.set(dst, &format!("(call $esc_array_new (i32.const {drop}))")));
+                let array = get(&self.names[dst.0]);
+                for value in self.operands(items) {
+                    let value = slot(item, value);
+                    self.line(format!("(call $esc_array_push {array} {value})"));
+                }
+                return;
+            }
+            Rvalue::New(ty, fields) => {
+                let kind = self.generator.drop_index(*ty);
+                let size = field_offset(fields.len());
+                let alloc = format!("(call $esc_alloc (i32.const {size}) (i32.const {kind}))");
+                self.line(self.set(dst, &alloc));
+                let object = get(&self.names[dst.0]);
+                let program = self.generator.program;
+                for (index, value) in self.operands(fields).into_iter().enumerate() {
+                    let field_ty = &program.types[ty.0].fields[index].ty;
+                    self.line(store(field_ty, &object, field_offset(index), &value));
+                }
+                return;
+            }
+            Rvalue::Field(obj, field) => {
+                let Ty::Obj(ty) = self.ty(obj) else {
+                    unreachable!("fields are read from objects")
+                };
+                let field_ty = &self.generator.program.types[ty.0].fields[*field].ty;
+                let obj = self.operand(obj);
+                load(field_ty, &obj, field_offset(*field))
+            }
+            Rvalue::Index(array, index) => {
+                let (item, address) = self.item(array, index);
+                load(&item, &address, 0)
+            }
+            Rvalue::Take(array, index) => {
+                // The array keeps an empty slot, which dropping it skips
+                let (item, address) = self.item(array, index);
+                self.line(self.set(dst, &load(&item, &address, 0)));
+                self.line(format!("(i32.store {address} (i32.const 0))"));
+                return;
+            }
+        };
+        self.line(self.set(dst, &value));
+    }
+
+    /// Gives the type of the items of `array` and code for the address of the one at `index`.
+    fn item(&mut self, array: &Operand, index: &Operand) -> (Ty, String) {
+        let Ty::Array(item) = self.ty(array) else {
+            unreachable!("items are read from arrays")
+        };
+        let array = self.operand(array);
+        let index = self.operand(index);
+        (*item, format!("(call $esc_array_item {array} {index})"))
+    }
+
+    fn call(&mut self, dst: Option<Local>, callee: Callee, args: &[Operand]) {
+        let program = self.generator.program;
+        let (call, ret) = match callee {
+            Callee::Func(func) => {
+                let args = self.operands(args);
+                let call = call(&self.generator.funcs[func.0], &args);
+                (call, &program.functions[func.0].ret)
+            }
+            Callee::Extern(func) => {
+                let args: Vec<_> = args
+                    .iter()
+                    .map(|arg| {
+                        let ty = self.ty(arg);
+                        let code = self.operand(arg);
+                        match ty {
+                            Ty::Str | Ty::String => format!(
+                                "(i32.add {code} (i32.const {BYTES})) \
+                                 (i32.load offset={LEN} {code})"
+                            ),
+                            _ => code,
+                        }
+                    })
+                    .collect();
+                let call = call(&self.generator.externs[func.0], &args);
+                let ret = &program.externs[func.0].ret;
+                match ret {
+                    Ty::Str | Ty::String => (format!("(call $esc_string_from {call})"), ret),
+                    _ => (call, ret),
+                }
+            }
+        };
+        match dst {
+            Some(dst) => self.line(self.set(dst, &call)),
+            // An ignored result is still owned by the caller
+            None if ret.is_ref() => {
+                let drop = self.generator.drop_fn(ret);
+                self.line(format!("(call {drop} {call})"));
+            }
+            None if *ret != Ty::Void => self.line(format!("(drop {call})")),
+            None => self.line(call),
+        }
+    }
+
+    /// Generates `terminator`, falling through to the block `next` generated after it, and
+    /// adding the blocks it jumps to to `targets`.
+    fn terminator(
+        &mut self,
+        terminator: &Terminator,
+        next: Option<BlockId>,
+        targets: &mut HashSet<BlockId>,
+    ) {
+        let mut jump = |target: BlockId| {
+            targets.insert(target);
+            format!("(local.set $next (i32.const {})) (br $top)", target.0)
+        };
+        match terminator {
+            Terminator::Goto(target) => {
+                if Some(*target) != next {
+                    self.line(jump(*target));
+                }
+            }
+            Terminator::Branch { cond, then, els } => {
+                let cond = self.operand(cond);
+                if Some(*then) == next {
+                    if Some(*els) != next {
+                        self.line(format!("(if (i32.eqz {cond}) (then {}))", jump(*els)));
+                    }
+                    return;
+                }
+                self.line(format!("(if {cond} (then {}))", jump(*then)));
+                if Some(*els) != next {
+                    self.line(jump(*els));
+                }
+            }
+            Terminator::Return(Some(value)) => {
+                let value = self.operand(value);
+                self.line(format!("(return {value})"));
+            }
+            Terminator::Return(None) => self.line("(return)"),
+            Terminator::Unreachable => self.line("(unreachable)"),
+        }
+    }
+}
+
+/// Gives code applying `op` to `lhs` and `rhs`, whose type is `ty`.
+fn binary(op: BinOp, ty: &Ty, lhs: &str, rhs: &str) -> String {
+    let instr = match (ty, op) {
+        (Ty::I32, BinOp::Div) => return format!("(call $esc_div {lhs} {rhs})"),
+        (Ty::Str | Ty::String, BinOp::Add) => return format!("(call $esc_concat {lhs} {rhs})"),
+        (Ty::Str | Ty::String, BinOp::Eq) => return format!("(call $esc_str_eq {lhs} {rhs})"),
+        (Ty::Str | Ty::String, BinOp::Ne) => {
+            return format!("(i32.eqz (call $esc_str_eq {lhs} {rhs}))");
+        }
+        (Ty::F32, _) => match op {
+            BinOp::Add => "f32.add",
+            BinOp::Sub => "f32.sub",
+            BinOp::Mul => "f32.mul",
+            BinOp::Div => "f32.div",
+            BinOp::Eq => "f32.eq",
+            BinOp::Ne => "f32.ne",
+            BinOp::Lt => "f32.lt",
+            BinOp::Le => "f32.le",
+            BinOp::Gt => "f32.gt",
+            BinOp::Ge => "f32.ge",
+            BinOp::And | BinOp::Or => unreachable!("`and` and `or` are lowered to branches"),
+        },
+        _ => match op {
+            BinOp::Add => "i32.add",
+            BinOp::Sub => "i32.sub",
+            BinOp::Mul => "i32.mul",
+            BinOp::Eq => "i32.eq",
+            BinOp::Ne => "i32.ne",
+            BinOp::Lt => "i32.lt_s",
+            BinOp::Le => "i32.le_s",
+            BinOp::Gt => "i32.gt_s",
+            BinOp::Ge => "i32.ge_s",
+            BinOp::Div => unreachable!("division is handled above"),
+            BinOp::And | BinOp::Or => unreachable!("`and` and `or` are lowered to branches"),
+        },
+    };
+    format!("({instr} {lhs} {rhs})")
+}
+
+/// Checks that the parentheses of `module` are balanced, and that it only calls functions it
+/// defines or imports, giving the names of the functions.
+#[cfg(test)]
+fn check_module(module: &str) -> HashSet<&str> {
+    fn name(text: &str) -> &str {
+        text.split([' ', ')', '\n']).next().unwrap()
+    }
+
+    let mut depth = 0usize;
+    let mut in_string = false;
+    for line in module.lines() {
+        let line = line.split(";;").next().unwrap();
+        for char in line.chars() {
+            match char {
+                '"' => in_string = !in_string,
+                '(' if !in_string => depth += 1,
+                ')' if !in_string => depth = depth.checked_sub(1).expect("balanced parentheses"),
+                _ => {}
+            }
+        }
+    }
+    assert_eq!(depth, 0);
+    let funcs: HashSet<_> = module.split("(func ").skip(1).map(name).collect();
+    for call in module.split("(call ").skip(1).map(name) {
+        assert!(funcs.contains(call), "{call} is not defined");
+    }
+    funcs
+}
+
+#[cfg(test)]
+fn generate_module(text: &str, level: u8) -> String {
+    crate::interp::with_checked(text, |modules, info| {
+        let mut program =
+            crate::mir::lower::lower(modules, info, crate::modules::ModuleId(0)).unwrap();
+        crate::mir::opt::optimize(&mut program, &crate::mir::opt::Options::level(level));
+        generate(&program)
+    })
+}
+
+#[test]
+fn wat_test() {
+    let module = generate_module(
+        r"identifier app.main
+obj counter is
+    count: i32 = 0,
+end
+impl counter is
+    func add() is
+        self.count++
+    end
+    func bool op-eq(counter other) is
+        return self.count == other.count
+    end
+end
+func i32 fib(i32 n) is
+    if n < 2 is
+        return n
+    end
+    return fib(n - 1) + fib(n - 2)
+end
+func void start() is
+    counter c = counter
+    c.add()
+    if c == counter is
+        print('a')
+    end
+    print(fib(10).to-string())
+end",
+        0,
+    );
+    assert!(module.starts_with("(module\n"));
+    let funcs = check_module(&module);
+    for func in [
+        "$app.main/start",
+        "$app.main/fib",
+        "$app.main/counter.add",
+        "$app.main/counter.op-eq",
+        "$drop:app.main/counter",
+        "$esc_host_print",
+    ] {
+        assert!(funcs.contains(func), "{func}");
+    }
+    assert!(module.contains("\n  (export \"start\" (func $app.main/start)))"));
+    assert!(module.contains("\n  (memory (export \"memory\") 1)\n"));
+    assert!(module.contains("(func $app.main/fib (param $n.0 i32) (result i32)\n"));
+    // Literals are in memory after the runtime, with the header of strings
+    assert!(module.contains(r#"(data (i32.const 1024) "\ff\ff\ff\ff\00\00\00\00\01\00\00\00a")"#));
+    assert!(module.contains(" $esc_array_free $drop:app.main/counter)\n"));
+}
+
+#[test]
+fn wat_ownership_test() {
+    let module = generate_module(
+        r"identifier app.main
+obj item is
+    name: string = 'item',
+end
+obj holder is
+    kept: item = item,
+    items: item[] = [],
+end
+func void start() is
+    holder h = holder
+    h.items.push(item)
+    item taken = h.kept
+    string name = taken.name
+end",
+        0,
+    );
+    check_module(&module);
+    // Items are reference counted, as one is moved out of `kept`, but their arrays are not
+    assert!(module.contains(
+        "\n    (call $esc_release (i32.load offset=8 (local.get $value)))\
+         \n    (call $esc_free (i32.load offset=12 (local.get $value)))\n"
+    ));
+    assert!(module.contains("(call $esc_array_new (i32.const 2))"));
+    assert_eq!(module.matches("(call $esc_retain ").count(), 1);
+    assert!(module.contains("(call $esc_free (local.get $h.2))"));
+}
+
+#[test]
+fn wat_extern_test() {
+    let module = generate_module(
+        r"identifier app.main
+extern func void print(str s)
+extern func f32 to-float(str s, i32 base)
+extern func str name(bool full)
+func void start() is
+    print(to-float(name(true), 10).to-string())
+end",
+        0,
+    );
+    check_module(&module);
+    assert!(module.contains(
+        r#"(import "env" "to-float" (func $extern:to-float (param i32 i32 i32) (result f32)))"#
+    ));
+    assert!(
+        module
+            .contains(r#"(import "env" "name" (func $extern:name (param i32) (result i32 i32)))"#)
+    );
+    assert!(module.contains("(call $esc_string_from (call $extern:name (i32.const 1)))"));
+    assert!(!module.contains(r#""print" (func $extern"#));
+}
@@ -1,7 +1,7 @@
 #![deny(missing_docs)]
 //! Implementation of `rustc`'s `Diag` diagnostic system.
 
-use std::{mem, sync::OnceLock};
+use std::{cell::Cell, mem, sync::OnceLock};
 
 use codespan_reporting::{
     diagnostic::{Diagnostic, Label, Severity},
@@ -9,10 +9,13 @@ use codespan_reporting::{
 };
 use termcolor::{ColorChoice, StandardStream};
 
-use crate::Source;
+use crate::{Source, span::Span};
 
 static BUG_FOUND: OnceLock<()> = OnceLock::new();
 
+/// How many labels [`DiagBuilder::with_calls`] adds at most.
+pub(crate) const MAX_CALLS: usize = 16;
+
 /// Will return true if there is a bug found in the compiler. Useful for testing.
 pub fn bug() -> bool {
     BUG_FOUND.get().is_some()
@@ -29,8 +32,19 @@ pub fn error() -> bool {
     ERROR_HIT.get().is_some()
 }
 
+thread_local! {
+    static ERROR_COUNT: Cell<usize> = const { Cell::new(0) };
+}
+
+/// Returns how many errors have been emitted on the current thread. Useful for testing, since
+/// [`error`] is shared between every test.
+pub fn error_count() -> usize {
+    ERROR_COUNT.get()
+}
+
 fn set_error() {
     let _ = ERROR_HIT.set(()); // If there's already a error, we don't care.
+    ERROR_COUNT.set(ERROR_COUNT.get() + 1);
 }
 
 /// Custom Diagnostic message type as a wrapper around [`codespan_reporting::Diagnostic`](Diagnostic)
@@ -162,6 +176,41 @@ impl<'src> DiagBuilder<'src> {
         self
     }
 
+    /// Adds a secondary label at `span` with `message`. A diagnostic can only show code of its
+    /// own source, so if `span` is in another file, the label becomes a note pointing at it.
+    pub fn with_secondary(self, span: Span<'src>, message: impl ToString) -> Self {
+        if std::ptr::eq(span.source(), self.inner.src) {
+            return self.with_label(Label::secondary((), span).with_message(message));
+        }
+        let (line, column) = span.get_start_code_pos();
+        let path = span.source().path().display();
+        self.with_note(format!("{} ({path}:{line}:{column})", message.to_string()))
+    }
+
+    /// Adds a "called from here" label at every call of `calls`, innermost first. Calls at the
+    /// same place, like those of recursive functions, share one label counting them, and only
+    /// the innermost [`MAX_CALLS`] places are labeled, with a note counting the calls left out.
+    pub fn with_calls(mut self, calls: impl IntoIterator<Item = Span<'src>>) -> Self {
+        let mut places: Vec<(Span, usize)> = Vec::new();
+        for call in calls {
+            match places.iter_mut().find(|(span, _)| *span == call) {
+                Some((_, count)) => *count += 1,
+                None => places.push((call, 1)),
+            }
+        }
+        let hidden: usize = places.iter().skip(MAX_CALLS).map(|x| x.1).sum();
+        for (span, count) in places.into_iter().take(MAX_CALLS) {
+            self = match count {
+                1 => self.with_secondary(span, "called from here"),
+                count => self.with_secondary(span, format!("called from here ({count} times)")),
+            };
+        }
+        if hidden > 0 {
+            self = self.with_note(format!("... and {hidden} more calls"));
+        }
+        self
+    }
+
     /// Finishes the `DiagBuilder`
     pub fn finish(self) -> Diag<'src> {
         self.inner
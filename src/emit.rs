@@ -0,0 +1,483 @@
+#![deny(missing_docs)]
+//! Module for dumping the stages of the compiler pipeline in a stable textual format.
+//!
+//! These formats are meant to be read by people and diffed by tests, so they only change when
+//! the stage they dump changes.
+//!
+//! # Tokens
+//! One token per line, written as `line:col Kind "text"`. `line` and `col` are 1-based and point
+//! at the first character of the token, `Kind` is the name of the [`TokenType`](crate::lexer::TokenType) and `text` is
+//! the source text of the token, quoted and escaped like a Rust string.
+//!
+//! # AST
+//! An S-expression tree with one node per line. Children are indented two spaces deeper than
+//! their parent, and closing parentheses are put at the end of the last child's line. Leaves
+//! (names, literals and types) stay on the same line as their parent's head, e.g.
+//! `(local val i32` followed by the indented initializer.
+//!
+//! # HIR
+//! The AST format, with every name annotated with what it resolved to: `(name val -> local 6:9)`
+//! for locals, parameters and `self` (pointing at their declaration), `(name greeting -> obj
+//! app.text.greeting)` for items and `(name print -> builtin print)` for builtins. Names that
+//! could not be resolved are annotated with `-> ?`. `let` declarations are followed by their
+//! inferred type, like the written type of other declarations: `(let input string`. The
+//! variable of a `for` loop is followed by its item type the same way: `(for line string`.
+//!
+//! Parameters end with the mode borrow inference gave them, like `(param text string owned)`,
+//! and methods list their receiver before their parameters as `(self mutable)`.
+//!
+//! Names that move the value out of their binding end with `move`, like
+//! `(name hello-world -> local 3:10 move)`, and the implicit drops after a statement follow it in
+//! the block as `(drop hello-world)`. Drops of values that are only moved on some paths end with
+//! `flagged`, like `(drop line flagged)`.
+//!
+//! # Escape
+//! Every function with allocations, as `func name` (`func type.name` for methods), followed by one
+//! line per allocation in source order: `  line:col type escape`, where `escape` is where
+//! [escape analysis](crate::escape) found it to end up, like `  3:23 printer stack`. Reference
+//! counted allocations end with `rc`, like `  5:9 node stored rc`.
+
+use std::io::{self, Write};
+
+use crate::{
+    Source,
+    ast::{
+        Block, Declaration, Expr, ExprKind, Func, FuncSig, Ident, NodeId, Stmt, StmtKind, TypeExpr,
+    },
+    borrow::Borrows,
+    escape::Escapes,
+    lexer::Lexer,
+    modules::ModuleGraph,
+    ownership::Ownership,
+    resolve::{Res, Resolutions},
+    ty::check::TypeckResults,
+};
+
+/// A node of an S-expression tree, used for dumps that are trees.
+pub(crate) struct SExpr {
+    head: String,
+    children: Vec<SExpr>,
+}
+
+impl SExpr {
+    pub(crate) fn new(head: impl Into<String>) -> Self {
+        SExpr {
+            head: head.into(),
+            children: Vec::new(),
+        }
+    }
+
+    pub(crate) fn with(mut self, child: SExpr) -> Self {
+        self.children.push(child);
+        self
+    }
+
+    pub(crate) fn push(&mut self, child: SExpr) {
+        self.children.push(child);
+    }
+
+    pub(crate) fn write(&self, out: &mut dyn Write, indent: usize) -> io::Result<()> {
+        write!(out, "{:indent$}({}", "", self.head)?;
+        for child in &self.children {
+            writeln!(out)?;
+            child.write(out, indent + 2)?;
+        }
+        write!(out, ")")
+    }
+}
+
+/// Writes every token of `src` to `out`.
+pub fn tokens(src: &Source, out: &mut dyn Write) -> io::Result<()> {
+    for token in Lexer::new(src) {
+        let span = token.span();
+        let (line, column) = span.get_start_code_pos();
+        let kind = token.token_type();
+        writeln!(out, "{line}:{column} {kind:?} {:?}", span.apply())?;
+    }
+    Ok(())
+}
+
+/// Writes the syntax tree of a file to `out`.
+pub fn ast(decls: &[Declaration], out: &mut dyn Write) -> io::Result<()> {
+    Printer { hir: None }.module(decls, out)
+}
+
+/// The results of the analysis passes annotated by the HIR dump.
+#[derive(Clone, Copy)]
+pub struct HirInfo<'a, 'src> {
+    /// The module graph of the program.
+    pub graph: &'a ModuleGraph<'src>,
+    /// The results of name resolution.
+    pub res: &'a Resolutions<'src>,
+    /// The results of type checking.
+    pub typeck: &'a TypeckResults<'src>,
+    /// The results of borrow inference.
+    pub borrows: &'a Borrows<'src>,
+    /// The results of ownership analysis.
+    pub ownership: &'a Ownership<'src>,
+}
+
+/// Writes the syntax tree of a file to `out`, annotated with the results of the analysis passes.
+pub fn hir(decls: &[Declaration], info: HirInfo, out: &mut dyn Write) -> io::Result<()> {
+    Printer { hir: Some(info) }.module(decls, out)
+}
+
+/// Writes where every allocation of a file ends up to `out`.
+pub fn escape(
+    decls: &[Declaration],
+    info: HirInfo,
+    escapes: &Escapes,
+    out: &mut dyn Write,
+) -> io::Result<()> {
+    let mut funcs = Vec::new();
+    for decl in decls {
+        match decl {
+            Declaration::Func(func) => funcs.push((func.sig.name.name.to_string(), func)),
+            Declaration::Impl(imp) => funcs.extend(
+                imp.methods
+                    .iter()
+                    .map(|x| (format!("{}.{}", imp.target.name, x.sig.name.name), x)),
+            ),
+            _ => {}
+        }
+    }
+    for (name, func) in funcs {
+        let mut exprs = Vec::new();
+        let mut stmts: Vec<_> = func.body.stmts.iter().collect();
+        while let Some(stmt) = stmts.pop() {
+            match &stmt.kind {
+                StmtKind::Local { init: expr, .. }
+                | StmtKind::Increment(expr)
+                | StmtKind::Expr(expr)
+                | StmtKind::Bulk(expr)
+                | StmtKind::Return(Some(expr)) => exprs.push(expr),
+                StmtKind::Assign { target, value } => exprs.extend([value, target]),
+                StmtKind::If { cond, then, els } => {
+                    exprs.push(cond);
+                    stmts.extend(then.stmts.iter().chain(els.iter().flat_map(|x| &x.stmts)));
+                }
+                StmtKind::While { cond: expr, body }
+                | StmtKind::For {
+                    iter: expr, body, ..
+                } => {
+                    exprs.push(expr);
+                    stmts.extend(&body.stmts);
+                }
+                StmtKind::Return(None) | StmtKind::Break | StmtKind::Continue => {}
+            }
+        }
+        let mut allocs = Vec::new();
+        while let Some(expr) = exprs.pop() {
+            if let Some(escape) = escapes.allocs.get(&expr.id) {
+                allocs.push((expr.span.start, expr, *escape));
+            }
+            match &expr.kind {
+                ExprKind::Array(items) => exprs.extend(items),
+                ExprKind::Field { base, .. } => exprs.push(base),
+                ExprKind::Call { callee, args } => exprs.extend(args.iter().chain([&**callee])),
+                ExprKind::Binary { lhs, rhs, .. } => exprs.extend([&**lhs, &**rhs]),
+                ExprKind::Unary { operand, .. } => exprs.push(operand),
+                ExprKind::Construct { fields, .. } => exprs.extend(fields.iter().map(|x| &x.value)),
+                ExprKind::Name(_)
+                | ExprKind::Str(_)
+                | ExprKind::Int(_)
+                | ExprKind::Float(_)
+                | ExprKind::Bool(_) => {}
+            }
+        }
+        if allocs.is_empty() {
+            continue;
+        }
+        allocs.sort_by_key(|(start, ..)| *start);
+        writeln!(out, "func {name}")?;
+        for (_, expr, escape) in allocs {
+            let (line, column) = expr.span.get_start_code_pos();
+            let ty = info
+                .typeck
+                .tys
+                .name(info.typeck.expr_ty(expr.id), info.graph);
+            let rc = if escapes.rc.contains(&expr.id) {
+                " rc"
+            } else {
+                ""
+            };
+            writeln!(out, "  {line}:{column} {ty} {}{rc}", escape.name())?;
+        }
+    }
+    Ok(())
+}
+
+pub(crate) fn type_expr(ty: &TypeExpr) -> String {
+    match ty {
+        TypeExpr::Void(_) => "void".to_string(),
+        TypeExpr::Named(ident) => ident.name.to_string(),
+        TypeExpr::Array(inner, _) => format!("{}[]", type_expr(inner)),
+    }
+}
+
+fn visibility(public: bool) -> &'static str {
+    if public { "pub " } else { "" }
+}
+
+struct Printer<'a, 'src> {
+    hir: Option<HirInfo<'a, 'src>>,
+}
+
+impl<'a, 'src> Printer<'a, 'src> {
+    fn module(&self, decls: &[Declaration], out: &mut dyn Write) -> io::Result<()> {
+        let mut module = SExpr::new("module");
+        for decl in decls {
+            module.push(self.declaration(decl));
+        }
+        module.write(out, 0)?;
+        writeln!(out)
+    }
+
+    fn name(&self, id: NodeId, ident: &Ident) -> String {
+        let Some(HirInfo {
+            graph,
+            res,
+            ownership,
+            ..
+        }) = self.hir
+        else {
+            return format!("name {}", ident.name);
+        };
+        let target = match res.names.get(&id) {
+            Some(Res::Local(binding)) => {
+                let (line, column) = res.bindings[binding].span.get_start_code_pos();
+                format!("local {line}:{column}")
+            }
+            Some(Res::Item(item)) => {
+                let item = graph.item(*item);
+                format!(
+                    "{} {}.{}",
+                    item.kind.describe(),
+                    graph.modules[item.module.0].name,
+                    item.name.name
+                )
+            }
+            Some(Res::Builtin(builtin)) => format!("builtin {}", builtin.name()),
+            None => "?".to_string(),
+        };
+        let moved = if ownership.moves.contains(&id) {
+            " move"
+        } else {
+            ""
+        };
+        format!("name {} -> {target}{moved}", ident.name)
+    }
+
+    fn declaration(&self, decl: &Declaration) -> SExpr {
+        match decl {
+            Declaration::Identifier(path) => SExpr::new(format!("identifier {}", path.to_dotted())),
+            Declaration::Use(path) => SExpr::new(format!("use {}", path.to_dotted())),
+            Declaration::Func(func) => self.function(func),
+            Declaration::Extern(func) => self.signature(
+                format!("{}extern", visibility(func.public)),
+                &func.sig,
+                None,
+            ),
+            Declaration::Obj(obj) => {
+                let mut node =
+                    SExpr::new(format!("{}obj {}", visibility(obj.public), obj.name.name));
+                for field in &obj.fields {
+                    let mut child = SExpr::new(format!(
+                        "{}field {} {}",
+                        visibility(field.public),
+                        field.name.name,
+                        type_expr(&field.ty)
+                    ));
+                    if let Some(default) = &field.default {
+                        child.push(self.expr(default));
+                    }
+                    node.push(child);
+                }
+                node
+            }
+            Declaration::Impl(imp) => {
+                let mut node = SExpr::new(format!("impl {}", imp.target.name));
+                for method in &imp.methods {
+                    node.push(self.function(method));
+                }
+                node
+            }
+        }
+    }
+
+    /// Gets the inferred mode of a parameter as a suffix, in the HIR.
+    fn mode(&self, id: NodeId) -> String {
+        let Some(HirInfo { borrows, .. }) = self.hir else {
+            return String::new();
+        };
+        borrows
+            .bindings
+            .get(&id)
+            .map_or(String::new(), |x| format!(" {}", x.name()))
+    }
+
+    /// Makes the node of a signature. `id` is the id of the function if it may be a method.
+    fn signature(&self, head: String, sig: &FuncSig, id: Option<NodeId>) -> SExpr {
+        let ret = sig.ret.as_ref().map_or("void".to_string(), type_expr);
+        let mut node =
+            SExpr::new(format!("{head} {}", sig.name.name)).with(SExpr::new(format!("ret {ret}")));
+        if let Some(id) = id
+            && let Some(HirInfo { borrows, .. }) = self.hir
+            && borrows.bindings.contains_key(&id)
+        {
+            node.push(SExpr::new(format!("self{}", self.mode(id))));
+        }
+        for param in &sig.params {
+            node.push(SExpr::new(format!(
+                "param {} {}{}",
+                param.name.name,
+                type_expr(&param.ty),
+                self.mode(param.id)
+            )));
+        }
+        node
+    }
+
+    fn function(&self, func: &Func) -> SExpr {
+        let head = format!("{}func", visibility(func.public));
+        let mut node = self.signature(head, &func.sig, Some(func.id));
+        node.push(self.block(&func.body));
+        node
+    }
+
+    fn block(&self, block: &Block) -> SExpr {
+        let mut node = SExpr::new("block");
+        for stmt in &block.stmts {
+            node.push(self.statement(stmt));
+            let Some(HirInfo { res, ownership, .. }) = self.hir else {
+                continue;
+            };
+            for binding in ownership.drops.get(&stmt.id).into_iter().flatten() {
+                let flag = if ownership.drop_flags.contains(binding) {
+                    " flagged"
+                } else {
+                    ""
+                };
+                node.push(SExpr::new(format!(
+                    "drop {}{flag}",
+                    res.bindings[binding].name
+                )));
+            }
+        }
+        node
+    }
+
+    fn statement(&self, stmt: &Stmt) -> SExpr {
+        match &stmt.kind {
+            StmtKind::Local {
+                ty: Some(ty),
+                name,
+                init,
+            } => SExpr::new(format!("local {} {}", name.name, type_expr(ty))).with(self.expr(init)),
+            StmtKind::Local {
+                ty: None,
+                name,
+                init,
+            } => {
+                let mut head = format!("let {}", name.name);
+                if let Some(HirInfo { graph, typeck, .. }) = self.hir {
+                    head += " ";
+                    head += &typeck.tys.name(typeck.bindings[&stmt.id], graph);
+                }
+                SExpr::new(head).with(self.expr(init))
+            }
+            StmtKind::Assign { target, value } => SExpr::new("assign")
+                .with(self.expr(target))
+                .with(self.expr(value)),
+            StmtKind::Increment(target) => SExpr::new("increment").with(self.expr(target)),
+            StmtKind::Expr(inner) => SExpr::new("expr").with(self.expr(inner)),
+            StmtKind::Bulk(call) => SExpr::new("bulk").with(self.expr(call)),
+            StmtKind::Return(value) => {
+                let mut node = SExpr::new("return");
+                if let Some(value) = value {
+                    node.push(self.expr(value));
+                }
+                node
+            }
+            StmtKind::If { cond, then, els } => {
+                let mut node = SExpr::new("if")
+                    .with(self.expr(cond))
+                    .with(self.block(then));
+                if let Some(els) = els {
+                    node.push(self.block(els));
+                }
+                node
+            }
+            StmtKind::While { cond, body } => SExpr::new("while")
+                .with(self.expr(cond))
+                .with(self.block(body)),
+            StmtKind::For { name, iter, body } => {
+                let mut head = format!("for {}", name.name);
+                if let Some(HirInfo { graph, typeck, .. }) = self.hir {
+                    head += " ";
+                    head += &typeck.tys.name(typeck.bindings[&stmt.id], graph);
+                }
+                SExpr::new(head)
+                    .with(self.expr(iter))
+                    .with(self.block(body))
+            }
+            StmtKind::Break => SExpr::new("break"),
+            StmtKind::Continue => SExpr::new("continue"),
+        }
+    }
+
+    fn expr(&self, expr_: &Expr) -> SExpr {
+        match &expr_.kind {
+            ExprKind::Name(ident) => SExpr::new(self.name(expr_.id, ident)),
+            ExprKind::Str(string) => SExpr::new(format!("str {string:?}")),
+            ExprKind::Int(int) => SExpr::new(format!("int {int}")),
+            ExprKind::Float(float) => SExpr::new(format!("float {float:?}")),
+            ExprKind::Bool(bool) => SExpr::new(format!("bool {bool}")),
+            ExprKind::Array(items) => {
+                let mut node = SExpr::new("array");
+                for item in items {
+                    node.push(self.expr(item));
+                }
+                node
+            }
+            ExprKind::Field { base, field } => {
+                SExpr::new(format!("field {}", field.name)).with(self.expr(base))
+            }
+            ExprKind::Call { callee, args } => {
+                let mut node = SExpr::new("call").with(self.expr(callee));
+                for arg in args {
+                    node.push(self.expr(arg));
+                }
+                node
+            }
+            ExprKind::Construct { ty, fields } => {
+                let mut node = SExpr::new(format!("construct {}", ty.name));
+                for field in fields {
+                    node.push(
+                        SExpr::new(format!("init {}", field.name.name))
+                            .with(self.expr(&field.value)),
+                    );
+                }
+                node
+            }
+            ExprKind::Binary { op, lhs, rhs } => SExpr::new(format!("binary {}", op.symbol()))
+                .with(self.expr(lhs))
+                .with(self.expr(rhs)),
+            ExprKind::Unary { op, operand } => {
+                SExpr::new(format!("unary {}", op.symbol())).with(self.expr(operand))
+            }
+        }
+    }
+}
+
+#[test]
+fn tokens_test() {
+    let src = Source::new("func void start() is\n  print('hi')\nend", "test.scp");
+    let mut out = Vec::new();
+    tokens(&src, &mut out).unwrap();
+    let out = String::from_utf8(out).unwrap();
+    let mut lines = out.lines();
+    assert_eq!(lines.next(), Some("1:1 Func \"func\""));
+    assert_eq!(lines.nth(5), Some("2:3 Identifier \"print\""));
+    assert_eq!(lines.nth(1), Some("2:9 StringLit \"'hi'\""));
+}
@@ -0,0 +1,361 @@
+#![deny(missing_docs)]
+//! Module for escape analysis: working out which `obj` values never outlive the function that
+//! creates them.
+//!
+//! The results are only diagnostics, shown by `--emit escape` and the `rc-report` unstable flag.
+//! No backend reads them: every allocation is still made on the heap.
+//!
+//! Every constructor (a bare type name used as a value, or called with its fields) is an
+//! allocation. The analysis follows each allocation through the local variables it is moved into,
+//! and classifies it by where it ends up:
+//! - [`Stack`](Escape::Stack) if it stays in the function's variables and temporaries.
+//! - [`Returned`](Escape::Returned) if it is returned to the caller.
+//! - [`Captured`](Escape::Captured) if it is passed to a parameter inferred as
+//!   [owned](crate::borrow::PassMode::Owned), which takes it over.
+//! - [`Stored`](Escape::Stored) if it is stored into a field or an array.
+//!
+//! An allocation escaping in several ways gets the last of these that applies, in the order
+//! above.
+//!
+//! Allocations of types or stored into fields that [ownership analysis](crate::ownership) demoted
+//! to reference counting are reference counted wherever they end up.
+
+use std::{
+    collections::{HashMap, HashSet},
+    slice,
+};
+
+use crate::{
+    ast::{Block, Declaration, Expr, ExprKind, Func, NodeId, Stmt, StmtKind},
+    borrow::{Borrows, PassMode},
+    modules::{ItemId, ItemKind, ModuleGraph},
+    ownership::Ownership,
+    project::Module,
+    resolve::{Res, Resolutions},
+    ty::check::TypeckResults,
+};
+
+/// Where an allocation ends up.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Escape {
+    /// The value never outlives the function.
+    Stack,
+    /// The value is returned from the function.
+    Returned,
+    /// The value is taken over by a function it is passed to.
+    Captured,
+    /// The value is stored into a field or an array.
+    Stored,
+}
+
+impl Escape {
+    /// Gets the name of the escape, as used in dumps.
+    pub fn name(self) -> &'static str {
+        match self {
+            Escape::Stack => "stack",
+            Escape::Returned => "returned",
+            Escape::Captured => "captured",
+            Escape::Stored => "stored",
+        }
+    }
+}
+
+/// The result of escape analysis.
+#[derive(Debug, Default)]
+pub struct Escapes {
+    /// Where every allocation ends up, keyed by the id of the constructor expression.
+    pub allocs: HashMap<NodeId, Escape>,
+    /// The allocations that are reference counted instead of having a static owner.
+    pub rc: HashSet<NodeId>,
+}
+
+/// Finds where every allocation of `modules` ends up.
+pub fn analyze<'src>(
+    modules: &[Module<'src>],
+    graph: &ModuleGraph<'src>,
+    res: &Resolutions<'src>,
+    typeck: &TypeckResults<'src>,
+    borrows: &Borrows<'src>,
+    ownership: &Ownership<'src>,
+) -> Escapes {
+    let mut analyzer = Analyzer {
+        graph,
+        res,
+        typeck,
+        borrows,
+        holds: HashMap::new(),
+        types: HashMap::new(),
+        stores: HashMap::new(),
+        out: Escapes::default(),
+    };
+    for decl in modules.iter().flat_map(|x| &x.decls) {
+        match decl {
+            Declaration::Func(func) => analyzer.func(func),
+            Declaration::Impl(imp) => imp.methods.iter().for_each(|x| analyzer.func(x)),
+            _ => {}
+        }
+    }
+    for (alloc, obj) in &analyzer.types {
+        let stores = analyzer.stores.get(alloc).map_or(&[][..], |x| &x[..]);
+        if ownership.is_rc(*obj, stores) {
+            analyzer.out.rc.insert(*alloc);
+        }
+    }
+    analyzer.out
+}
+
+struct Analyzer<'a, 'src> {
+    graph: &'a ModuleGraph<'src>,
+    res: &'a Resolutions<'src>,
+    typeck: &'a TypeckResults<'src>,
+    borrows: &'a Borrows<'src>,
+    /// The allocations each local variable may hold.
+    holds: HashMap<NodeId, Vec<NodeId>>,
+    /// The object type of every allocation.
+    types: HashMap<NodeId, ItemId>,
+    /// The fields every allocation may be stored into.
+    stores: HashMap<NodeId, Vec<(ItemId, usize)>>,
+    out: Escapes,
+}
+
+impl<'a, 'src> Analyzer<'a, 'src> {
+    fn func(&mut self, func: &Func<'src>) {
+        self.holds.clear();
+        self.block(&func.body);
+    }
+
+    fn block(&mut self, block: &Block<'src>) {
+        block.stmts.iter().for_each(|x| self.stmt(x));
+    }
+
+    fn stmt(&mut self, stmt: &Stmt<'src>) {
+        match &stmt.kind {
+            StmtKind::Local { init, .. } => {
+                let allocs = self.value(init);
+                self.holds.entry(stmt.id).or_default().extend(allocs);
+            }
+            StmtKind::Assign { target, value } => {
+                let allocs = self.value(value);
+                match (&target.kind, self.res.names.get(&target.id)) {
+                    (ExprKind::Name(_), Some(Res::Local(binding))) => {
+                        self.holds.entry(*binding).or_default().extend(allocs);
+                    }
+                    _ => {
+                        self.escape(&allocs, Escape::Stored);
+                        self.store(&allocs, target);
+                        self.value(target);
+                    }
+                }
+            }
+            StmtKind::Increment(expr) | StmtKind::Expr(expr) | StmtKind::Bulk(expr) => {
+                self.value(expr);
+            }
+            StmtKind::Return(value) => {
+                if let Some(value) = value {
+                    let allocs = self.value(value);
+                    self.escape(&allocs, Escape::Returned);
+                }
+            }
+            StmtKind::If { cond, then, els } => {
+                self.value(cond);
+                self.block(then);
+                if let Some(els) = els {
+                    self.block(els);
+                }
+            }
+            StmtKind::While { cond, body } => {
+                self.value(cond);
+                self.block(body);
+            }
+            // The loop variable only borrows the items, which stay where they are
+            StmtKind::For { iter, body, .. } => {
+                self.value(iter);
+                self.block(body);
+            }
+            StmtKind::Break | StmtKind::Continue => {}
+        }
+    }
+
+    /// Marks `allocs` as escaping through `escape`.
+    fn escape(&mut self, allocs: &[NodeId], escape: Escape) {
+        for alloc in allocs {
+            let old = self.out.allocs.entry(*alloc).or_insert(escape);
+            *old = escape.max(*old);
+        }
+    }
+
+    /// Records that `allocs` may be stored into the field read by `place`.
+    fn store(&mut self, allocs: &[NodeId], place: &Expr) {
+        let Some(field) = self.typeck.field(place) else {
+            return;
+        };
+        for alloc in allocs {
+            self.stores.entry(*alloc).or_default().push(field);
+        }
+    }
+
+    /// Walks a call or an overloaded operator, returning the allocations its value may be.
+    fn call(&mut self, expr: &Expr<'src>, callee: &Expr<'src>, args: &[Expr<'src>]) -> Vec<NodeId> {
+        let modes = self.borrows.calls.get(&expr.id);
+        let receiver = modes.and_then(|x| x.receiver);
+        match (self.typeck.receiver(expr), receiver) {
+            (Some(base), Some(receiver)) => {
+                let allocs = self.value(base);
+                if receiver.mode == PassMode::Owned {
+                    self.escape(&allocs, Escape::Captured);
+                }
+            }
+            // Calling a type name constructs it, like naming it
+            _ => {
+                let allocs = self.value(callee);
+                if self.types.contains_key(&callee.id) {
+                    return allocs;
+                }
+            }
+        }
+        for (index, arg) in args.iter().enumerate() {
+            let allocs = self.value(arg);
+            let passing = modes.and_then(|x| x.args.get(index));
+            match passing {
+                Some(passing) if passing.mode == PassMode::Owned => {
+                    // Builtins taking ownership either store the value (`push`) or
+                    // free it right away (`drop`)
+                    let escape = match (passing.param, &callee.kind) {
+                        (Some(_), _) => Escape::Captured,
+                        (None, ExprKind::Field { base, .. }) => {
+                            self.store(&allocs, base);
+                            Escape::Stored
+                        }
+                        (None, _) => Escape::Stack,
+                    };
+                    self.escape(&allocs, escape);
+                }
+                _ => {}
+            }
+        }
+        Vec::new()
+    }
+
+    /// Walks an expression, returning the allocations its value may be.
+    fn value(&mut self, expr: &Expr<'src>) -> Vec<NodeId> {
+        match &expr.kind {
+            ExprKind::Name(_) => match self.res.names.get(&expr.id) {
+                Some(Res::Local(binding)) => self.holds.get(binding).cloned().unwrap_or_default(),
+                Some(Res::Item(item)) if self.graph.item(*item).kind == ItemKind::Obj => {
+                    self.out.allocs.insert(expr.id, Escape::Stack);
+                    self.types.insert(expr.id, *item);
+                    vec![expr.id]
+                }
+                _ => Vec::new(),
+            },
+            ExprKind::Str(_) | ExprKind::Int(_) | ExprKind::Float(_) | ExprKind::Bool(_) => {
+                Vec::new()
+            }
+            // The items are stored in the array, but still follow it if it is stored somewhere
+            ExprKind::Array(items) => {
+                let mut contained = Vec::new();
+                for item in items {
+                    let allocs = self.value(item);
+                    self.escape(&allocs, Escape::Stored);
+                    contained.extend(allocs);
+                }
+                contained
+            }
+            ExprKind::Field { base, .. } => {
+                self.value(base);
+                Vec::new()
+            }
+            ExprKind::Call { callee, args } => self.call(expr, callee, args),
+            ExprKind::Construct { fields, .. } => {
+                let Some(Res::Item(obj)) = self.res.names.get(&expr.id) else {
+                    for field in fields {
+                        self.value(&field.value);
+                    }
+                    return Vec::new();
+                };
+                for field in fields {
+                    let allocs = self.value(&field.value);
+                    self.escape(&allocs, Escape::Stored);
+                    if let Some((index, _)) = self.typeck.items.field(*obj, field.name.name) {
+                        for alloc in &allocs {
+                            self.stores.entry(*alloc).or_default().push((*obj, index));
+                        }
+                    }
+                }
+                self.out.allocs.insert(expr.id, Escape::Stack);
+                self.types.insert(expr.id, *obj);
+                vec![expr.id]
+            }
+            ExprKind::Binary { lhs, rhs, .. } if self.typeck.methods.contains_key(&expr.id) => {
+                self.call(expr, lhs, slice::from_ref(rhs))
+            }
+            ExprKind::Binary { lhs, rhs, .. } => {
+                self.value(lhs);
+                self.value(rhs);
+                Vec::new()
+            }
+            ExprKind::Unary { operand, .. } => {
+                self.value(operand);
+                Vec::new()
+            }
+        }
+    }
+}
+
+#[test]
+fn escape_test() {
+    let sources = [crate::Source::new(
+        r"identifier app.main
+obj box is
+    value: i32 = 0,
+end
+obj holder is
+    inner: box[] = [],
+end
+func void keep(box b) is
+    box kept = b
+end
+func box make() is
+    box made = box
+    return made
+end
+func void start() is
+    box a = box
+    a.value = 1
+    box b = box
+    box c = b
+    keep(c)
+    holder h = holder
+    h.inner.push(box)
+    box d = box
+    drop(d)
+end",
+        "main.scp",
+    )];
+    let modules = crate::project::test_modules(&sources);
+    let graph = ModuleGraph::build(&modules);
+    let res = crate::resolve::resolve(&modules, &graph);
+    let typeck = crate::ty::check::check(&modules, &graph, &res);
+    let borrows = crate::borrow::infer(&modules, &graph, &res, &typeck);
+    let ownership = crate::ownership::analyze(&modules, &graph, &res, &typeck, &borrows);
+    let escapes = analyze(&modules, &graph, &res, &typeck, &borrows, &ownership);
+    let info = crate::emit::HirInfo {
+        graph: &graph,
+        res: &res,
+        typeck: &typeck,
+        borrows: &borrows,
+        ownership: &ownership,
+    };
+    let mut out = Vec::new();
+    crate::emit::escape(&modules[0].decls, info, &escapes, &mut out).unwrap();
+    let expected = r"func make
+  12:16 box returned
+func start
+  16:13 box stack
+  18:13 box captured
+  21:16 holder stack
+  22:18 box stored
+  23:13 box stack
+";
+    assert_eq!(String::from_utf8(out).unwrap(), expected);
+}
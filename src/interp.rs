@@ -0,0 +1,883 @@
+#![deny(missing_docs)]
+//! Module for running programs by walking their checked AST.
+//!
+//! The interpreter starts at the `start` function of the entry module and follows the results of
+//! the analyses: names that [ownership analysis](crate::ownership) marked as moves take the value
+//! out of their binding, and bindings are dropped after the statements it lists. Numbers and
+//! booleans are copied, strings are immutable and shared, and `obj` values and arrays live in
+//! reference counted cells, which only have more than one owner when ownership analysis demoted
+//! them to reference counting.
+//!
+//! `extern` functions named like a builtin function (`print` and `println`) run the builtin.
+//! Other `extern` functions aren't available, and calling one stops the program.
+//!
+//! # Cycle detection
+//! In debug builds, the interpreter remembers every object it allocates. Once `start` returns
+//! and every variable is dropped, only a reference cycle can keep an object alive, so the objects
+//! still alive are returned as [`Leak`]s, which [`report_leaks`] reports as warnings.
+
+use std::{
+    cell::RefCell,
+    io::Write,
+    rc::{Rc, Weak},
+};
+
+use codespan_reporting::diagnostic::Label;
+
+use crate::{
+    ast::{BinOp, Block, Declaration, Expr, ExprKind, Func, NodeId, Stmt, StmtKind, UnOp},
+    borrow::PassMode,
+    builtins::{Builtin, BuiltinMethod},
+    diag::Diag,
+    emit::HirInfo,
+    modules::{ItemId, ItemKind, ModuleGraph, ModuleId},
+    project::Module,
+    resolve::{MethodRef, Res},
+    span::Span,
+    ty::{TyId, check::Method},
+};
+
+use std::collections::HashMap;
+
+/// The size of the stack of the thread running the interpreter, which recurses once for every
+/// call, block and expression. Only the pages it touches are allocated.
+pub const STACK_SIZE: usize = 1 << 30;
+
+/// How deep calls can nest before the program is stopped: the stack fits this many calls of
+/// 64 KiB, more than a call of a debug build takes even in deeply nested code.
+const MAX_DEPTH: usize = STACK_SIZE / (64 << 10);
+
+/// A value of the running program.
+#[derive(Debug, Clone)]
+enum Value {
+    /// The result of calls to `void` functions.
+    Void,
+    Int(i32),
+    Float(f32),
+    Bool(bool),
+    /// A `str` or `string`.
+    Str(Rc<str>),
+    Array(Rc<RefCell<Vec<Value>>>),
+    Obj(Rc<RefCell<Object>>),
+    /// A function or `extern` function named as a value.
+    Func(ItemId),
+    Builtin(Builtin),
+}
+
+/// An `obj` value.
+#[derive(Debug)]
+struct Object {
+    /// The values of the fields, in declaration order.
+    fields: Vec<Value>,
+}
+
+/// An error that stopped the program, like a division by zero.
+#[derive(Debug)]
+pub struct RuntimeError<'src> {
+    /// What went wrong.
+    pub message: String,
+    /// The expression that failed.
+    pub span: Span<'src>,
+    /// The calls that led to the error, innermost first.
+    pub trace: Vec<Span<'src>>,
+}
+
+impl RuntimeError<'_> {
+    /// Reports the error through [`Diag`].
+    pub fn emit(&self) {
+        // Labels can only point into the file of the diagnostic
+        let path = self.span.source().path();
+        Diag::error(self.span.source())
+            .with_message(&self.message)
+            .with_label(Label::primary((), self.span))
+            .with_calls(
+                self.trace
+                    .iter()
+                    .copied()
+                    .filter(|x| x.source().path() == path),
+            )
+            .finish()
+            .emit();
+    }
+}
+
+/// Objects allocated at the same place that were kept alive by reference cycles.
+#[derive(Debug, Clone, Copy)]
+pub struct Leak<'src> {
+    /// The constructor that allocated the objects.
+    pub span: Span<'src>,
+    /// The type of the objects.
+    pub ty: ItemId,
+    /// How many objects were leaked.
+    pub count: usize,
+}
+
+/// Runs the `start` function of the module `entry`, writing what the program prints to `out`.
+/// Gives the objects leaked by reference cycles, which are only looked for in debug builds.
+///
+/// The program runs on its own thread, with a stack of [`STACK_SIZE`] bytes.
+pub fn run<'src>(
+    modules: &[Module<'src>],
+    info: HirInfo<'_, 'src>,
+    entry: ModuleId,
+    out: &mut (dyn Write + Send),
+) -> std::result::Result<Vec<Leak<'src>>, RuntimeError<'src>> {
+    std::thread::scope(|scope| {
+        std::thread::Builder::new()
+            .name("interp".to_string())
+            .stack_size(STACK_SIZE)
+            .spawn_scoped(scope, || run_on_thread(modules, info, entry, out))
+            .expect("the interpreter thread can be started")
+            .join()
+            .unwrap_or_else(|panic| std::panic::resume_unwind(panic))
+    })
+}
+
+/// Runs the `start` function of the module `entry` like [`run`], but on the current thread,
+/// which needs a stack of [`STACK_SIZE`] bytes for programs to recurse as deep as they can.
+pub fn run_on_thread<'src>(
+    modules: &[Module<'src>],
+    info: HirInfo<'_, 'src>,
+    entry: ModuleId,
+    out: &mut dyn Write,
+) -> std::result::Result<Vec<Leak<'src>>, RuntimeError<'src>> {
+    let module = &modules[entry.0];
+    let start = info
+        .graph
+        .lookup(entry, "start")
+        .filter(|x| info.graph.item(*x).kind == ItemKind::Func);
+    let Some(start) = start else {
+        return Err(RuntimeError {
+            message: format!("no `start` function in module `{}`", module.name),
+            span: Span::new(module.src),
+            trace: Vec::new(),
+        });
+    };
+    let mut interp = Interpreter {
+        modules,
+        info,
+        out,
+        frames: Vec::new(),
+        calls: Vec::new(),
+        allocs: cfg!(debug_assertions).then(Vec::new),
+    };
+    let func = interp.func(start);
+    if !func.sig.params.is_empty() {
+        return Err(interp.error("`start` can't take parameters", func.sig.name.span));
+    }
+    let result = interp.call_func(func, None, Vec::new());
+    let flushed = interp.out.flush();
+    result?;
+    if let Err(err) = flushed {
+        return Err(interp.error(
+            format!("could not write the output: {err}"),
+            func.sig.name.span,
+        ));
+    }
+    Ok(interp.leaks())
+}
+
+/// Explains the warnings about reference cycles.
+pub(crate) const CYCLE_NOTE: &str =
+    "objects that own each other are never freed, even when nothing else uses them";
+
+/// Reports objects leaked by reference cycles, as warnings.
+pub fn report_leaks(leaks: &[Leak], graph: &ModuleGraph) {
+    for leak in leaks {
+        let name = graph.item(leak.ty).name.name;
+        let what = match leak.count {
+            1 => format!("a value of type `{name}` was"),
+            count => format!("{count} values of type `{name}` were"),
+        };
+        Diag::warn(leak.span.source())
+            .with_code("rc-cycle")
+            .with_message(format!("{what} leaked by a reference cycle"))
+            .with_label(Label::primary((), leak.span).with_message("allocated here"))
+            .with_note(CYCLE_NOTE)
+            .finish()
+            .emit();
+    }
+}
+
+/// How running a statement ended.
+enum Flow {
+    /// Run the next statement.
+    Next,
+    Break,
+    Continue,
+    Return(Value),
+}
+
+/// What a call calls.
+#[derive(Clone)]
+enum Target<'a, 'src> {
+    /// A function, or a method with its receiver.
+    Func(&'a Func<'src>, Option<Value>),
+    Builtin(Builtin),
+    Method(BuiltinMethod, Value),
+    /// An `extern` function, by name.
+    Extern(&'src str),
+    /// The object made by calling a type name.
+    Constructed(Value),
+}
+
+type Result<'src, T> = std::result::Result<T, RuntimeError<'src>>;
+
+struct Interpreter<'a, 'src> {
+    modules: &'a [Module<'src>],
+    info: HirInfo<'a, 'src>,
+    out: &'a mut dyn Write,
+    /// The variables of every running function, innermost last.
+    frames: Vec<HashMap<NodeId, Value>>,
+    /// The calls being run, innermost last.
+    calls: Vec<Span<'src>>,
+    /// Every object allocated so far, with its constructor, if cycles are looked for.
+    allocs: Option<Vec<Alloc<'src>>>,
+}
+
+/// An object allocated by the program, remembered to look for reference cycles.
+struct Alloc<'src> {
+    object: Weak<RefCell<Object>>,
+    ty: ItemId,
+    /// The constructor that allocated the object.
+    span: Span<'src>,
+}
+
+impl<'a, 'src> Interpreter<'a, 'src> {
+    fn error(&self, message: impl Into<String>, span: Span<'src>) -> RuntimeError<'src> {
+        RuntimeError {
+            message: message.into(),
+            span,
+            trace: self.calls.iter().rev().copied().collect(),
+        }
+    }
+
+    fn locals(&mut self) -> &mut HashMap<NodeId, Value> {
+        self.frames
+            .last_mut()
+            .expect("code only runs inside of a function")
+    }
+
+    fn func(&self, item: ItemId) -> &'a Func<'src> {
+        let modules = self.modules;
+        let item = self.info.graph.item(item);
+        match &modules[item.module.0].decls[item.decl] {
+            Declaration::Func(func) => func,
+            _ => unreachable!("function items are function declarations"),
+        }
+    }
+
+    fn method(&self, method: MethodRef) -> &'a Func<'src> {
+        let modules = self.modules;
+        match &modules[method.module.0].decls[method.decl] {
+            Declaration::Impl(imp) => &imp.methods[method.method],
+            _ => unreachable!("methods always come from impl blocks"),
+        }
+    }
+
+    fn call_func(
+        &mut self,
+        func: &'a Func<'src>,
+        receiver: Option<Value>,
+        args: Vec<Value>,
+    ) -> Result<'src, Value> {
+        if self.frames.len() >= MAX_DEPTH {
+            let span = self.calls.last().copied().unwrap_or(func.sig.name.span);
+            return Err(self.error(
+                format!("stack overflow: calls are nested more than {MAX_DEPTH} deep"),
+                span,
+            ));
+        }
+        let mut frame = HashMap::new();
+        if let Some(receiver) = receiver {
+            frame.insert(func.id, receiver);
+        }
+        for (param, arg) in func.sig.params.iter().zip(args) {
+            frame.insert(param.id, arg);
+        }
+        self.frames.push(frame);
+        let flow = self.block(&func.body);
+        self.frames.pop();
+        match flow? {
+            Flow::Return(value) => Ok(value),
+            _ => Ok(Value::Void),
+        }
+    }
+
+    fn block(&mut self, block: &'a Block<'src>) -> Result<'src, Flow> {
+        let mut flow = Flow::Next;
+        for stmt in &block.stmts {
+            flow = self.stmt(stmt)?;
+            if !matches!(flow, Flow::Next) {
+                break;
+            }
+            let drops = self.info.ownership.drops.get(&stmt.id);
+            for binding in drops.into_iter().flatten() {
+                self.locals().remove(binding);
+            }
+        }
+        // Leaving the block ends the lifetime of the variables declared in it
+        for stmt in &block.stmts {
+            if let StmtKind::Local { .. } = stmt.kind {
+                self.locals().remove(&stmt.id);
+            }
+        }
+        Ok(flow)
+    }
+
+    fn stmt(&mut self, stmt: &'a Stmt<'src>) -> Result<'src, Flow> {
+        match &stmt.kind {
+            StmtKind::Local { init, .. } => {
+                let value = self.expr(init)?;
+                self.locals().insert(stmt.id, value);
+            }
+            StmtKind::Assign { target, value } => {
+                let value = self.expr(value)?;
+                self.assign(target, value)?;
+            }
+            StmtKind::Increment(target) => {
+                let value = match self.expr(target)? {
+                    Value::Int(int) => Value::Int(int.wrapping_add(1)),
+                    Value::Float(float) => Value::Float(float + 1.0),
+                    _ => unreachable!("only numbers are incremented"),
+                };
+                self.assign(target, value)?;
+            }
+            StmtKind::Expr(expr) => {
+                self.expr(expr)?;
+            }
+            StmtKind::Bulk(call) => self.bulk(call)?,
+            StmtKind::Return(value) => {
+                let value = match value {
+                    Some(value) => self.expr(value)?,
+                    None => Value::Void,
+                };
+                return Ok(Flow::Return(value));
+            }
+            StmtKind::If { cond, then, els } => {
+                if self.truth(cond)? {
+                    return self.block(then);
+                }
+                if let Some(els) = els {
+                    return self.block(els);
+                }
+            }
+            StmtKind::While { cond, body } => {
+                while self.truth(cond)? {
+                    match self.block(body)? {
+                        Flow::Break => break,
+                        Flow::Return(value) => return Ok(Flow::Return(value)),
+                        Flow::Next | Flow::Continue => {}
+                    }
+                }
+            }
+            StmtKind::For { iter, body, .. } => {
+                let Value::Array(items) = self.expr(iter)? else {
+                    unreachable!("`for` loops go through arrays")
+                };
+                // The body may push onto the array, so its length is read every iteration
+                for index in 0.. {
+                    let item = items.borrow().get(index).cloned();
+                    let Some(item) = item else {
+                        break;
+                    };
+                    self.locals().insert(stmt.id, item);
+                    match self.block(body)? {
+                        Flow::Break => break,
+                        Flow::Return(value) => return Ok(Flow::Return(value)),
+                        Flow::Next | Flow::Continue => {}
+                    }
+                }
+                self.locals().remove(&stmt.id);
+            }
+            StmtKind::Break => return Ok(Flow::Break),
+            StmtKind::Continue => return Ok(Flow::Continue),
+        }
+        Ok(Flow::Next)
+    }
+
+    /// Runs a `bulk` call, calling once for every item of the last argument.
+    fn bulk(&mut self, call: &'a Expr<'src>) -> Result<'src, ()> {
+        let ExprKind::Call { callee, args } = &call.kind else {
+            unreachable!("`bulk` is only used on calls")
+        };
+        let (target, mut args) = self.callee(call, callee, args)?;
+        let Some(Value::Array(items)) = args.pop() else {
+            unreachable!("the last argument of `bulk` is an array")
+        };
+        // Copied, so the calls can change the array
+        let items = items.borrow().clone();
+        for item in items {
+            let mut args = args.clone();
+            args.push(item);
+            self.invoke(target.clone(), args, call.span)?;
+        }
+        Ok(())
+    }
+
+    fn assign(&mut self, target: &'a Expr<'src>, value: Value) -> Result<'src, ()> {
+        match &target.kind {
+            ExprKind::Name(_) => {
+                let Some(Res::Local(binding)) = self.info.res.names.get(&target.id) else {
+                    unreachable!("only variables are assigned to")
+                };
+                // A mutable parameter is the caller's value, so it is replaced in place
+                if self.info.borrows.bindings.get(binding) == Some(&PassMode::Mutable) {
+                    match (self.locals().get(binding), &value) {
+                        (Some(Value::Array(old)), Value::Array(new)) if !Rc::ptr_eq(old, new) => {
+                            *old.borrow_mut() = new.take();
+                            return Ok(());
+                        }
+                        (Some(Value::Obj(old)), Value::Obj(new)) if !Rc::ptr_eq(old, new) => {
+                            let fields = std::mem::take(&mut new.borrow_mut().fields);
+                            old.borrow_mut().fields = fields;
+                            return Ok(());
+                        }
+                        _ => {}
+                    }
+                }
+                self.locals().insert(*binding, value);
+            }
+            ExprKind::Field { base, .. } => {
+                let Value::Obj(obj) = self.expr(base)? else {
+                    unreachable!("only objects have fields")
+                };
+                let (_, index) = self.info.typeck.field(target).expect("fields are checked");
+                let old = std::mem::replace(&mut obj.borrow_mut().fields[index], value);
+                drop(old);
+            }
+            _ => unreachable!("only places are assigned to"),
+        }
+        Ok(())
+    }
+
+    fn truth(&mut self, cond: &'a Expr<'src>) -> Result<'src, bool> {
+        match self.expr(cond)? {
+            Value::Bool(bool) => Ok(bool),
+            _ => unreachable!("conditions are booleans"),
+        }
+    }
+
+    fn expr(&mut self, expr: &'a Expr<'src>) -> Result<'src, Value> {
+        let value = match &expr.kind {
+            ExprKind::Name(_) => match self.info.res.names.get(&expr.id) {
+                Some(Res::Local(binding)) => {
+                    let moved = self.info.ownership.moves.contains(&expr.id);
+                    let locals = self.locals();
+                    let value = if moved {
+                        locals.remove(binding)
+                    } else {
+                        locals.get(binding).cloned()
+                    };
+                    value.expect("ownership analysis only lets variables holding a value be used")
+                }
+                Some(Res::Item(item)) if self.info.graph.item(*item).kind == ItemKind::Obj => {
+                    self.construct(*item, Vec::new(), expr.span)?
+                }
+                Some(Res::Item(item)) => Value::Func(*item),
+                Some(Res::Builtin(builtin)) => Value::Builtin(*builtin),
+                None => unreachable!("programs with errors aren't run"),
+            },
+            ExprKind::Str(string) => Value::Str(Rc::from(*string)),
+            ExprKind::Int(int) if self.info.typeck.expr_ty(expr.id) == TyId::F32 => {
+                Value::Float(*int as f32)
+            }
+            ExprKind::Int(int) => Value::Int(*int as i32),
+            ExprKind::Float(float) => Value::Float(*float as f32),
+            ExprKind::Bool(bool) => Value::Bool(*bool),
+            ExprKind::Array(items) => {
+                let items = items
+                    .iter()
+                    .map(|x| self.expr(x))
+                    .collect::<Result<Vec<_>>>()?;
+                Value::Array(Rc::new(RefCell::new(items)))
+            }
+            ExprKind::Field { base, .. } => {
+                let Value::Obj(obj) = self.expr(base)? else {
+                    unreachable!("only objects have fields")
+                };
+                let (_, index) = self.info.typeck.field(expr).expect("fields are checked");
+                obj.borrow().fields[index].clone()
+            }
+            ExprKind::Call { callee, args } => {
+                let (target, args) = self.callee(expr, callee, args)?;
+                self.invoke(target, args, expr.span)?
+            }
+            ExprKind::Construct { fields, .. } => {
+                let Some(Res::Item(obj)) = self.info.res.names.get(&expr.id) else {
+                    unreachable!("constructors name a type")
+                };
+                let mut inits = Vec::new();
+                for field in fields {
+                    let value = self.expr(&field.value)?;
+                    let (index, _) = self
+                        .info
+                        .typeck
+                        .items
+                        .field(*obj, field.name.name)
+                        .expect("fields are checked");
+                    inits.push((index, value));
+                }
+                self.construct(*obj, inits, expr.span)?
+            }
+            ExprKind::Binary { op, lhs, rhs } => self.binary(expr, *op, lhs, rhs)?,
+            ExprKind::Unary { op, operand } => match (op, self.expr(operand)?) {
+                (UnOp::Neg, Value::Int(int)) => Value::Int(int.wrapping_neg()),
+                (UnOp::Neg, Value::Float(float)) => Value::Float(-float),
+                (UnOp::Not, Value::Bool(bool)) => Value::Bool(!bool),
+                _ => unreachable!("operands are checked"),
+            },
+        };
+        Ok(value)
+    }
+
+    /// Makes an object of type `obj`, with the fields in `inits` and the default value of the
+    /// others.
+    fn construct(
+        &mut self,
+        obj: ItemId,
+        mut inits: Vec<(usize, Value)>,
+        span: Span<'src>,
+    ) -> Result<'src, Value> {
+        let modules = self.modules;
+        let item = self.info.graph.item(obj);
+        let Declaration::Obj(decl) = &modules[item.module.0].decls[item.decl] else {
+            unreachable!("object items are object declarations")
+        };
+        let mut fields = Vec::with_capacity(decl.fields.len());
+        for (index, field) in decl.fields.iter().enumerate() {
+            let value = match inits.iter().position(|x| x.0 == index) {
+                Some(init) => inits.swap_remove(init).1,
+                None => {
+                    let default = field.default.as_ref().expect("missing fields are checked");
+                    self.expr(default)?
+                }
+            };
+            fields.push(value);
+        }
+        let object = Rc::new(RefCell::new(Object { fields }));
+        if let Some(allocs) = &mut self.allocs {
+            // Forget freed objects before growing, so long programs don't pile them up
+            if allocs.len() == allocs.capacity() {
+                allocs.retain(|x| x.object.strong_count() > 0);
+            }
+            allocs.push(Alloc {
+                object: Rc::downgrade(&object),
+                ty: obj,
+                span,
+            });
+        }
+        Ok(Value::Obj(object))
+    }
+
+    /// Evaluates what a call calls and its arguments.
+    fn callee(
+        &mut self,
+        call: &'a Expr<'src>,
+        callee: &'a Expr<'src>,
+        args: &'a [Expr<'src>],
+    ) -> Result<'src, (Target<'a, 'src>, Vec<Value>)> {
+        let target = match self.info.typeck.methods.get(&call.id) {
+            Some(method) => {
+                let receiver = self.info.typeck.receiver(call);
+                let receiver = self.expr(receiver.expect("method calls have a receiver"))?;
+                match method {
+                    Method::User(method) => Target::Func(self.method(*method), Some(receiver)),
+                    Method::Builtin(method) => Target::Method(*method, receiver),
+                }
+            }
+            None => match self.expr(callee)? {
+                Value::Func(item) => {
+                    let item_ref = self.info.graph.item(item);
+                    match item_ref.kind {
+                        ItemKind::Extern => Target::Extern(item_ref.name.name),
+                        _ => Target::Func(self.func(item), None),
+                    }
+                }
+                Value::Builtin(builtin) => Target::Builtin(builtin),
+                // Calling a type name constructs it
+                value => Target::Constructed(value),
+            },
+        };
+        let args = args
+            .iter()
+            .map(|x| self.expr(x))
+            .collect::<Result<Vec<_>>>()?;
+        Ok((target, args))
+    }
+
+    fn invoke(
+        &mut self,
+        target: Target<'a, 'src>,
+        args: Vec<Value>,
+        span: Span<'src>,
+    ) -> Result<'src, Value> {
+        match target {
+            Target::Func(func, receiver) => {
+                self.calls.push(span);
+                let value = self.call_func(func, receiver, args);
+                self.calls.pop();
+                value
+            }
+            Target::Builtin(builtin) => self.builtin(builtin, args, span),
+            Target::Extern(name) => match Builtin::from_name(name) {
+                Some(builtin @ (Builtin::Print | Builtin::Println)) => {
+                    self.builtin(builtin, args, span)
+                }
+                _ => Err(self.error(
+                    format!("extern function `{name}` isn't available in the interpreter"),
+                    span,
+                )),
+            },
+            Target::Method(method, receiver) => Ok(match (method, receiver) {
+                (BuiltinMethod::ToString, Value::Int(int)) => Value::Str(int.to_string().into()),
+                (BuiltinMethod::ToString, Value::Float(float)) => {
+                    Value::Str(float.to_string().into())
+                }
+                (BuiltinMethod::ToString, Value::Bool(bool)) => Value::Str(bool.to_string().into()),
+                (BuiltinMethod::ToString, Value::Str(string)) => Value::Str(string),
+                (BuiltinMethod::Len, Value::Str(string)) => Value::Int(string.len() as i32),
+                (BuiltinMethod::Len, Value::Array(items)) => {
+                    Value::Int(items.borrow().len() as i32)
+                }
+                (BuiltinMethod::Push, Value::Array(items)) => {
+                    items.borrow_mut().extend(args);
+                    Value::Void
+                }
+                _ => unreachable!("builtin methods are checked"),
+            }),
+            Target::Constructed(value) => Ok(value),
+        }
+    }
+
+    fn builtin(
+        &mut self,
+        builtin: Builtin,
+        args: Vec<Value>,
+        span: Span<'src>,
+    ) -> Result<'src, Value> {
+        let written = match (builtin, args.first()) {
+            (Builtin::Print, Some(Value::Str(string))) => self.out.write_all(string.as_bytes()),
+            (Builtin::Println, _) => self.out.write_all(b"\n"),
+            // The value was moved out of its variable, and is freed with `args`
+            (Builtin::Drop, _) => Ok(()),
+            _ => unreachable!("builtin calls are checked"),
+        };
+        written.map_err(|err| self.error(format!("could not write the output: {err}"), span))?;
+        Ok(Value::Void)
+    }
+
+    fn binary(
+        &mut self,
+        expr: &'a Expr<'src>,
+        op: BinOp,
+        lhs: &'a Expr<'src>,
+        rhs: &'a Expr<'src>,
+    ) -> Result<'src, Value> {
+        if let Some(Method::User(method)) = self.info.typeck.methods.get(&expr.id) {
+            let receiver = self.expr(lhs)?;
+            let arg = self.expr(rhs)?;
+            let func = self.method(*method);
+            let value = self.invoke(Target::Func(func, Some(receiver)), vec![arg], expr.span)?;
+            return Ok(match (op, value) {
+                (BinOp::Ne, Value::Bool(bool)) => Value::Bool(!bool),
+                (_, value) => value,
+            });
+        }
+        // `and` and `or` only evaluate the right hand side if it decides the result
+        match op {
+            BinOp::And => return Ok(Value::Bool(self.truth(lhs)? && self.truth(rhs)?)),
+            BinOp::Or => return Ok(Value::Bool(self.truth(lhs)? || self.truth(rhs)?)),
+            _ => {}
+        }
+        let value = match (self.expr(lhs)?, self.expr(rhs)?) {
+            (Value::Int(_), Value::Int(0)) if op == BinOp::Div => {
+                return Err(self.error("attempt to divide by zero", expr.span));
+            }
+            (Value::Int(a), Value::Int(b)) => match op {
+                BinOp::Add => Value::Int(a.wrapping_add(b)),
+                BinOp::Sub => Value::Int(a.wrapping_sub(b)),
+                BinOp::Mul => Value::Int(a.wrapping_mul(b)),
+                BinOp::Div => Value::Int(a.wrapping_div(b)),
+                _ => Value::Bool(compare(op, a, b)),
+            },
+            (Value::Float(a), Value::Float(b)) => match op {
+                BinOp::Add => Value::Float(a + b),
+                BinOp::Sub => Value::Float(a - b),
+                BinOp::Mul => Value::Float(a * b),
+                BinOp::Div => Value::Float(a / b),
+                _ => Value::Bool(compare(op, a, b)),
+            },
+            (Value::Str(a), Value::Str(b)) if op == BinOp::Add => {
+                Value::Str(format!("{a}{b}").into())
+            }
+            (Value::Str(a), Value::Str(b)) => Value::Bool(compare(op, a, b)),
+            (Value::Bool(a), Value::Bool(b)) => Value::Bool(compare(op, a, b)),
+            _ => unreachable!("operands are checked"),
+        };
+        Ok(value)
+    }
+
+    /// Finds the objects still alive, which only reference cycles can keep alive once the
+    /// program is done.
+    fn leaks(&self) -> Vec<Leak<'src>> {
+        let mut leaks: Vec<Leak<'src>> = Vec::new();
+        let alive = self.allocs.iter().flatten();
+        for alloc in alive.filter(|x| x.object.strong_count() > 0) {
+            match leaks.iter_mut().find(|x| x.span == alloc.span) {
+                Some(leak) => leak.count += 1,
+                None => leaks.push(Leak {
+                    span: alloc.span,
+                    ty: alloc.ty,
+                    count: 1,
+                }),
+            }
+        }
+        leaks
+    }
+}
+
+/// Applies the comparison operator `op`.
+pub(crate) fn compare<T: PartialOrd>(op: BinOp, a: T, b: T) -> bool {
+    match op {
+        BinOp::Eq => a == b,
+        BinOp::Ne => a != b,
+        BinOp::Lt => a < b,
+        BinOp::Le => a <= b,
+        BinOp::Gt => a > b,
+        BinOp::Ge => a >= b,
+        _ => unreachable!("only comparisons give booleans"),
+    }
+}
+
+/// Checks the program `text`, which must not have errors, and gives it to `f`.
+#[cfg(test)]
+pub(crate) fn with_checked<R>(text: &str, f: impl FnOnce(&[Module], HirInfo) -> R) -> R {
+    let sources = [crate::Source::new(text, "main.scp")];
+    let modules = crate::project::test_modules(&sources);
+    let graph = ModuleGraph::build(&modules);
+    let res = crate::resolve::resolve(&modules, &graph);
+    let errors = crate::diag::error_count();
+    let typeck = crate::ty::check::check(&modules, &graph, &res);
+    let borrows = crate::borrow::infer(&modules, &graph, &res, &typeck);
+    let ownership = crate::ownership::analyze(&modules, &graph, &res, &typeck, &borrows);
+    assert_eq!(crate::diag::error_count(), errors);
+    let info = HirInfo {
+        graph: &graph,
+        res: &res,
+        typeck: &typeck,
+        borrows: &borrows,
+        ownership: &ownership,
+    };
+    f(&modules, info)
+}
+
+/// A program using most of the language, printing `abc\n55 3 3\n134abc\nequal`.
+#[cfg(test)]
+const SAMPLE: &str = r"identifier app.main
+obj counter is
+    count: i32 = 0,
+    names: string[] = [],
+end
+impl counter is
+    func add(string name) is
+        self.names.push(name)
+        self.count++
+    end
+    func bool op-eq(counter other) is
+        return self.count == other.count
+    end
+end
+func i32 fib(i32 n) is
+    if n < 2 is
+        return n
+    end
+    return fib(n - 1) + fib(n - 2)
+end
+func void start() is
+    counter c = counter
+    c.add('a')
+    c.add('b' + 'c')
+    bulk print(c.names)
+    println()
+    print(fib(10).to-string() + ' ' + (7 / 2).to-string() + ' ' + (1.5 * 2).to-string())
+    println()
+    i32 i = 0
+    while true is
+        i++
+        if i == 2 is
+            continue
+        else if i > 4 or false is
+            break
+        end
+        print(i.to-string())
+    end
+    for name in c.names is
+        print(name)
+    end
+    counter d = counter(count: 2)
+    if c == d and not (c != d) is
+        println()
+        print('equal')
+    end
+end";
+
+#[cfg(test)]
+fn run_source(text: &str) -> (String, Vec<usize>) {
+    with_checked(text, |modules, info| {
+        let mut out = Vec::new();
+        let leaks = run(modules, info, ModuleId(0), &mut out).unwrap();
+        let leaks = leaks.iter().map(|x| x.count).collect();
+        (String::from_utf8(out).unwrap(), leaks)
+    })
+}
+
+#[test]
+fn run_test() {
+    let (out, leaks) = run_source(SAMPLE);
+    assert_eq!(out, "abc\n55 3 3\n134abc\nequal");
+    assert!(leaks.is_empty());
+}
+
+#[test]
+fn depth_test() {
+    let (out, _) = run_source(
+        r"identifier app.main
+func i32 sum(i32 n) is
+    if n == 0 is
+        return 0
+    end
+    return n + sum(n - 1)
+end
+func void start() is
+    print(sum(5000).to-string())
+end",
+    );
+    assert_eq!(out, "12502500");
+}
+
+// Cycles are only looked for in debug builds
+#[cfg(debug_assertions)]
+#[test]
+fn cycle_test() {
+    let (_, leaks) = run_source(
+        r"identifier app.main
+obj node is
+    children: node[] = [],
+end
+obj holder is
+    first: node = node,
+end
+func void start() is
+    holder h = holder
+    node a = h.first
+    node child = node
+    child.children.push(h.first)
+    a.children.push(child)
+    node lonely = node
+    lonely.children.push(node)
+end",
+    );
+    // `a` and its child own each other, `lonely` and its child are freed
+    assert_eq!(leaks, [1, 1]);
+}
@@ -52,6 +52,58 @@ pub enum TokenType {
     Slash,
     /// Colon (:)
     Colon,
+    /// Increment operator (++)
+    Increment,
+    /// Pub keyword
+    Pub,
+    /// Obj keyword
+    Obj,
+    /// Impl keyword
+    Impl,
+    /// Use keyword
+    Use,
+    /// Return keyword
+    Return,
+    /// Let keyword
+    Let,
+    /// If keyword
+    If,
+    /// Else keyword
+    Else,
+    /// While keyword
+    While,
+    /// For keyword
+    For,
+    /// In keyword
+    In,
+    /// Break keyword
+    Break,
+    /// Continue keyword
+    Continue,
+    /// And keyword
+    And,
+    /// Or keyword
+    Or,
+    /// Not keyword
+    Not,
+    /// True keyword
+    True,
+    /// False keyword
+    False,
+    /// Bulk keyword
+    Bulk,
+    /// Equality operator (==)
+    EqualsEquals,
+    /// Inequality operator (!=)
+    NotEquals,
+    /// Less than sign (<)
+    Less,
+    /// Less than or equal operator (<=)
+    LessEquals,
+    /// Greater than sign (>)
+    Greater,
+    /// Greater than or equal operator (>=)
+    GreaterEquals,
 }
 
 /// Represents a value in the lexer that a token might have.
@@ -121,11 +173,37 @@ impl<'src> Display for Token<'src> {
             TokenType::Equals => write!(f, "="),
             TokenType::Plus => write!(f, "+"),
             TokenType::Minus => write!(f, "-"),
-            TokenType::Star => write!(f, "/"),
-            TokenType::Slash => write!(f, "*"),
+            TokenType::Star => write!(f, "*"),
+            TokenType::Slash => write!(f, "/"),
             TokenType::OpenBracket => write!(f, "["),
             TokenType::CloseBracket => write!(f, "]"),
             TokenType::Colon => write!(f, ":"),
+            TokenType::Increment => write!(f, "++"),
+            TokenType::Pub => write!(f, "pub"),
+            TokenType::Return => write!(f, "return"),
+            TokenType::Let => write!(f, "let"),
+            TokenType::Obj => write!(f, "obj"),
+            TokenType::Impl => write!(f, "impl"),
+            TokenType::Use => write!(f, "use"),
+            TokenType::If => write!(f, "if"),
+            TokenType::Else => write!(f, "else"),
+            TokenType::While => write!(f, "while"),
+            TokenType::For => write!(f, "for"),
+            TokenType::In => write!(f, "in"),
+            TokenType::Break => write!(f, "break"),
+            TokenType::Continue => write!(f, "continue"),
+            TokenType::And => write!(f, "and"),
+            TokenType::Or => write!(f, "or"),
+            TokenType::Not => write!(f, "not"),
+            TokenType::True => write!(f, "true"),
+            TokenType::False => write!(f, "false"),
+            TokenType::Bulk => write!(f, "bulk"),
+            TokenType::EqualsEquals => write!(f, "=="),
+            TokenType::NotEquals => write!(f, "!="),
+            TokenType::Less => write!(f, "<"),
+            TokenType::LessEquals => write!(f, "<="),
+            TokenType::Greater => write!(f, ">"),
+            TokenType::GreaterEquals => write!(f, ">="),
         }
     }
 }
@@ -187,17 +265,30 @@ impl<'src> Lexer<'src> {
         self.span.update();
     }
 
-    #[inline]
+    /// Skips whitespace and line comments, in a loop so long runs of comments don't recurse.
     fn skip_whitespace(&mut self) {
-        let mut next = 0;
-        while let Some(char) = self.peek_char() {
-            if !char.is_ascii_whitespace() {
+        loop {
+            let mut next = 0;
+            while let Some(char) = self.peek_char() {
+                if !char.is_ascii_whitespace() {
+                    break;
+                }
+                self.source.next();
+                next += 1;
+            }
+            self.span.grow_front(next);
+            let mut ahead = self.source.clone();
+            if ahead.next() != Some(b'/') || ahead.peek() != Some(&b'/') {
                 break;
             }
-            self.source.next();
-            next += 1;
+            // Line comment, skip until the end of the line
+            while let Some(c) = self.peek_char() {
+                if c == b'\n' {
+                    break;
+                }
+                self.next_char();
+            }
         }
-        self.span.grow_front(next);
         self.span.update();
     }
 
@@ -262,9 +353,35 @@ impl<'src> Iterator for Lexer<'src> {
                 make_token!(self, TokenType::Dot)
             }
             b'=' => {
+                if self.peek_char() == Some(b'=') {
+                    self.next_char();
+                    return make_token!(self, TokenType::EqualsEquals);
+                }
                 make_token!(self, TokenType::Equals)
             }
+            b'!' if self.peek_char() == Some(b'=') => {
+                self.next_char();
+                make_token!(self, TokenType::NotEquals)
+            }
+            b'<' => {
+                if self.peek_char() == Some(b'=') {
+                    self.next_char();
+                    return make_token!(self, TokenType::LessEquals);
+                }
+                make_token!(self, TokenType::Less)
+            }
+            b'>' => {
+                if self.peek_char() == Some(b'=') {
+                    self.next_char();
+                    return make_token!(self, TokenType::GreaterEquals);
+                }
+                make_token!(self, TokenType::Greater)
+            }
             b'+' => {
+                if self.peek_char() == Some(b'+') {
+                    self.next_char();
+                    return make_token!(self, TokenType::Increment);
+                }
                 make_token!(self, TokenType::Plus)
             }
             b'-' => {
@@ -319,6 +436,25 @@ impl<'src> Iterator for Lexer<'src> {
                     "extern" => make_token!(self, TokenType::Extern),
                     "func" => make_token!(self, TokenType::Func),
                     "void" => make_token!(self, TokenType::Void),
+                    "pub" => make_token!(self, TokenType::Pub),
+                    "obj" => make_token!(self, TokenType::Obj),
+                    "impl" => make_token!(self, TokenType::Impl),
+                    "use" => make_token!(self, TokenType::Use),
+                    "return" => make_token!(self, TokenType::Return),
+                    "let" => make_token!(self, TokenType::Let),
+                    "if" => make_token!(self, TokenType::If),
+                    "else" => make_token!(self, TokenType::Else),
+                    "while" => make_token!(self, TokenType::While),
+                    "for" => make_token!(self, TokenType::For),
+                    "in" => make_token!(self, TokenType::In),
+                    "break" => make_token!(self, TokenType::Break),
+                    "continue" => make_token!(self, TokenType::Continue),
+                    "and" => make_token!(self, TokenType::And),
+                    "or" => make_token!(self, TokenType::Or),
+                    "not" => make_token!(self, TokenType::Not),
+                    "true" => make_token!(self, TokenType::True),
+                    "false" => make_token!(self, TokenType::False),
+                    "bulk" => make_token!(self, TokenType::Bulk),
                     _ => make_token!(self, TokenType::Identifier, LexerValue::String(string)),
                 }
             }
@@ -378,3 +514,41 @@ fn span_test() {
     let token = lexer.next().unwrap();
     assert_eq!(token.span().apply(), "5553");
 }
+
+#[test]
+fn comment_test() {
+    let src = Source::new("a // comment ++ 'x\nb ++ c", "test.scp");
+    let tokens: Vec<_> = Lexer::new(&src).map(|x| x.span().apply()).collect();
+    assert_eq!(tokens, ["a", "b", "++", "c"]);
+    let text = format!("a\n{}b a / b", "// comment\n".repeat(100_000));
+    let src = Source::new(&text, "test.scp");
+    let tokens: Vec<_> = Lexer::new(&src).map(|x| x.span().apply()).collect();
+    assert_eq!(tokens, ["a", "b", "a", "/", "b"]);
+}
+
+#[test]
+fn comparison_test() {
+    let src = Source::new("a == b != c < d <= e > f >= g = h", "test.scp");
+    let tokens: Vec<_> = Lexer::new(&src).map(|x| x.token_type()).collect();
+    use TokenType::*;
+    assert_eq!(
+        tokens,
+        [
+            Identifier,
+            EqualsEquals,
+            Identifier,
+            NotEquals,
+            Identifier,
+            Less,
+            Identifier,
+            LessEquals,
+            Identifier,
+            Greater,
+            Identifier,
+            GreaterEquals,
+            Identifier,
+            Equals,
+            Identifier
+        ]
+    );
+}
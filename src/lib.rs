@@ -4,10 +4,25 @@ use std::{borrow::Cow, fmt::Debug, ops::Range, path::PathBuf};
 
 use codespan_reporting::files::{self, Error, Files};
 
+pub mod ast;
+pub mod borrow;
+pub mod builtins;
+pub mod bytecode;
+pub mod codegen;
 pub mod diag;
+pub mod emit;
+pub mod escape;
+pub mod interp;
 pub mod lexer;
+pub mod manifest;
+pub mod mir;
+pub mod modules;
+pub mod ownership;
 pub mod parser;
+pub mod project;
+pub mod resolve;
 pub mod span;
+pub mod ty;
 
 #[derive(Clone, PartialEq, Eq, Hash)]
 pub struct Source<'src> {
@@ -21,6 +36,10 @@ impl<'src> Source<'src> {
         &self.path
     }
 
+    pub(crate) fn text(&self) -> &'src str {
+        self.source
+    }
+
     pub fn new(source: &'src str, path: impl Into<PathBuf>) -> Self {
         let line_starts = files::line_starts(source).collect();
         Source {
@@ -0,0 +1,350 @@
+#![deny(missing_docs)]
+//! Module for reading `Escoop.toml` project manifests.
+//!
+//! Only the subset of TOML used by manifests is supported: `[tables]`, `key = "string"` pairs,
+//! inline tables of strings (`{ path = "../printer" }`) and `#` comments.
+//!
+//! ```toml
+//! [project]
+//! name = "hello-world"
+//! version = "0.1.0"
+//! entry = "entrypoint"  # Module holding `start()`, defaults to `entrypoint`
+//! source-root = "src"   # Directory holding the modules, defaults to the manifest's directory
+//!
+//! [dependencies]
+//! printer = { path = "../printer" }
+//! ```
+
+use std::path::{Path, PathBuf};
+
+use codespan_reporting::diagnostic::Label;
+
+use crate::{Source, diag::Diag, span::Span};
+
+/// The file name of a manifest.
+pub const MANIFEST_NAME: &str = "Escoop.toml";
+
+/// A parsed `Escoop.toml`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Manifest {
+    /// Name of the project. This is the first segment of every module path in the project.
+    pub name: String,
+    /// Version of the project.
+    pub version: String,
+    /// Module path of the entry module, relative to the project (e.g. `entrypoint`).
+    pub entry: String,
+    /// Directory holding the modules of the project, relative to the manifest.
+    pub source_root: PathBuf,
+    /// Projects this project depends on.
+    pub dependencies: Vec<Dependency>,
+}
+
+/// A dependency on another project on the local file system.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Dependency {
+    /// Name of the dependency, which has to match the name in its manifest.
+    pub name: String,
+    /// Directory holding the manifest of the dependency, relative to this manifest.
+    pub path: PathBuf,
+}
+
+/// Searches `dir` and then every parent of `dir` for a manifest, returning the path of the
+/// first one found.
+pub fn find(dir: &Path) -> Option<PathBuf> {
+    dir.ancestors()
+        .map(|dir| dir.join(MANIFEST_NAME))
+        .find(|path| path.is_file())
+}
+
+/// Checks if `name` can be used as a segment of a module path.
+pub fn valid_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars.next().is_some_and(|c| c.is_ascii_alphabetic())
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Table {
+    None,
+    Project,
+    Dependencies,
+}
+
+struct ManifestParser<'src> {
+    src: &'src Source<'src>,
+    text: &'src str,
+    pos: usize,
+    ok: bool,
+}
+
+impl<'src> ManifestParser<'src> {
+    fn span(&self, start: usize, end: usize) -> Span<'src> {
+        let end = end.min(self.text.len());
+        Span::new_from(self.src, start.min(end) as u32, end as u32)
+    }
+
+    fn error(&mut self, message: impl ToString, span: Span<'src>) {
+        self.ok = false;
+        Diag::error(self.src)
+            .with_message(message)
+            .with_label(Label::primary((), span))
+            .finish()
+            .emit();
+    }
+
+    fn peek(&self) -> Option<u8> {
+        self.text.as_bytes().get(self.pos).copied()
+    }
+
+    fn skip_spaces(&mut self) {
+        while matches!(self.peek(), Some(b' ' | b'\t' | b'\r')) {
+            self.pos += 1;
+        }
+        if self.peek() == Some(b'#') {
+            while !matches!(self.peek(), Some(b'\n') | None) {
+                self.pos += 1;
+            }
+        }
+    }
+
+    /// Skips the rest of the line, reporting anything that isn't whitespace or a comment.
+    fn end_line(&mut self) {
+        self.skip_spaces();
+        let start = self.pos;
+        while !matches!(self.peek(), Some(b'\n') | None) {
+            self.pos += 1;
+        }
+        if start != self.pos {
+            self.error("expected a new line", self.span(start, self.pos));
+        }
+        self.pos += 1;
+    }
+
+    fn key(&mut self) -> Option<(&'src str, Span<'src>)> {
+        let start = self.pos;
+        while self
+            .peek()
+            .is_some_and(|c| c.is_ascii_alphanumeric() || c == b'-' || c == b'_')
+        {
+            self.pos += 1;
+        }
+        if start == self.pos {
+            self.error("expected a key", self.span(start, start + 1));
+            return None;
+        }
+        Some((&self.text[start..self.pos], self.span(start, self.pos)))
+    }
+
+    fn expect(&mut self, c: u8) -> Option<()> {
+        self.skip_spaces();
+        if self.peek() == Some(c) {
+            self.pos += 1;
+            self.skip_spaces();
+            Some(())
+        } else {
+            let span = self.span(self.pos, self.pos + 1);
+            self.error(format!("expected `{}`", c as char), span);
+            None
+        }
+    }
+
+    fn string(&mut self) -> Option<(&'src str, Span<'src>)> {
+        let start = self.pos;
+        if self.peek() != Some(b'"') {
+            self.error("expected a string", self.span(start, start + 1));
+            return None;
+        }
+        self.pos += 1;
+        while !matches!(self.peek(), Some(b'"' | b'\n') | None) {
+            self.pos += 1;
+        }
+        if self.peek() != Some(b'"') {
+            self.error("unterminated string", self.span(start, self.pos));
+            return None;
+        }
+        self.pos += 1;
+        Some((
+            &self.text[start + 1..self.pos - 1],
+            self.span(start, self.pos),
+        ))
+    }
+
+    fn inline_table(&mut self) -> Option<Vec<(&'src str, Span<'src>, &'src str)>> {
+        self.expect(b'{')?;
+        let mut pairs = Vec::new();
+        while self.peek() != Some(b'}') {
+            let (key, span) = self.key()?;
+            self.expect(b'=')?;
+            let (value, _) = self.string()?;
+            pairs.push((key, span, value));
+            self.skip_spaces();
+            if self.peek() != Some(b',') {
+                break;
+            }
+            self.pos += 1;
+            self.skip_spaces();
+        }
+        self.expect(b'}')?;
+        Some(pairs)
+    }
+}
+
+impl Manifest {
+    /// Parses the manifest in `src`, reporting every problem through [`Diag`]. Returns `None` if
+    /// the manifest is invalid.
+    pub fn parse(src: &Source) -> Option<Manifest> {
+        let mut parser = ManifestParser {
+            src,
+            text: src.source,
+            pos: 0,
+            ok: true,
+        };
+        let mut table = Table::None;
+        let mut name = None;
+        let mut version = None;
+        let mut entry = None;
+        let mut source_root = None;
+        let mut dependencies = Vec::new();
+
+        while parser.pos < parser.text.len() {
+            parser.skip_spaces();
+            match parser.peek() {
+                None => break,
+                Some(b'\n') => {
+                    parser.pos += 1;
+                    continue;
+                }
+                Some(b'[') => {
+                    parser.pos += 1;
+                    let Some((key, span)) = parser.key() else {
+                        parser.end_line();
+                        continue;
+                    };
+                    table = match key {
+                        "project" => Table::Project,
+                        "dependencies" => Table::Dependencies,
+                        _ => {
+                            parser.error(format!("unknown table `{key}`"), span);
+                            Table::None
+                        }
+                    };
+                    parser.expect(b']');
+                    parser.end_line();
+                    continue;
+                }
+                _ => {}
+            }
+
+            let Some((key, key_span)) = parser.key() else {
+                parser.end_line();
+                continue;
+            };
+            if parser.expect(b'=').is_none() {
+                parser.end_line();
+                continue;
+            }
+            match table {
+                Table::None => parser.error("keys must be inside of a table", key_span),
+                Table::Project => {
+                    let value = parser.string();
+                    let slot = match key {
+                        "name" => &mut name,
+                        "version" => &mut version,
+                        "entry" => &mut entry,
+                        "source-root" => &mut source_root,
+                        _ => {
+                            parser.error(format!("unknown key `{key}`"), key_span);
+                            parser.end_line();
+                            continue;
+                        }
+                    };
+                    if value.is_some() {
+                        *slot = value;
+                    }
+                }
+                Table::Dependencies => {
+                    let Some(pairs) = parser.inline_table() else {
+                        parser.end_line();
+                        continue;
+                    };
+                    let mut path = None;
+                    for (pair_key, pair_span, value) in pairs {
+                        if pair_key == "path" {
+                            path = Some(value);
+                        } else {
+                            parser.error(format!("unknown key `{pair_key}`"), pair_span);
+                        }
+                    }
+                    match path {
+                        Some(path) => dependencies.push(Dependency {
+                            name: key.to_string(),
+                            path: PathBuf::from(path),
+                        }),
+                        None => parser
+                            .error(format!("dependency `{key}` is missing a `path`"), key_span),
+                    }
+                }
+            }
+            parser.end_line();
+        }
+
+        let end = parser.span(0, 0);
+        if let Some((name, span)) = name
+            && !valid_name(name)
+        {
+            parser.error(format!("`{name}` is not a valid project name"), span);
+        }
+        if name.is_none() {
+            parser.error("manifest is missing `name` in `[project]`", end);
+        }
+        if version.is_none() {
+            parser.error("manifest is missing `version` in `[project]`", end);
+        }
+        if !parser.ok {
+            return None;
+        }
+        Some(Manifest {
+            name: name?.0.to_string(),
+            version: version?.0.to_string(),
+            entry: entry.map_or("entrypoint", |x| x.0).to_string(),
+            source_root: PathBuf::from(source_root.map_or(".", |x| x.0)),
+            dependencies,
+        })
+    }
+}
+
+#[test]
+fn manifest_test() {
+    let text = r#"# A comment
+[project]
+name = "hello-world"
+version = "0.1.0" # trailing
+source-root = "src"
+
+[dependencies]
+printer = { path = "../printer" }
+"#;
+    let src = Source::new(text, "Escoop.toml");
+    let manifest = Manifest::parse(&src).unwrap();
+    assert_eq!(manifest.name, "hello-world");
+    assert_eq!(manifest.version, "0.1.0");
+    assert_eq!(manifest.entry, "entrypoint");
+    assert_eq!(manifest.source_root, PathBuf::from("src"));
+    assert_eq!(
+        manifest.dependencies,
+        [Dependency {
+            name: "printer".to_string(),
+            path: PathBuf::from("../printer"),
+        }]
+    );
+}
+
+#[test]
+fn manifest_error_test() {
+    let errors = crate::diag::error_count();
+    let text = "[project]\nname = \"hello world\"\ncolour = \"blue\"\n";
+    let src = Source::new(text, "Escoop.toml");
+    assert!(Manifest::parse(&src).is_none());
+    // Unknown key, invalid name and missing version
+    assert_eq!(crate::diag::error_count(), errors + 3);
+}
@@ -0,0 +1,723 @@
+#![deny(missing_docs)]
+//! Module for the mid-level IR, a control-flow graph of every function, between the checked AST
+//! and the backends.
+//!
+//! The [bytecode](crate::bytecode) compiler and every [code generator](crate::codegen) compile
+//! it, so they all run the same optimized program. Only the [interpreter](crate::interp) works
+//! on the checked AST directly.
+//!
+//! A [`Program`] is [lowered](lower) from the checked AST of a project. Every [`Function`] has
+//! numbered [locals](Local), starting with its parameters (and the receiver of methods, before
+//! them), and a list of [basic blocks](BasicBlock), starting with the entry block. Blocks run
+//! their [statements](StatementKind) in order and end with a [`Terminator`] choosing the next
+//! block.
+//!
+//! Every local holds a value or is empty, which they all start as. Ownership is explicit: a
+//! local either owns the strings, arrays and objects it holds, or [borrows](LocalDecl::borrowed)
+//! them, like parameters the caller keeps and fields and items that were read.
+//! [`Operand::Move`] takes the value out of an owning local, leaving it empty, and
+//! [`Operand::Copy`] reads it without taking it. Where a value is consumed (assigned to an
+//! owning local, passed to an owned parameter, stored in an array or object, or returned) a
+//! reference has to be moved, or given a new owner by [`Rvalue::Share`] first, which only
+//! strings and [reference counted](Program::is_counted) values can be. Assigning a local
+//! doesn't drop the value it held, so the lowering places a [`Drop`](StatementKind::Drop)
+//! before, and where [ownership analysis](crate::ownership) ends the lifetime of variables.
+//! Dropping an empty local does nothing, and borrowing locals are never dropped.
+//!
+//! Programs are checked by [`validate`](validate::validate), [optimized](opt) and written by
+//! [`pretty`], which `esci --emit ir` uses.
+
+use std::{
+    collections::HashSet,
+    fmt::{self, Display},
+    io::{self, Write},
+    rc::Rc,
+};
+
+use crate::{
+    ast::{BinOp, UnOp},
+    span::Span,
+};
+
+pub mod lower;
+pub mod opt;
+pub mod validate;
+
+/// A local of a function, indexing [`Function::locals`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Local(pub usize);
+
+/// A basic block of a function, indexing [`Function::blocks`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct BlockId(pub usize);
+
+/// A function of a program, indexing [`Program::functions`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct FuncId(pub usize);
+
+/// An `obj` type of a program, indexing [`Program::types`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct TypeId(pub usize);
+
+/// An `extern func` of a program, indexing [`Program::externs`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct ExternId(pub usize);
+
+/// A lowered program.
+#[derive(Debug, Clone)]
+pub struct Program<'src> {
+    /// Every function and method.
+    pub functions: Vec<Function<'src>>,
+    /// The `obj` types.
+    pub types: Vec<TypeDef>,
+    /// The `extern func`s, besides the builtin functions.
+    pub externs: Vec<Extern>,
+    /// The array types whose values are reference counted.
+    pub counted_arrays: HashSet<Ty>,
+    /// The `start` function of the entry module.
+    pub entry: FuncId,
+}
+
+/// The type of a local or a value.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Ty {
+    /// `void`, the type of calls that don't give a value.
+    Void,
+    /// `i32`
+    I32,
+    /// `f32`
+    F32,
+    /// `bool`
+    Bool,
+    /// `str`
+    Str,
+    /// `string`
+    String,
+    /// An array of the inner type. Empty array literals whose items are never known are arrays
+    /// of `void`.
+    Array(Box<Ty>),
+    /// An `obj` type.
+    Obj(TypeId),
+}
+
+impl Ty {
+    /// Checks if values of the type are references to strings, arrays or objects, which are
+    /// shared by copies and have to be dropped.
+    pub fn is_ref(&self) -> bool {
+        matches!(self, Ty::Str | Ty::String | Ty::Array(_) | Ty::Obj(_))
+    }
+
+    /// Checks if values of the type can be used where values of `other` are expected. `str` and
+    /// `string` are the same at runtime, and arrays of `void` are arrays of anything.
+    pub fn fits(&self, other: &Ty) -> bool {
+        match (self, other) {
+            (Ty::Str | Ty::String, Ty::Str | Ty::String) => true,
+            (Ty::Array(item), Ty::Array(other)) => {
+                **item == Ty::Void || **other == Ty::Void || item.fits(other)
+            }
+            _ => self == other,
+        }
+    }
+}
+
+/// An `obj` type.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TypeDef {
+    /// Name of the type, `<module>/<type>`.
+    pub name: String,
+    /// The fields, in order of declaration.
+    pub fields: Vec<FieldDef>,
+    /// Whether values of the type are reference counted, because ownership analysis demoted the
+    /// type or a field holding it, instead of having a single owner.
+    pub counted: bool,
+}
+
+/// A field of an `obj` type.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FieldDef {
+    /// Name of the field.
+    pub name: String,
+    /// Type of the field.
+    pub ty: Ty,
+}
+
+/// An `extern func`, implemented outside of the program.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Extern {
+    /// Name of the function, as declared.
+    pub name: String,
+    /// Types of the parameters.
+    pub params: Vec<Ty>,
+    /// Return type.
+    pub ret: Ty,
+}
+
+/// A function or method.
+#[derive(Debug, Clone)]
+pub struct Function<'src> {
+    /// Name of the function, `<module>/<name>`, or `<module>/<type>.<method>` for methods.
+    pub name: String,
+    /// Number of parameters, counting the receiver of methods. They are the first locals.
+    pub params: usize,
+    /// Return type.
+    pub ret: Ty,
+    /// Every local.
+    pub locals: Vec<LocalDecl>,
+    /// Every basic block, starting with the entry block.
+    pub blocks: Vec<BasicBlock<'src>>,
+    /// Where the function is declared.
+    pub span: Span<'src>,
+}
+
+/// A local of a function.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LocalDecl {
+    /// Type of the local.
+    pub ty: Ty,
+    /// Name of the variable the local holds, or `None` for temporaries.
+    pub name: Option<String>,
+    /// Whether the local borrows the reference it holds instead of owning it: it can't be moved
+    /// out of or dropped, and assigning it only borrows the value. Parameters the function
+    /// doesn't own, and locals holding fields and items, are borrowed.
+    pub borrowed: bool,
+}
+
+/// A basic block: statements run in order, then a terminator.
+#[derive(Debug, Clone)]
+pub struct BasicBlock<'src> {
+    /// The statements.
+    pub stmts: Vec<Statement<'src>>,
+    /// How the block ends.
+    pub terminator: Terminator,
+}
+
+/// A statement, with the area of the source it comes from.
+#[derive(Debug, Clone)]
+pub struct Statement<'src> {
+    /// What the statement does.
+    pub kind: StatementKind,
+    /// The statement or call it was lowered from.
+    pub span: Span<'src>,
+}
+
+/// Every kind of statement.
+#[derive(Debug, Clone, PartialEq)]
+pub enum StatementKind {
+    /// Evaluates the rvalue into the local, without dropping what it held.
+    Assign(Local, Rvalue),
+    /// Calls a function with arguments, giving its result to `dst` if any. Functions consume
+    /// the arguments of the parameters they own and borrow the others, and externs borrow them
+    /// all.
+    Call {
+        /// The local getting the result, `None` for `void` calls or ignored results.
+        dst: Option<Local>,
+        /// The function called.
+        callee: Callee,
+        /// The arguments, starting with the receiver of methods.
+        args: Vec<Operand>,
+    },
+    /// Moves a value into a field of the object in `obj`, dropping what the field held.
+    SetField {
+        /// The local holding the object.
+        obj: Local,
+        /// Index of the field.
+        field: usize,
+        /// The new value of the field.
+        value: Operand,
+    },
+    /// Moves a value into the array or object in `dst`, replacing its contents in place, so
+    /// the caller passing it as a mutable parameter sees the change.
+    Replace {
+        /// The local holding the array or object replaced.
+        dst: Local,
+        /// The array or object replacing its contents.
+        value: Operand,
+    },
+    /// Moves a value to the end of the array in `array`.
+    Push {
+        /// The local holding the array.
+        array: Local,
+        /// The item pushed.
+        value: Operand,
+    },
+    /// Writes a string to the output.
+    Print(Operand),
+    /// Ends the current line of the output.
+    Println,
+    /// Drops the value in the local, if any, leaving it empty.
+    Drop(Local),
+}
+
+/// What a [`Call`](StatementKind::Call) calls.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Callee {
+    /// A function or method of the program.
+    Func(FuncId),
+    /// An `extern func`.
+    Extern(ExternId),
+}
+
+/// A value computed by an [`Assign`](StatementKind::Assign).
+#[derive(Debug, Clone, PartialEq)]
+pub enum Rvalue {
+    /// The operand itself.
+    Use(Operand),
+    /// A binary operator, besides `and` and `or`, which are lowered to branches. `add` also
+    /// concatenates strings, and `eq` and `ne` compare them.
+    Binary(BinOp, Operand, Operand),
+    /// A unary operator.
+    Unary(UnOp, Operand),
+    /// Converts a number, boolean or string to a new `string`.
+    ToString(Operand),
+    /// Gets the length of a string or array.
+    Len(Operand),
+    /// A new array of the items, of the given item type.
+    Array(Ty, Vec<Operand>),
+    /// A new object of the type, with every field in order.
+    New(TypeId, Vec<Operand>),
+    /// Gets a field of an object, borrowing it.
+    Field(Operand, usize),
+    /// Gets the item at an index of an array, borrowing it.
+    Index(Operand, Operand),
+    /// Gives a new owner to a string, by copying it, or to an array or object that is
+    /// [reference counted](Program::is_counted).
+    Share(Operand),
+    /// Takes the item at an index out of an array, leaving its slot empty. Nothing reads the item
+    /// from the array again, so it can be shared instead if the array is reference counted.
+    Take(Operand, Operand),
+}
+
+/// A value used by a statement or terminator.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Operand {
+    /// Reads the value of the local without taking it. References can only be read where they
+    /// are borrowed.
+    Copy(Local),
+    /// Takes the value of the owning local, leaving it empty.
+    Move(Local),
+    /// A constant.
+    Const(Const),
+}
+
+/// A constant value.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Const {
+    /// An `i32`.
+    Int(i32),
+    /// An `f32`.
+    Float(f32),
+    /// A `bool`.
+    Bool(bool),
+    /// A string literal.
+    Str(Rc<str>),
+}
+
+/// How a basic block ends.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Terminator {
+    /// Continues at the block.
+    Goto(BlockId),
+    /// Continues at `then` if the boolean `cond` is true, and at `els` otherwise.
+    Branch {
+        /// The condition.
+        cond: Operand,
+        /// The block run if the condition is true.
+        then: BlockId,
+        /// The block run if the condition is false.
+        els: BlockId,
+    },
+    /// Returns from the function, moving the value out of it if it isn't `void`.
+    Return(Option<Operand>),
+    /// Never reached, like the end of functions returning on every path.
+    Unreachable,
+}
+
+impl Operand {
+    /// Gets the local the operand reads, if any.
+    pub fn local(&self) -> Option<Local> {
+        match self {
+            Operand::Copy(local) | Operand::Move(local) => Some(*local),
+            Operand::Const(_) => None,
+        }
+    }
+}
+
+impl Rvalue {
+    /// Gets every operand of the rvalue.
+    pub fn operands(&self) -> Vec<&Operand> {
+        match self {
+            Rvalue::Use(operand)
+            | Rvalue::Unary(_, operand)
+            | Rvalue::ToString(operand)
+            | Rvalue::Len(operand)
+            | Rvalue::Field(operand, _)
+            | Rvalue::Share(operand) => vec![operand],
+            Rvalue::Binary(_, lhs, rhs) | Rvalue::Index(lhs, rhs) | Rvalue::Take(lhs, rhs) => {
+                vec![lhs, rhs]
+            }
+            Rvalue::Array(_, items) | Rvalue::New(_, items) => items.iter().collect(),
+        }
+    }
+
+    /// Gets every operand of the rvalue, to change them.
+    pub fn operands_mut(&mut self) -> Vec<&mut Operand> {
+        match self {
+            Rvalue::Use(operand)
+            | Rvalue::Unary(_, operand)
+            | Rvalue::ToString(operand)
+            | Rvalue::Len(operand)
+            | Rvalue::Field(operand, _)
+            | Rvalue::Share(operand) => vec![operand],
+            Rvalue::Binary(_, lhs, rhs) | Rvalue::Index(lhs, rhs) | Rvalue::Take(lhs, rhs) => {
+                vec![lhs, rhs]
+            }
+            Rvalue::Array(_, items) | Rvalue::New(_, items) => items.iter_mut().collect(),
+        }
+    }
+
+    /// Checks if the rvalue consumes its operands, instead of borrowing them, when it is
+    /// assigned to an owning local.
+    pub fn consumes(&self) -> bool {
+        matches!(self, Rvalue::Use(_) | Rvalue::Array(..) | Rvalue::New(..))
+    }
+
+    /// Checks if the rvalue borrows a reference from its operands, instead of giving a value of
+    /// its own.
+    pub fn borrows(&self) -> bool {
+        matches!(self, Rvalue::Use(_) | Rvalue::Field(..) | Rvalue::Index(..))
+    }
+}
+
+impl StatementKind {
+    /// Gets every operand of the statement.
+    pub fn operands(&self) -> Vec<&Operand> {
+        match self {
+            StatementKind::Assign(_, rvalue) => rvalue.operands(),
+            StatementKind::Call { args, .. } => args.iter().collect(),
+            StatementKind::SetField { value, .. }
+            | StatementKind::Replace { value, .. }
+            | StatementKind::Push { value, .. }
+            | StatementKind::Print(value) => vec![value],
+            StatementKind::Println | StatementKind::Drop(_) => Vec::new(),
+        }
+    }
+
+    /// Gets every operand of the statement, to change them.
+    pub fn operands_mut(&mut self) -> Vec<&mut Operand> {
+        match self {
+            StatementKind::Assign(_, rvalue) => rvalue.operands_mut(),
+            StatementKind::Call { args, .. } => args.iter_mut().collect(),
+            StatementKind::SetField { value, .. }
+            | StatementKind::Replace { value, .. }
+            | StatementKind::Push { value, .. }
+            | StatementKind::Print(value) => vec![value],
+            StatementKind::Println | StatementKind::Drop(_) => Vec::new(),
+        }
+    }
+
+    /// Gets the local the statement assigns, if any.
+    pub fn defines(&self) -> Option<Local> {
+        match self {
+            StatementKind::Assign(local, _) => Some(*local),
+            StatementKind::Call { dst, .. } => *dst,
+            _ => None,
+        }
+    }
+
+    /// Gets the local holding the array or object the statement changes in place, if any.
+    pub fn changes(&self) -> Option<Local> {
+        match self {
+            StatementKind::SetField { obj: local, .. }
+            | StatementKind::Replace { dst: local, .. }
+            | StatementKind::Push { array: local, .. } => Some(*local),
+            _ => None,
+        }
+    }
+}
+
+impl Terminator {
+    /// Gets the operand of the terminator, if any.
+    pub fn operand(&self) -> Option<&Operand> {
+        match self {
+            Terminator::Branch { cond: operand, .. } | Terminator::Return(Some(operand)) => {
+                Some(operand)
+            }
+            _ => None,
+        }
+    }
+
+    /// Gets the operand of the terminator, if any, to change it.
+    pub fn operand_mut(&mut self) -> Option<&mut Operand> {
+        match self {
+            Terminator::Branch { cond: operand, .. } | Terminator::Return(Some(operand)) => {
+                Some(operand)
+            }
+            _ => None,
+        }
+    }
+
+    /// Gets the blocks the terminator can continue at.
+    pub fn successors(&self) -> Vec<BlockId> {
+        match self {
+            Terminator::Goto(target) => vec![*target],
+            Terminator::Branch { then, els, .. } => vec![*then, *els],
+            Terminator::Return(_) | Terminator::Unreachable => Vec::new(),
+        }
+    }
+
+    /// Gets the blocks the terminator can continue at, to change them.
+    pub fn successors_mut(&mut self) -> Vec<&mut BlockId> {
+        match self {
+            Terminator::Goto(target) => vec![target],
+            Terminator::Branch { then, els, .. } => vec![then, els],
+            Terminator::Return(_) | Terminator::Unreachable => Vec::new(),
+        }
+    }
+}
+
+impl Const {
+    /// Gets the type of the constant.
+    pub fn ty(&self) -> Ty {
+        match self {
+            Const::Int(_) => Ty::I32,
+            Const::Float(_) => Ty::F32,
+            Const::Bool(_) => Ty::Bool,
+            Const::Str(_) => Ty::Str,
+        }
+    }
+}
+
+impl Function<'_> {
+    /// Gets the type of the value of `operand`, read in the function.
+    pub fn operand_ty(&self, operand: &Operand) -> Ty {
+        match operand {
+            Operand::Copy(local) | Operand::Move(local) => self.locals[local.0].ty.clone(),
+            Operand::Const(constant) => constant.ty(),
+        }
+    }
+
+    /// Finds the blocks that can be reached from the entry block.
+    pub fn reachable(&self) -> Vec<bool> {
+        let mut reachable = vec![false; self.blocks.len()];
+        let mut work = vec![BlockId(0)];
+        while let Some(block) = work.pop() {
+            if !std::mem::replace(&mut reachable[block.0], true) {
+                work.extend(self.blocks[block.0].terminator.successors());
+            }
+        }
+        reachable
+    }
+}
+
+impl Display for Local {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "_{}", self.0)
+    }
+}
+
+impl Display for BlockId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "bb{}", self.0)
+    }
+}
+
+impl Display for Const {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Const::Int(int) => write!(f, "{int}"),
+            Const::Float(float) => write!(f, "{float:?}"),
+            Const::Bool(value) => write!(f, "{value}"),
+            Const::Str(string) => write!(f, "{string:?}"),
+        }
+    }
+}
+
+impl Display for Operand {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Operand::Copy(local) => write!(f, "copy {local}"),
+            Operand::Move(local) => write!(f, "move {local}"),
+            Operand::Const(constant) => write!(f, "{constant}"),
+        }
+    }
+}
+
+/// Gets the name of a binary operator in the IR.
+fn op_name(op: BinOp) -> &'static str {
+    match op {
+        BinOp::Add => "add",
+        BinOp::Sub => "sub",
+        BinOp::Mul => "mul",
+        BinOp::Div => "div",
+        BinOp::Eq => "eq",
+        BinOp::Ne => "ne",
+        BinOp::Lt => "lt",
+        BinOp::Le => "le",
+        BinOp::Gt => "gt",
+        BinOp::Ge => "ge",
+        BinOp::And => "and",
+        BinOp::Or => "or",
+    }
+}
+
+/// Joins operands with commas.
+fn list(operands: &[Operand]) -> String {
+    let operands: Vec<_> = operands.iter().map(Operand::to_string).collect();
+    operands.join(", ")
+}
+
+impl Program<'_> {
+    /// Checks if values of `ty` are reference counted, instead of having a single owner.
+    pub fn is_counted(&self, ty: &Ty) -> bool {
+        match ty {
+            Ty::Obj(obj) => self.types[obj.0].counted,
+            Ty::Array(_) => self.counted_arrays.contains(ty),
+            _ => false,
+        }
+    }
+
+    /// Gets the name of a type as it is written in the IR.
+    pub fn ty_name(&self, ty: &Ty) -> String {
+        match ty {
+            Ty::Void => "void".to_owned(),
+            Ty::I32 => "i32".to_owned(),
+            Ty::F32 => "f32".to_owned(),
+            Ty::Bool => "bool".to_owned(),
+            Ty::Str => "str".to_owned(),
+            Ty::String => "string".to_owned(),
+            Ty::Array(item) => format!("{}[]", self.ty_name(item)),
+            Ty::Obj(obj) => self.types[obj.0].name.clone(),
+        }
+    }
+
+    fn callee_name(&self, callee: Callee) -> String {
+        match callee {
+            Callee::Func(func) => self.functions[func.0].name.clone(),
+            Callee::Extern(func) => format!("extern {}", self.externs[func.0].name),
+        }
+    }
+
+    fn rvalue(&self, rvalue: &Rvalue) -> String {
+        match rvalue {
+            Rvalue::Use(operand) => operand.to_string(),
+            Rvalue::Binary(op, lhs, rhs) => format!("{} {lhs}, {rhs}", op_name(*op)),
+            Rvalue::Unary(UnOp::Neg, operand) => format!("neg {operand}"),
+            Rvalue::Unary(UnOp::Not, operand) => format!("not {operand}"),
+            Rvalue::ToString(operand) => format!("to-string {operand}"),
+            Rvalue::Len(operand) => format!("len {operand}"),
+            Rvalue::Array(ty, items) => format!("array {} [{}]", self.ty_name(ty), list(items)),
+            Rvalue::New(ty, fields) => {
+                format!("new {} {{{}}}", self.types[ty.0].name, list(fields))
+            }
+            Rvalue::Field(obj, field) => format!("field {obj}, {field}"),
+            Rvalue::Index(array, index) => format!("index {array}, {index}"),
+            Rvalue::Share(operand) => format!("share {operand}"),
+            Rvalue::Take(array, index) => format!("take {array}, {index}"),
+        }
+    }
+
+    fn statement(&self, stmt: &StatementKind) -> String {
+        match stmt {
+            StatementKind::Assign(local, rvalue) => format!("{local} = {}", self.rvalue(rvalue)),
+            StatementKind::Call { dst, callee, args } => {
+                let call = format!("call {}({})", self.callee_name(*callee), list(args));
+                match dst {
+                    Some(dst) => format!("{dst} = {call}"),
+                    None => call,
+                }
+            }
+            StatementKind::SetField { obj, field, value } => {
+                format!("set-field {obj}, {field}, {value}")
+            }
+            StatementKind::Replace { dst, value } => format!("replace {dst}, {value}"),
+            StatementKind::Push { array, value } => format!("push {array}, {value}"),
+            StatementKind::Print(operand) => format!("print {operand}"),
+            StatementKind::Println => "println".to_owned(),
+            StatementKind::Drop(local) => format!("drop {local}"),
+        }
+    }
+
+    fn local(&self, func: &Function, local: usize) -> String {
+        let decl = &func.locals[local];
+        let ty = self.ty_name(&decl.ty);
+        let ty = if decl.borrowed { format!("&{ty}") } else { ty };
+        match &decl.name {
+            Some(name) => format!("_{local} {name}: {ty}"),
+            None => format!("_{local}: {ty}"),
+        }
+    }
+}
+
+/// Writes a readable listing of `program`: its types, externs, and the locals and blocks of
+/// every function. The listing only depends on the program, so it can be compared between
+/// versions of the compiler.
+pub fn pretty(program: &Program, out: &mut dyn Write) -> io::Result<()> {
+    for ty in &program.types {
+        let fields: Vec<_> = ty
+            .fields
+            .iter()
+            .map(|x| format!("{}: {}", x.name, program.ty_name(&x.ty)))
+            .collect();
+        let rc = if ty.counted { "rc " } else { "" };
+        writeln!(out, "{rc}obj {} {{{}}}", ty.name, fields.join(", "))?;
+    }
+    let mut arrays: Vec<_> = program
+        .counted_arrays
+        .iter()
+        .map(|x| program.ty_name(x))
+        .collect();
+    arrays.sort();
+    for array in arrays {
+        writeln!(out, "rc {array}")?;
+    }
+    for func in &program.externs {
+        let params: Vec<_> = func.params.iter().map(|x| program.ty_name(x)).collect();
+        writeln!(
+            out,
+            "extern {}({}) -> {}",
+            func.name,
+            params.join(", "),
+            program.ty_name(&func.ret)
+        )?;
+    }
+    for (index, func) in program.functions.iter().enumerate() {
+        let header = !program.types.is_empty()
+            || !program.externs.is_empty()
+            || !program.counted_arrays.is_empty();
+        if index > 0 || header {
+            writeln!(out)?;
+        }
+        let params: Vec<_> = (0..func.params).map(|x| program.local(func, x)).collect();
+        let entry = if index == program.entry.0 {
+            " entry"
+        } else {
+            ""
+        };
+        writeln!(
+            out,
+            "func {}({}) -> {}{entry} {{",
+            func.name,
+            params.join(", "),
+            program.ty_name(&func.ret)
+        )?;
+        for local in func.params..func.locals.len() {
+            writeln!(out, "    let {}", program.local(func, local))?;
+        }
+        for (index, block) in func.blocks.iter().enumerate() {
+            writeln!(out, "  bb{index}:")?;
+            for stmt in &block.stmts {
+                writeln!(out, "    {}", program.statement(&stmt.kind))?;
+            }
+            let terminator = match &block.terminator {
+                Terminator::Goto(target) => format!("goto {target}"),
+                Terminator::Branch { cond, then, els } => format!("branch {cond}, {then}, {els}"),
+                Terminator::Return(Some(value)) => format!("return {value}"),
+                Terminator::Return(None) => "return".to_owned(),
+                Terminator::Unreachable => "unreachable".to_owned(),
+            };
+            writeln!(out, "    {terminator}")?;
+        }
+        writeln!(out, "}}")?;
+    }
+    Ok(())
+}
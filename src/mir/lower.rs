@@ -0,0 +1,1213 @@
+#![deny(missing_docs)]
+//! Module for lowering the checked AST of a project to [MIR](super).
+//!
+//! Every variable gets its own local, and expressions are evaluated into temporaries. Reading a
+//! variable that isn't moved uses its local directly, and constants are used as they are.
+//! Temporaries that are only borrowed are dropped at the end of their statement, and variables
+//! where ownership analysis ends their lifetime or at the end of their block. Fields, array items
+//! and `for` loop variables are borrowed, and parameters are owned or borrowed as
+//! [borrow inference](crate::borrow) found, except mutable strings, which the function owns so
+//! assigning them only changes its own copy. Borrowed values given a new owner are shared, and
+//! owned items passed by `bulk` are taken out of the array, unless it is reference counted.
+//! Loops and the short-circuiting `and` and `or` become branches between blocks. Code after a
+//! `return`, `break` or `continue` goes into blocks nothing branches to.
+
+use std::{
+    collections::{HashMap, HashSet},
+    rc::Rc,
+};
+
+use codespan_reporting::diagnostic::Label;
+
+use super::{
+    BasicBlock, BlockId, Callee, Const, Extern, FieldDef, FuncId, Function, Local, LocalDecl,
+    Operand, Program, Rvalue, Statement, StatementKind, Terminator, Ty, TypeDef, TypeId,
+};
+use crate::{
+    ast::{BinOp, Block, Declaration, Expr, ExprKind, FieldInit, Func, NodeId, StmtKind},
+    borrow::PassMode,
+    builtins::{Builtin, BuiltinMethod},
+    diag::Diag,
+    emit::HirInfo,
+    modules::{ItemId, ItemKind, ModuleId},
+    project::Module,
+    resolve::{MethodRef, Res},
+    span::Span,
+    ty::{Signature, TyId, TyKind, check::Method},
+};
+
+/// Lowers every function of `modules`, starting the program at the `start` function of the
+/// module `entry`. Reports an error and gives `None` if there is no such function.
+pub fn lower<'src>(
+    modules: &[Module<'src>],
+    info: HirInfo<'_, 'src>,
+    entry: ModuleId,
+) -> Option<Program<'src>> {
+    let start = start_function(modules, info, entry)?;
+    let mut lowerer = Lowerer {
+        modules,
+        info,
+        funcs: HashMap::new(),
+        types: HashMap::new(),
+        externs: HashMap::new(),
+        owned: Vec::new(),
+        counted_objs: HashSet::new(),
+        counted_arrays: HashSet::new(),
+    };
+    // Every item is numbered first, so code can refer to items lowered after it
+    let mut types = Vec::new();
+    let mut externs = Vec::new();
+    let mut bodies = Vec::new();
+    for (index, item) in info.graph.items.iter().enumerate() {
+        let id = ItemId(index);
+        let module = &modules[item.module.0];
+        let name = format!("{}/{}", module.name, item.name.name);
+        match &module.decls[item.decl] {
+            Declaration::Obj(_) => {
+                lowerer.types.insert(id, TypeId(types.len()));
+                types.push((id, name));
+            }
+            Declaration::Func(func) => {
+                lowerer.funcs.insert(Key::Item(id), FuncId(bodies.len()));
+                let sig = &info.typeck.items.funcs[&id];
+                bodies.push((name, func, None, sig));
+            }
+            Declaration::Extern(_) if Builtin::from_name(item.name.name).is_none() => {
+                lowerer.externs.insert(id, super::ExternId(externs.len()));
+                externs.push(id);
+            }
+            _ => {}
+        }
+    }
+    for (module_index, module) in modules.iter().enumerate() {
+        for (decl, declaration) in module.decls.iter().enumerate() {
+            let Declaration::Impl(imp) = declaration else {
+                continue;
+            };
+            for (method, func) in imp.methods.iter().enumerate() {
+                let method_ref = MethodRef {
+                    module: ModuleId(module_index),
+                    decl,
+                    method,
+                };
+                lowerer
+                    .funcs
+                    .insert(Key::Method(method_ref), FuncId(bodies.len()));
+                let name = format!("{}/{}.{}", module.name, imp.target.name, func.sig.name.name);
+                let sig = &info.typeck.items.methods[&method_ref];
+                let self_ty = info.typeck.bindings.get(&func.id).copied();
+                bodies.push((name, func, self_ty, sig));
+            }
+        }
+    }
+
+    for obj in info.ownership.rc_types.keys() {
+        lowerer.counted_objs.insert(lowerer.types[obj]);
+    }
+    for (obj, index) in info.ownership.rc_fields.keys() {
+        let ty = lowerer.ty(info.typeck.items.objs[obj][*index].ty);
+        lowerer.count(&ty);
+    }
+    lowerer.owned = bodies
+        .iter()
+        .map(|(_, func, self_ty, sig)| {
+            let receiver = self_ty.map(|ty| (func.id, ty));
+            let params = func
+                .sig
+                .params
+                .iter()
+                .map(|x| x.id)
+                .zip(sig.params.iter().copied());
+            receiver
+                .into_iter()
+                .chain(params)
+                .map(|(id, ty)| lowerer.owns_param(id, ty))
+                .collect()
+        })
+        .collect();
+
+    let types = types
+        .into_iter()
+        .map(|(obj, name)| TypeDef {
+            name,
+            fields: info.typeck.items.objs[&obj]
+                .iter()
+                .map(|field| FieldDef {
+                    name: field.name.name.to_owned(),
+                    ty: lowerer.ty(field.ty),
+                })
+                .collect(),
+            counted: lowerer.counted_objs.contains(&lowerer.types[&obj]),
+        })
+        .collect();
+    let externs = externs
+        .into_iter()
+        .map(|item| {
+            let sig = &info.typeck.items.funcs[&item];
+            Extern {
+                name: info.graph.item(item).name.name.to_owned(),
+                params: sig.params.iter().map(|ty| lowerer.ty(*ty)).collect(),
+                ret: lowerer.ty(sig.ret),
+            }
+        })
+        .collect();
+    let functions = bodies
+        .into_iter()
+        .enumerate()
+        .map(|(index, (name, func, self_ty, sig))| {
+            lowerer.function(FuncId(index), name, func, self_ty, sig)
+        })
+        .collect();
+    Some(Program {
+        functions,
+        types,
+        externs,
+        counted_arrays: lowerer.counted_arrays,
+        entry: lowerer.funcs[&Key::Item(start)],
+    })
+}
+
+/// Finds the `start` function of the module `entry`, which programs start by calling. Reports an
+/// error and gives `None` if there is none, or if it takes parameters.
+fn start_function(modules: &[Module], info: HirInfo, entry: ModuleId) -> Option<ItemId> {
+    let module = &modules[entry.0];
+    let start = info
+        .graph
+        .lookup(entry, "start")
+        .filter(|x| info.graph.item(*x).kind == ItemKind::Func);
+    let Some(start) = start else {
+        Diag::error(module.src)
+            .with_message(format!("no `start` function in module `{}`", module.name))
+            .with_note("programs start by calling `func void start()` in their entry module")
+            .finish()
+            .emit();
+        return None;
+    };
+    let start_item = info.graph.item(start);
+    if let Declaration::Func(func) = &module.decls[start_item.decl]
+        && !func.sig.params.is_empty()
+    {
+        Diag::error(module.src)
+            .with_message("`start` can't take parameters")
+            .with_label(Label::primary((), func.sig.name.span))
+            .finish()
+            .emit();
+        return None;
+    }
+    Some(start)
+}
+
+/// A function or method of the AST.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Key {
+    Item(ItemId),
+    Method(MethodRef),
+}
+
+/// What a call does.
+#[derive(Debug, Clone, Copy)]
+enum Target {
+    Func(FuncId),
+    Builtin(Builtin),
+    Method(BuiltinMethod),
+    Extern(super::ExternId),
+}
+
+struct Lowerer<'a, 'src> {
+    modules: &'a [Module<'src>],
+    info: HirInfo<'a, 'src>,
+    funcs: HashMap<Key, FuncId>,
+    types: HashMap<ItemId, TypeId>,
+    externs: HashMap<ItemId, super::ExternId>,
+    /// Whether every function owns each of its parameters, instead of borrowing it.
+    owned: Vec<Vec<bool>>,
+    counted_objs: HashSet<TypeId>,
+    counted_arrays: HashSet<Ty>,
+}
+
+/// A loop being lowered.
+struct Loop {
+    /// How many scopes were open outside of the loop body.
+    scopes: usize,
+    /// How many temporaries were alive outside of the loop body.
+    temps: usize,
+    /// The block `break` continues at.
+    exit: BlockId,
+    /// The block `continue` continues at.
+    next: BlockId,
+}
+
+/// The state of the function being lowered.
+struct FuncState<'l, 'a, 'src> {
+    lowerer: &'l Lowerer<'a, 'src>,
+    locals: Vec<LocalDecl>,
+    /// Every block, and how it ends once it does.
+    blocks: Vec<(Vec<Statement<'src>>, Option<Terminator>)>,
+    /// The block statements are added to.
+    current: BlockId,
+    /// Where the statements being added come from: the expression being lowered, or the
+    /// statement outside of expressions.
+    span: Span<'src>,
+    /// The local of every variable.
+    vars: HashMap<NodeId, Local>,
+    /// Parameters holding references, dropped when the function returns.
+    params: Vec<Local>,
+    /// Variables holding references declared in every open block, innermost last.
+    scopes: Vec<Vec<Local>>,
+    loops: Vec<Loop>,
+    /// Borrowed temporaries holding references of the statements being lowered, dropped at the
+    /// end of their statement.
+    temps: Vec<Local>,
+}
+
+impl<'a, 'src> Lowerer<'a, 'src> {
+    fn ty(&self, ty: TyId) -> Ty {
+        let tys = &self.info.typeck.tys;
+        match tys.kind(tys.shallow(ty)) {
+            TyKind::I32 => Ty::I32,
+            TyKind::F32 => Ty::F32,
+            TyKind::Bool => Ty::Bool,
+            TyKind::Str => Ty::Str,
+            TyKind::String => Ty::String,
+            TyKind::Array(item) => Ty::Array(Box::new(self.ty(*item))),
+            TyKind::Obj(obj) => Ty::Obj(self.types[obj]),
+            _ => Ty::Void,
+        }
+    }
+
+    /// Makes values of the object or array type `ty` reference counted. Owned items can be taken
+    /// from a shared array by `bulk`, so they are counted too.
+    fn count(&mut self, ty: &Ty) {
+        match ty {
+            Ty::Obj(obj) => {
+                self.counted_objs.insert(*obj);
+            }
+            Ty::Array(item) if self.counted_arrays.insert(ty.clone()) => self.count(item),
+            _ => {}
+        }
+    }
+
+    fn is_counted(&self, ty: &Ty) -> bool {
+        match ty {
+            Ty::Obj(obj) => self.counted_objs.contains(obj),
+            Ty::Array(_) => self.counted_arrays.contains(ty),
+            _ => false,
+        }
+    }
+
+    /// Checks if a function owns its parameter or receiver `id` of type `ty`, instead of
+    /// borrowing it from the caller.
+    fn owns_param(&self, id: NodeId, ty: TyId) -> bool {
+        let ty = self.ty(ty);
+        match self.info.borrows.bindings.get(&id) {
+            _ if !ty.is_ref() => true,
+            Some(PassMode::Owned) => true,
+            Some(PassMode::Mutable) => matches!(ty, Ty::Str | Ty::String),
+            _ => false,
+        }
+    }
+
+    fn obj_decl(&self, obj: ItemId) -> &'a crate::ast::Obj<'src> {
+        let modules = self.modules;
+        let item = self.info.graph.item(obj);
+        match &modules[item.module.0].decls[item.decl] {
+            Declaration::Obj(decl) => decl,
+            _ => unreachable!("object items are object declarations"),
+        }
+    }
+
+    fn function(
+        &self,
+        id: FuncId,
+        name: String,
+        func: &'a Func<'src>,
+        self_ty: Option<TyId>,
+        sig: &Signature,
+    ) -> Function<'src> {
+        let mut state = FuncState {
+            lowerer: self,
+            locals: Vec::new(),
+            blocks: vec![(Vec::new(), None)],
+            current: BlockId(0),
+            span: func.sig.name.span,
+            vars: HashMap::new(),
+            params: Vec::new(),
+            scopes: Vec::new(),
+            loops: Vec::new(),
+            temps: Vec::new(),
+        };
+        let receiver = self_ty.map(|ty| (func.id, "self", ty));
+        let params = func
+            .sig
+            .params
+            .iter()
+            .zip(&sig.params)
+            .map(|(param, ty)| (param.id, param.name.name, *ty));
+        let params = receiver.into_iter().chain(params);
+        for ((id, param, ty), owned) in params.zip(&self.owned[id.0]) {
+            let local = match owned {
+                true => state.local(self.ty(ty), Some(param)),
+                false => state.borrowed(self.ty(ty), Some(param)),
+            };
+            if *owned && state.is_ref(local) {
+                state.params.push(local);
+            }
+            state.vars.insert(id, local);
+        }
+        let param_count = state.locals.len();
+        state.block(&func.body);
+        let ret = self.ty(sig.ret);
+        if ret == Ty::Void && !func.body.diverges() {
+            for param in std::mem::take(&mut state.params) {
+                state.push(StatementKind::Drop(param));
+            }
+            state.terminate(Terminator::Return(None));
+        }
+        Function {
+            name,
+            params: param_count,
+            ret,
+            locals: state.locals,
+            blocks: state
+                .blocks
+                .into_iter()
+                .map(|(stmts, terminator)| BasicBlock {
+                    stmts,
+                    // Functions returning values return before their end
+                    terminator: terminator.unwrap_or(Terminator::Unreachable),
+                })
+                .collect(),
+            span: func.sig.name.span,
+        }
+    }
+}
+
+impl<'a, 'src> FuncState<'_, 'a, 'src> {
+    /// Declares a new local, for the variable `name` or a temporary.
+    fn local(&mut self, ty: Ty, name: Option<&str>) -> Local {
+        self.locals.push(LocalDecl {
+            ty,
+            name: name.map(str::to_owned),
+            borrowed: false,
+        });
+        Local(self.locals.len() - 1)
+    }
+
+    /// Declares a new local borrowing the references it holds.
+    fn borrowed(&mut self, ty: Ty, name: Option<&str>) -> Local {
+        let local = self.local(ty, name);
+        self.locals[local.0].borrowed = self.is_ref(local);
+        local
+    }
+
+    fn is_ref(&self, local: Local) -> bool {
+        self.locals[local.0].ty.is_ref()
+    }
+
+    fn new_block(&mut self) -> BlockId {
+        self.blocks.push((Vec::new(), None));
+        BlockId(self.blocks.len() - 1)
+    }
+
+    /// Makes sure the current block hasn't ended, continuing in a new block nothing branches to
+    /// if it has.
+    fn open(&mut self) {
+        if self.blocks[self.current.0].1.is_some() {
+            self.current = self.new_block();
+        }
+    }
+
+    fn push(&mut self, kind: StatementKind) {
+        self.open();
+        let span = self.span;
+        self.blocks[self.current.0].0.push(Statement { kind, span });
+    }
+
+    fn assign(&mut self, local: Local, rvalue: Rvalue) {
+        self.push(StatementKind::Assign(local, rvalue));
+    }
+
+    /// Evaluates `rvalue`, lowered from `expr`, into `local`.
+    fn assign_expr(&mut self, local: Local, rvalue: Rvalue, expr: &Expr<'src>) {
+        let outer = std::mem::replace(&mut self.span, expr.span);
+        self.assign(local, rvalue);
+        self.span = outer;
+    }
+
+    fn terminate(&mut self, terminator: Terminator) {
+        self.open();
+        self.blocks[self.current.0].1 = Some(terminator);
+    }
+
+    /// Continues at `block`, unless the current block has already ended.
+    fn goto(&mut self, block: BlockId) {
+        if self.blocks[self.current.0].1.is_none() {
+            self.terminate(Terminator::Goto(block));
+        }
+    }
+
+    /// Continues adding statements to `block`, which the current block falls through to.
+    fn start(&mut self, block: BlockId) {
+        self.goto(block);
+        self.current = block;
+    }
+
+    fn ty(&self, expr: &Expr) -> Ty {
+        self.lowerer.ty(self.lowerer.info.typeck.expr_ty(expr.id))
+    }
+
+    /// Drops the temporaries after the first `mark`.
+    fn drop_temps(&mut self, mark: usize) {
+        for temp in self.temps.split_off(mark) {
+            self.push(StatementKind::Drop(temp));
+        }
+    }
+
+    fn block(&mut self, block: &'a Block<'src>) {
+        let info = self.lowerer.info;
+        self.scopes.push(Vec::new());
+        let mut dropped = Vec::new();
+        for stmt in &block.stmts {
+            self.span = stmt.span;
+            let temps = self.temps.len();
+            match &stmt.kind {
+                StmtKind::Local { name, init, .. } => {
+                    let ty = self.lowerer.ty(info.typeck.bindings[&stmt.id]);
+                    let rvalue = self.owned_rvalue(init);
+                    let local = self.local(ty, Some(name.name));
+                    self.assign_expr(local, rvalue, init);
+                    if self.is_ref(local) {
+                        self.scopes.last_mut().unwrap().push(local);
+                    }
+                    self.vars.insert(stmt.id, local);
+                }
+                kind => self.stmt(stmt.id, kind),
+            }
+            self.drop_temps(temps);
+            let drops = info.ownership.drops.get(&stmt.id);
+            for binding in drops.into_iter().flatten() {
+                if let Some(&local) = self.vars.get(binding).filter(|x| self.is_ref(**x)) {
+                    self.push(StatementKind::Drop(local));
+                    dropped.push(local);
+                }
+            }
+        }
+        let locals = self.scopes.pop().unwrap();
+        if !block.diverges() {
+            // Variables dropped by a statement of the block itself are always gone by now
+            for local in locals.into_iter().filter(|x| !dropped.contains(x)) {
+                self.push(StatementKind::Drop(local));
+            }
+        }
+    }
+
+    fn stmt(&mut self, id: NodeId, kind: &'a StmtKind<'src>) {
+        match kind {
+            StmtKind::Local { .. } => unreachable!("variables are declared by `block`"),
+            StmtKind::Assign { target, value } => self.assign_stmt(target, value),
+            StmtKind::Increment(target) => {
+                let ty = self.ty(target);
+                let one = match ty {
+                    Ty::F32 => Const::Float(1.0),
+                    _ => Const::Int(1),
+                };
+                match &target.kind {
+                    ExprKind::Field { base, .. } => {
+                        let obj = self.operand(base);
+                        let field = self.field(target);
+                        let value = self.local(ty, None);
+                        self.assign(value, Rvalue::Field(obj.clone(), field));
+                        let sum =
+                            Rvalue::Binary(BinOp::Add, Operand::Copy(value), Operand::Const(one));
+                        self.assign(value, sum);
+                        self.push(StatementKind::SetField {
+                            obj: obj.local().expect("objects are in locals"),
+                            field,
+                            value: Operand::Move(value),
+                        });
+                    }
+                    _ => {
+                        let local = self.var(target);
+                        let sum =
+                            Rvalue::Binary(BinOp::Add, Operand::Copy(local), Operand::Const(one));
+                        self.assign(local, sum);
+                    }
+                }
+            }
+            StmtKind::Expr(expr) => {
+                let rvalue = match &expr.kind {
+                    ExprKind::Call { .. } => self.call_expr(expr),
+                    _ => {
+                        self.operand(expr);
+                        return;
+                    }
+                };
+                // The value is unused, so it is dropped with the temporaries
+                let local = match rvalue {
+                    Some(Rvalue::Use(Operand::Move(local))) => local,
+                    Some(rvalue) => {
+                        let local = self.local(self.ty(expr), None);
+                        self.assign(local, rvalue);
+                        local
+                    }
+                    None => return,
+                };
+                if self.is_ref(local) {
+                    self.temps.push(local);
+                }
+            }
+            StmtKind::Bulk(call) => self.bulk(call),
+            StmtKind::Return(value) => {
+                let value = value.as_ref().map(|value| {
+                    let ty = self.ty(value);
+                    let operand = self.owned(value);
+                    let dropped = |local: &Local| {
+                        self.temps.contains(local)
+                            || self.params.contains(local)
+                            || self.scopes.iter().flatten().any(|x| x == local)
+                    };
+                    match operand.local() {
+                        // The value outlives the variables dropped below
+                        Some(local) if ty.is_ref() && dropped(&local) => {
+                            let local = self.local(ty, None);
+                            self.assign(local, Rvalue::Use(operand));
+                            Operand::Move(local)
+                        }
+                        _ => operand,
+                    }
+                });
+                let locals: Vec<_> = self
+                    .temps
+                    .iter()
+                    .chain(self.scopes.iter().rev().flatten())
+                    .chain(&self.params)
+                    .copied()
+                    .collect();
+                for local in locals {
+                    self.push(StatementKind::Drop(local));
+                }
+                self.terminate(Terminator::Return(value));
+            }
+            StmtKind::If { cond, then, els } => {
+                let cond = self.cond(cond);
+                let then_block = self.new_block();
+                let end = self.new_block();
+                let else_block = match els {
+                    Some(_) => self.new_block(),
+                    None => end,
+                };
+                self.terminate(Terminator::Branch {
+                    cond,
+                    then: then_block,
+                    els: else_block,
+                });
+                self.start(then_block);
+                self.block(then);
+                if let Some(els) = els {
+                    self.goto(end);
+                    self.start(else_block);
+                    self.block(els);
+                }
+                self.start(end);
+            }
+            StmtKind::While { cond, body } => {
+                let head = self.new_block();
+                let body_block = self.new_block();
+                let end = self.new_block();
+                self.start(head);
+                if !matches!(cond.kind, ExprKind::Bool(true)) {
+                    let cond = self.cond(cond);
+                    self.terminate(Terminator::Branch {
+                        cond,
+                        then: body_block,
+                        els: end,
+                    });
+                }
+                self.start(body_block);
+                self.open_loop(end, head);
+                self.block(body);
+                self.loops.pop();
+                self.goto(head);
+                self.start(end);
+            }
+            StmtKind::For { name, iter, body } => {
+                let array = self.operand(iter);
+                let Ty::Array(item_ty) = self.ty(iter) else {
+                    unreachable!("`for` loops go through arrays")
+                };
+                // The variable borrows the items, which stay in the array
+                let item = self.borrowed(*item_ty, Some(name.name));
+                self.vars.insert(id, item);
+                self.items(&array, |state, index, exit, next| {
+                    state.assign(item, Rvalue::Index(array.clone(), Operand::Copy(index)));
+                    state.open_loop(exit, next);
+                    state.block(body);
+                    state.loops.pop();
+                });
+            }
+            StmtKind::Break => {
+                self.exit_scopes();
+                let exit = self.loops.last().unwrap().exit;
+                self.terminate(Terminator::Goto(exit));
+            }
+            StmtKind::Continue => {
+                self.exit_scopes();
+                let next = self.loops.last().unwrap().next;
+                self.terminate(Terminator::Goto(next));
+            }
+        }
+    }
+
+    /// Lowers a condition, dropping its temporaries right away, as it can be evaluated again by
+    /// a loop.
+    fn cond(&mut self, cond: &'a Expr<'src>) -> Operand {
+        let temps = self.temps.len();
+        let operand = self.operand(cond);
+        self.drop_temps(temps);
+        operand
+    }
+
+    fn open_loop(&mut self, exit: BlockId, next: BlockId) {
+        self.loops.push(Loop {
+            scopes: self.scopes.len(),
+            temps: self.temps.len(),
+            exit,
+            next,
+        });
+    }
+
+    /// Drops the variables of the blocks a `break` or `continue` leaves.
+    fn exit_scopes(&mut self) {
+        let lp = self.loops.last().expect("loop exits are in loops");
+        let locals: Vec<_> = self.temps[lp.temps..]
+            .iter()
+            .chain(self.scopes[lp.scopes..].iter().flatten())
+            .copied()
+            .collect();
+        for local in locals {
+            self.push(StatementKind::Drop(local));
+        }
+    }
+
+    /// Lowers a loop going through every item of `array`, giving `body` the local holding the
+    /// index of the item, and the blocks leaving the loop and going to the next item.
+    fn items(&mut self, array: &Operand, body: impl FnOnce(&mut Self, Local, BlockId, BlockId)) {
+        let index = self.local(Ty::I32, None);
+        let len = self.local(Ty::I32, None);
+        let more = self.local(Ty::Bool, None);
+        self.assign(index, Rvalue::Use(Operand::Const(Const::Int(0))));
+        let head = self.new_block();
+        let body_block = self.new_block();
+        let next = self.new_block();
+        let end = self.new_block();
+        self.start(head);
+        // The length is read every iteration, as the body can push onto the array
+        self.assign(len, Rvalue::Len(array.clone()));
+        let lt = Rvalue::Binary(BinOp::Lt, Operand::Copy(index), Operand::Copy(len));
+        self.assign(more, lt);
+        self.terminate(Terminator::Branch {
+            cond: Operand::Copy(more),
+            then: body_block,
+            els: end,
+        });
+        self.start(body_block);
+        body(self, index, end, next);
+        self.start(next);
+        let add = Rvalue::Binary(
+            BinOp::Add,
+            Operand::Copy(index),
+            Operand::Const(Const::Int(1)),
+        );
+        self.assign(index, add);
+        self.goto(head);
+        self.start(end);
+    }
+
+    fn assign_stmt(&mut self, target: &'a Expr<'src>, value: &'a Expr<'src>) {
+        let info = self.lowerer.info;
+        match &target.kind {
+            ExprKind::Field { base, .. } => {
+                let obj = self.operand(base);
+                let field = self.field(target);
+                let value = self.owned(value);
+                self.push(StatementKind::SetField {
+                    obj: obj.local().expect("objects are in locals"),
+                    field,
+                    value,
+                });
+            }
+            _ => {
+                let Some(Res::Local(binding)) = info.res.names.get(&target.id) else {
+                    unreachable!("only variables are assigned to")
+                };
+                let local = self.var(target);
+                let ty = self.locals[local.0].ty.clone();
+                // A mutable parameter is the caller's value, so it is replaced in place
+                let mutable = info.borrows.bindings.get(binding) == Some(&PassMode::Mutable);
+                if mutable && matches!(ty, Ty::Array(_) | Ty::Obj(_)) {
+                    let value = self.owned(value);
+                    self.push(StatementKind::Replace { dst: local, value });
+                    return;
+                }
+                let rvalue = self.owned_rvalue(value);
+                if !ty.is_ref() {
+                    self.assign_expr(local, rvalue, value);
+                    return;
+                }
+                // The old value is dropped once the new one no longer needs it
+                let reads = rvalue.operands().iter().any(|x| x.local() == Some(local));
+                let rvalue = match reads {
+                    true => {
+                        let temp = self.local(ty, None);
+                        self.assign_expr(temp, rvalue, value);
+                        Rvalue::Use(Operand::Move(temp))
+                    }
+                    false => rvalue,
+                };
+                self.push(StatementKind::Drop(local));
+                self.assign(local, rvalue);
+            }
+        }
+    }
+
+    /// Gets the local of the variable named by `expr`.
+    fn var(&self, expr: &Expr) -> Local {
+        match self.lowerer.info.res.names.get(&expr.id) {
+            Some(Res::Local(binding)) => self.vars[binding],
+            _ => unreachable!("only variables are places"),
+        }
+    }
+
+    fn field(&self, expr: &Expr) -> usize {
+        let (_, index) = self
+            .lowerer
+            .info
+            .typeck
+            .field(expr)
+            .expect("fields are checked");
+        index
+    }
+
+    /// Lowers `expr` to an operand that is only borrowed: a variable, a constant, a temporary
+    /// dropped at the end of the statement, or one borrowing a field or item.
+    fn operand(&mut self, expr: &'a Expr<'src>) -> Operand {
+        match self.rvalue(expr) {
+            Rvalue::Use(Operand::Move(local)) => {
+                if self.is_ref(local) {
+                    self.temps.push(local);
+                }
+                Operand::Copy(local)
+            }
+            Rvalue::Use(operand) => operand,
+            rvalue @ (Rvalue::Field(..) | Rvalue::Index(..)) => {
+                let local = self.borrowed(self.ty(expr), None);
+                self.assign_expr(local, rvalue, expr);
+                Operand::Copy(local)
+            }
+            rvalue => {
+                let local = self.local(self.ty(expr), None);
+                self.assign_expr(local, rvalue, expr);
+                if self.is_ref(local) {
+                    self.temps.push(local);
+                }
+                Operand::Copy(local)
+            }
+        }
+    }
+
+    /// Lowers `expr` to an operand that is consumed.
+    fn owned(&mut self, expr: &'a Expr<'src>) -> Operand {
+        match self.owned_rvalue(expr) {
+            Rvalue::Use(operand) => operand,
+            rvalue => {
+                let local = self.local(self.ty(expr), None);
+                self.assign_expr(local, rvalue, expr);
+                Operand::Move(local)
+            }
+        }
+    }
+
+    /// Lowers `expr` to an rvalue giving a value of its own, sharing borrowed references.
+    fn owned_rvalue(&mut self, expr: &'a Expr<'src>) -> Rvalue {
+        match self.rvalue(expr) {
+            Rvalue::Use(operand @ Operand::Copy(local)) if self.is_ref(local) => {
+                Rvalue::Share(operand)
+            }
+            rvalue @ (Rvalue::Field(..) | Rvalue::Index(..)) if self.ty(expr).is_ref() => {
+                let local = self.borrowed(self.ty(expr), None);
+                self.assign_expr(local, rvalue, expr);
+                Rvalue::Share(Operand::Copy(local))
+            }
+            rvalue => rvalue,
+        }
+    }
+
+    /// Gives a new owner to the borrowed `operand`, in a temporary if it is a reference.
+    fn share(&mut self, operand: Operand) -> Operand {
+        match operand {
+            Operand::Copy(local) if self.is_ref(local) => {
+                let temp = self.local(self.locals[local.0].ty.clone(), None);
+                self.assign(temp, Rvalue::Share(operand));
+                Operand::Move(temp)
+            }
+            operand => operand,
+        }
+    }
+
+    /// Checks if the argument at `index` is moved into the call to `target`, instead of
+    /// borrowed.
+    fn owns_arg(&self, target: Target, index: usize) -> bool {
+        match target {
+            Target::Func(func) => self.lowerer.owned[func.0][index],
+            Target::Builtin(Builtin::Drop) => true,
+            Target::Method(BuiltinMethod::Push) => index == 1,
+            _ => false,
+        }
+    }
+
+    /// Lowers the argument `arg` at `index` of a call to `target`, owned or borrowed as the
+    /// target expects.
+    fn arg(&mut self, target: Target, index: usize, arg: &'a Expr<'src>) -> Operand {
+        match self.owns_arg(target, index) {
+            true => self.owned(arg),
+            false => self.operand(arg),
+        }
+    }
+
+    /// Lowers `expr`, which isn't `void`. The statements evaluating its operands point at it.
+    fn rvalue(&mut self, expr: &'a Expr<'src>) -> Rvalue {
+        let outer = std::mem::replace(&mut self.span, expr.span);
+        let rvalue = self.rvalue_kind(expr);
+        self.span = outer;
+        rvalue
+    }
+
+    fn rvalue_kind(&mut self, expr: &'a Expr<'src>) -> Rvalue {
+        let info = self.lowerer.info;
+        match &expr.kind {
+            ExprKind::Name(_) => match info.res.names.get(&expr.id) {
+                Some(Res::Local(binding)) => {
+                    let local = self.vars[binding];
+                    match info.ownership.moves.contains(&expr.id) {
+                        true => Rvalue::Use(Operand::Move(local)),
+                        false => Rvalue::Use(Operand::Copy(local)),
+                    }
+                }
+                Some(Res::Item(item)) if info.graph.item(*item).kind == ItemKind::Obj => {
+                    self.construct(*item, &[])
+                }
+                _ => unreachable!("functions can only be called"),
+            },
+            ExprKind::Str(string) => Rvalue::Use(Operand::Const(Const::Str(Rc::from(*string)))),
+            ExprKind::Int(int) if self.ty(expr) == Ty::F32 => {
+                Rvalue::Use(Operand::Const(Const::Float(*int as f32)))
+            }
+            ExprKind::Int(int) => Rvalue::Use(Operand::Const(Const::Int(*int as i32))),
+            ExprKind::Float(float) => Rvalue::Use(Operand::Const(Const::Float(*float as f32))),
+            ExprKind::Bool(value) => Rvalue::Use(Operand::Const(Const::Bool(*value))),
+            ExprKind::Array(items) => {
+                let Ty::Array(item_ty) = self.ty(expr) else {
+                    unreachable!("array literals are arrays")
+                };
+                let items = items.iter().map(|x| self.owned(x)).collect();
+                Rvalue::Array(*item_ty, items)
+            }
+            ExprKind::Field { base, .. } => {
+                let obj = self.operand(base);
+                Rvalue::Field(obj, self.field(expr))
+            }
+            ExprKind::Call { .. } => self
+                .call_expr(expr)
+                .expect("only calls of `void` functions are statements"),
+            ExprKind::Construct { fields, .. } => {
+                let Some(Res::Item(obj)) = info.res.names.get(&expr.id) else {
+                    unreachable!("constructors name a type")
+                };
+                self.construct(*obj, fields)
+            }
+            ExprKind::Binary { op, lhs, rhs } => self.binary(expr, *op, lhs, rhs),
+            ExprKind::Unary { op, operand } => Rvalue::Unary(*op, self.operand(operand)),
+        }
+    }
+
+    /// Lowers an object of type `obj`, with the fields in `inits` and the default value of the
+    /// others.
+    fn construct(&mut self, obj: ItemId, inits: &'a [FieldInit<'src>]) -> Rvalue {
+        let decl = self.lowerer.obj_decl(obj);
+        let mut values = vec![None; decl.fields.len()];
+        for init in inits {
+            let (index, _) = self
+                .lowerer
+                .info
+                .typeck
+                .items
+                .field(obj, init.name.name)
+                .expect("fields are checked");
+            values[index] = Some(self.owned(&init.value));
+        }
+        for (index, field) in decl.fields.iter().enumerate() {
+            if values[index].is_none() {
+                let default = field.default.as_ref().expect("missing fields are checked");
+                values[index] = Some(self.owned(default));
+            }
+        }
+        let values = values.into_iter().map(Option::unwrap).collect();
+        Rvalue::New(self.lowerer.types[&obj], values)
+    }
+
+    /// Lowers the call `call`, giving its result, or `None` if it doesn't give any.
+    fn call_expr(&mut self, call: &'a Expr<'src>) -> Option<Rvalue> {
+        let ExprKind::Call { callee, args } = &call.kind else {
+            unreachable!("only calls are lowered as calls")
+        };
+        let info = self.lowerer.info;
+        let outer = std::mem::replace(&mut self.span, call.span);
+        let rvalue = match self.target(call, callee) {
+            Some(target) => {
+                let receiver = info.typeck.receiver(call);
+                let args: Vec<_> = receiver.into_iter().chain(args).collect();
+                let args = args
+                    .into_iter()
+                    .enumerate()
+                    .map(|(index, arg)| self.arg(target, index, arg))
+                    .collect();
+                self.call(target, args, self.ty(call))
+            }
+            // Calling a type name constructs it
+            None => match info.res.names.get(&callee.id) {
+                Some(Res::Item(obj)) => Some(self.construct(*obj, &[])),
+                _ => unreachable!("calls without a target call a type name"),
+            },
+        };
+        self.span = outer;
+        rvalue
+    }
+
+    /// Finds what the call `call` calls, or `None` if it calls a type name.
+    fn target(&self, call: &Expr<'src>, callee: &Expr<'src>) -> Option<Target> {
+        let lowerer = self.lowerer;
+        let info = lowerer.info;
+        if let Some(method) = info.typeck.methods.get(&call.id) {
+            return Some(match method {
+                Method::User(method) => Target::Func(lowerer.funcs[&Key::Method(*method)]),
+                Method::Builtin(method) => Target::Method(*method),
+            });
+        }
+        match info.res.names.get(&callee.id) {
+            Some(Res::Builtin(builtin)) => Some(Target::Builtin(*builtin)),
+            Some(Res::Item(item)) => {
+                let item_ref = info.graph.item(*item);
+                match item_ref.kind {
+                    ItemKind::Func => Some(Target::Func(lowerer.funcs[&Key::Item(*item)])),
+                    ItemKind::Extern => match Builtin::from_name(item_ref.name.name) {
+                        Some(builtin) => Some(Target::Builtin(builtin)),
+                        None => Some(Target::Extern(lowerer.externs[item])),
+                    },
+                    ItemKind::Obj => None,
+                }
+            }
+            _ => None,
+        }
+    }
+
+    /// Lowers a call to `target` with `args`, owned or borrowed as the target expects, giving its
+    /// result, or `None` if it doesn't give any.
+    fn call(&mut self, target: Target, mut args: Vec<Operand>, ret: Ty) -> Option<Rvalue> {
+        let callee = match target {
+            Target::Func(func) => Callee::Func(func),
+            Target::Extern(func) => Callee::Extern(func),
+            Target::Builtin(Builtin::Print) => {
+                self.push(StatementKind::Print(args.remove(0)));
+                return None;
+            }
+            Target::Builtin(Builtin::Println) => {
+                self.push(StatementKind::Println);
+                return None;
+            }
+            Target::Builtin(Builtin::Drop) => {
+                // Dropping a copy would only share the value to drop it again
+                if let Operand::Move(local) = args[0] {
+                    self.push(StatementKind::Drop(local));
+                }
+                return None;
+            }
+            Target::Method(BuiltinMethod::ToString) => {
+                return Some(Rvalue::ToString(args.remove(0)));
+            }
+            Target::Method(BuiltinMethod::Len) => return Some(Rvalue::Len(args.remove(0))),
+            Target::Method(BuiltinMethod::Push) => {
+                let value = args.pop().expect("`push` has an item");
+                let array = args[0].local().expect("arrays are in locals");
+                self.push(StatementKind::Push { array, value });
+                return None;
+            }
+        };
+        let dst = (ret != Ty::Void).then(|| self.local(ret, None));
+        self.push(StatementKind::Call { dst, callee, args });
+        dst.map(|dst| Rvalue::Use(Operand::Move(dst)))
+    }
+
+    /// Lowers a `bulk` call, calling once for every item of the last argument.
+    fn bulk(&mut self, call: &'a Expr<'src>) {
+        let ExprKind::Call { callee, args } = &call.kind else {
+            unreachable!("`bulk` is only used on calls")
+        };
+        let info = self.lowerer.info;
+        let target = self.target(call, callee).expect("`bulk` calls are checked");
+        let receiver = info.typeck.receiver(call);
+        let args: Vec<_> = receiver.into_iter().chain(args).collect();
+        let (array, fixed) = args.split_last().expect("`bulk` calls have an array");
+        // The other arguments are evaluated once, and shared by every call. Ownership analysis
+        // only lets strings be moved into the calls, which get a copy each
+        let fixed: Vec<_> = fixed.iter().map(|x| self.operand(x)).collect();
+        let array_ty = self.ty(array);
+        let array = self.operand(array);
+        // Owned items are taken from arrays with a single owner, as the array was moved
+        let take = !self.lowerer.is_counted(&array_ty);
+        let Ty::Array(item_ty) = array_ty else {
+            unreachable!("`bulk` goes through arrays")
+        };
+        let ret = self.ty(call);
+        self.items(&array, |state, index, _, _| {
+            let temps = state.temps.len();
+            let mut args: Vec<_> = fixed
+                .iter()
+                .enumerate()
+                .map(|(arg, operand)| match state.owns_arg(target, arg) {
+                    true => state.share(operand.clone()),
+                    false => operand.clone(),
+                })
+                .collect();
+            let index = Operand::Copy(index);
+            let item = match state.owns_arg(target, args.len()) {
+                true if take && item_ty.is_ref() => {
+                    let item = state.local(*item_ty, None);
+                    state.assign(item, Rvalue::Take(array.clone(), index));
+                    Operand::Move(item)
+                }
+                true => {
+                    let item = state.borrowed(*item_ty, None);
+                    state.assign(item, Rvalue::Index(array.clone(), index));
+                    state.share(Operand::Copy(item))
+                }
+                false => {
+                    let item = state.borrowed(*item_ty, None);
+                    state.assign(item, Rvalue::Index(array.clone(), index));
+                    Operand::Copy(item)
+                }
+            };
+            args.push(item);
+            if let Some(rvalue) = state.call(target, args, ret.clone()) {
+                let result = state.local(ret, None);
+                state.assign(result, rvalue);
+                if state.is_ref(result) {
+                    state.temps.push(result);
+                }
+            }
+            state.drop_temps(temps);
+        });
+    }
+
+    fn binary(
+        &mut self,
+        expr: &'a Expr<'src>,
+        op: BinOp,
+        lhs: &'a Expr<'src>,
+        rhs: &'a Expr<'src>,
+    ) -> Rvalue {
+        if let Some(Method::User(method)) = self.lowerer.info.typeck.methods.get(&expr.id) {
+            let target = Target::Func(self.lowerer.funcs[&Key::Method(*method)]);
+            let args = vec![self.arg(target, 0, lhs), self.arg(target, 1, rhs)];
+            let result = self.call(target, args, self.ty(expr));
+            let result = result.expect("operators give a value");
+            return match (op, result) {
+                (BinOp::Ne, Rvalue::Use(Operand::Move(local))) => {
+                    Rvalue::Unary(crate::ast::UnOp::Not, Operand::Copy(local))
+                }
+                (_, result) => result,
+            };
+        }
+        if let BinOp::And | BinOp::Or = op {
+            // `and` and `or` only evaluate the right hand side if it decides the result
+            let result = self.local(Ty::Bool, None);
+            let lhs = self.operand(lhs);
+            self.assign(result, Rvalue::Use(lhs));
+            let rhs_block = self.new_block();
+            let end = self.new_block();
+            let (then, els) = match op {
+                BinOp::And => (rhs_block, end),
+                _ => (end, rhs_block),
+            };
+            self.terminate(Terminator::Branch {
+                cond: Operand::Copy(result),
+                then,
+                els,
+            });
+            self.start(rhs_block);
+            let rhs = self.operand(rhs);
+            self.assign(result, Rvalue::Use(rhs));
+            self.start(end);
+            return Rvalue::Use(Operand::Copy(result));
+        }
+        let lhs = self.operand(lhs);
+        let rhs = self.operand(rhs);
+        Rvalue::Binary(op, lhs, rhs)
+    }
+}
+
+#[test]
+fn lower_test() {
+    let text = r"identifier app.main
+func i32 find(i32[] xs, i32 x) is
+    i32 i = 0
+    for y in xs is
+        if y == x is
+            return i
+        end
+        i++
+    end
+    return -1
+end
+func void start() is
+    print(find([1, 2], 2).to-string())
+end";
+    let out = crate::interp::with_checked(text, |modules, info| {
+        let program = lower(modules, info, ModuleId(0)).unwrap();
+        let mut out = Vec::new();
+        super::pretty(&program, &mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    });
+    // The array is only borrowed, so the returns don't drop it, and the loop keeps its index in
+    // a hidden local
+    let find = &out[..out.find("\n\n").unwrap()];
+    assert!(find.contains("_0 xs: &i32[]"), "{find}");
+    assert_eq!(
+        find.lines().skip(8).collect::<Vec<_>>(),
+        [
+            "  bb0:",
+            "    _2 = 0",
+            "    _4 = 0",
+            "    goto bb1",
+            "  bb1:",
+            "    _5 = len copy _0",
+            "    _6 = lt copy _4, copy _5",
+            "    branch copy _6, bb2, bb4",
+            "  bb2:",
+            "    _3 = index copy _0, copy _4",
+            "    _7 = eq copy _3, copy _1",
+            "    branch copy _7, bb5, bb6",
+            "  bb3:",
+            "    _4 = add copy _4, 1",
+            "    goto bb1",
+            "  bb4:",
+            "    _8 = neg 1",
+            "    return move _8",
+            "  bb5:",
+            "    return copy _2",
+            "  bb6:",
+            "    _2 = add copy _2, 1",
+            "    goto bb3",
+            "}",
+        ]
+    );
+}
@@ -0,0 +1,326 @@
+#![deny(missing_docs)]
+//! Module for the optimization passes run on the [IR](super), and the pass manager choosing
+//! them.
+//!
+//! Every [`Pass`] rewrites the functions of a [`Program`] into equivalent ones: they print the
+//! same output, fail with the same errors, and keep every value they drop (though maybe for a
+//! shorter time). [`Options::level`] picks the passes run by `esci -O0` to `-O3`, and passes can
+//! be enabled or disabled on their own to find which one changes a program. In debug builds,
+//! the program is [validated](super::validate) after every pass.
+
+use super::{Function, Local, Operand, Program, StatementKind};
+
+pub mod cfg;
+pub mod copy_prop;
+pub mod dce;
+pub mod drops;
+pub mod fold;
+pub mod inline;
+
+/// An optimization pass.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Pass {
+    /// Replaces calls to small functions with their body.
+    Inline,
+    /// Reads the original of copied locals, instead of the copy.
+    CopyProp,
+    /// Computes operators on constants, and branches on constant conditions.
+    Fold,
+    /// Removes unreachable blocks, and merges blocks that always follow each other.
+    SimplifyCfg,
+    /// Removes values that are never read, and the locals holding them.
+    Dce,
+    /// Turns copies right before a drop into moves, and removes drops of empty locals.
+    Drops,
+}
+
+impl Pass {
+    /// Every pass, in the order they run.
+    pub const ALL: [Pass; 6] = [
+        Pass::Inline,
+        Pass::CopyProp,
+        Pass::Fold,
+        Pass::SimplifyCfg,
+        Pass::Dce,
+        Pass::Drops,
+    ];
+
+    /// Gets the name of the pass, as given to `esci --passes`.
+    pub fn name(self) -> &'static str {
+        match self {
+            Pass::Inline => "inline",
+            Pass::CopyProp => "copy-prop",
+            Pass::Fold => "fold",
+            Pass::SimplifyCfg => "simplify-cfg",
+            Pass::Dce => "dce",
+            Pass::Drops => "drops",
+        }
+    }
+
+    /// Gets the pass named `name`.
+    pub fn from_name(name: &str) -> Option<Pass> {
+        Pass::ALL.into_iter().find(|x| x.name() == name)
+    }
+
+    fn run(self, program: &mut Program, options: &Options) {
+        let run: fn(&mut Function) = match self {
+            Pass::Inline => return inline::run(program, options.inline_limit),
+            Pass::CopyProp => copy_prop::run,
+            Pass::Fold => fold::run,
+            Pass::SimplifyCfg => cfg::run,
+            Pass::Dce => dce::run,
+            Pass::Drops => drops::run,
+        };
+        program.functions.iter_mut().for_each(run);
+    }
+}
+
+/// Which passes run, and how much they do.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Options {
+    /// The passes run, in the order of [`Pass::ALL`].
+    pub passes: Vec<Pass>,
+    /// How many statements and blocks a function can have to be inlined.
+    pub inline_limit: usize,
+    /// How many times every pass runs, since passes make room for each other.
+    pub rounds: usize,
+}
+
+impl Options {
+    /// Gets the options of an optimization level, from 0 (no passes) to 3.
+    pub fn level(level: u8) -> Options {
+        let passes = match level {
+            0 => Vec::new(),
+            1 => Pass::ALL[1..].to_vec(),
+            _ => Pass::ALL.to_vec(),
+        };
+        Options {
+            passes,
+            inline_limit: if level >= 3 { 48 } else { 16 },
+            rounds: match level {
+                0 => 0,
+                1 => 1,
+                2 => 2,
+                _ => 4,
+            },
+        }
+    }
+
+    /// Runs `pass`, at least once.
+    pub fn enable(&mut self, pass: Pass) {
+        if !self.passes.contains(&pass) {
+            self.passes.push(pass);
+            self.passes
+                .sort_by_key(|x| Pass::ALL.iter().position(|y| y == x));
+        }
+        self.rounds = self.rounds.max(1);
+    }
+
+    /// Stops running `pass`.
+    pub fn disable(&mut self, pass: Pass) {
+        self.passes.retain(|x| *x != pass);
+    }
+}
+
+/// Runs the passes of `options` on `program`.
+pub fn optimize(program: &mut Program, options: &Options) {
+    for _ in 0..options.rounds {
+        for pass in &options.passes {
+            pass.run(program, options);
+            if cfg!(debug_assertions)
+                && let Err(errors) = super::validate::validate(program)
+            {
+                let errors: Vec<_> = errors.iter().map(|x| x.to_string()).collect();
+                panic!("invalid IR after `{}`:\n{}", pass.name(), errors.join("\n"));
+            }
+        }
+    }
+}
+
+/// Finds which locals may hold a value at the start of every block of `func`, and at its end
+/// before the terminator.
+fn maybe_full(func: &Function) -> (Vec<Vec<bool>>, Vec<Vec<bool>>) {
+    let mut entry = vec![vec![false; func.locals.len()]; func.blocks.len()];
+    let mut exit = entry.clone();
+    let mut visited = vec![false; func.blocks.len()];
+    entry[0][..func.params].fill(true);
+    visited[0] = true;
+    let mut work = vec![0];
+    while let Some(block) = work.pop() {
+        let mut full = entry[block].clone();
+        for stmt in &func.blocks[block].stmts {
+            step_full(&stmt.kind, &mut full);
+        }
+        for succ in func.blocks[block].terminator.successors() {
+            let mut changed = !visited[succ.0];
+            visited[succ.0] = true;
+            for (local, full) in full.iter().enumerate() {
+                if *full && !entry[succ.0][local] {
+                    entry[succ.0][local] = true;
+                    changed = true;
+                }
+            }
+            if changed {
+                work.push(succ.0);
+            }
+        }
+        exit[block] = full;
+    }
+    (entry, exit)
+}
+
+/// Updates which locals may hold a value after `stmt`.
+fn step_full(stmt: &StatementKind, full: &mut [bool]) {
+    for operand in stmt.operands() {
+        if let Operand::Move(local) = operand {
+            full[local.0] = false;
+        }
+    }
+    match stmt {
+        StatementKind::Drop(local) => full[local.0] = false,
+        _ => {
+            if let Some(local) = stmt.defines() {
+                full[local.0] = true;
+            }
+        }
+    }
+}
+
+/// Counts how many statements assign every local of `func`.
+fn definitions(func: &Function) -> Vec<usize> {
+    let mut defs = vec![0; func.locals.len()];
+    for stmt in func.blocks.iter().flat_map(|x| &x.stmts) {
+        if let Some(local) = stmt.kind.defines() {
+            defs[local.0] += 1;
+        }
+    }
+    defs
+}
+
+/// Finds the locals of `func` that are read: used by an operand, or changed in place.
+fn reads(func: &Function) -> Vec<bool> {
+    let mut reads = vec![false; func.locals.len()];
+    for block in &func.blocks {
+        for stmt in &block.stmts {
+            for local in stmt.kind.operands().iter().filter_map(|x| x.local()) {
+                reads[local.0] = true;
+            }
+            if let Some(local) = stmt.kind.changes() {
+                reads[local.0] = true;
+            }
+        }
+        if let Some(local) = block.terminator.operand().and_then(Operand::local) {
+            reads[local.0] = true;
+        }
+    }
+    reads
+}
+
+/// Renames the local `operand` reads, if any.
+fn rename_operand(operand: &mut Operand, rename: &impl Fn(Local) -> Local) {
+    if let Operand::Copy(local) | Operand::Move(local) = operand {
+        *local = rename(*local);
+    }
+}
+
+/// Renames every local `stmt` uses.
+fn rename_stmt(stmt: &mut StatementKind, rename: &impl Fn(Local) -> Local) {
+    for operand in stmt.operands_mut() {
+        rename_operand(operand, rename);
+    }
+    match stmt {
+        StatementKind::Assign(local, _)
+        | StatementKind::Call {
+            dst: Some(local), ..
+        }
+        | StatementKind::SetField { obj: local, .. }
+        | StatementKind::Replace { dst: local, .. }
+        | StatementKind::Push { array: local, .. }
+        | StatementKind::Drop(local) => *local = rename(*local),
+        StatementKind::Call { dst: None, .. }
+        | StatementKind::Print(_)
+        | StatementKind::Println => {}
+    }
+}
+
+/// Renames every local of `func`.
+fn rename_locals(func: &mut Function, rename: impl Fn(Local) -> Local) {
+    for block in &mut func.blocks {
+        for stmt in &mut block.stmts {
+            rename_stmt(&mut stmt.kind, &rename);
+        }
+        if let Some(operand) = block.terminator.operand_mut() {
+            rename_operand(operand, &rename);
+        }
+    }
+}
+
+/// Removes the blocks of `func` that `keep` doesn't mark, renumbering the others.
+fn retain_blocks(func: &mut Function, keep: &[bool]) {
+    let mut map = Vec::with_capacity(keep.len());
+    let mut next = 0;
+    for keep in keep {
+        map.push(super::BlockId(next));
+        next += *keep as usize;
+    }
+    let mut index = 0;
+    func.blocks.retain(|_| {
+        index += 1;
+        keep[index - 1]
+    });
+    for block in &mut func.blocks {
+        for target in block.terminator.successors_mut() {
+            *target = map[target.0];
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) fn run_optimized(text: &str, level: u8) -> String {
+    crate::interp::with_checked(text, |modules, info| {
+        let mut program = super::lower::lower(modules, info, crate::modules::ModuleId(0)).unwrap();
+        optimize(&mut program, &Options::level(level));
+        let program = crate::bytecode::from_mir::compile(&program, modules);
+        let mut out = Vec::new();
+        crate::bytecode::vm::run(&program, &mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    })
+}
+
+#[test]
+fn levels_test() {
+    let text = r"identifier app.main
+obj log is
+    lines: string[] = [],
+end
+func i32 square(i32 x) is
+    return x * x
+end
+func void add(log l, string line) is
+    l.lines.push(line)
+end
+func void start() is
+    log l = log
+    i32 total = 0
+    i32 i = 0
+    while i < 4 is
+        total = total + square(i)
+        i++
+    end
+    add(l, 'sum ' + total.to-string())
+    if 2 * 3 == 6 is
+        add(l, 'folded')
+    end
+    for line in l.lines is
+        print(line + ';')
+    end
+end";
+    for level in 0..=3 {
+        assert_eq!(run_optimized(text, level), "sum 14;folded;");
+    }
+    let mut options = Options::level(0);
+    options.enable(Pass::Dce);
+    options.enable(Pass::Fold);
+    assert_eq!(options.passes, [Pass::Fold, Pass::Dce]);
+    assert_eq!(options.rounds, 1);
+}
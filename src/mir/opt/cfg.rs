@@ -0,0 +1,117 @@
+#![deny(missing_docs)]
+//! Module for simplifying the control-flow graph.
+//!
+//! Branches going to the same block either way become gotos, jumps to blocks that only go to
+//! another block skip them, and a block that is the only way into the block it goes to absorbs
+//! it. Blocks that can't be reached from the entry block are then removed, renumbering the
+//! others.
+
+use crate::mir::{BlockId, Function, Terminator};
+
+/// Simplifies the control-flow graph of `func`.
+pub fn run(func: &mut Function) {
+    for block in &mut func.blocks {
+        if let Terminator::Branch { then, els, .. } = block.terminator
+            && then == els
+        {
+            block.terminator = Terminator::Goto(then);
+        }
+    }
+    for index in 0..func.blocks.len() {
+        let mut targets = func.blocks[index].terminator.successors();
+        for target in &mut targets {
+            *target = skip_empty(func, *target);
+        }
+        for (slot, target) in func.blocks[index]
+            .terminator
+            .successors_mut()
+            .into_iter()
+            .zip(targets)
+        {
+            *slot = target;
+        }
+    }
+    remove_unreachable(func);
+    merge(func);
+    remove_unreachable(func);
+}
+
+/// Removes the blocks that can't be reached from the entry block.
+fn remove_unreachable(func: &mut Function) {
+    let reachable = func.reachable();
+    super::retain_blocks(func, &reachable);
+}
+
+/// Follows `target` through blocks without statements that only go to another block.
+fn skip_empty(func: &Function, mut target: BlockId) -> BlockId {
+    // Bounded, since empty blocks can go around in a loop
+    for _ in 0..func.blocks.len() {
+        let block = &func.blocks[target.0];
+        match block.terminator {
+            Terminator::Goto(next) if block.stmts.is_empty() && next != target => target = next,
+            _ => break,
+        }
+    }
+    target
+}
+
+/// Merges blocks into the block before them, if it is the only one going to them.
+fn merge(func: &mut Function) {
+    let mut preds = vec![0; func.blocks.len()];
+    for block in &func.blocks {
+        for succ in block.terminator.successors() {
+            preds[succ.0] += 1;
+        }
+    }
+    for index in 0..func.blocks.len() {
+        while let Terminator::Goto(next) = func.blocks[index].terminator
+            && next.0 != index
+            && next.0 != 0
+            && preds[next.0] == 1
+        {
+            let next =
+                std::mem::replace(&mut func.blocks[next.0].terminator, Terminator::Unreachable);
+            let Terminator::Goto(BlockId(absorbed)) =
+                std::mem::replace(&mut func.blocks[index].terminator, next)
+            else {
+                unreachable!("the block ends with a goto");
+            };
+            let stmts = std::mem::take(&mut func.blocks[absorbed].stmts);
+            func.blocks[index].stmts.extend(stmts);
+            preds[absorbed] = 0;
+        }
+    }
+}
+
+#[test]
+fn cfg_test() {
+    let text = r"identifier app.main
+func void start() is
+    if true is
+        print('a')
+    else
+        print('b')
+    end
+    print('c')
+end";
+    let ir = crate::interp::with_checked(text, |modules, info| {
+        let mut program =
+            crate::mir::lower::lower(modules, info, crate::modules::ModuleId(0)).unwrap();
+        super::fold::run(&mut program.functions[0]);
+        run(&mut program.functions[0]);
+        let mut out = Vec::new();
+        crate::mir::pretty(&program, &mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    });
+    assert_eq!(
+        ir.lines().collect::<Vec<_>>(),
+        [
+            "func app.main/start() -> void entry {",
+            "  bb0:",
+            "    print \"a\"",
+            "    print \"c\"",
+            "    return",
+            "}",
+        ]
+    );
+}
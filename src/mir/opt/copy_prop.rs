@@ -0,0 +1,124 @@
+#![deny(missing_docs)]
+//! Module for copy propagation.
+//!
+//! After `_a = copy _b` or `_a = share copy _b`, both locals hold the same value until either is
+//! assigned, moved or dropped. Where that holds on every path, which is found by a forward analysis over the
+//! blocks, reads of `_a` are replaced with reads of `_b`, so the copy is often left unread for
+//! [dead-code elimination](super::dce). Moves out of `_a` are only replaced when nothing has to
+//! be dropped, since `_a` would keep its share of the value otherwise.
+
+use std::collections::HashSet;
+
+use crate::mir::{Function, Local, LocalDecl, Operand, Rvalue, StatementKind};
+
+/// Copies that hold, as `(copy, original)` pairs.
+type Copies = HashSet<(Local, Local)>;
+
+/// Propagates the copies of `func`.
+pub fn run(func: &mut Function) {
+    let entry = analyze(func);
+    for (block, copies) in func.blocks.iter_mut().zip(entry) {
+        let Some(mut copies) = copies else {
+            // Unreachable blocks are left to `simplify-cfg`
+            continue;
+        };
+        for stmt in &mut block.stmts {
+            for operand in stmt.kind.operands_mut() {
+                replace(operand, &copies, &func.locals);
+            }
+            step(&stmt.kind, &mut copies);
+        }
+        if let Some(operand) = block.terminator.operand_mut() {
+            replace(operand, &copies, &func.locals);
+        }
+    }
+}
+
+/// Finds the copies holding at the start of every block, `None` for unreachable blocks.
+fn analyze(func: &Function) -> Vec<Option<Copies>> {
+    let mut entry: Vec<Option<Copies>> = vec![None; func.blocks.len()];
+    entry[0] = Some(Copies::new());
+    let mut work = vec![0];
+    while let Some(block) = work.pop() {
+        let mut copies = entry[block].clone().unwrap_or_default();
+        for stmt in &func.blocks[block].stmts {
+            step(&stmt.kind, &mut copies);
+        }
+        for succ in func.blocks[block].terminator.successors() {
+            let merged = match &entry[succ.0] {
+                Some(old) => old.intersection(&copies).copied().collect(),
+                None => copies.clone(),
+            };
+            if entry[succ.0].as_ref() != Some(&merged) {
+                entry[succ.0] = Some(merged);
+                work.push(succ.0);
+            }
+        }
+    }
+    entry
+}
+
+/// Updates the copies holding after `stmt`.
+fn step(stmt: &StatementKind, copies: &mut Copies) {
+    let mut killed = Vec::new();
+    for operand in stmt.operands() {
+        if let Operand::Move(local) = operand {
+            killed.push(*local);
+        }
+    }
+    match stmt {
+        StatementKind::Drop(local) => killed.push(*local),
+        _ => killed.extend(stmt.defines()),
+    }
+    copies.retain(|(copy, original)| !killed.contains(copy) && !killed.contains(original));
+    if let StatementKind::Assign(
+        copy,
+        Rvalue::Use(Operand::Copy(original)) | Rvalue::Share(Operand::Copy(original)),
+    ) = stmt
+        && copy != original
+    {
+        copies.insert((*copy, *original));
+    }
+}
+
+/// Replaces a read of a copy with a read of its original.
+fn replace(operand: &mut Operand, copies: &Copies, locals: &[LocalDecl]) {
+    let local = match operand {
+        Operand::Copy(local) => *local,
+        Operand::Move(local) if !locals[local.0].ty.is_ref() => *local,
+        _ => return,
+    };
+    if let Some((_, original)) = copies.iter().find(|(copy, _)| *copy == local) {
+        *operand = Operand::Copy(*original);
+    }
+}
+
+#[test]
+fn copy_prop_test() {
+    let text = r"identifier app.main
+func void start() is
+    i32 a = 1
+    if a > 0 is
+        a++
+    end
+    i32 b = a
+    i32 c = b
+    while c < 10 is
+        c = c + b
+    end
+    print(c.to-string())
+end";
+    let ir = crate::interp::with_checked(text, |modules, info| {
+        let mut program =
+            crate::mir::lower::lower(modules, info, crate::modules::ModuleId(0)).unwrap();
+        run(&mut program.functions[0]);
+        let mut out = Vec::new();
+        crate::mir::pretty(&program, &mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    });
+    // `c` changes in the loop, so only the reads of `b` go back to `a`
+    assert!(ir.contains("_3 c: i32"), "{ir}");
+    assert!(ir.contains("_3 = copy _0"), "{ir}");
+    assert!(ir.contains("_3 = add copy _3, copy _0"), "{ir}");
+    assert!(ir.contains("lt copy _3, 10"), "{ir}");
+}
@@ -0,0 +1,138 @@
+#![deny(missing_docs)]
+//! Module for dead-code elimination.
+//!
+//! Assignments to locals that are never read are removed, unless computing the value could
+//! fail, like an index out of bounds or a division by zero. Values they moved are dropped
+//! instead, so removing them doesn't keep anything alive. Calls are kept, but stop giving
+//! their unread result to a local. Drops of locals that are never assigned are removed, and
+//! then locals that are used nowhere, renumbering the others.
+
+use std::collections::HashMap;
+
+use crate::{
+    ast::BinOp,
+    mir::{Const, Function, Local, LocalDecl, Operand, Rvalue, Statement, StatementKind, Ty},
+};
+
+/// Removes the dead code of `func`.
+pub fn run(func: &mut Function) {
+    loop {
+        let reads = super::reads(func);
+        let defs = super::definitions(func);
+        let mut changed = false;
+        for block in &mut func.blocks {
+            let stmts = std::mem::take(&mut block.stmts);
+            for mut stmt in stmts {
+                match &mut stmt.kind {
+                    StatementKind::Assign(local, rvalue)
+                        if !reads[local.0] && !fails(rvalue, &func.locals) =>
+                    {
+                        if rvalue.consumes() {
+                            for operand in rvalue.operands() {
+                                if let Operand::Move(moved) = operand
+                                    && func.locals[moved.0].ty.is_ref()
+                                {
+                                    block.stmts.push(Statement {
+                                        kind: StatementKind::Drop(*moved),
+                                        span: stmt.span,
+                                    });
+                                }
+                            }
+                        }
+                        changed = true;
+                        continue;
+                    }
+                    StatementKind::Call { dst, .. } if dst.is_some_and(|x| !reads[x.0]) => {
+                        *dst = None;
+                        changed = true;
+                    }
+                    StatementKind::Drop(local) if local.0 >= func.params && defs[local.0] == 0 => {
+                        changed = true;
+                        continue;
+                    }
+                    _ => {}
+                }
+                block.stmts.push(stmt);
+            }
+        }
+        if !changed {
+            break;
+        }
+    }
+    remove_locals(func);
+}
+
+/// Checks if computing `rvalue` could fail at runtime.
+fn fails(rvalue: &Rvalue, locals: &[LocalDecl]) -> bool {
+    match rvalue {
+        Rvalue::Index(..) | Rvalue::Take(..) => true,
+        Rvalue::Binary(BinOp::Div, lhs, rhs) => {
+            let int = match lhs {
+                Operand::Copy(local) | Operand::Move(local) => locals[local.0].ty == Ty::I32,
+                Operand::Const(constant) => matches!(constant, Const::Int(_)),
+            };
+            int && !matches!(rhs, Operand::Const(Const::Int(rhs)) if *rhs != 0)
+        }
+        _ => false,
+    }
+}
+
+/// Removes the locals of `func` used nowhere, besides its parameters.
+fn remove_locals(func: &mut Function) {
+    let mut used = super::reads(func);
+    for stmt in func.blocks.iter().flat_map(|x| &x.stmts) {
+        if let StatementKind::Drop(local) = stmt.kind {
+            used[local.0] = true;
+        }
+        if let Some(local) = stmt.kind.defines() {
+            used[local.0] = true;
+        }
+    }
+    used[..func.params].fill(true);
+    if used.iter().all(|x| *x) {
+        return;
+    }
+    let mut map = HashMap::new();
+    let mut locals = Vec::new();
+    for (index, decl) in std::mem::take(&mut func.locals).into_iter().enumerate() {
+        if used[index] {
+            map.insert(Local(index), Local(locals.len()));
+            locals.push(decl);
+        }
+    }
+    func.locals = locals;
+    super::rename_locals(func, |x| map[&x]);
+}
+
+#[test]
+fn dce_test() {
+    let text = r"identifier app.main
+func void start() is
+    string[] unused = ['a']
+    i32 zero = 0
+    i32 n = 1 / zero
+    i32 m = 2 * 3
+end";
+    let ir = crate::interp::with_checked(text, |modules, info| {
+        let mut program =
+            crate::mir::lower::lower(modules, info, crate::modules::ModuleId(0)).unwrap();
+        run(&mut program.functions[0]);
+        let mut out = Vec::new();
+        crate::mir::pretty(&program, &mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    });
+    // The division could fail, so it stays, with the locals it reads
+    assert_eq!(
+        ir.lines().collect::<Vec<_>>(),
+        [
+            "func app.main/start() -> void entry {",
+            "    let _0 zero: i32",
+            "    let _1 n: i32",
+            "  bb0:",
+            "    _0 = 0",
+            "    _1 = div 1, copy _0",
+            "    return",
+            "}",
+        ]
+    );
+}
@@ -0,0 +1,51 @@
+#![deny(missing_docs)]
+//! Module for redundant-drop elimination.
+//!
+//! Drops of locals that are empty on every path, which is found by a forward analysis over the
+//! blocks, and drops of numbers and booleans, which own nothing, are removed. Values moved out
+//! of a local before the end of its scope leave such drops behind.
+
+use crate::mir::{Function, StatementKind};
+
+/// Removes the redundant drops of `func`.
+pub fn run(func: &mut Function) {
+    let (entry, _) = super::maybe_full(func);
+    for (block, mut full) in func.blocks.iter_mut().zip(entry) {
+        block.stmts.retain(|stmt| {
+            let keep = match stmt.kind {
+                StatementKind::Drop(local) => full[local.0] && func.locals[local.0].ty.is_ref(),
+                _ => true,
+            };
+            super::step_full(&stmt.kind, &mut full);
+            keep
+        });
+    }
+}
+
+#[test]
+fn drops_test() {
+    let text = r"identifier app.main
+obj log is
+    lines: string[] = [],
+end
+func void consume(log l) is
+    drop(l)
+end
+func void start() is
+    log a = log
+    log b = log
+    consume(b)
+    i32 n = 1
+    print(n.to-string())
+end";
+    let ir = crate::interp::with_checked(text, |modules, info| {
+        let mut program =
+            crate::mir::lower::lower(modules, info, crate::modules::ModuleId(0)).unwrap();
+        run(&mut program.functions[1]);
+        let mut out = Vec::new();
+        crate::mir::pretty(&program, &mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    });
+    assert!(ir.contains("call app.main/consume(move _3)"), "{ir}");
+    assert!(!ir.contains("drop _3"), "{ir}");
+}
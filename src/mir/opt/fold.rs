@@ -0,0 +1,201 @@
+#![deny(missing_docs)]
+//! Module for constant folding.
+//!
+//! Locals assigned a constant once in the whole function always hold it, so every read of them
+//! is replaced with the constant, and so are reads in the same block after other assignments
+//! of constants. Operators, conversions and lengths of constants are then computed like the
+//! [virtual machine](crate::bytecode::vm) would, concatenating string literals too, except for
+//! what would fail, like dividing by zero. Branches on constant conditions become gotos.
+
+use std::collections::HashMap;
+
+use crate::{
+    ast::{BinOp, UnOp},
+    interp::compare,
+    mir::{Const, Function, Local, Operand, Rvalue, StatementKind, Terminator},
+};
+
+/// Folds the constants of `func`.
+pub fn run(func: &mut Function) {
+    loop {
+        let defs = super::definitions(func);
+        // Locals always holding the same constant
+        let mut constants = HashMap::new();
+        for stmt in func.blocks.iter().flat_map(|x| &x.stmts) {
+            if let StatementKind::Assign(local, Rvalue::Use(Operand::Const(constant))) = &stmt.kind
+                && local.0 >= func.params
+                && defs[local.0] == 1
+            {
+                constants.insert(*local, constant.clone());
+            }
+        }
+        let mut changed = false;
+        for block in &mut func.blocks {
+            let mut known = constants.clone();
+            for stmt in &mut block.stmts {
+                for operand in stmt.kind.operands_mut() {
+                    changed |= replace(operand, &known, &constants, &func.locals);
+                }
+                if let StatementKind::Assign(_, rvalue) = &mut stmt.kind
+                    && let Some(constant) = fold(rvalue)
+                {
+                    *rvalue = Rvalue::Use(Operand::Const(constant));
+                    changed = true;
+                }
+                // Only locals assigned once keep their constant after being moved or dropped
+                for operand in stmt.kind.operands() {
+                    if let Operand::Move(local) = operand
+                        && !constants.contains_key(local)
+                    {
+                        known.remove(local);
+                    }
+                }
+                match &stmt.kind {
+                    StatementKind::Assign(local, Rvalue::Use(Operand::Const(constant))) => {
+                        known.insert(*local, constant.clone());
+                    }
+                    StatementKind::Drop(local) if !constants.contains_key(local) => {
+                        known.remove(local);
+                    }
+                    kind => {
+                        if let Some(local) = kind.defines() {
+                            known.remove(&local);
+                        }
+                    }
+                }
+            }
+            if let Some(operand) = block.terminator.operand_mut() {
+                changed |= replace(operand, &known, &constants, &func.locals);
+            }
+            if let Terminator::Branch {
+                cond: Operand::Const(Const::Bool(cond)),
+                then,
+                els,
+            } = block.terminator
+            {
+                block.terminator = Terminator::Goto(if cond { then } else { els });
+                changed = true;
+            }
+        }
+        if !changed {
+            return;
+        }
+    }
+}
+
+/// Replaces `operand` with the constant its local holds, if it is known. Moves are only
+/// replaced when nothing has to be dropped, or when the local holds the constant for the
+/// whole function, so it is never read again.
+fn replace(
+    operand: &mut Operand,
+    known: &HashMap<Local, Const>,
+    constants: &HashMap<Local, Const>,
+    locals: &[crate::mir::LocalDecl],
+) -> bool {
+    let constant = match operand {
+        Operand::Copy(local) => known.get(local),
+        Operand::Move(local) if !locals[local.0].ty.is_ref() => known.get(local),
+        Operand::Move(local) => constants.get(local),
+        Operand::Const(_) => None,
+    };
+    match constant {
+        Some(constant) => {
+            *operand = Operand::Const(constant.clone());
+            true
+        }
+        None => false,
+    }
+}
+
+/// Computes `rvalue` if its operands are constants and it can't fail.
+fn fold(rvalue: &Rvalue) -> Option<Const> {
+    let constant = |operand: &Operand| match operand {
+        Operand::Const(constant) => Some(constant.clone()),
+        _ => None,
+    };
+    match rvalue {
+        Rvalue::Binary(op, lhs, rhs) => binary(*op, &constant(lhs)?, &constant(rhs)?),
+        Rvalue::Unary(op, operand) => match (op, constant(operand)?) {
+            (UnOp::Neg, Const::Int(int)) => Some(Const::Int(int.wrapping_neg())),
+            (UnOp::Neg, Const::Float(float)) => Some(Const::Float(-float)),
+            (UnOp::Not, Const::Bool(value)) => Some(Const::Bool(!value)),
+            _ => None,
+        },
+        Rvalue::ToString(operand) => Some(Const::Str(match constant(operand)? {
+            Const::Int(int) => int.to_string().into(),
+            Const::Float(float) => float.to_string().into(),
+            Const::Bool(value) => value.to_string().into(),
+            Const::Str(string) => string,
+        })),
+        Rvalue::Len(operand) => match constant(operand)? {
+            Const::Str(string) => Some(Const::Int(string.len() as i32)),
+            _ => None,
+        },
+        // Constants have no owner, so sharing one gives it as it is
+        Rvalue::Share(operand) => constant(operand),
+        _ => None,
+    }
+}
+
+/// Applies a binary operator to constants, giving `None` where it would fail.
+pub fn binary(op: BinOp, lhs: &Const, rhs: &Const) -> Option<Const> {
+    let value = match (lhs, rhs) {
+        (Const::Int(_), Const::Int(0)) if op == BinOp::Div => return None,
+        (Const::Int(a), Const::Int(b)) => match op {
+            BinOp::Add => Const::Int(a.wrapping_add(*b)),
+            BinOp::Sub => Const::Int(a.wrapping_sub(*b)),
+            BinOp::Mul => Const::Int(a.wrapping_mul(*b)),
+            BinOp::Div => Const::Int(a.wrapping_div(*b)),
+            BinOp::And | BinOp::Or => return None,
+            _ => Const::Bool(compare(op, a, b)),
+        },
+        (Const::Float(a), Const::Float(b)) => match op {
+            BinOp::Add => Const::Float(a + b),
+            BinOp::Sub => Const::Float(a - b),
+            BinOp::Mul => Const::Float(a * b),
+            BinOp::Div => Const::Float(a / b),
+            BinOp::And | BinOp::Or => return None,
+            _ => Const::Bool(compare(op, a, b)),
+        },
+        (Const::Str(a), Const::Str(b)) => match op {
+            BinOp::Add => Const::Str(format!("{a}{b}").into()),
+            BinOp::Eq | BinOp::Ne | BinOp::Lt | BinOp::Le | BinOp::Gt | BinOp::Ge => {
+                Const::Bool(compare(op, a, b))
+            }
+            _ => return None,
+        },
+        (Const::Bool(a), Const::Bool(b)) if op == BinOp::Eq || op == BinOp::Ne => {
+            Const::Bool(compare(op, a, b))
+        }
+        _ => return None,
+    };
+    Some(value)
+}
+
+#[test]
+fn fold_test() {
+    let text = r"identifier app.main
+func void start() is
+    string s = 'Hello, ' + 'World!'
+    i32 n = 6 * 7
+    if n > 40 is
+        print(s + ' ' + n.to-string())
+    end
+end";
+    let ir = crate::interp::with_checked(text, |modules, info| {
+        let mut program =
+            crate::mir::lower::lower(modules, info, crate::modules::ModuleId(0)).unwrap();
+        run(&mut program.functions[0]);
+        let mut out = Vec::new();
+        crate::mir::pretty(&program, &mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    });
+    assert!(ir.contains("_0 = \"Hello, World!\""), "{ir}");
+    assert!(ir.contains("goto bb1"), "{ir}");
+    assert!(ir.contains("print \"Hello, World! 42\""), "{ir}");
+    assert_eq!(
+        binary(BinOp::Div, &Const::Int(1), &Const::Int(0)),
+        None,
+        "dividing by zero fails at runtime"
+    );
+}
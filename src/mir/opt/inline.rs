@@ -0,0 +1,195 @@
+#![deny(missing_docs)]
+//! Module for inlining small functions.
+//!
+//! A call to a function of at most [`Options::inline_limit`](super::Options::inline_limit)
+//! statements and blocks, which doesn't call itself, is replaced with a copy of its blocks and
+//! locals. The arguments are assigned to the locals of its parameters, and every return
+//! assigns the result and goes on with the statements after the call, dropping the locals the
+//! function still held, like returning would. Inlined statements point at the call, so errors
+//! in them are reported there. Calls found in the inlined blocks are left for the next round.
+
+use crate::{
+    mir::{
+        BasicBlock, BlockId, Callee, FuncId, Function, Local, LocalDecl, Operand, Program, Rvalue,
+        Statement, StatementKind, Terminator,
+    },
+    span::Span,
+};
+
+/// Inlines the calls to small functions of `program`.
+pub fn run(program: &mut Program, limit: usize) {
+    let inlined: Vec<_> = program
+        .functions
+        .iter()
+        .enumerate()
+        .map(|(index, func)| size(func) <= limit && !calls(func, FuncId(index)))
+        .collect();
+    for caller in 0..program.functions.len() {
+        // Only the blocks of the caller itself are searched for calls, not the inlined ones
+        let mut work: Vec<_> = (0..program.functions[caller].blocks.len()).collect();
+        while let Some(block) = work.pop() {
+            let stmts = &program.functions[caller].blocks[block].stmts;
+            let call = stmts.iter().position(|stmt| {
+                matches!(stmt.kind, StatementKind::Call { callee: Callee::Func(callee), .. }
+                    if callee.0 != caller && inlined[callee.0])
+            });
+            let Some(call) = call else {
+                continue;
+            };
+            let StatementKind::Call {
+                callee: Callee::Func(callee),
+                ..
+            } = stmts[call].kind
+            else {
+                unreachable!("the statement is a call");
+            };
+            let callee = program.functions[callee.0].clone();
+            work.push(inline(&mut program.functions[caller], block, call, &callee));
+        }
+    }
+}
+
+/// Measures a function, to compare it to the inlining limit.
+fn size(func: &Function) -> usize {
+    func.blocks.iter().map(|x| x.stmts.len() + 1).sum()
+}
+
+/// Checks if `func` calls `callee`.
+fn calls(func: &Function, callee: FuncId) -> bool {
+    func.blocks.iter().flat_map(|x| &x.stmts).any(|stmt| {
+        matches!(stmt.kind, StatementKind::Call { callee: Callee::Func(x), .. } if x == callee)
+    })
+}
+
+/// Inlines `callee` at the statement `call` of `block`, giving the block of the statements
+/// after the call.
+fn inline(func: &mut Function, block: usize, call: usize, callee: &Function) -> usize {
+    let mut after = func.blocks[block].stmts.split_off(call);
+    let stmt = after.remove(0);
+    let span = stmt.span;
+    let StatementKind::Call { dst, args, .. } = stmt.kind else {
+        unreachable!("the statement is a call");
+    };
+    let locals = func.locals.len();
+    let blocks = func.blocks.len();
+    let rest = blocks + callee.blocks.len();
+    func.locals.extend(callee.locals.iter().cloned());
+    // An unused result is dropped like a call would
+    let (dst, ignored) = match dst {
+        Some(dst) => (Some(dst), false),
+        None if callee.ret == crate::mir::Ty::Void => (None, false),
+        None => {
+            func.locals.push(LocalDecl {
+                ty: callee.ret.clone(),
+                name: None,
+                borrowed: false,
+            });
+            (Some(Local(func.locals.len() - 1)), callee.ret.is_ref())
+        }
+    };
+    let local = |local: Local| Local(local.0 + locals);
+    for (param, arg) in args.into_iter().enumerate() {
+        func.blocks[block].stmts.push(Statement {
+            kind: StatementKind::Assign(Local(param + locals), Rvalue::Use(arg)),
+            span,
+        });
+    }
+    let terminator = std::mem::replace(
+        &mut func.blocks[block].terminator,
+        Terminator::Goto(BlockId(blocks)),
+    );
+    let (_, full) = super::maybe_full(callee);
+    for (inlined, full) in callee.blocks.iter().zip(full) {
+        let mut stmts: Vec<_> = inlined
+            .stmts
+            .iter()
+            .map(|stmt| {
+                let mut kind = stmt.kind.clone();
+                super::rename_stmt(&mut kind, &local);
+                Statement { kind, span }
+            })
+            .collect();
+        let mut terminator = inlined.terminator.clone();
+        if let Some(operand) = terminator.operand_mut() {
+            super::rename_operand(operand, &local);
+        }
+        for target in terminator.successors_mut() {
+            target.0 += blocks;
+        }
+        if let Terminator::Return(value) = terminator {
+            let mut full = full;
+            if let Some(value) = value {
+                if let Some(moved) = inlined.terminator.operand().and_then(Operand::local)
+                    && matches!(value, Operand::Move(_))
+                {
+                    full[moved.0] = false;
+                }
+                let dst = dst.expect("calls of functions giving a value have a result");
+                stmts.push(Statement {
+                    kind: StatementKind::Assign(dst, Rvalue::Use(value)),
+                    span,
+                });
+                if ignored {
+                    stmts.push(drop(dst, span));
+                }
+            }
+            for (index, decl) in callee.locals.iter().enumerate() {
+                if full[index] && decl.ty.is_ref() && !decl.borrowed {
+                    stmts.push(drop(local(Local(index)), span));
+                }
+            }
+            terminator = Terminator::Goto(BlockId(rest));
+        }
+        func.blocks.push(BasicBlock { stmts, terminator });
+    }
+    func.blocks.push(BasicBlock {
+        stmts: after,
+        terminator,
+    });
+    rest
+}
+
+fn drop(local: Local, span: Span) -> Statement {
+    Statement {
+        kind: StatementKind::Drop(local),
+        span,
+    }
+}
+
+#[test]
+fn inline_test() {
+    let text = r"identifier app.main
+func i32 twice(i32 x) is
+    return x * 2
+end
+func string greet(string name) is
+    return 'Hello, ' + name
+end
+func void start() is
+    print(greet('World!'))
+    print(twice(21).to-string())
+end";
+    let out = crate::mir::opt::run_optimized(text, 2);
+    assert_eq!(out, "Hello, World!42");
+    let ir = crate::interp::with_checked(text, |modules, info| {
+        let mut program =
+            crate::mir::lower::lower(modules, info, crate::modules::ModuleId(0)).unwrap();
+        super::optimize(&mut program, &super::Options::level(2));
+        let mut out = Vec::new();
+        crate::mir::pretty(&program, &mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    });
+    // Both calls are gone, and their results folded
+    let start = &ir[ir.rfind("\n\n").unwrap()..];
+    assert_eq!(
+        start.trim().lines().collect::<Vec<_>>(),
+        [
+            "func app.main/start() -> void entry {",
+            "  bb0:",
+            "    print \"Hello, World!\"",
+            "    print \"42\"",
+            "    return",
+            "}",
+        ]
+    );
+}
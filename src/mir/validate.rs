@@ -0,0 +1,483 @@
+#![deny(missing_docs)]
+//! Module for checking that a [MIR](super) program is well formed, so a mistake of the lowering
+//! or an optimization is caught where it is made, instead of by a backend.
+//!
+//! Every block, local, function, type and extern referred to has to exist, calls have to pass as
+//! many arguments as the callee takes, values have to fit where they are stored, branches have to
+//! test booleans, returns have to match the return type of their function, and values can only
+//! be moved where they are consumed, out of locals owning them. References that are consumed
+//! have to be moved or shared, and borrowing locals can only be assigned what other locals hold
+//! and are never dropped. Blocks nothing branches to are allowed.
+
+use std::fmt::{self, Display};
+
+use super::{
+    BlockId, Callee, Function, Local, Operand, Program, Rvalue, StatementKind, Terminator, Ty,
+};
+use crate::ast::{BinOp, UnOp};
+
+/// A problem found in a program.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Error {
+    /// Name of the function with the problem.
+    pub func: String,
+    /// The block with the problem, if it is in one.
+    pub block: Option<BlockId>,
+    /// What the problem is.
+    pub message: String,
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.block {
+            Some(block) => write!(f, "`{}`, {block}: {}", self.func, self.message),
+            None => write!(f, "`{}`: {}", self.func, self.message),
+        }
+    }
+}
+
+/// Checks every function of `program`, giving every problem found.
+pub fn validate(program: &Program) -> Result<(), Vec<Error>> {
+    let mut errors = Vec::new();
+    if program.entry.0 >= program.functions.len() {
+        errors.push(Error {
+            func: "{entry}".to_owned(),
+            block: None,
+            message: format!("entry function f{} doesn't exist", program.entry.0),
+        });
+    }
+    for func in &program.functions {
+        let mut validator = Validator {
+            program,
+            func,
+            block: None,
+            errors: &mut errors,
+        };
+        validator.function();
+    }
+    match errors.is_empty() {
+        true => Ok(()),
+        false => Err(errors),
+    }
+}
+
+struct Validator<'v, 'p, 'src> {
+    program: &'p Program<'src>,
+    func: &'p Function<'src>,
+    block: Option<BlockId>,
+    errors: &'v mut Vec<Error>,
+}
+
+impl Validator<'_, '_, '_> {
+    fn error(&mut self, message: String) {
+        self.errors.push(Error {
+            func: self.func.name.clone(),
+            block: self.block,
+            message,
+        });
+    }
+
+    fn function(&mut self) {
+        if self.func.blocks.is_empty() {
+            self.error("there is no entry block".to_owned());
+        }
+        if self.func.params > self.func.locals.len() {
+            self.error(format!(
+                "{} parameters, but only {} locals",
+                self.func.params,
+                self.func.locals.len()
+            ));
+        }
+        for (index, block) in self.func.blocks.iter().enumerate() {
+            self.block = Some(BlockId(index));
+            for stmt in &block.stmts {
+                self.statement(&stmt.kind);
+            }
+            self.terminator(&block.terminator);
+        }
+    }
+
+    /// Gets the type of `local`, reporting it if it doesn't exist.
+    fn local(&mut self, local: Local) -> Option<Ty> {
+        match self.func.locals.get(local.0) {
+            Some(decl) => Some(decl.ty.clone()),
+            None => {
+                self.error(format!("{local} doesn't exist"));
+                None
+            }
+        }
+    }
+
+    /// Gets the type of `operand`, which is consumed if `consumed` is set, and only borrowed
+    /// otherwise.
+    fn operand(&mut self, operand: &Operand, consumed: bool) -> Option<Ty> {
+        match operand {
+            Operand::Copy(local) => {
+                let ty = self.local(*local)?;
+                if consumed && ty.is_ref() {
+                    self.error(format!(
+                        "{operand} is consumed, so it has to be moved or shared"
+                    ));
+                }
+                Some(ty)
+            }
+            Operand::Move(local) => {
+                if !consumed {
+                    self.error(format!("{operand} is only borrowed, so it can't be moved"));
+                }
+                if self.borrowed(*local) {
+                    self.error(format!("{local} borrows its value, so it can't be moved"));
+                }
+                self.local(*local)
+            }
+            Operand::Const(constant) => Some(constant.ty()),
+        }
+    }
+
+    /// Checks if `local` borrows the reference it holds.
+    fn borrowed(&self, local: Local) -> bool {
+        self.func.locals.get(local.0).is_some_and(|x| x.borrowed)
+    }
+
+    /// Reports `what` if it has type `found` where a value of type `expected` is needed.
+    fn expect(&mut self, what: impl Display, found: Option<Ty>, expected: &Ty) {
+        if let Some(found) = found
+            && !found.fits(expected)
+        {
+            self.error(format!(
+                "{what} is {}, but {} is expected",
+                self.program.ty_name(&found),
+                self.program.ty_name(expected)
+            ));
+        }
+    }
+
+    fn statement(&mut self, stmt: &StatementKind) {
+        match stmt {
+            StatementKind::Assign(local, rvalue) => {
+                let borrowed = self.borrowed(*local);
+                let ty = self.rvalue(rvalue, !borrowed);
+                if borrowed && !rvalue.borrows() {
+                    self.error(format!(
+                        "{local} borrows its value, so it can't be assigned a new one"
+                    ));
+                }
+                let field = matches!(rvalue, Rvalue::Field(..) | Rvalue::Index(..));
+                if !borrowed && field && ty.as_ref().is_some_and(Ty::is_ref) {
+                    self.error(format!(
+                        "{local} owns its value, so what it is assigned has to be shared"
+                    ));
+                }
+                if let Some(expected) = self.local(*local) {
+                    self.expect(format_args!("the value assigned to {local}"), ty, &expected);
+                }
+            }
+            StatementKind::Call { dst, callee, args } => {
+                let (params, ret): (Vec<_>, _) = match callee {
+                    Callee::Func(func) => match self.program.functions.get(func.0) {
+                        Some(func) => (
+                            func.locals[..func.params.min(func.locals.len())]
+                                .iter()
+                                .map(|x| (x.ty.clone(), !x.borrowed))
+                                .collect(),
+                            func.ret.clone(),
+                        ),
+                        None => return self.error(format!("function f{} doesn't exist", func.0)),
+                    },
+                    Callee::Extern(func) => match self.program.externs.get(func.0) {
+                        Some(func) => (
+                            func.params.iter().map(|x| (x.clone(), false)).collect(),
+                            func.ret.clone(),
+                        ),
+                        None => return self.error(format!("extern e{} doesn't exist", func.0)),
+                    },
+                };
+                if args.len() != params.len() {
+                    self.error(format!(
+                        "{} arguments are passed to a function taking {}",
+                        args.len(),
+                        params.len()
+                    ));
+                }
+                for (arg, (param, owned)) in args.iter().zip(&params) {
+                    let ty = self.operand(arg, *owned);
+                    self.expect(format_args!("argument {arg}"), ty, param);
+                }
+                if let Some(dst) = dst {
+                    if ret == Ty::Void {
+                        self.error(format!("{dst} is assigned the result of a `void` call"));
+                    } else if let Some(expected) = self.local(*dst) {
+                        self.expect("the result of the call", Some(ret), &expected);
+                    }
+                }
+            }
+            StatementKind::SetField { obj, field, value } => {
+                let ty = self.operand(value, true);
+                if let Some(expected) = self.field(*obj, *field) {
+                    self.expect(format_args!("the value of field {field}"), ty, &expected);
+                }
+            }
+            StatementKind::Replace { dst, value } => {
+                let ty = self.operand(value, true);
+                let Some(expected) = self.local(*dst) else {
+                    return;
+                };
+                if !matches!(expected, Ty::Array(_) | Ty::Obj(_)) {
+                    self.error(format!("{dst} is replaced, but isn't an array or object"));
+                }
+                self.expect(format_args!("the value replacing {dst}"), ty, &expected);
+            }
+            StatementKind::Push { array, value } => {
+                let ty = self.operand(value, true);
+                match self.local(*array) {
+                    Some(Ty::Array(item)) => self.expect("the item pushed", ty, &item),
+                    Some(_) => self.error(format!("{array} is pushed onto, but isn't an array")),
+                    None => {}
+                }
+            }
+            StatementKind::Print(operand) => {
+                let ty = self.operand(operand, false);
+                self.expect("the value printed", ty, &Ty::String);
+            }
+            StatementKind::Println => {}
+            StatementKind::Drop(local) => {
+                if self.borrowed(*local) {
+                    self.error(format!("{local} borrows its value, so it can't be dropped"));
+                }
+                self.local(*local);
+            }
+        }
+    }
+
+    /// Gets the type of the field `field` of the object in `obj`.
+    fn field(&mut self, obj: Local, field: usize) -> Option<Ty> {
+        match self.local(obj)? {
+            Ty::Obj(ty) => match self.program.types.get(ty.0) {
+                Some(def) => match def.fields.get(field) {
+                    Some(field) => Some(field.ty.clone()),
+                    None => {
+                        self.error(format!("`{}` has no field {field}", def.name));
+                        None
+                    }
+                },
+                None => {
+                    self.error(format!("type t{} doesn't exist", ty.0));
+                    None
+                }
+            },
+            _ => {
+                self.error(format!("{obj} has fields accessed, but isn't an object"));
+                None
+            }
+        }
+    }
+
+    /// Gets the type of the value of `rvalue`, assigned to an owning local if `owned` is set.
+    fn rvalue(&mut self, rvalue: &Rvalue, owned: bool) -> Option<Ty> {
+        let consumed = owned && rvalue.consumes();
+        match rvalue {
+            Rvalue::Use(operand) => self.operand(operand, consumed),
+            Rvalue::Binary(op, lhs, rhs) => {
+                let lhs_ty = self.operand(lhs, consumed)?;
+                let rhs_ty = self.operand(rhs, consumed);
+                self.expect(format_args!("the right hand side {rhs}"), rhs_ty, &lhs_ty);
+                match op {
+                    BinOp::And | BinOp::Or => {
+                        self.error(format!("`{}` is lowered to branches", op.symbol()));
+                        None
+                    }
+                    BinOp::Eq | BinOp::Ne | BinOp::Lt | BinOp::Le | BinOp::Gt | BinOp::Ge => {
+                        Some(Ty::Bool)
+                    }
+                    BinOp::Add if matches!(lhs_ty, Ty::Str | Ty::String) => Some(Ty::String),
+                    _ => {
+                        if !matches!(lhs_ty, Ty::I32 | Ty::F32) {
+                            self.error(format!(
+                                "`{}` is applied to {}",
+                                op.symbol(),
+                                self.program.ty_name(&lhs_ty)
+                            ));
+                        }
+                        Some(lhs_ty)
+                    }
+                }
+            }
+            Rvalue::Unary(op, operand) => {
+                let ty = self.operand(operand, consumed);
+                match op {
+                    UnOp::Neg => ty,
+                    UnOp::Not => {
+                        self.expect(format_args!("the operand {operand}"), ty, &Ty::Bool);
+                        Some(Ty::Bool)
+                    }
+                }
+            }
+            Rvalue::ToString(operand) => {
+                self.operand(operand, consumed);
+                Some(Ty::String)
+            }
+            Rvalue::Len(operand) => {
+                match self.operand(operand, consumed) {
+                    Some(Ty::Str | Ty::String | Ty::Array(_)) | None => {}
+                    Some(ty) => self.error(format!(
+                        "the length of {} is taken",
+                        self.program.ty_name(&ty)
+                    )),
+                }
+                Some(Ty::I32)
+            }
+            Rvalue::Array(item, items) => {
+                for value in items {
+                    let ty = self.operand(value, consumed);
+                    self.expect(format_args!("the item {value}"), ty, item);
+                }
+                Some(Ty::Array(Box::new(item.clone())))
+            }
+            Rvalue::New(ty, values) => {
+                let Some(def) = self.program.types.get(ty.0) else {
+                    self.error(format!("type t{} doesn't exist", ty.0));
+                    return None;
+                };
+                if values.len() != def.fields.len() {
+                    self.error(format!(
+                        "`{}` has {} fields, but {} are given",
+                        def.name,
+                        def.fields.len(),
+                        values.len()
+                    ));
+                }
+                for (value, field) in values.iter().zip(&def.fields) {
+                    let found = self.operand(value, consumed);
+                    self.expect(format_args!("field `{}`", field.name), found, &field.ty);
+                }
+                Some(Ty::Obj(*ty))
+            }
+            Rvalue::Field(obj, field) => {
+                self.operand(obj, consumed);
+                match obj {
+                    Operand::Const(_) => {
+                        self.error(format!("{obj} has fields accessed, but isn't an object"));
+                        None
+                    }
+                    Operand::Copy(local) | Operand::Move(local) => self.field(*local, *field),
+                }
+            }
+            Rvalue::Index(array, index) => self.index(array, index),
+            Rvalue::Share(operand) => {
+                let ty = self.operand(operand, false)?;
+                if !matches!(ty, Ty::Str | Ty::String) && !self.program.is_counted(&ty) {
+                    self.error(format!(
+                        "{} is shared, but isn't reference counted",
+                        self.program.ty_name(&ty)
+                    ));
+                }
+                Some(ty)
+            }
+            Rvalue::Take(array, index) => {
+                let item = self.index(array, index)?;
+                if !item.is_ref() {
+                    self.error(format!(
+                        "{} is taken out of an array, but isn't a reference",
+                        self.program.ty_name(&item)
+                    ));
+                }
+                Some(item)
+            }
+        }
+    }
+
+    /// Gets the type of the item at `index` of `array`, which are borrowed.
+    fn index(&mut self, array: &Operand, index: &Operand) -> Option<Ty> {
+        let array_ty = self.operand(array, false);
+        let index_ty = self.operand(index, false);
+        self.expect("the index", index_ty, &Ty::I32);
+        match array_ty? {
+            Ty::Array(item) => Some(*item),
+            ty => {
+                self.error(format!("{} is indexed", self.program.ty_name(&ty)));
+                None
+            }
+        }
+    }
+
+    fn terminator(&mut self, terminator: &Terminator) {
+        for target in terminator.successors() {
+            if target.0 >= self.func.blocks.len() {
+                self.error(format!("{target} doesn't exist"));
+            }
+        }
+        match terminator {
+            Terminator::Goto(_) | Terminator::Unreachable => {}
+            Terminator::Branch { cond, .. } => {
+                let ty = self.operand(cond, false);
+                self.expect("the condition", ty, &Ty::Bool);
+            }
+            Terminator::Return(value) => match (value, &self.func.ret) {
+                (None, Ty::Void) => {}
+                (None, ret) => {
+                    let ret = self.program.ty_name(ret);
+                    self.error(format!(
+                        "nothing is returned from a function returning {ret}"
+                    ));
+                }
+                (Some(value), Ty::Void) => {
+                    self.error(format!("{value} is returned from a `void` function"));
+                }
+                (Some(value), ret) => {
+                    let ret = ret.clone();
+                    let ty = self.operand(value, true);
+                    self.expect("the returned value", ty, &ret);
+                }
+            },
+        }
+    }
+}
+
+#[test]
+fn validate_test() {
+    let text = r"identifier app.main
+func i32 fib(i32 n) is
+    if n < 2 is
+        return n
+    end
+    return fib(n - 1) + fib(n - 2)
+end
+func void start() is
+    print(fib(10).to-string())
+end";
+    crate::interp::with_checked(text, |modules, info| {
+        let mut program = super::lower::lower(modules, info, crate::modules::ModuleId(0)).unwrap();
+        assert_eq!(validate(&program), Ok(()));
+
+        let func = program
+            .functions
+            .iter_mut()
+            .find(|x| x.name == "app.main/fib")
+            .unwrap();
+        func.blocks[0].terminator = Terminator::Goto(BlockId(99));
+        let Some(StatementKind::Assign(_, rvalue)) =
+            func.blocks[0].stmts.first_mut().map(|x| &mut x.kind)
+        else {
+            panic!("`fib` starts with an assignment")
+        };
+        *rvalue = Rvalue::Binary(
+            BinOp::Add,
+            Operand::Const(super::Const::Bool(true)),
+            Operand::Move(Local(0)),
+        );
+        let errors: Vec<_> = validate(&program)
+            .unwrap_err()
+            .iter()
+            .map(|x| x.to_string())
+            .collect();
+        assert_eq!(
+            errors,
+            [
+                "`app.main/fib`, bb0: move _0 is only borrowed, so it can't be moved",
+                "`app.main/fib`, bb0: the right hand side move _0 is i32, but bool is expected",
+                "`app.main/fib`, bb0: `+` is applied to bool",
+                "`app.main/fib`, bb0: bb99 doesn't exist",
+            ]
+        );
+    });
+}
@@ -0,0 +1,500 @@
+#![deny(missing_docs)]
+//! Module for the module graph: which items every module defines, and which items it can use
+//! through `use` declarations.
+//!
+//! `use a.b` imports every `pub` item of module `a.b`, while `use a.b.item` imports a single
+//! `pub` item. Items defined in a module always take precedence over imported ones.
+
+use std::collections::{HashMap, hash_map::Entry};
+
+use codespan_reporting::diagnostic::Label;
+
+use crate::{
+    ast::{Declaration, Ident, Path},
+    diag::Diag,
+    project::Module,
+    span::Span,
+};
+
+/// Identifies a module of a [`ModuleGraph`]. This is the index of the module in the slice the
+/// graph was built from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct ModuleId(pub usize);
+
+/// Identifies an item of a [`ModuleGraph`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct ItemId(pub usize);
+
+/// Every kind of item a module can define.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ItemKind {
+    /// A function with a body.
+    Func,
+    /// An `extern func`.
+    Extern,
+    /// An `obj` type.
+    Obj,
+}
+
+impl ItemKind {
+    /// Gets a description of the kind for diagnostics.
+    pub fn describe(self) -> &'static str {
+        match self {
+            ItemKind::Func => "function",
+            ItemKind::Extern => "extern function",
+            ItemKind::Obj => "type",
+        }
+    }
+}
+
+/// A named declaration at the top level of a module.
+#[derive(Debug, Clone, Copy)]
+pub struct Item<'src> {
+    /// Name of the item.
+    pub name: Ident<'src>,
+    /// What kind of declaration the item is.
+    pub kind: ItemKind,
+    /// Whether other modules can use the item.
+    pub public: bool,
+    /// The module defining the item.
+    pub module: ModuleId,
+    /// Index of the item's declaration in [`Module::decls`].
+    pub decl: usize,
+}
+
+/// An import of a module or item by a `use` declaration.
+#[derive(Debug, Clone, Copy)]
+pub struct Import<'src> {
+    /// The imported module.
+    pub module: ModuleId,
+    /// The imported item, or `None` if every public item of the module is imported.
+    pub item: Option<ItemId>,
+    /// Span of the imported path.
+    pub span: Span<'src>,
+}
+
+/// The items of a module, and the items it can see.
+#[derive(Debug)]
+pub struct ModuleScope<'src> {
+    /// The module path of the module.
+    pub name: String,
+    /// Items defined by the module.
+    pub items: HashMap<&'src str, ItemId>,
+    /// Every successful import of the module.
+    pub imports: Vec<Import<'src>>,
+    scope: HashMap<&'src str, ItemId>,
+}
+
+/// Every module and item of a program.
+#[derive(Debug)]
+pub struct ModuleGraph<'src> {
+    /// Every module, indexed by [`ModuleId`].
+    pub modules: Vec<ModuleScope<'src>>,
+    /// Every item, indexed by [`ItemId`].
+    pub items: Vec<Item<'src>>,
+    by_name: HashMap<String, ModuleId>,
+}
+
+/// Gets the span of the `identifier` declaration of `module`, or of the whole file if it has
+/// none.
+fn header<'src>(module: &Module<'src>) -> Span<'src> {
+    module
+        .decls
+        .iter()
+        .find_map(|x| match x {
+            Declaration::Identifier(path) => Some(path.span),
+            _ => None,
+        })
+        .unwrap_or_else(|| Span::new(module.src))
+}
+
+impl<'src> ModuleGraph<'src> {
+    /// Builds the module graph of `modules`, reporting duplicate modules and items, bad imports
+    /// and import cycles through [`Diag`].
+    pub fn build(modules: &[Module<'src>]) -> Self {
+        let mut graph = ModuleGraph {
+            modules: Vec::new(),
+            items: Vec::new(),
+            by_name: HashMap::new(),
+        };
+        for (index, module) in modules.iter().enumerate() {
+            // Imports of a module defined twice go to the first file
+            match graph.by_name.entry(module.name.clone()) {
+                Entry::Occupied(first) => {
+                    Diag::error(module.src)
+                        .with_message(format!(
+                            "module `{}` is defined multiple times",
+                            module.name
+                        ))
+                        .with_label(
+                            Label::primary((), header(module)).with_message("redefined here"),
+                        )
+                        .with_secondary(header(&modules[first.get().0]), "first defined here")
+                        .finish()
+                        .emit();
+                }
+                Entry::Vacant(entry) => {
+                    entry.insert(ModuleId(index));
+                }
+            }
+            graph.add_items(ModuleId(index), module);
+        }
+        for (index, module) in modules.iter().enumerate() {
+            graph.add_imports(ModuleId(index), module);
+        }
+        graph.check_cycles(modules);
+        graph
+    }
+
+    /// Gets an item by its id.
+    pub fn item(&self, id: ItemId) -> &Item<'src> {
+        &self.items[id.0]
+    }
+
+    /// Gets a module by its module path.
+    pub fn module_by_name(&self, name: &str) -> Option<ModuleId> {
+        self.by_name.get(name).copied()
+    }
+
+    /// Looks up `name` in the scope of `module`, which holds the module's own items and every
+    /// item it imported.
+    pub fn lookup(&self, module: ModuleId, name: &str) -> Option<ItemId> {
+        self.modules[module.0].scope.get(name).copied()
+    }
+
+    /// Finds a private item named `name` in a module imported by `module`. Useful for explaining
+    /// why a name couldn't be found.
+    pub fn private_import(&self, module: ModuleId, name: &str) -> Option<ItemId> {
+        self.modules[module.0]
+            .imports
+            .iter()
+            .filter(|x| x.item.is_none())
+            .filter_map(|x| self.modules[x.module.0].items.get(name).copied())
+            .find(|x| !self.item(*x).public)
+    }
+
+    /// Gets every name visible in `module`, for suggesting similar names.
+    pub fn visible_names(&self, module: ModuleId) -> impl Iterator<Item = &'src str> + '_ {
+        self.modules[module.0].scope.keys().copied()
+    }
+
+    fn add_items(&mut self, module: ModuleId, ast: &Module<'src>) {
+        let mut scope = ModuleScope {
+            name: ast.name.clone(),
+            items: HashMap::new(),
+            imports: Vec::new(),
+            scope: HashMap::new(),
+        };
+        for (decl, declaration) in ast.decls.iter().enumerate() {
+            let (name, kind, public) = match declaration {
+                Declaration::Func(func) => (func.sig.name, ItemKind::Func, func.public),
+                Declaration::Extern(func) => (func.sig.name, ItemKind::Extern, func.public),
+                Declaration::Obj(obj) => (obj.name, ItemKind::Obj, obj.public),
+                _ => continue,
+            };
+            if let Some(previous) = scope.items.get(name.name) {
+                let previous = self.item(*previous).name;
+                Diag::error(ast.src)
+                    .with_message(format!("`{}` is defined multiple times", name.name))
+                    .with_label(Label::primary((), name.span).with_message("redefined here"))
+                    .with_label(
+                        Label::secondary((), previous.span).with_message("first defined here"),
+                    )
+                    .finish()
+                    .emit();
+                continue;
+            }
+            let id = ItemId(self.items.len());
+            self.items.push(Item {
+                name,
+                kind,
+                public,
+                module,
+                decl,
+            });
+            scope.items.insert(name.name, id);
+        }
+        scope.scope = scope.items.clone();
+        self.modules.push(scope);
+    }
+
+    fn add_imports(&mut self, module: ModuleId, ast: &Module<'src>) {
+        for declaration in &ast.decls {
+            let Declaration::Use(path) = declaration else {
+                continue;
+            };
+            let Some(import) = self.resolve_import(module, ast, path) else {
+                continue;
+            };
+            let imported: Vec<ItemId> = match import.item {
+                Some(item) => vec![item],
+                None => {
+                    let mut items: Vec<_> = self.modules[import.module.0]
+                        .items
+                        .values()
+                        .copied()
+                        .filter(|x| self.item(*x).public)
+                        .collect();
+                    items.sort();
+                    items
+                }
+            };
+            for item in imported {
+                let name = self.item(item).name.name;
+                let Some(existing) = self.lookup(module, name) else {
+                    self.modules[module.0].scope.insert(name, item);
+                    continue;
+                };
+                if existing == item {
+                    continue;
+                }
+                let existing = *self.item(existing);
+                // Explicitly importing an item that a module import brought in is fine
+                if import.item.is_some() && existing.module != module {
+                    self.modules[module.0].scope.insert(name, item);
+                    continue;
+                }
+                let note = if existing.module == module {
+                    "it is already defined in this module".to_string()
+                } else {
+                    format!(
+                        "it is already imported from `{}`",
+                        self.modules[existing.module.0].name
+                    )
+                };
+                Diag::error(ast.src)
+                    .with_message(format!("`{name}` is imported more than once"))
+                    .with_label(Label::primary((), path.span))
+                    .with_note(note)
+                    .finish()
+                    .emit();
+            }
+            self.modules[module.0].imports.push(import);
+        }
+    }
+
+    fn resolve_import(
+        &self,
+        module: ModuleId,
+        ast: &Module<'src>,
+        path: &Path<'src>,
+    ) -> Option<Import<'src>> {
+        let dotted = path.to_dotted();
+        if let Some(target) = self.module_by_name(&dotted) {
+            if target == module {
+                Diag::error(ast.src)
+                    .with_message("a module cannot import itself")
+                    .with_label(Label::primary((), path.span))
+                    .finish()
+                    .emit();
+                return None;
+            }
+            return Some(Import {
+                module: target,
+                item: None,
+                span: path.span,
+            });
+        }
+
+        let (last, prefix) = path.segments.split_last()?;
+        let prefix_name: Vec<_> = prefix.iter().map(|x| x.name).collect();
+        let prefix_name = prefix_name.join(".");
+        let Some(target) = self.module_by_name(&prefix_name) else {
+            Diag::error(ast.src)
+                .with_message(format!("module `{dotted}` does not exist"))
+                .with_label(Label::primary((), path.span).with_message("no such module"))
+                .finish()
+                .emit();
+            return None;
+        };
+        if target == module {
+            Diag::error(ast.src)
+                .with_message("a module cannot import itself")
+                .with_label(Label::primary((), path.span))
+                .finish()
+                .emit();
+            return None;
+        }
+        let Some(item) = self.modules[target.0].items.get(last.name).copied() else {
+            Diag::error(ast.src)
+                .with_message(format!(
+                    "module `{prefix_name}` has no item named `{}`",
+                    last.name
+                ))
+                .with_label(Label::primary((), last.span).with_message("not found"))
+                .finish()
+                .emit();
+            return None;
+        };
+        if !self.item(item).public {
+            Diag::error(ast.src)
+                .with_message(format!(
+                    "{} `{}` is private",
+                    self.item(item).kind.describe(),
+                    last.name
+                ))
+                .with_label(Label::primary((), last.span).with_message("private item"))
+                .with_note(format!(
+                    "mark it `pub` in `{prefix_name}` to use it from other modules"
+                ))
+                .finish()
+                .emit();
+            return None;
+        }
+        Some(Import {
+            module: target,
+            item: Some(item),
+            span: path.span,
+        })
+    }
+
+    /// Reports every import that closes a cycle.
+    fn check_cycles(&self, modules: &[Module<'src>]) {
+        #[derive(Clone, Copy, PartialEq)]
+        enum State {
+            Unvisited,
+            Visiting,
+            Done,
+        }
+
+        fn visit(
+            graph: &ModuleGraph,
+            modules: &[Module],
+            module: ModuleId,
+            states: &mut [State],
+            stack: &mut Vec<ModuleId>,
+        ) {
+            states[module.0] = State::Visiting;
+            stack.push(module);
+            for import in &graph.modules[module.0].imports {
+                match states[import.module.0] {
+                    State::Unvisited => visit(graph, modules, import.module, states, stack),
+                    State::Visiting => {
+                        let start = stack
+                            .iter()
+                            .position(|x| *x == import.module)
+                            .expect("visiting modules are on the stack");
+                        let mut cycle: Vec<_> = stack[start..]
+                            .iter()
+                            .map(|x| graph.modules[x.0].name.as_str())
+                            .collect();
+                        cycle.push(&graph.modules[import.module.0].name);
+                        Diag::error(modules[module.0].src)
+                            .with_message("import cycle detected")
+                            .with_label(
+                                Label::primary((), import.span)
+                                    .with_message("this import closes the cycle"),
+                            )
+                            .with_note(format!("cycle: {}", cycle.join(" -> ")))
+                            .finish()
+                            .emit();
+                    }
+                    State::Done => {}
+                }
+            }
+            stack.pop();
+            states[module.0] = State::Done;
+        }
+
+        let mut states = vec![State::Unvisited; self.modules.len()];
+        let mut stack = Vec::new();
+        for module in 0..self.modules.len() {
+            if states[module] == State::Unvisited {
+                visit(self, modules, ModuleId(module), &mut states, &mut stack);
+            }
+        }
+    }
+}
+
+#[test]
+fn import_test() {
+    use crate::Source;
+
+    let sources = [
+        Source::new(
+            r"identifier app.main
+use app.printer
+use app.util.helper
+func void start() is end",
+            "main.scp",
+        ),
+        Source::new(
+            r"identifier app.printer
+pub obj printer is end
+func void secret() is end",
+            "printer.scp",
+        ),
+        Source::new(
+            r"identifier app.util
+pub func void helper() is end
+pub func void other() is end",
+            "util.scp",
+        ),
+    ];
+    let modules = crate::project::test_modules(&sources);
+    let errors = crate::diag::error_count();
+    let graph = ModuleGraph::build(&modules);
+    assert_eq!(crate::diag::error_count(), errors);
+
+    let main = ModuleId(0);
+    let printer = graph.lookup(main, "printer").unwrap();
+    assert_eq!(graph.item(printer).module, ModuleId(1));
+    assert!(graph.lookup(main, "helper").is_some());
+    assert!(graph.lookup(main, "other").is_none());
+    assert!(graph.lookup(main, "secret").is_none());
+    assert!(graph.private_import(main, "secret").is_some());
+}
+
+#[test]
+fn import_error_test() {
+    use crate::Source;
+
+    let sources = [
+        Source::new(
+            r"identifier app.a
+use app.b
+use app.c.secret
+use app.missing",
+            "a.scp",
+        ),
+        Source::new(
+            r"identifier app.b
+use app.c",
+            "b.scp",
+        ),
+        Source::new(
+            r"identifier app.c
+use app.a
+func void secret() is end",
+            "c.scp",
+        ),
+    ];
+    let modules = crate::project::test_modules(&sources);
+    let errors = crate::diag::error_count();
+    ModuleGraph::build(&modules);
+    // Private item, missing module and the a -> b -> c -> a cycle
+    assert_eq!(crate::diag::error_count(), errors + 3);
+}
+
+#[test]
+fn duplicate_module_test() {
+    use crate::Source;
+
+    let sources = [
+        Source::new(
+            r"identifier app.util
+pub func void helper() is end",
+            "util.scp",
+        ),
+        Source::new(
+            r"identifier app.util
+pub func void other() is end",
+            "deps/util.scp",
+        ),
+    ];
+    let modules = crate::project::test_modules(&sources);
+    let errors = crate::diag::error_count();
+    let graph = ModuleGraph::build(&modules);
+    assert_eq!(crate::diag::error_count(), errors + 1);
+    assert_eq!(graph.module_by_name("app.util"), Some(ModuleId(0)));
+}
@@ -0,0 +1,1049 @@
+#![deny(missing_docs)]
+//! Module for ownership analysis: working out when values are moved and when they are dropped.
+//!
+//! Every local binding owning memory (see [`TyInterner::needs_drop`]) owns its value. Using the
+//! binding where a value is needed (initializing another variable, passing it to a parameter
+//! inferred as [owned](crate::borrow::PassMode::Owned), returning it, ...) moves the value out,
+//! after which the binding can't be used until it is assigned again. Reading a field, calling a
+//! method on the binding or using it as an operand only borrows it. The arguments of a `bulk`
+//! call besides the array are evaluated once and shared by every call, so they can't be moved.
+//!
+//! A value that isn't moved away is dropped right after the last statement of its block that
+//! uses it. Values still alive when the function returns are dropped when it returns. Parameters
+//! are only dropped by the function if it owns them; borrowed ones are dropped by the caller.
+//!
+//! # Reference counting
+//! Some values can't be given a single static owner. Moving a value out of a field leaves the
+//! object holding it too, so the value has two owners, and an object type that can contain
+//! itself (through its fields or arrays in them) can form cycles no owner is responsible for.
+//! Values of such fields and types fall back to reference counting; everything else is freed by
+//! the drops above. [`lint_rc`] explains why each of them was demoted.
+//!
+//! The builtin `drop(value)` ends the lifetime of a binding early. The analysis follows the
+//! function in execution order, so a binding that is dropped or moved stays unusable on every
+//! path after it until it is assigned a new value.
+//!
+//! # Control flow
+//! The branches of an `if` are checked from the same starting state, and a value moved in only
+//! some of them counts as moved after the `if`. Loop bodies are checked a second time if they move
+//! values declared outside of the loop, so a use of such a value is reported as a use after a move
+//! in the previous iteration. A value moved on some paths but not others still has to be dropped
+//! when it wasn't moved, so it gets a [drop flag](Ownership::drop_flags).
+//!
+//! The variable of a `for` loop borrows the items of the array in turn, so its value can't be
+//! moved out or replaced. The array itself stays borrowed by the loop: neither it nor the
+//! variables and fields holding it can be moved, dropped, replaced or passed to a mutable
+//! parameter in the body, as that would free the item the variable borrows.
+
+use std::{
+    collections::{HashMap, HashSet},
+    iter, mem, slice,
+};
+
+use codespan_reporting::diagnostic::Label;
+
+use crate::{
+    ast::{Block, Declaration, Expr, ExprKind, Func, NodeId, Stmt, StmtKind},
+    borrow::{Borrows, PassMode, Passing},
+    builtins::Builtin,
+    diag::Diag,
+    modules::{ItemId, ModuleGraph},
+    project::Module,
+    resolve::{Res, Resolutions},
+    span::Span,
+    ty::{TyInterner, TyKind, check::TypeckResults},
+};
+
+/// The result of ownership analysis.
+#[derive(Debug, Default)]
+pub struct Ownership<'src> {
+    /// Every name expression that moves the value out of its binding.
+    pub moves: HashSet<NodeId>,
+    /// The bindings to drop after each statement, keyed by the id of the statement.
+    pub drops: HashMap<NodeId, Vec<NodeId>>,
+    /// Bindings that are moved on some paths to their drop but not others. They are only dropped
+    /// if they still hold a value, which backends track with a flag.
+    pub drop_flags: HashSet<NodeId>,
+    /// Fields whose values are reference counted, as their object type and index, with the
+    /// first expression moving a value out of them.
+    pub rc_fields: HashMap<(ItemId, usize), Span<'src>>,
+    /// Object types whose values are reference counted because they can contain themselves,
+    /// with the index of the field they can contain themselves through.
+    pub rc_types: HashMap<ItemId, usize>,
+}
+
+impl Ownership<'_> {
+    /// Checks if values of the object type `obj` or stored in any of `fields` are reference
+    /// counted.
+    pub fn is_rc(&self, obj: ItemId, fields: &[(ItemId, usize)]) -> bool {
+        self.rc_types.contains_key(&obj) || fields.iter().any(|x| self.rc_fields.contains_key(x))
+    }
+}
+
+/// Finds the moves and drops of every function of `modules`, reporting uses of moved values
+/// through [`Diag`].
+pub fn analyze<'src>(
+    modules: &[Module<'src>],
+    graph: &ModuleGraph<'src>,
+    res: &Resolutions<'src>,
+    typeck: &TypeckResults<'src>,
+    borrows: &Borrows<'src>,
+) -> Ownership<'src> {
+    let mut analyzer = Analyzer {
+        graph,
+        res,
+        typeck,
+        borrows,
+        gone: HashMap::new(),
+        used: Vec::new(),
+        loops: Vec::new(),
+        loop_vars: HashSet::new(),
+        iterated: Vec::new(),
+        reported: HashSet::new(),
+        out: Ownership::default(),
+    };
+    for module in modules {
+        for decl in &module.decls {
+            match decl {
+                Declaration::Func(func) => analyzer.func(func),
+                Declaration::Impl(imp) => imp.methods.iter().for_each(|x| analyzer.func(x)),
+                _ => {}
+            }
+        }
+    }
+    analyzer.cyclic_types();
+    analyzer.out
+}
+
+/// Reports why every reference counted field and type was demoted, as warnings.
+pub fn lint_rc(ownership: &Ownership, graph: &ModuleGraph, typeck: &TypeckResults) {
+    let mut fields: Vec<_> = ownership.rc_fields.iter().collect();
+    fields.sort_by_key(|(_, span)| (span.source().path(), span.start));
+    for ((obj, index), span) in fields {
+        let field = &typeck.items.objs[obj][*index];
+        Diag::warn(span.source())
+            .with_code("rc-fallback")
+            .with_message(format!(
+                "values of field `{}` are reference counted",
+                field.name.name
+            ))
+            .with_label(Label::primary((), *span).with_message("value moved out of the field here"))
+            .with_note(format!(
+                "`{}` keeps the value too, so it has two owners and can't be freed statically",
+                graph.item(*obj).name.name
+            ))
+            .finish()
+            .emit();
+    }
+    let mut types: Vec<_> = ownership.rc_types.iter().collect();
+    types.sort_by_key(|(obj, _)| obj.0);
+    for (obj, index) in types {
+        let name = graph.item(*obj).name;
+        let field = &typeck.items.objs[obj][*index];
+        Diag::warn(name.span.source())
+            .with_code("rc-fallback")
+            .with_message(format!(
+                "values of type `{}` are reference counted",
+                name.name
+            ))
+            .with_label(Label::primary((), name.span))
+            .with_secondary(
+                field.ty_span,
+                format!("`{}` can contain itself through this field", name.name),
+            )
+            .with_note(
+                "values that can contain themselves can form cycles, so no single owner frees them",
+            )
+            .finish()
+            .emit();
+    }
+}
+
+/// How an expression's value is used.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Mode {
+    /// The value is moved into something else.
+    Move,
+    /// The value is moved into the parameter with this id.
+    Pass(NodeId),
+    /// The value is only looked at.
+    Borrow,
+    /// The value is given to `drop`.
+    Drop,
+}
+
+/// Why a binding can't be used anymore.
+#[derive(Debug, Clone, Copy)]
+struct Gone<'src> {
+    /// The expression that moved or dropped the value.
+    span: Span<'src>,
+    /// Whether the value was dropped instead of moved.
+    dropped: bool,
+    /// The parameter the value was moved into, if it was passed to a function.
+    into: Option<NodeId>,
+    /// Whether a use of the binding after this was already reported.
+    reported: bool,
+    /// Whether the value is only gone on some of the paths leading here.
+    maybe: bool,
+}
+
+/// A variable or a field of one, as the binding and the names of the fields leading to it.
+type Place<'src> = (NodeId, Vec<&'src str>);
+
+/// Which bindings are gone at some point of a function.
+type State<'src> = HashMap<NodeId, Gone<'src>>;
+
+/// The states at the `break`s and `continue`s of a loop.
+#[derive(Default)]
+struct Exits<'src> {
+    breaks: Vec<State<'src>>,
+    continues: Vec<State<'src>>,
+}
+
+/// Merges the states of paths coming together, or gives `None` if no path does.
+fn join<'src>(states: Vec<State<'src>>) -> Option<State<'src>> {
+    let count = states.len();
+    let mut joined = State::new();
+    let mut seen: HashMap<NodeId, usize> = HashMap::new();
+    for state in states {
+        for (binding, gone) in state {
+            *seen.entry(binding).or_default() += 1;
+            joined
+                .entry(binding)
+                .and_modify(|x| x.maybe |= gone.maybe)
+                .or_insert(gone);
+        }
+    }
+    for (binding, gone) in &mut joined {
+        gone.maybe |= seen[binding] < count;
+    }
+    (count > 0).then_some(joined)
+}
+
+struct Analyzer<'a, 'src> {
+    graph: &'a ModuleGraph<'src>,
+    res: &'a Resolutions<'src>,
+    typeck: &'a TypeckResults<'src>,
+    borrows: &'a Borrows<'src>,
+    /// Every binding whose value was moved or dropped.
+    gone: State<'src>,
+    /// Every binding used so far, in order. Blocks look at what was pushed while checking each
+    /// statement to find the last use of their bindings.
+    used: Vec<NodeId>,
+    /// The exits of the loops being checked, innermost last.
+    loops: Vec<Exits<'src>>,
+    /// The variables of `for` loops, which borrow their values.
+    loop_vars: HashSet<NodeId>,
+    /// The arrays the `for` loops being checked go through, innermost last, as the variable and
+    /// the fields leading to them.
+    iterated: Vec<(Place<'src>, Span<'src>)>,
+    /// Expressions errors were reported at, since loop bodies are checked twice.
+    reported: HashSet<NodeId>,
+    out: Ownership<'src>,
+}
+
+impl<'a, 'src> Analyzer<'a, 'src> {
+    fn tys(&self) -> &'a TyInterner {
+        &self.typeck.tys
+    }
+
+    /// Gets the binding a name expression refers to, if it owns its value.
+    fn owned_binding(&self, expr: &Expr) -> Option<NodeId> {
+        let Some(Res::Local(binding)) = self.res.names.get(&expr.id) else {
+            return None;
+        };
+        let ty = self.typeck.bindings.get(binding)?;
+        self.tys().needs_drop(*ty).then_some(*binding)
+    }
+
+    fn func(&mut self, func: &Func<'src>) {
+        self.gone.clear();
+        self.used.clear();
+        // Borrowed parameters are dropped by the caller
+        let params: Vec<_> = func
+            .sig
+            .params
+            .iter()
+            .map(|x| x.id)
+            .filter(|x| self.borrows.bindings.get(x) == Some(&PassMode::Owned))
+            .collect();
+        self.block(&func.body, &params);
+    }
+
+    /// Checks a block, where `params` are bindings declared before its first statement.
+    fn block(&mut self, block: &Block<'src>, params: &[NodeId]) {
+        let mut last_use: HashMap<NodeId, usize> = HashMap::new();
+        let mut declared: Vec<(NodeId, usize)> = Vec::new();
+        for binding in params {
+            declared.push((*binding, 0));
+        }
+        for (index, stmt) in block.stmts.iter().enumerate() {
+            let start = self.used.len();
+            self.stmt(stmt);
+            for binding in &self.used[start..] {
+                last_use.insert(*binding, index);
+            }
+            if let StmtKind::Local { .. } = stmt.kind {
+                declared.push((stmt.id, index));
+            }
+        }
+
+        for (binding, index) in declared {
+            let Some(ty) = self.typeck.bindings.get(&binding) else {
+                continue;
+            };
+            if !self.tys().needs_drop(*ty) {
+                continue;
+            }
+            match self.gone.remove(&binding) {
+                Some(gone) if !gone.maybe => continue,
+                Some(_) => {
+                    self.out.drop_flags.insert(binding);
+                }
+                None => {}
+            }
+            // Parameters that are never used live until the function returns
+            let Some(last) = last_use.get(&binding).copied().or_else(|| {
+                let is_local = !params.contains(&binding);
+                is_local.then_some(index)
+            }) else {
+                continue;
+            };
+            let drops = self.out.drops.entry(block.stmts[last].id).or_default();
+            if !drops.contains(&binding) {
+                drops.push(binding);
+            }
+        }
+    }
+
+    fn stmt(&mut self, stmt: &Stmt<'src>) {
+        match &stmt.kind {
+            StmtKind::Local { init, .. } => {
+                self.expr(init, Mode::Move);
+                // A variable declared in a loop gets a new value in every iteration
+                self.gone.remove(&stmt.id);
+            }
+            StmtKind::Assign { target, value } => {
+                self.expr(value, Mode::Move);
+                self.check_iterated(target, "assign to");
+                if let ExprKind::Name(name) = target.kind
+                    && let Some(binding) = self.owned_binding(target)
+                {
+                    if self.loop_vars.contains(&binding) {
+                        self.borrowed_loop_var(target, name.name, "assign to");
+                        return;
+                    }
+                    // Assigning gives the binding a new value, even if the old one was moved
+                    self.gone.remove(&binding);
+                    self.used.push(binding);
+                } else {
+                    self.expr(target, Mode::Borrow);
+                }
+            }
+            StmtKind::Increment(target) | StmtKind::Expr(target) => self.expr(target, Mode::Borrow),
+            StmtKind::Bulk(call) => {
+                self.bulk_args(call);
+                self.expr(call, Mode::Borrow);
+            }
+            StmtKind::Return(value) => {
+                if let Some(value) = value {
+                    self.expr(value, Mode::Move);
+                }
+            }
+            StmtKind::If { cond, then, els } => {
+                self.expr(cond, Mode::Borrow);
+                let start = self.gone.clone();
+                let mut ends = Vec::new();
+                for block in iter::once(then).chain(els) {
+                    self.gone = start.clone();
+                    self.block(block, &[]);
+                    if !block.diverges() {
+                        ends.push(mem::take(&mut self.gone));
+                    }
+                }
+                if els.is_none() {
+                    ends.push(start.clone());
+                }
+                self.gone = join(ends).unwrap_or(start);
+            }
+            StmtKind::While { cond, body } => self.looped(Some(cond), body),
+            StmtKind::For { iter, body, .. } => {
+                self.loop_vars.insert(stmt.id);
+                match self.place(iter) {
+                    // The loop variable borrows from the array for the whole body
+                    Some(place) => {
+                        self.iterated.push((place, iter.span));
+                        self.looped(Some(iter), body);
+                        self.iterated.pop();
+                    }
+                    None => {
+                        self.expr(iter, Mode::Borrow);
+                        self.looped(None, body);
+                    }
+                }
+            }
+            StmtKind::Break => {
+                let state = self.gone.clone();
+                if let Some(exits) = self.loops.last_mut() {
+                    exits.breaks.push(state);
+                }
+            }
+            StmtKind::Continue => {
+                let state = self.gone.clone();
+                if let Some(exits) = self.loops.last_mut() {
+                    exits.continues.push(state);
+                }
+            }
+        }
+    }
+
+    /// Checks a loop, with the condition checked before every iteration if it is a `while`.
+    fn looped(&mut self, cond: Option<&Expr<'src>>, body: &Block<'src>) {
+        let entry = self.gone.clone();
+        let infinite = cond.is_some_and(|x| matches!(x.kind, ExprKind::Bool(true)));
+        let mut exits = Vec::new();
+        for pass in 0..2 {
+            if let Some(cond) = cond {
+                self.expr(cond, Mode::Borrow);
+            }
+            exits.clear();
+            if !infinite {
+                exits.push(self.gone.clone());
+            }
+            self.loops.push(Exits::default());
+            self.block(body, &[]);
+            let frame = self
+                .loops
+                .pop()
+                .expect("the loop's exits were pushed above");
+            exits.extend(frame.breaks);
+            let mut back = frame.continues;
+            if !body.diverges() {
+                back.push(mem::take(&mut self.gone));
+            }
+            // Check the body again if an iteration moves values the next one might use
+            let moved = back
+                .iter()
+                .any(|x| x.keys().any(|x| !entry.contains_key(x)));
+            if pass == 1 || !moved {
+                break;
+            }
+            back.push(entry.clone());
+            self.gone = join(back).expect("the entry state is always joined");
+        }
+        self.gone = join(exits).unwrap_or_default();
+    }
+
+    /// Gets the place `expr` names, if it is a variable or a field of one.
+    fn place(&self, expr: &Expr<'src>) -> Option<Place<'src>> {
+        match &expr.kind {
+            ExprKind::Name(_) => match self.res.names.get(&expr.id) {
+                Some(Res::Local(binding)) => Some((*binding, Vec::new())),
+                _ => None,
+            },
+            ExprKind::Field { base, field } => {
+                let (binding, mut fields) = self.place(base)?;
+                fields.push(field.name);
+                Some((binding, fields))
+            }
+            _ => None,
+        }
+    }
+
+    /// Reports moving or replacing an array a `for` loop goes through, or an object holding it,
+    /// which would free the item its variable borrows. Gives whether `expr` holds such an array.
+    fn check_iterated(&mut self, expr: &Expr<'src>, action: &str) -> bool {
+        let Some((binding, fields)) = self.place(expr) else {
+            return false;
+        };
+        let iter = self
+            .iterated
+            .iter()
+            .find(|((x, path), _)| *x == binding && path.starts_with(&fields));
+        let Some((_, iter)) = iter else {
+            return false;
+        };
+        if !self.reported.insert(expr.id) {
+            return true;
+        }
+        Diag::error(expr.span.source())
+            .with_message(format!(
+                "cannot {action} `{}` while a `for` loop goes through `{}`",
+                expr.span.apply(),
+                iter.apply()
+            ))
+            .with_label(Label::primary((), expr.span))
+            .with_secondary(*iter, "the loop borrows the items of this array")
+            .with_note("the item the loop variable borrows would be freed with the array")
+            .finish()
+            .emit();
+        true
+    }
+
+    /// Reports moving or assigning to the variable of a `for` loop.
+    fn borrowed_loop_var(&mut self, expr: &Expr<'src>, name: &str, action: &str) {
+        if !self.reported.insert(expr.id) {
+            return;
+        }
+        Diag::error(expr.span.source())
+            .with_message(format!(
+                "cannot {action} `{name}`, which borrows an item of the array"
+            ))
+            .with_label(Label::primary((), expr.span))
+            .with_note(format!(
+                "`{name}` is the variable of a `for` loop, which stays in the array it is from"
+            ))
+            .finish()
+            .emit();
+    }
+
+    fn expr(&mut self, expr: &Expr<'src>, mode: Mode) {
+        match &expr.kind {
+            ExprKind::Name(name) => {
+                let Some(binding) = self.owned_binding(expr) else {
+                    return;
+                };
+                self.used.push(binding);
+                if let Some(gone) = self.gone.get_mut(&binding)
+                    && !gone.reported
+                {
+                    // Only the first use after the move or drop is reported
+                    gone.reported = true;
+                    let gone = *gone;
+                    if self.reported.insert(expr.id) {
+                        self.use_after_gone(expr, name.name, binding, gone);
+                    }
+                }
+                let action = match mode {
+                    Mode::Drop => "drop",
+                    _ => "move",
+                };
+                if mode != Mode::Borrow && self.loop_vars.contains(&binding) {
+                    self.borrowed_loop_var(expr, name.name, "move out of");
+                } else if mode != Mode::Borrow && self.check_iterated(expr, action) {
+                    // Reported above, the value stays where it is
+                } else if mode != Mode::Borrow {
+                    let into = match mode {
+                        Mode::Pass(param) => Some(param),
+                        _ => None,
+                    };
+                    let gone = Gone {
+                        span: expr.span,
+                        dropped: mode == Mode::Drop,
+                        into,
+                        reported: false,
+                        maybe: false,
+                    };
+                    self.gone.insert(binding, gone);
+                    self.out.moves.insert(expr.id);
+                }
+            }
+            ExprKind::Str(_) | ExprKind::Int(_) | ExprKind::Float(_) | ExprKind::Bool(_) => {}
+            ExprKind::Array(items) => items.iter().for_each(|x| self.expr(x, Mode::Move)),
+            ExprKind::Field { base, .. } => {
+                self.expr(base, Mode::Borrow);
+                if !matches!(mode, Mode::Borrow)
+                    && self.tys().needs_drop(self.typeck.expr_ty(expr.id))
+                    && let Some(field) = self.typeck.field(expr)
+                {
+                    self.out.rc_fields.entry(field).or_insert(expr.span);
+                }
+            }
+            ExprKind::Call { callee, args } if self.is_drop(callee) => {
+                self.expr(callee, Mode::Borrow);
+                for arg in args {
+                    if !matches!(arg.kind, ExprKind::Name(_)) && self.reported.insert(arg.id) {
+                        Diag::error(arg.span.source())
+                            .with_message("only variables can be dropped")
+                            .with_label(Label::primary((), arg.span))
+                            .with_note("temporary values are dropped when they are no longer used")
+                            .finish()
+                            .emit();
+                    }
+                    self.expr(arg, Mode::Drop);
+                }
+            }
+            ExprKind::Call { callee, args } => self.call(expr, callee, args),
+            ExprKind::Construct { fields, .. } => {
+                for field in fields {
+                    self.expr(&field.value, Mode::Move);
+                }
+            }
+            ExprKind::Binary { lhs, rhs, .. } if self.typeck.methods.contains_key(&expr.id) => {
+                self.call(expr, lhs, slice::from_ref(rhs));
+            }
+            ExprKind::Binary { lhs, rhs, .. } => {
+                self.expr(lhs, Mode::Borrow);
+                self.expr(rhs, Mode::Borrow);
+            }
+            ExprKind::Unary { operand, .. } => self.expr(operand, Mode::Borrow),
+        }
+    }
+
+    /// Checks a call or an overloaded operator.
+    fn call(&mut self, expr: &Expr<'src>, callee: &Expr<'src>, args: &[Expr<'src>]) {
+        let modes = self.borrows.calls.get(&expr.id);
+        match (self.typeck.receiver(expr), modes.and_then(|x| x.receiver)) {
+            (Some(base), Some(receiver)) => {
+                self.check_mutable(base, receiver);
+                self.expr(base, passing_mode(receiver.mode, receiver.param));
+            }
+            _ => self.expr(callee, Mode::Borrow),
+        }
+        for (index, arg) in args.iter().enumerate() {
+            let passing = modes.and_then(|x| x.args.get(index));
+            if let Some(passing) = passing {
+                self.check_mutable(arg, *passing);
+            }
+            let mode = passing.map_or(Mode::Borrow, |x| passing_mode(x.mode, x.param));
+            self.expr(arg, mode);
+        }
+    }
+
+    /// Reports passing an array a `for` loop goes through to a mutable parameter, which can
+    /// replace it. The builtin `push` only adds to the array, so it is fine.
+    fn check_mutable(&mut self, arg: &Expr<'src>, passing: Passing) {
+        if passing.mode == PassMode::Mutable && passing.param.is_some() {
+            self.check_iterated(arg, "mutably borrow");
+        }
+    }
+
+    /// Reports arguments of a `bulk` call other than the array that are moved into the calls.
+    /// They are evaluated once, so every call would take over the same value.
+    fn bulk_args(&mut self, call: &Expr<'src>) {
+        let ExprKind::Call { args, .. } = &call.kind else {
+            return;
+        };
+        let Some(modes) = self.borrows.calls.get(&call.id) else {
+            return;
+        };
+        let receiver = self.typeck.receiver(call).zip(modes.receiver);
+        let fixed = args
+            .iter()
+            .zip(&modes.args)
+            .take(args.len().saturating_sub(1));
+        for (arg, passing) in receiver.into_iter().chain(fixed.map(|(x, y)| (x, *y))) {
+            let owned = passing.mode == PassMode::Owned
+                && self.tys().needs_drop(self.typeck.expr_ty(arg.id));
+            if !owned || !self.reported.insert(arg.id) {
+                continue;
+            }
+            let diag = Diag::error(arg.span.source())
+                .with_message("cannot move a value into every call of `bulk`")
+                .with_label(Label::primary((), arg.span).with_message("evaluated once"))
+                .with_note("every call would take over the same value");
+            let diag = match passing.param {
+                Some(param) => {
+                    let name = self.res.bindings[&param].name;
+                    let diag = diag.with_note(format!(
+                        "the value is passed to the parameter `{name}`, which is inferred as owned"
+                    ));
+                    match self.borrows.reasons.get(&param) {
+                        Some(reason) => diag.with_secondary(
+                            *reason,
+                            format!("`{name}` is owned because it is moved here"),
+                        ),
+                        None => diag,
+                    }
+                }
+                None => diag,
+            };
+            diag.finish().emit();
+        }
+    }
+
+    /// Finds the object types that can contain themselves.
+    fn cyclic_types(&mut self) {
+        let objs = &self.typeck.items.objs;
+        for (obj, fields) in objs {
+            'fields: for (index, field) in fields.iter().enumerate() {
+                let mut seen = HashSet::new();
+                let mut stack = vec![field.ty];
+                while let Some(ty) = stack.pop() {
+                    match self.tys().kind(ty) {
+                        TyKind::Array(elem) => stack.push(*elem),
+                        TyKind::Obj(inner) if inner == obj => {
+                            self.out.rc_types.insert(*obj, index);
+                            break 'fields;
+                        }
+                        TyKind::Obj(inner) if seen.insert(*inner) => {
+                            stack.extend(objs[inner].iter().map(|x| x.ty));
+                        }
+                        _ => {}
+                    }
+                }
+            }
+        }
+    }
+
+    /// Reports a use of `binding` after its value was moved or dropped.
+    fn use_after_gone(&self, expr: &Expr<'src>, name: &str, binding: NodeId, gone: Gone<'src>) {
+        // Only a previous iteration of a loop can move the value after the use
+        let previous = if gone.span.start >= expr.span.start {
+            ", in previous iteration of loop"
+        } else {
+            ""
+        };
+        let diag = if gone.dropped {
+            Diag::error(expr.span.source())
+                .with_message("value used after drop")
+                .with_label(
+                    Label::primary((), expr.span).with_message("value used here after drop"),
+                )
+                .with_secondary(gone.span, format!("value dropped here{previous}"))
+                .with_note(format!("`{name}` can't be used anymore once it is dropped"))
+        } else {
+            let ty = self.typeck.bindings[&binding];
+            Diag::error(expr.span.source())
+                .with_message(format!("use of moved value: `{name}`"))
+                .with_label(
+                    Label::primary((), expr.span).with_message("value used here after move"),
+                )
+                .with_secondary(gone.span, format!("value moved here{previous}"))
+                .with_note(format!(
+                    "`{name}` has type `{}`, which is moved instead of copied",
+                    self.typeck.tys.name(ty, self.graph)
+                ))
+        };
+        // Explain why the parameter it was passed to takes ownership
+        let diag = match gone.into {
+            Some(param) if !gone.dropped => {
+                let param_name = self.res.bindings[&param].name;
+                let diag = diag.with_note(format!(
+                    "the value was moved into the parameter `{param_name}`, which is inferred as owned"
+                ));
+                match self.borrows.reasons.get(&param) {
+                    Some(reason) => diag.with_secondary(
+                        *reason,
+                        format!("`{param_name}` is owned because it is moved here"),
+                    ),
+                    None => diag,
+                }
+            }
+            _ => diag,
+        };
+        diag.finish().emit();
+    }
+
+    /// Checks if `callee` is the builtin `drop`.
+    fn is_drop(&self, callee: &Expr) -> bool {
+        matches!(
+            self.res.names.get(&callee.id),
+            Some(Res::Builtin(Builtin::Drop))
+        )
+    }
+}
+
+/// Gets how an argument passed as `mode` to `param` is used.
+fn passing_mode(mode: PassMode, param: Option<NodeId>) -> Mode {
+    match (mode, param) {
+        (PassMode::Owned, Some(param)) => Mode::Pass(param),
+        (PassMode::Owned, None) => Mode::Move,
+        (PassMode::Shared | PassMode::Mutable, _) => Mode::Borrow,
+    }
+}
+
+#[cfg(test)]
+fn analyze_source(text: &str) -> (usize, Vec<(String, Vec<String>)>) {
+    let sources = [crate::Source::new(text, "main.scp")];
+    let modules = crate::project::test_modules(&sources);
+    let graph = ModuleGraph::build(&modules);
+    let res = crate::resolve::resolve(&modules, &graph);
+    let typeck = crate::ty::check::check(&modules, &graph, &res);
+    let errors = crate::diag::error_count();
+    let borrows = crate::borrow::infer(&modules, &graph, &res, &typeck);
+    let ownership = analyze(&modules, &graph, &res, &typeck, &borrows);
+    let errors = crate::diag::error_count() - errors;
+
+    // The drops of `start`, as the statement they follow and the dropped bindings
+    let Some(Declaration::Func(start)) = modules[0]
+        .decls
+        .iter()
+        .find(|x| matches!(x, Declaration::Func(func) if func.sig.name.name == "start"))
+    else {
+        panic!("expected start");
+    };
+    let drops = start
+        .body
+        .stmts
+        .iter()
+        .filter_map(|stmt| {
+            let drops = ownership.drops.get(&stmt.id)?;
+            let names = drops
+                .iter()
+                .map(|x| res.bindings[x].name.to_string())
+                .collect();
+            Some((stmt.span.apply().to_string(), names))
+        })
+        .collect();
+    (errors, drops)
+}
+
+#[test]
+fn drop_test() {
+    let (errors, drops) = analyze_source(
+        r"identifier app.main
+obj box is
+    value: i32 = 0,
+end
+func void keep(box b) is
+    box kept = b
+end
+func void start() is
+    box a = box
+    box b = box
+    a.value = 1
+    keep(b)
+    box c = box
+    i32 n = a.value
+    n++
+end",
+    );
+    assert_eq!(errors, 0);
+    // `b` is moved into `keep`, so only `a` and `c` are dropped
+    assert_eq!(
+        drops,
+        [
+            ("box c = box".to_string(), vec!["c".to_string()]),
+            ("i32 n = a.value".to_string(), vec!["a".to_string()]),
+        ]
+    );
+}
+
+#[test]
+fn use_after_move_test() {
+    let (errors, _) = analyze_source(
+        r"identifier app.main
+obj box is
+    value: i32 = 0,
+end
+func void keep(box b) is
+    box kept = b
+end
+func void start() is
+    box a = box
+    box b = a
+    a.value = 1
+    keep(b)
+    keep(b)
+    b = box
+    keep(b)
+end",
+    );
+    // `a` after `box b = a`, and `b` after the first `keep(b)`
+    assert_eq!(errors, 2);
+}
+
+#[test]
+fn use_after_drop_test() {
+    let (errors, drops) = analyze_source(
+        r"identifier app.main
+obj box is
+    value: i32 = 0,
+end
+func void start() is
+    box a = box
+    drop(a)
+    a.value = 1
+    box b = box
+    drop(b)
+    drop(b)
+    b = box
+    b.value = 2
+    i32 n = 3
+    drop(n)
+end",
+    );
+    assert_eq!(errors, 2);
+    // Explicitly dropped values are not dropped again
+    assert_eq!(drops, [("b.value = 2".to_string(), vec!["b".to_string()])]);
+}
+
+#[test]
+fn rc_test() {
+    let sources = [crate::Source::new(
+        r"identifier app.main
+obj box is
+    value: i32 = 0,
+end
+obj node is
+    children: node[] = [],
+end
+obj holder is
+    one: box = box,
+    two: box = box,
+end
+func void start() is
+    holder h = holder
+    box taken = h.one
+    i32 n = h.two.value
+end",
+        "main.scp",
+    )];
+    let modules = crate::project::test_modules(&sources);
+    let graph = ModuleGraph::build(&modules);
+    let res = crate::resolve::resolve(&modules, &graph);
+    let typeck = crate::ty::check::check(&modules, &graph, &res);
+    let borrows = crate::borrow::infer(&modules, &graph, &res, &typeck);
+    let ownership = analyze(&modules, &graph, &res, &typeck, &borrows);
+
+    let names = |obj: &ItemId| graph.item(*obj).name.name;
+    let fields: Vec<_> = ownership
+        .rc_fields
+        .keys()
+        .map(|(obj, index)| (names(obj), *index))
+        .collect();
+    assert_eq!(fields, [("holder", 0)]);
+    let types: Vec<_> = ownership.rc_types.keys().map(names).collect();
+    assert_eq!(types, ["node"]);
+}
+
+#[test]
+fn control_flow_test() {
+    let (errors, drops) = analyze_source(
+        r"identifier app.main
+obj box is
+    value: i32 = 0,
+end
+func void keep(box b) is
+    box kept = b
+end
+func void start() is
+    box a = box
+    box b = box
+    if a.value == 0 is
+        keep(a)
+    end
+    if b.value == 0 is
+        keep(b)
+        return
+    end
+    box[] all = [box]
+    for item in all is
+        i32 n = item.value
+    end
+    i32 m = b.value
+end",
+    );
+    assert_eq!(errors, 0);
+    // `a` is only moved on one path, and `b` is only moved on a path that returns
+    assert_eq!(
+        drops,
+        [
+            (
+                "if a.value == 0 is\n        keep(a)\n    end".to_string(),
+                vec!["a".to_string()]
+            ),
+            (
+                "for item in all is\n        i32 n = item.value\n    end".to_string(),
+                vec!["all".to_string()]
+            ),
+            ("i32 m = b.value".to_string(), vec!["b".to_string()]),
+        ]
+    );
+
+    let (errors, _) = analyze_source(
+        r"identifier app.main
+obj box is
+    value: i32 = 0,
+end
+func void keep(box b) is
+    box kept = b
+end
+func void start() is
+    box a = box
+    while a.value < 3 is
+        keep(a)
+    end
+    box b = box
+    if b.value == 0 is
+        drop(b)
+    end
+    b.value = 1
+    box c = box
+    while true is
+        keep(c)
+        c = box
+        break
+    end
+    for item in [box] is
+        keep(item)
+    end
+end",
+    );
+    // `a` in the next iteration, `b` after the `if` and moving the loop variable
+    assert_eq!(errors, 3);
+}
+
+#[test]
+fn iterated_test() {
+    let (errors, _) = analyze_source(
+        r"identifier app.main
+obj log is
+    lines: string[] = [],
+    count: i32 = 0,
+end
+func void clear(string[] lines) is
+    lines = []
+end
+func void keep(log l) is
+    log kept = l
+end
+func void start() is
+    log a = log
+    for line in a.lines is
+        a.lines.push('x')
+        a.count = 1
+        a.lines = []
+        clear(a.lines)
+        a = log
+    end
+    for line in a.lines is
+        keep(a)
+        print(line)
+        break
+    end
+    log b = log
+    for line in b.lines is
+        drop(b)
+        break
+    end
+    for line in b.lines is
+        print(line)
+    end
+    keep(b)
+end",
+    );
+    // Replacing `a.lines` three ways, then moving `a` and dropping `b` even right before leaving
+    // the loop
+    assert_eq!(errors, 5);
+}
+
+#[test]
+fn bulk_test() {
+    let (errors, _) = analyze_source(
+        r"identifier app.main
+obj box is
+    items: box[] = [],
+end
+func void keep(box b, i32 n) is
+    box kept = b
+end
+func void show(box b, i32 n) is
+end
+impl box is
+    func void take(box other) is
+        self.items.push(other)
+    end
+end
+func void start() is
+    box a = box
+    bulk show(a, [1, 2])
+    bulk a.items.push([box, box])
+    bulk a.take([box])
+    bulk keep(a, [1, 2])
+end",
+    );
+    // Only `a` passed to `keep`
+    assert_eq!(errors, 1);
+}
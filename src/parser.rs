@@ -1,26 +1,830 @@
+#![deny(missing_docs)]
+//! Module for turning the tokens of a [`Lexer`] into the [`ast`](crate::ast) of a file.
+
+use codespan_reporting::diagnostic::Label;
 use peek_again::Peekable;
 
-use crate::{Source, lexer::Lexer};
+use crate::{
+    Source,
+    ast::{
+        BinOp, Block, Declaration, Expr, ExprKind, ExternFunc, Field, FieldInit, Func, FuncSig,
+        Ident, Impl, NodeId, Obj, Param, Path, Stmt, StmtKind, TypeExpr, UnOp,
+    },
+    diag::Diag,
+    lexer::{Lexer, LexerValue, Token, TokenType},
+    span::Span,
+};
 
+/// Escoop parser. Turns the tokens of a source file into [`Declaration`]s.
+///
+/// Syntax errors are reported through [`Diag`]. After an error, the parser skips ahead to the
+/// next declaration, so a single mistake doesn't produce a flood of errors.
 pub struct Parser<'src> {
     lexer: Peekable<Lexer<'src>>,
+    src: &'src Source<'src>,
+    last_span: Span<'src>,
+    next_id: u32,
 }
 
-#[derive(Debug)]
-pub enum Declaration {}
-
 impl<'src> Parser<'src> {
+    /// Creates a new `Parser` reading from `src`.
     pub fn new(src: &'src Source<'src>) -> Self {
-        Self::new_from_lexer(Lexer::new(src))
+        Self::new_from_lexer(src, Lexer::new(src))
     }
 
-    pub fn new_from_lexer(lexer: Lexer<'src>) -> Self {
+    /// Creates a new `Parser` reading tokens from an existing [`Lexer`] over `src`.
+    pub fn new_from_lexer(src: &'src Source<'src>, lexer: Lexer<'src>) -> Self {
         Parser {
             lexer: Peekable::new(lexer),
+            src,
+            last_span: Span::new(src),
+            next_id: 0,
+        }
+    }
+
+    /// Makes the parser number its nodes starting at `first`. Parsers of different files in
+    /// the same program should never hand out the same [`NodeId`].
+    pub fn with_first_id(mut self, first: NodeId) -> Self {
+        self.next_id = first.0;
+        self
+    }
+
+    /// Gets the [`NodeId`] the next parsed node will get.
+    pub fn next_id(&self) -> NodeId {
+        NodeId(self.next_id)
+    }
+
+    #[inline]
+    fn id(&mut self) -> NodeId {
+        let id = NodeId(self.next_id);
+        self.next_id += 1;
+        id
+    }
+
+    /// Parses every declaration in the source.
+    pub fn parse(&mut self) -> Vec<Declaration<'src>> {
+        let mut decls = Vec::new();
+        while self.peek_type().is_some() {
+            match self.parse_declaration() {
+                Some(decl) => decls.push(decl),
+                None => self.recover(),
+            }
+        }
+        decls
+    }
+
+    #[inline]
+    fn peek_type(&mut self) -> Option<TokenType> {
+        self.lexer.peek().get().map(Token::token_type)
+    }
+
+    #[inline]
+    fn peek_2_type(&mut self) -> Option<TokenType> {
+        self.lexer.peek_2().map(Token::token_type)
+    }
+
+    #[inline]
+    fn next(&mut self) -> Option<Token<'src>> {
+        let token = self.lexer.next()?;
+        self.last_span = token.span();
+        Some(token)
+    }
+
+    #[inline]
+    fn eat(&mut self, ty: TokenType) -> Option<Token<'src>> {
+        if self.peek_type() == Some(ty) {
+            self.next()
+        } else {
+            None
+        }
+    }
+
+    fn expect(&mut self, ty: TokenType, what: &str) -> Option<Token<'src>> {
+        if let Some(token) = self.eat(ty) {
+            return Some(token);
+        }
+        self.error_expected(what);
+        None
+    }
+
+    fn error_expected(&mut self, what: &str) {
+        let (found, span) = match self.lexer.peek().get() {
+            Some(token) => (format!("`{}`", token.span().apply()), token.span()),
+            None => {
+                let mut span = self.last_span;
+                span.update();
+                ("end of file".to_string(), span)
+            }
+        };
+        Diag::error(self.src)
+            .with_message(format!("expected {what}, found {found}"))
+            .with_label(Label::primary((), span).with_message(format!("expected {what}")))
+            .finish()
+            .emit();
+    }
+
+    /// Skips tokens until something that looks like the start of a declaration.
+    fn recover(&mut self) {
+        self.next();
+        while let Some(ty) = self.peek_type() {
+            if matches!(
+                ty,
+                TokenType::IdentifierKey
+                    | TokenType::Use
+                    | TokenType::Func
+                    | TokenType::Extern
+                    | TokenType::Obj
+                    | TokenType::Impl
+                    | TokenType::Pub
+            ) {
+                break;
+            }
+            self.next();
+        }
+    }
+
+    fn parse_ident(&mut self, what: &str) -> Option<Ident<'src>> {
+        let token = self.expect(TokenType::Identifier, what)?;
+        Some(Ident {
+            name: token.span().apply(),
+            span: token.span(),
+        })
+    }
+
+    fn parse_path(&mut self) -> Option<Path<'src>> {
+        let first = self.parse_ident("a module path")?;
+        let mut span = first.span;
+        let mut segments = vec![first];
+        while self.eat(TokenType::Dot).is_some() {
+            let segment = self.parse_ident("a path segment")?;
+            span = span.to(segment.span);
+            segments.push(segment);
         }
+        Some(Path { segments, span })
     }
 
-    pub fn parse(&mut self) -> Vec<Declaration> {
-        todo!()
+    fn parse_declaration(&mut self) -> Option<Declaration<'src>> {
+        if self.eat(TokenType::IdentifierKey).is_some() {
+            return Some(Declaration::Identifier(self.parse_path()?));
+        }
+        if self.eat(TokenType::Use).is_some() {
+            return Some(Declaration::Use(self.parse_path()?));
+        }
+        let public = self.eat(TokenType::Pub).is_some();
+        match self.peek_type() {
+            Some(TokenType::Func) => Some(Declaration::Func(self.parse_func(public)?)),
+            Some(TokenType::Extern) => {
+                self.next();
+                self.expect(TokenType::Func, "`func`")?;
+                let sig = self.parse_sig()?;
+                Some(Declaration::Extern(ExternFunc { public, sig }))
+            }
+            Some(TokenType::Obj) => Some(Declaration::Obj(self.parse_obj(public)?)),
+            Some(TokenType::Impl) if !public => Some(Declaration::Impl(self.parse_impl()?)),
+            _ => {
+                self.error_expected("a declaration");
+                None
+            }
+        }
+    }
+
+    fn parse_func(&mut self, public: bool) -> Option<Func<'src>> {
+        self.expect(TokenType::Func, "`func`")?;
+        let sig = self.parse_sig()?;
+        self.expect(TokenType::Is, "`is`")?;
+        let body = self.parse_block()?;
+        self.expect(TokenType::End, "`end`")?;
+        Some(Func {
+            id: self.id(),
+            public,
+            sig,
+            body,
+        })
+    }
+
+    fn parse_sig(&mut self) -> Option<FuncSig<'src>> {
+        let start = self.last_span;
+        let ret = if self.peek_type() == Some(TokenType::Identifier)
+            && self.peek_2_type() == Some(TokenType::OpenParen)
+        {
+            None
+        } else {
+            Some(self.parse_type()?)
+        };
+        let name = self.parse_ident("a function name")?;
+        self.expect(TokenType::OpenParen, "`(`")?;
+        let mut params = Vec::new();
+        while self.peek_type() != Some(TokenType::CloseParen) {
+            params.push(self.parse_param()?);
+            if self.eat(TokenType::Comma).is_none() {
+                break;
+            }
+        }
+        self.expect(TokenType::CloseParen, "`)`")?;
+        Some(FuncSig {
+            name,
+            ret,
+            params,
+            span: start.to(self.last_span),
+        })
+    }
+
+    fn parse_param(&mut self) -> Option<Param<'src>> {
+        if self.peek_type() == Some(TokenType::Identifier)
+            && self.peek_2_type() == Some(TokenType::Colon)
+        {
+            let name = self.parse_ident("a parameter name")?;
+            self.next();
+            let ty = self.parse_type()?;
+            Some(Param {
+                id: self.id(),
+                name,
+                ty,
+            })
+        } else {
+            let ty = self.parse_type()?;
+            let name = self.parse_ident("a parameter name")?;
+            Some(Param {
+                id: self.id(),
+                name,
+                ty,
+            })
+        }
     }
+
+    fn parse_type(&mut self) -> Option<TypeExpr<'src>> {
+        let mut ty = match self.eat(TokenType::Void) {
+            Some(token) => TypeExpr::Void(token.span()),
+            None => TypeExpr::Named(self.parse_ident("a type")?),
+        };
+        while self.peek_type() == Some(TokenType::OpenBracket)
+            && self.peek_2_type() == Some(TokenType::CloseBracket)
+        {
+            self.next();
+            self.next();
+            let span = ty.span().to(self.last_span);
+            ty = TypeExpr::Array(Box::new(ty), span);
+        }
+        Some(ty)
+    }
+
+    fn parse_obj(&mut self, public: bool) -> Option<Obj<'src>> {
+        self.expect(TokenType::Obj, "`obj`")?;
+        let name = self.parse_ident("a type name")?;
+        self.expect(TokenType::Is, "`is`")?;
+        let mut fields = Vec::new();
+        while !matches!(self.peek_type(), Some(TokenType::End) | None) {
+            let public = self.eat(TokenType::Pub).is_some();
+            let name = self.parse_ident("a field name")?;
+            self.expect(TokenType::Colon, "`:`")?;
+            let ty = self.parse_type()?;
+            let default = match self.eat(TokenType::Equals) {
+                Some(_) => Some(self.parse_expr()?),
+                None => None,
+            };
+            self.eat(TokenType::Comma);
+            fields.push(Field {
+                public,
+                name,
+                ty,
+                default,
+            });
+        }
+        self.expect(TokenType::End, "`end`")?;
+        Some(Obj {
+            public,
+            name,
+            fields,
+        })
+    }
+
+    fn parse_impl(&mut self) -> Option<Impl<'src>> {
+        self.expect(TokenType::Impl, "`impl`")?;
+        let target = self.parse_ident("a type name")?;
+        self.expect(TokenType::Is, "`is`")?;
+        let mut methods = Vec::new();
+        while !matches!(self.peek_type(), Some(TokenType::End) | None) {
+            let public = self.eat(TokenType::Pub).is_some();
+            methods.push(self.parse_func(public)?);
+        }
+        self.expect(TokenType::End, "`end`")?;
+        Some(Impl { target, methods })
+    }
+
+    fn parse_block(&mut self) -> Option<Block<'src>> {
+        let mut span = self.last_span;
+        span.update();
+        let mut stmts = Vec::new();
+        while !matches!(
+            self.peek_type(),
+            Some(TokenType::End | TokenType::Else) | None
+        ) {
+            let stmt = self.parse_stmt()?;
+            span = span.to(stmt.span);
+            stmts.push(stmt);
+        }
+        Some(Block { stmts, span })
+    }
+
+    fn parse_stmt(&mut self) -> Option<Stmt<'src>> {
+        if let Some(token) = self.eat(TokenType::Return) {
+            // A `return` directly followed by `end` returns nothing
+            let value = match self.peek_type() {
+                Some(TokenType::End | TokenType::Else) | None => None,
+                _ => Some(self.parse_expr()?),
+            };
+            let span = value
+                .as_ref()
+                .map_or(token.span(), |x| token.span().to(x.span));
+            return Some(Stmt {
+                id: self.id(),
+                kind: StmtKind::Return(value),
+                span,
+            });
+        }
+        if let Some(token) = self.eat(TokenType::If) {
+            let mut stmt = self.parse_if(token.span())?;
+            self.expect(TokenType::End, "`end`")?;
+            stmt.span = stmt.span.to(self.last_span);
+            return Some(stmt);
+        }
+        if let Some(token) = self.eat(TokenType::While) {
+            let cond = self.parse_expr()?;
+            self.expect(TokenType::Is, "`is`")?;
+            let body = self.parse_block()?;
+            self.expect(TokenType::End, "`end`")?;
+            return Some(Stmt {
+                id: self.id(),
+                kind: StmtKind::While { cond, body },
+                span: token.span().to(self.last_span),
+            });
+        }
+        if let Some(token) = self.eat(TokenType::For) {
+            let name = self.parse_ident("a loop variable name")?;
+            self.expect(TokenType::In, "`in`")?;
+            let iter = self.parse_expr()?;
+            self.expect(TokenType::Is, "`is`")?;
+            let body = self.parse_block()?;
+            self.expect(TokenType::End, "`end`")?;
+            return Some(Stmt {
+                id: self.id(),
+                kind: StmtKind::For { name, iter, body },
+                span: token.span().to(self.last_span),
+            });
+        }
+        if let Some(token) = self.eat(TokenType::Bulk) {
+            let call = self.parse_expr()?;
+            let span = token.span().to(call.span);
+            return Some(Stmt {
+                id: self.id(),
+                kind: StmtKind::Bulk(call),
+                span,
+            });
+        }
+        for (ty, kind) in [
+            (TokenType::Break, StmtKind::Break),
+            (TokenType::Continue, StmtKind::Continue),
+        ] {
+            if let Some(token) = self.eat(ty) {
+                return Some(Stmt {
+                    id: self.id(),
+                    kind,
+                    span: token.span(),
+                });
+            }
+        }
+        let is_local = matches!(
+            (self.peek_type(), self.peek_2_type()),
+            (Some(TokenType::Void), _)
+                | (
+                    Some(TokenType::Identifier),
+                    Some(TokenType::Identifier | TokenType::OpenBracket)
+                )
+        );
+        let let_span = self.eat(TokenType::Let).map(|x| x.span());
+        if is_local || let_span.is_some() {
+            let (ty, start) = match let_span {
+                Some(span) => (None, span),
+                None => {
+                    let ty = self.parse_type()?;
+                    let span = ty.span();
+                    (Some(ty), span)
+                }
+            };
+            let name = self.parse_ident("a variable name")?;
+            self.expect(TokenType::Equals, "`=`")?;
+            let init = self.parse_expr()?;
+            let span = start.to(init.span);
+            return Some(Stmt {
+                id: self.id(),
+                kind: StmtKind::Local { ty, name, init },
+                span,
+            });
+        }
+
+        let expr = self.parse_expr()?;
+        if self.eat(TokenType::Equals).is_some() {
+            let value = self.parse_expr()?;
+            let span = expr.span.to(value.span);
+            return Some(Stmt {
+                id: self.id(),
+                kind: StmtKind::Assign {
+                    target: expr,
+                    value,
+                },
+                span,
+            });
+        }
+        if self.eat(TokenType::Increment).is_some() {
+            let span = expr.span.to(self.last_span);
+            return Some(Stmt {
+                id: self.id(),
+                kind: StmtKind::Increment(expr),
+                span,
+            });
+        }
+        let span = expr.span;
+        Some(Stmt {
+            id: self.id(),
+            kind: StmtKind::Expr(expr),
+            span,
+        })
+    }
+
+    /// Parses an `if` after its keyword, up to but not including the `end` shared by the whole
+    /// `else if` chain.
+    fn parse_if(&mut self, start: Span<'src>) -> Option<Stmt<'src>> {
+        let cond = self.parse_expr()?;
+        self.expect(TokenType::Is, "`is`")?;
+        let then = self.parse_block()?;
+        let mut span = start.to(then.span);
+        let els = match self.eat(TokenType::Else) {
+            Some(_) => match self.eat(TokenType::If) {
+                Some(token) => {
+                    let nested = self.parse_if(token.span())?;
+                    span = span.to(nested.span);
+                    Some(Block {
+                        span: nested.span,
+                        stmts: vec![nested],
+                    })
+                }
+                None => {
+                    let els = self.parse_block()?;
+                    span = span.to(els.span);
+                    Some(els)
+                }
+            },
+            None => None,
+        };
+        Some(Stmt {
+            id: self.id(),
+            kind: StmtKind::If { cond, then, els },
+            span,
+        })
+    }
+
+    /// Parses a single expression.
+    pub fn parse_expr(&mut self) -> Option<Expr<'src>> {
+        self.parse_or()
+    }
+
+    fn parse_or(&mut self) -> Option<Expr<'src>> {
+        let mut lhs = self.parse_and()?;
+        while self.eat(TokenType::Or).is_some() {
+            let rhs = self.parse_and()?;
+            lhs = self.binary(BinOp::Or, lhs, rhs);
+        }
+        Some(lhs)
+    }
+
+    fn parse_and(&mut self) -> Option<Expr<'src>> {
+        let mut lhs = self.parse_not()?;
+        while self.eat(TokenType::And).is_some() {
+            let rhs = self.parse_not()?;
+            lhs = self.binary(BinOp::And, lhs, rhs);
+        }
+        Some(lhs)
+    }
+
+    fn parse_not(&mut self) -> Option<Expr<'src>> {
+        if let Some(token) = self.eat(TokenType::Not) {
+            let operand = self.parse_not()?;
+            let span = token.span().to(operand.span);
+            return Some(Expr {
+                id: self.id(),
+                kind: ExprKind::Unary {
+                    op: UnOp::Not,
+                    operand: Box::new(operand),
+                },
+                span,
+            });
+        }
+        self.parse_comparison()
+    }
+
+    /// Parses a comparison. Comparisons don't chain, so `a < b < c` is an error.
+    fn parse_comparison(&mut self) -> Option<Expr<'src>> {
+        let lhs = self.parse_additive()?;
+        let op = match self.peek_type() {
+            Some(TokenType::EqualsEquals) => BinOp::Eq,
+            Some(TokenType::NotEquals) => BinOp::Ne,
+            Some(TokenType::Less) => BinOp::Lt,
+            Some(TokenType::LessEquals) => BinOp::Le,
+            Some(TokenType::Greater) => BinOp::Gt,
+            Some(TokenType::GreaterEquals) => BinOp::Ge,
+            _ => return Some(lhs),
+        };
+        self.next();
+        let rhs = self.parse_additive()?;
+        Some(self.binary(op, lhs, rhs))
+    }
+
+    fn parse_additive(&mut self) -> Option<Expr<'src>> {
+        let mut lhs = self.parse_multiplicative()?;
+        loop {
+            let op = match self.peek_type() {
+                Some(TokenType::Plus) => BinOp::Add,
+                Some(TokenType::Minus) => BinOp::Sub,
+                _ => break,
+            };
+            self.next();
+            let rhs = self.parse_multiplicative()?;
+            lhs = self.binary(op, lhs, rhs);
+        }
+        Some(lhs)
+    }
+
+    fn parse_multiplicative(&mut self) -> Option<Expr<'src>> {
+        let mut lhs = self.parse_unary()?;
+        loop {
+            let op = match self.peek_type() {
+                Some(TokenType::Star) => BinOp::Mul,
+                Some(TokenType::Slash) => BinOp::Div,
+                _ => break,
+            };
+            self.next();
+            let rhs = self.parse_unary()?;
+            lhs = self.binary(op, lhs, rhs);
+        }
+        Some(lhs)
+    }
+
+    fn parse_unary(&mut self) -> Option<Expr<'src>> {
+        if let Some(token) = self.eat(TokenType::Minus) {
+            let operand = self.parse_unary()?;
+            let span = token.span().to(operand.span);
+            return Some(Expr {
+                id: self.id(),
+                kind: ExprKind::Unary {
+                    op: UnOp::Neg,
+                    operand: Box::new(operand),
+                },
+                span,
+            });
+        }
+        self.parse_postfix()
+    }
+
+    fn parse_postfix(&mut self) -> Option<Expr<'src>> {
+        let mut expr = self.parse_primary()?;
+        loop {
+            match self.peek_type() {
+                Some(TokenType::Dot) => {
+                    self.next();
+                    let field = self.parse_ident("a field or method name")?;
+                    let span = expr.span.to(field.span);
+                    expr = Expr {
+                        id: self.id(),
+                        kind: ExprKind::Field {
+                            base: Box::new(expr),
+                            field,
+                        },
+                        span,
+                    };
+                }
+                Some(TokenType::OpenParen) => {
+                    self.next();
+                    if let ExprKind::Name(ty) = expr.kind
+                        && self.peek_type() == Some(TokenType::Identifier)
+                        && self.peek_2_type() == Some(TokenType::Colon)
+                    {
+                        let fields = self.parse_field_inits()?;
+                        expr = Expr {
+                            id: self.id(),
+                            kind: ExprKind::Construct { ty, fields },
+                            span: expr.span.to(self.last_span),
+                        };
+                        continue;
+                    }
+                    let args = self.parse_list(TokenType::CloseParen, "`)`")?;
+                    let span = expr.span.to(self.last_span);
+                    expr = Expr {
+                        id: self.id(),
+                        kind: ExprKind::Call {
+                            callee: Box::new(expr),
+                            args,
+                        },
+                        span,
+                    };
+                }
+                _ => break,
+            }
+        }
+        Some(expr)
+    }
+
+    /// Parses the `name: value` field initializers of a constructor up to and including `)`.
+    fn parse_field_inits(&mut self) -> Option<Vec<FieldInit<'src>>> {
+        let mut fields = Vec::new();
+        while self.peek_type() != Some(TokenType::CloseParen) {
+            let name = self.parse_ident("a field name")?;
+            self.expect(TokenType::Colon, "`:`")?;
+            let value = self.parse_expr()?;
+            fields.push(FieldInit { name, value });
+            if self.eat(TokenType::Comma).is_none() {
+                break;
+            }
+        }
+        self.expect(TokenType::CloseParen, "`)`")?;
+        Some(fields)
+    }
+
+    /// Parses comma separated expressions up to and including `close`.
+    fn parse_list(&mut self, close: TokenType, what: &str) -> Option<Vec<Expr<'src>>> {
+        let mut items = Vec::new();
+        while self.peek_type() != Some(close) {
+            items.push(self.parse_expr()?);
+            if self.eat(TokenType::Comma).is_none() {
+                break;
+            }
+        }
+        self.expect(close, what)?;
+        Some(items)
+    }
+
+    fn parse_primary(&mut self) -> Option<Expr<'src>> {
+        let Some(ty) = self.peek_type() else {
+            self.error_expected("an expression");
+            return None;
+        };
+        match ty {
+            TokenType::Identifier => {
+                let ident = self.parse_ident("an expression")?;
+                Some(Expr {
+                    id: self.id(),
+                    kind: ExprKind::Name(ident),
+                    span: ident.span,
+                })
+            }
+            TokenType::StringLit => {
+                let token = self.next()?;
+                let span = token.span();
+                let Some(LexerValue::String(string)) = token.move_value() else {
+                    unreachable!("string literals always hold a string")
+                };
+                Some(Expr {
+                    id: self.id(),
+                    kind: ExprKind::Str(string),
+                    span,
+                })
+            }
+            TokenType::NumberLit => {
+                let token = self.next()?;
+                self.parse_number(token.span())
+            }
+            TokenType::True | TokenType::False => {
+                let token = self.next()?;
+                Some(Expr {
+                    id: self.id(),
+                    kind: ExprKind::Bool(ty == TokenType::True),
+                    span: token.span(),
+                })
+            }
+            TokenType::OpenBracket => {
+                let open = self.next()?;
+                let items = self.parse_list(TokenType::CloseBracket, "`]`")?;
+                Some(Expr {
+                    id: self.id(),
+                    kind: ExprKind::Array(items),
+                    span: open.span().to(self.last_span),
+                })
+            }
+            TokenType::OpenParen => {
+                let open = self.next()?;
+                let inner = self.parse_expr()?;
+                self.expect(TokenType::CloseParen, "`)`")?;
+                Some(Expr {
+                    id: self.id(),
+                    kind: inner.kind,
+                    span: open.span().to(self.last_span),
+                })
+            }
+            _ => {
+                self.error_expected("an expression");
+                None
+            }
+        }
+    }
+
+    fn binary(&mut self, op: BinOp, lhs: Expr<'src>, rhs: Expr<'src>) -> Expr<'src> {
+        let span = lhs.span.to(rhs.span);
+        Expr {
+            id: self.id(),
+            kind: ExprKind::Binary {
+                op,
+                lhs: Box::new(lhs),
+                rhs: Box::new(rhs),
+            },
+            span,
+        }
+    }
+
+    fn parse_number(&mut self, span: Span<'src>) -> Option<Expr<'src>> {
+        let text = span.apply();
+        let kind = if text.contains('.') {
+            ExprKind::Float(text.parse().expect("lexer only produces valid numbers"))
+        } else {
+            match text.parse() {
+                Ok(int) => ExprKind::Int(int),
+                Err(_) => {
+                    Diag::error(self.src)
+                        .with_message("integer literal is too large")
+                        .with_label(Label::primary((), span))
+                        .finish()
+                        .emit();
+                    return None;
+                }
+            }
+        };
+        Some(Expr {
+            id: self.id(),
+            kind,
+            span,
+        })
+    }
+}
+
+#[test]
+fn parse_simple_test() {
+    let file = r"identifier a.b
+extern func void print(str msg)
+func void start() is
+    i32 val = 1 + 2 * 3
+    print('val: ' + val.to-string())
+    val++
+end";
+    let src = Source::new(file, "test.scp");
+    let decls = Parser::new(&src).parse();
+    assert_eq!(decls.len(), 3);
+    let Declaration::Func(func) = &decls[2] else {
+        panic!("expected a function");
+    };
+    assert_eq!(func.sig.name.name, "start");
+    assert_eq!(func.body.stmts.len(), 3);
+    let StmtKind::Local { init, .. } = &func.body.stmts[0].kind else {
+        panic!("expected a local");
+    };
+    assert_eq!(init.span.apply(), "1 + 2 * 3");
+    let ExprKind::Binary {
+        op: BinOp::Add,
+        rhs,
+        ..
+    } = &init.kind
+    else {
+        panic!("expected an addition");
+    };
+    assert!(matches!(rhs.kind, ExprKind::Binary { op: BinOp::Mul, .. }));
+    assert!(matches!(func.body.stmts[2].kind, StmtKind::Increment(_)));
+}
+
+#[test]
+fn parse_error_recovery_test() {
+    let errors = crate::diag::error_count();
+    let file = "func void broken( is end\nobj point is\n    x: i32 = 0,\n    y: i32,\nend";
+    let src = Source::new(file, "test.scp");
+    let decls = Parser::new(&src).parse();
+    assert_eq!(crate::diag::error_count(), errors + 1);
+    assert!(matches!(&decls[..], [Declaration::Obj(obj)] if obj.fields.len() == 2));
+}
+
+#[test]
+fn parse_control_flow_test() {
+    let file = "func i32 f(i32[] xs) is\n    for x in xs is\n        if x < 0 or not x != 1 and true is\n            continue\n        else if x == 2 is\n            break\n        else\n            return x\n        end\n    end\n    while false is\n    end\n    return 0\nend";
+    let src = Source::new(file, "test.scp");
+    let decls = Parser::new(&src).parse();
+    let [Declaration::Func(func)] = &decls[..] else {
+        panic!("expected a function");
+    };
+    assert_eq!(func.body.stmts.len(), 3);
+    let StmtKind::For { body, .. } = &func.body.stmts[0].kind else {
+        panic!("expected a for loop");
+    };
+    let StmtKind::If { cond, els, .. } = &body.stmts[0].kind else {
+        panic!("expected an if");
+    };
+    assert!(matches!(cond.kind, ExprKind::Binary { op: BinOp::Or, .. }));
+    let nested = &els.as_ref().unwrap().stmts[0];
+    assert!(matches!(&nested.kind, StmtKind::If { els: Some(_), .. }));
+    assert_eq!(body.stmts[0].span.apply().lines().count(), 7);
+    assert!(matches!(func.body.stmts[1].kind, StmtKind::While { .. }));
 }
@@ -0,0 +1,274 @@
+#![deny(missing_docs)]
+//! Module for loading the files of a project, and mapping them to module paths.
+//!
+//! A module path like `hello-world.gfx.printer` starts with the name of the project, followed
+//! by the location of the file under the project's source root (`gfx/printer.scp`).
+
+use std::{
+    collections::HashSet,
+    fmt::Display,
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use codespan_reporting::diagnostic::Label;
+
+use crate::{
+    Source,
+    ast::{Declaration, NodeId},
+    diag::Diag,
+    manifest::{self, Manifest},
+    parser::Parser,
+};
+
+/// The file extension of Escoop source files.
+pub const EXTENSION: &str = "scp";
+
+/// Errors that stop a project from being loaded.
+#[derive(Debug)]
+pub enum ProjectError {
+    /// A file or directory couldn't be read.
+    Io(PathBuf, io::Error),
+    /// A manifest was invalid. The problems have already been reported through [`Diag`].
+    InvalidManifest(PathBuf),
+    /// A dependency's manifest has a different name than the one it was depended on with.
+    DependencyName {
+        /// The name used in the `[dependencies]` table.
+        expected: String,
+        /// The name in the dependency's manifest.
+        found: String,
+    },
+    /// The entry module given in the manifest doesn't exist.
+    MissingEntry(String),
+}
+
+impl Display for ProjectError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ProjectError::Io(path, err) => {
+                write!(f, "could not open `{}`: {}", path.to_string_lossy(), err)
+            }
+            ProjectError::InvalidManifest(path) => {
+                write!(f, "invalid manifest `{}`", path.to_string_lossy())
+            }
+            ProjectError::DependencyName { expected, found } => write!(
+                f,
+                "dependency `{expected}` is named `{found}` in its manifest"
+            ),
+            ProjectError::MissingEntry(entry) => write!(f, "entry module `{entry}` does not exist"),
+        }
+    }
+}
+
+/// A source file of a project, before it is parsed.
+#[derive(Debug)]
+pub struct ProjectFile {
+    /// The module path the location of the file maps to, if it is part of a project.
+    pub module: Option<String>,
+    /// Path of the file.
+    pub path: PathBuf,
+    /// Contents of the file.
+    pub text: String,
+}
+
+/// Every file of a project and its dependencies.
+#[derive(Debug)]
+pub struct Project {
+    /// The manifest of the project, or `None` when compiling a lone file.
+    pub manifest: Option<Manifest>,
+    /// Module path of the entry module, or `None` if the first file is the entry module.
+    pub entry: Option<String>,
+    /// Every file, with the files of the project itself first.
+    pub files: Vec<ProjectFile>,
+}
+
+/// A parsed source file.
+#[derive(Debug)]
+pub struct Module<'src> {
+    /// The module path, taken from the file's location or its `identifier` declaration.
+    pub name: String,
+    /// The source of the module.
+    pub src: &'src Source<'src>,
+    /// Every declaration of the module.
+    pub decls: Vec<Declaration<'src>>,
+}
+
+impl Project {
+    /// Creates a project out of a single file, without a manifest.
+    pub fn single_file(path: impl Into<PathBuf>) -> Result<Project, ProjectError> {
+        let path = path.into();
+        let text = fs::read_to_string(&path).map_err(|err| ProjectError::Io(path.clone(), err))?;
+        Ok(Project {
+            manifest: None,
+            entry: None,
+            files: vec![ProjectFile {
+                module: None,
+                path,
+                text,
+            }],
+        })
+    }
+
+    /// Loads the project described by the manifest at `manifest_path`, along with all of its
+    /// dependencies.
+    pub fn load(manifest_path: &Path) -> Result<Project, ProjectError> {
+        let mut files = Vec::new();
+        let mut visited = HashSet::new();
+        let manifest = load_project(manifest_path, None, &mut files, &mut visited)?;
+        let entry = format!("{}.{}", manifest.name, manifest.entry);
+        if !files.iter().any(|x| x.module.as_ref() == Some(&entry)) {
+            return Err(ProjectError::MissingEntry(entry));
+        }
+        Ok(Project {
+            manifest: Some(manifest),
+            entry: Some(entry),
+            files,
+        })
+    }
+
+    /// Creates a [`Source`] for every file, in the same order as [`files`](Project::files).
+    pub fn sources(&self) -> Vec<Source<'_>> {
+        self.files
+            .iter()
+            .map(|x| Source::new(x.text.as_str(), x.path.clone()))
+            .collect()
+    }
+
+    /// Parses every source, checking that each `identifier` declaration matches the location of
+    /// its file. `sources` must come from [`sources`](Project::sources).
+    pub fn parse<'src>(&self, sources: &'src [Source<'src>]) -> Vec<Module<'src>> {
+        let mut next_id = NodeId(0);
+        self.files
+            .iter()
+            .zip(sources)
+            .map(|(file, src)| {
+                let mut parser = Parser::new(src).with_first_id(next_id);
+                let decls = parser.parse();
+                next_id = parser.next_id();
+                let header = decls.iter().find_map(|x| match x {
+                    Declaration::Identifier(path) => Some(path),
+                    _ => None,
+                });
+                let name = match (&file.module, header) {
+                    (Some(module), Some(header)) => {
+                        let found = header.to_dotted();
+                        if *module != found {
+                            Diag::error(src)
+                                .with_message(format!(
+                                    "module is declared as `{found}`, but its location makes it `{module}`"
+                                ))
+                                .with_label(
+                                    Label::primary((), header.span)
+                                        .with_message(format!("expected `{module}`")),
+                                )
+                                .with_note(format!(
+                                    "either change the declaration or move the file to match `{found}`"
+                                ))
+                                .finish()
+                                .emit();
+                        }
+                        module.clone()
+                    }
+                    (Some(module), None) => module.clone(),
+                    (None, Some(header)) => header.to_dotted(),
+                    (None, None) => file
+                        .path
+                        .file_stem()
+                        .map_or(String::new(), |x| x.to_string_lossy().into_owned()),
+                };
+                Module {
+                    name,
+                    src,
+                    decls,
+                }
+            })
+            .collect()
+    }
+}
+
+fn load_project(
+    manifest_path: &Path,
+    expected_name: Option<&str>,
+    files: &mut Vec<ProjectFile>,
+    visited: &mut HashSet<PathBuf>,
+) -> Result<Manifest, ProjectError> {
+    let io_err = |err| ProjectError::Io(manifest_path.to_path_buf(), err);
+    let canonical = manifest_path.canonicalize().map_err(io_err)?;
+    let text = fs::read_to_string(manifest_path).map_err(io_err)?;
+    let src = Source::new(text.as_str(), manifest_path);
+    let manifest = Manifest::parse(&src)
+        .ok_or_else(|| ProjectError::InvalidManifest(manifest_path.to_path_buf()))?;
+    if let Some(expected) = expected_name
+        && expected != manifest.name
+    {
+        return Err(ProjectError::DependencyName {
+            expected: expected.to_string(),
+            found: manifest.name,
+        });
+    }
+    if !visited.insert(canonical) {
+        return Ok(manifest);
+    }
+
+    let dir = manifest_path.parent().unwrap_or(Path::new("."));
+    let root = dir.join(&manifest.source_root);
+    let mut found = Vec::new();
+    find_modules(&root, &mut found)?;
+    found.sort();
+    for path in found {
+        let text = fs::read_to_string(&path).map_err(|err| ProjectError::Io(path.clone(), err))?;
+        let mut module = manifest.name.clone();
+        let relative = path.strip_prefix(&root).expect("found under the root");
+        for segment in relative.with_extension("").iter() {
+            module.push('.');
+            module += &segment.to_string_lossy();
+        }
+        files.push(ProjectFile {
+            module: Some(module),
+            path,
+            text,
+        });
+    }
+
+    for dependency in &manifest.dependencies {
+        let path = dir.join(&dependency.path).join(manifest::MANIFEST_NAME);
+        load_project(&path, Some(&dependency.name), files, visited)?;
+    }
+    Ok(manifest)
+}
+
+fn find_modules(dir: &Path, found: &mut Vec<PathBuf>) -> Result<(), ProjectError> {
+    let entries = fs::read_dir(dir).map_err(|err| ProjectError::Io(dir.to_path_buf(), err))?;
+    for entry in entries {
+        let path = entry
+            .map_err(|err| ProjectError::Io(dir.to_path_buf(), err))?
+            .path();
+        if path.is_dir() {
+            // Nested projects are only part of this one if they are a dependency
+            if !path.join(manifest::MANIFEST_NAME).is_file() {
+                find_modules(&path, found)?;
+            }
+        } else if path.extension().is_some_and(|x| x == EXTENSION) {
+            found.push(path);
+        }
+    }
+    Ok(())
+}
+
+/// Parses in-memory sources into modules named by their `identifier` declarations.
+#[cfg(test)]
+pub(crate) fn test_modules<'src>(sources: &'src [Source<'src>]) -> Vec<Module<'src>> {
+    let project = Project {
+        manifest: None,
+        entry: None,
+        files: sources
+            .iter()
+            .map(|src| ProjectFile {
+                module: None,
+                path: src.path().clone(),
+                text: String::new(),
+            })
+            .collect(),
+    };
+    project.parse(sources)
+}
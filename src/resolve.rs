@@ -0,0 +1,631 @@
+#![deny(missing_docs)]
+//! Module for name resolution: binding every name used in the program to its declaration.
+//!
+//! Names are looked up in the innermost block first, then in the enclosing blocks and the
+//! function's parameters, then in the module's items and imports, and finally in the
+//! [`builtins`](crate::builtins). The results are stored in side tables keyed by [`NodeId`].
+//!
+//! Fields and methods (`self.text`, `hello-world.add`) depend on the type of the value they are
+//! used on, so they are resolved by the type checker instead.
+
+use std::collections::HashMap;
+
+use codespan_reporting::diagnostic::Label;
+
+use crate::{
+    Source,
+    ast::{Block, Declaration, Expr, ExprKind, Func, Ident, NodeId, Stmt, StmtKind, TypeExpr},
+    builtins::{Builtin, PrimType},
+    diag::{Diag, DiagBuilder},
+    modules::{ItemId, ItemKind, ModuleGraph, ModuleId},
+    project::Module,
+    span::Span,
+};
+
+/// What a name refers to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Res {
+    /// A local variable, parameter or `self`, identified by the node declaring it. See
+    /// [`Resolutions::bindings`].
+    Local(NodeId),
+    /// An item of a module.
+    Item(ItemId),
+    /// A builtin function.
+    Builtin(Builtin),
+}
+
+/// Every kind of local binding.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BindingKind {
+    /// A local variable, declared by a statement. This includes the variable of a `for` loop.
+    Local,
+    /// A function parameter.
+    Param,
+    /// The `self` parameter of a method, declared by the method.
+    SelfParam,
+}
+
+/// A local variable, parameter or `self`.
+#[derive(Debug, Clone, Copy)]
+pub struct Binding<'src> {
+    /// Name of the binding.
+    pub name: &'src str,
+    /// Where the binding is declared.
+    pub span: Span<'src>,
+    /// What kind of binding it is.
+    pub kind: BindingKind,
+}
+
+/// Identifies a method: the module and declaration of its `impl` block, and its index in the
+/// block.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct MethodRef {
+    /// The module of the `impl` block.
+    pub module: ModuleId,
+    /// Index of the `impl` block in [`Module::decls`].
+    pub decl: usize,
+    /// Index of the method in the `impl` block.
+    pub method: usize,
+}
+
+/// The result of name resolution.
+#[derive(Debug, Default)]
+pub struct Resolutions<'src> {
+    /// What every [`Name`](ExprKind::Name) expression and the type of every
+    /// [`Construct`](ExprKind::Construct) expression refers to.
+    pub names: HashMap<NodeId, Res>,
+    /// Every local binding, keyed by the node declaring it.
+    pub bindings: HashMap<NodeId, Binding<'src>>,
+    /// The methods of every object type, by name.
+    pub methods: HashMap<ItemId, HashMap<&'src str, MethodRef>>,
+    /// Every use of a local binding, with the binding it refers to.
+    pub uses: Vec<(Span<'src>, NodeId)>,
+}
+
+impl<'src> Resolutions<'src> {
+    /// Gets the span of the declaration the name expression `id` refers to, for
+    /// go-to-definition. Returns `None` for unresolved names and builtins.
+    pub fn definition(&self, graph: &ModuleGraph<'src>, id: NodeId) -> Option<Span<'src>> {
+        match self.names.get(&id)? {
+            Res::Local(binding) => Some(self.bindings[binding].span),
+            Res::Item(item) => Some(graph.item(*item).name.span),
+            Res::Builtin(_) => None,
+        }
+    }
+
+    /// Finds the local binding declared or used at `offset` of `src`, for tooling like hovers.
+    pub fn binding_at(&self, src: &Source, offset: u32) -> Option<NodeId> {
+        let declared = self
+            .bindings
+            .iter()
+            .find(|(_, binding)| binding.span.contains(src, offset))
+            .map(|(id, _)| *id);
+        declared.or_else(|| {
+            self.uses
+                .iter()
+                .find(|(span, _)| span.contains(src, offset))
+                .map(|(_, id)| *id)
+        })
+    }
+
+    /// Finds the method `name` of the object type `ty`.
+    pub fn method(&self, ty: ItemId, name: &str) -> Option<MethodRef> {
+        self.methods.get(&ty)?.get(name).copied()
+    }
+}
+
+/// Computes the edit distance between two strings, used for suggesting similar names.
+pub fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, a) in a.chars().enumerate() {
+        let mut previous = row[0];
+        row[0] = i + 1;
+        for (j, b) in b.iter().enumerate() {
+            let substitution = previous + usize::from(a != *b);
+            previous = row[j + 1];
+            row[j + 1] = substitution.min(row[j] + 1).min(previous + 1);
+        }
+    }
+    row[b.len()]
+}
+
+/// Finds the candidate closest to `name`, if any is close enough to be a likely typo.
+pub fn suggest<'a>(name: &str, candidates: impl IntoIterator<Item = &'a str>) -> Option<&'a str> {
+    let max = name.chars().count().div_ceil(3);
+    candidates
+        .into_iter()
+        .filter(|x| *x != name)
+        .map(|x| (edit_distance(name, x), x))
+        .filter(|(distance, _)| *distance <= max)
+        .min()
+        .map(|(_, x)| x)
+}
+
+/// Resolves every name in `modules`, reporting names that can't be found through [`Diag`].
+pub fn resolve<'src>(modules: &[Module<'src>], graph: &ModuleGraph<'src>) -> Resolutions<'src> {
+    let mut resolver = Resolver {
+        graph,
+        module: ModuleId(0),
+        src_module: None,
+        scopes: Vec::new(),
+        loops: 0,
+        res: Resolutions::default(),
+    };
+    for (index, module) in modules.iter().enumerate() {
+        resolver.module = ModuleId(index);
+        resolver.src_module = Some(module);
+        resolver.collect_methods();
+    }
+    for (index, module) in modules.iter().enumerate() {
+        resolver.module = ModuleId(index);
+        resolver.src_module = Some(module);
+        resolver.resolve_module();
+    }
+    resolver.res
+}
+
+struct Resolver<'a, 'src> {
+    graph: &'a ModuleGraph<'src>,
+    module: ModuleId,
+    src_module: Option<&'a Module<'src>>,
+    scopes: Vec<Vec<(&'src str, NodeId)>>,
+    /// How many loops the statement being resolved is inside of.
+    loops: usize,
+    res: Resolutions<'src>,
+}
+
+impl<'a, 'src> Resolver<'a, 'src> {
+    fn error(&self, message: impl ToString, span: Span<'src>) -> DiagBuilder<'src> {
+        Diag::error(span.source())
+            .with_message(message)
+            .with_label(Label::primary((), span))
+    }
+
+    /// Finds the object type an `impl` block is for.
+    fn impl_target(&self, name: &Ident<'src>) -> Option<ItemId> {
+        let Some(item) = self.graph.modules[self.module.0].items.get(name.name) else {
+            let mut diag = self.error(format!("cannot find type `{}`", name.name), name.span);
+            if self.graph.lookup(self.module, name.name).is_some() {
+                diag = diag.with_note("methods can only be added to types of the same module");
+            }
+            diag.finish().emit();
+            return None;
+        };
+        let kind = self.graph.item(*item).kind;
+        if kind != ItemKind::Obj {
+            self.error(
+                format!("`{}` is a {}, not a type", name.name, kind.describe()),
+                name.span,
+            )
+            .finish()
+            .emit();
+            return None;
+        }
+        Some(*item)
+    }
+
+    fn collect_methods(&mut self) {
+        let module = self.src_module.expect("resolving a module");
+        for (decl, declaration) in module.decls.iter().enumerate() {
+            let Declaration::Impl(imp) = declaration else {
+                continue;
+            };
+            let Some(target) = self.impl_target(&imp.target) else {
+                continue;
+            };
+            for (method, func) in imp.methods.iter().enumerate() {
+                let name = func.sig.name;
+                let methods = self.res.methods.entry(target).or_default();
+                if let Some(previous) = methods.get(name.name) {
+                    let Declaration::Impl(previous_impl) = &module.decls[previous.decl] else {
+                        unreachable!("methods always come from impl blocks");
+                    };
+                    let previous = previous_impl.methods[previous.method].sig.name.span;
+                    self.error(
+                        format!("method `{}` is defined multiple times", name.name),
+                        name.span,
+                    )
+                    .with_label(Label::secondary((), previous).with_message("first defined here"))
+                    .finish()
+                    .emit();
+                    continue;
+                }
+                methods.insert(
+                    name.name,
+                    MethodRef {
+                        module: self.module,
+                        decl,
+                        method,
+                    },
+                );
+            }
+        }
+    }
+
+    fn resolve_module(&mut self) {
+        let module = self.src_module.expect("resolving a module");
+        for declaration in &module.decls {
+            match declaration {
+                Declaration::Func(func) => self.resolve_func(func, false),
+                Declaration::Extern(func) => {
+                    for param in &func.sig.params {
+                        self.resolve_type(&param.ty);
+                    }
+                    if let Some(ret) = &func.sig.ret {
+                        self.resolve_type(ret);
+                    }
+                }
+                Declaration::Obj(obj) => {
+                    for field in &obj.fields {
+                        self.resolve_type(&field.ty);
+                        if let Some(default) = &field.default {
+                            self.resolve_expr(default);
+                        }
+                    }
+                }
+                Declaration::Impl(imp) => {
+                    for method in &imp.methods {
+                        self.resolve_func(method, true);
+                    }
+                }
+                Declaration::Identifier(_) | Declaration::Use(_) => {}
+            }
+        }
+    }
+
+    fn bind(&mut self, id: NodeId, name: &Ident<'src>, kind: BindingKind) {
+        self.res.bindings.insert(
+            id,
+            Binding {
+                name: name.name,
+                span: name.span,
+                kind,
+            },
+        );
+        self.scopes
+            .last_mut()
+            .expect("bindings are always inside of a scope")
+            .push((name.name, id));
+    }
+
+    fn resolve_func(&mut self, func: &Func<'src>, method: bool) {
+        self.scopes.push(Vec::new());
+        if method {
+            self.res.bindings.insert(
+                func.id,
+                Binding {
+                    name: "self",
+                    span: func.sig.name.span,
+                    kind: BindingKind::SelfParam,
+                },
+            );
+            self.scopes.last_mut().unwrap().push(("self", func.id));
+        }
+        for param in &func.sig.params {
+            self.resolve_type(&param.ty);
+            self.bind(param.id, &param.name, BindingKind::Param);
+        }
+        if let Some(ret) = &func.sig.ret {
+            self.resolve_type(ret);
+        }
+        self.resolve_block(&func.body);
+        self.scopes.pop();
+    }
+
+    fn resolve_block(&mut self, block: &Block<'src>) {
+        self.scopes.push(Vec::new());
+        for stmt in &block.stmts {
+            self.resolve_stmt(stmt);
+        }
+        self.scopes.pop();
+    }
+
+    fn resolve_stmt(&mut self, stmt: &Stmt<'src>) {
+        match &stmt.kind {
+            StmtKind::Local { ty, name, init } => {
+                if let Some(ty) = ty {
+                    self.resolve_type(ty);
+                }
+                self.resolve_expr(init);
+                self.bind(stmt.id, name, BindingKind::Local);
+            }
+            StmtKind::Assign { target, value } => {
+                self.resolve_expr(target);
+                self.resolve_expr(value);
+            }
+            StmtKind::Increment(target) | StmtKind::Expr(target) | StmtKind::Bulk(target) => {
+                self.resolve_expr(target)
+            }
+            StmtKind::Return(value) => {
+                if let Some(value) = value {
+                    self.resolve_expr(value);
+                }
+            }
+            StmtKind::If { cond, then, els } => {
+                self.resolve_expr(cond);
+                self.resolve_block(then);
+                if let Some(els) = els {
+                    self.resolve_block(els);
+                }
+            }
+            StmtKind::While { cond, body } => {
+                self.resolve_expr(cond);
+                self.resolve_loop(body, None);
+            }
+            StmtKind::For { name, iter, body } => {
+                self.resolve_expr(iter);
+                self.resolve_loop(body, Some((stmt.id, name)));
+            }
+            StmtKind::Break | StmtKind::Continue if self.loops == 0 => {
+                let keyword = stmt.span.apply();
+                self.error(format!("`{keyword}` outside of a loop"), stmt.span)
+                    .with_note(format!(
+                        "`{keyword}` can only be used inside of `while` and `for` loops"
+                    ))
+                    .finish()
+                    .emit();
+            }
+            StmtKind::Break | StmtKind::Continue => {}
+        }
+    }
+
+    /// Resolves the body of a loop, with the loop variable of a `for` loop in scope.
+    fn resolve_loop(&mut self, body: &Block<'src>, var: Option<(NodeId, &Ident<'src>)>) {
+        self.loops += 1;
+        self.scopes.push(Vec::new());
+        if let Some((id, name)) = var {
+            self.bind(id, name, BindingKind::Local);
+        }
+        self.resolve_block(body);
+        self.scopes.pop();
+        self.loops -= 1;
+    }
+
+    fn resolve_type(&mut self, ty: &TypeExpr<'src>) {
+        match ty {
+            TypeExpr::Void(_) => {}
+            TypeExpr::Array(inner, _) => self.resolve_type(inner),
+            TypeExpr::Named(name) => {
+                if PrimType::from_name(name.name).is_some() {
+                    return;
+                }
+                match self.graph.lookup(self.module, name.name) {
+                    Some(item) if self.graph.item(item).kind == ItemKind::Obj => {}
+                    Some(item) => {
+                        let kind = self.graph.item(item).kind;
+                        self.error(
+                            format!("`{}` is a {}, not a type", name.name, kind.describe()),
+                            name.span,
+                        )
+                        .finish()
+                        .emit();
+                    }
+                    None => {
+                        let types = self
+                            .graph
+                            .visible_names(self.module)
+                            .filter(|x| {
+                                self.graph
+                                    .lookup(self.module, x)
+                                    .is_some_and(|x| self.graph.item(x).kind == ItemKind::Obj)
+                            })
+                            .chain(PrimType::ALL.map(PrimType::name));
+                        let suggestion = suggest(name.name, types);
+                        self.not_found("type", name.name, name.span, suggestion);
+                    }
+                }
+            }
+        }
+    }
+
+    fn not_found(&self, what: &str, name: &'src str, span: Span<'src>, suggestion: Option<&str>) {
+        let mut diag = self.error(format!("cannot find {what} `{name}` in this scope"), span);
+        if let Some(private) = self.graph.private_import(self.module, name) {
+            let private = self.graph.item(private);
+            diag = diag.with_note(format!(
+                "{} `{name}` exists in `{}`, but it is private",
+                private.kind.describe(),
+                self.graph.modules[private.module.0].name
+            ));
+        } else if let Some(suggestion) = suggestion {
+            diag = diag.with_note(format!("help: did you mean `{suggestion}`?"));
+        }
+        diag.finish().emit();
+    }
+
+    fn lookup_local(&self, name: &str) -> Option<NodeId> {
+        self.scopes
+            .iter()
+            .rev()
+            .flat_map(|x| x.iter().rev())
+            .find(|x| x.0 == name)
+            .map(|x| x.1)
+    }
+
+    fn resolve_name(&mut self, id: NodeId, name: &Ident<'src>) {
+        let res = if let Some(local) = self.lookup_local(name.name) {
+            self.res.uses.push((name.span, local));
+            Res::Local(local)
+        } else if let Some(item) = self.graph.lookup(self.module, name.name) {
+            Res::Item(item)
+        } else if let Some(builtin) = Builtin::from_name(name.name) {
+            Res::Builtin(builtin)
+        } else if name.name == "self" {
+            self.error("`self` can only be used inside of methods", name.span)
+                .with_note("methods are functions inside of an `impl` block")
+                .finish()
+                .emit();
+            return;
+        } else {
+            let locals = self.scopes.iter().flatten().map(|x| x.0);
+            let candidates = locals
+                .chain(self.graph.visible_names(self.module))
+                .chain(Builtin::ALL.map(Builtin::name));
+            let suggestion = suggest(name.name, candidates);
+            self.not_found("value", name.name, name.span, suggestion);
+            return;
+        };
+        self.res.names.insert(id, res);
+    }
+
+    fn resolve_expr(&mut self, expr: &Expr<'src>) {
+        match &expr.kind {
+            ExprKind::Name(name) => self.resolve_name(expr.id, name),
+            ExprKind::Str(_) | ExprKind::Int(_) | ExprKind::Float(_) | ExprKind::Bool(_) => {}
+            ExprKind::Array(items) => {
+                for item in items {
+                    self.resolve_expr(item);
+                }
+            }
+            ExprKind::Field { base, .. } => self.resolve_expr(base),
+            ExprKind::Call { callee, args } => {
+                self.resolve_expr(callee);
+                for arg in args {
+                    self.resolve_expr(arg);
+                }
+            }
+            ExprKind::Construct { ty, fields } => {
+                self.resolve_type(&TypeExpr::Named(*ty));
+                if let Some(item) = self.graph.lookup(self.module, ty.name)
+                    && self.graph.item(item).kind == ItemKind::Obj
+                {
+                    self.res.names.insert(expr.id, Res::Item(item));
+                }
+                for field in fields {
+                    self.resolve_expr(&field.value);
+                }
+            }
+            ExprKind::Binary { lhs, rhs, .. } => {
+                self.resolve_expr(lhs);
+                self.resolve_expr(rhs);
+            }
+            ExprKind::Unary { operand, .. } => self.resolve_expr(operand),
+        }
+    }
+}
+
+#[test]
+fn edit_distance_test() {
+    assert_eq!(edit_distance("print", "print"), 0);
+    assert_eq!(edit_distance("prnt", "print"), 1);
+    assert_eq!(edit_distance("kitten", "sitting"), 3);
+    assert_eq!(
+        suggest("pritn", ["print", "println", "drop"]),
+        Some("print")
+    );
+    assert_eq!(suggest("zzz", ["print", "println", "drop"]), None);
+}
+
+#[test]
+fn resolve_test() {
+    use crate::Source;
+
+    let sources = [Source::new(
+        r"identifier app.main
+func void start() is
+    i32 val = 0
+    val = val + 1
+    i32 val = val
+    counter c = counter
+    c.bump(val)
+    print('done')
+end
+obj counter is
+    count: i32 = 0,
+end
+impl counter is
+    func bump(i32 by) is
+        self.count = self.count + by
+    end
+end",
+        "main.scp",
+    )];
+    let modules = crate::project::test_modules(&sources);
+    let graph = ModuleGraph::build(&modules);
+    let errors = crate::diag::error_count();
+    let res = resolve(&modules, &graph);
+    assert_eq!(crate::diag::error_count(), errors);
+
+    // Map every name to the text of the declaration it resolves to
+    let mut found = Vec::new();
+    fn walk<'src>(expr: &Expr<'src>, out: &mut Vec<(&'src str, NodeId)>) {
+        match &expr.kind {
+            ExprKind::Name(name) => out.push((name.name, expr.id)),
+            ExprKind::Field { base, .. } => walk(base, out),
+            ExprKind::Call { callee, args } => {
+                walk(callee, out);
+                args.iter().for_each(|x| walk(x, out));
+            }
+            ExprKind::Binary { lhs, rhs, .. } => {
+                walk(lhs, out);
+                walk(rhs, out);
+            }
+            _ => {}
+        }
+    }
+    let Declaration::Func(start) = &modules[0].decls[1] else {
+        panic!("expected start");
+    };
+    for stmt in &start.body.stmts {
+        match &stmt.kind {
+            StmtKind::Local { init, .. } => walk(init, &mut found),
+            StmtKind::Assign { target, value } => {
+                walk(target, &mut found);
+                walk(value, &mut found);
+            }
+            StmtKind::Expr(expr) | StmtKind::Increment(expr) => walk(expr, &mut found),
+            _ => {}
+        }
+    }
+    let lines: Vec<_> = found
+        .iter()
+        .map(|(name, id)| {
+            let def = res.definition(&graph, *id);
+            (*name, def.map(|x| x.get_start_code_pos().0))
+        })
+        .collect();
+    assert_eq!(
+        lines,
+        [
+            ("val", Some(3)),
+            ("val", Some(3)),
+            // The second `val` shadows the first, but only after its initializer
+            ("val", Some(3)),
+            ("counter", Some(10)),
+            ("c", Some(6)),
+            ("val", Some(5)),
+            ("print", None),
+        ]
+    );
+    assert!(
+        res.method(graph.lookup(ModuleId(0), "counter").unwrap(), "bump")
+            .is_some()
+    );
+}
+
+#[test]
+fn resolve_error_test() {
+    use crate::Source;
+
+    let sources = [Source::new(
+        r"identifier app.main
+func void start() is
+    i32 value = 0
+    prnt(valeu)
+    self.x = 1
+    nothing x = 0
+    break
+    for item in [1] is
+        continue
+    end
+    item++
+end",
+        "main.scp",
+    )];
+    let modules = crate::project::test_modules(&sources);
+    let graph = ModuleGraph::build(&modules);
+    let errors = crate::diag::error_count();
+    resolve(&modules, &graph);
+    assert_eq!(crate::diag::error_count(), errors + 6);
+}
@@ -3,6 +3,8 @@
 
 use std::{fmt::Display, ops::Range};
 
+use codespan_reporting::files::Files;
+
 use crate::Source;
 
 /// The `Span` type represents an area of a file. `'src` represents the lifetime of the source.
@@ -237,44 +239,82 @@ impl<'src> Span<'src> {
         &self.src.source[self.start as usize..self.end as usize]
     }
 
+    /// Checks if the byte at `offset` of `src` is inside of the span. The end of the span counts
+    /// as inside, so a cursor placed right after a name is still on the name.
+    ///
+    /// # Examples
+    /// ```
+    /// use escoop::{span::Span, Source};
+    ///
+    /// let file = "foo bar baz";
+    /// let src = Source::new(file, "test.txt");
+    /// let bar = Span::new_from(&src, 4, 7);
+    /// assert!(bar.contains(&src, 4));
+    /// assert!(bar.contains(&src, 7));
+    /// assert!(!bar.contains(&src, 8));
+    /// ```
+    #[inline]
+    pub fn contains(&self, src: &Source, offset: u32) -> bool {
+        std::ptr::eq(self.src, src) && self.start <= offset && offset <= self.end
+    }
+
+    /// Creates a new `Span` covering both `self` and `other`, including anything between them.
+    ///
+    /// # Examples
+    /// ```
+    /// use escoop::{span::Span, Source};
+    ///
+    /// let file = "foo bar baz";
+    /// let src = Source::new(file, "test.txt");
+    /// let foo = Span::new_from(&src, 0, 3);
+    /// let bar = Span::new_from(&src, 4, 7);
+    /// assert_eq!(foo.to(bar).apply(), "foo bar");
+    /// assert_eq!(bar.to(foo).apply(), "foo bar");
+    /// ```
+    #[inline]
+    pub fn to(self, other: Span<'src>) -> Span<'src> {
+        Span {
+            start: self.start.min(other.start),
+            end: self.end.max(other.end),
+            src: self.src,
+        }
+    }
+
+    /// Gets the source the `Span` points into.
+    #[inline]
+    pub fn source(&self) -> &'src Source<'src> {
+        self.src
+    }
+
     /// Gets a tuple of `(line, column)` for the start of the `Span`.
+    ///
+    /// # Examples
+    /// ```
+    /// use escoop::{span::Span, Source};
+    ///
+    /// let file = "foo\nbar baz";
+    /// let src = Source::new(file, "test.txt");
+    /// let span = Span::new_from(&src, 8, 11);
+    /// assert_eq!(span.get_start_code_pos(), (2, 5));
+    /// ```
     pub fn get_start_code_pos(&self) -> (u32, u32) {
-        let src = self.src.source.chars();
-        let mut column = 1;
-        let mut line = 1;
-        for i in src.enumerate() {
-            if i.0 == self.start as usize {
-                break;
-            }
-            match i.1 {
-                '\n' => {
-                    line += 1;
-                    column = 1
-                }
-                _ => column += 1,
-            }
-        }
-        (line, column)
+        self.code_pos(self.start)
     }
 
     /// Gets a tuple of `(line, column)` for the end of the `Span`.
     pub fn get_end_code_pos(&self) -> (u32, u32) {
-        let src = self.src.source.chars();
-        let mut column = 1;
-        let mut line = 1;
-        for i in src.enumerate() {
-            if i.0 == self.end as usize {
-                break;
-            }
-            match i.1 {
-                '\n' => {
-                    line += 1;
-                    column = 1
-                }
-                _ => column += 1,
-            }
-        }
-        (line, column)
+        self.code_pos(self.end)
+    }
+
+    fn code_pos(&self, index: u32) -> (u32, u32) {
+        let index = (index as usize).min(self.src.source.len());
+        let line = self
+            .src
+            .line_index((), index)
+            .expect("line index is infallible");
+        let line_start = self.src.line_starts[line];
+        let column = self.src.source[line_start..index].chars().count();
+        (line as u32 + 1, column as u32 + 1)
     }
 }
 
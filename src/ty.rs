@@ -0,0 +1,441 @@
+#![deny(missing_docs)]
+//! Module for the types of Escoop programs.
+//!
+//! Types are interned by a [`TyInterner`], so comparing two types is comparing two [`TyId`]s.
+//! Types that have to be inferred, like the type of a `let` or the element type of a bare
+//! `array`, start out as inference variables, which are bound by [`TyInterner::unify`].
+//! The signatures of functions and methods and the fields of object types are collected into
+//! [`ItemTypes`] before any function body is checked by [`check`].
+
+use std::collections::HashMap;
+
+use crate::{
+    ast::{Declaration, Ident, TypeExpr},
+    builtins::{Builtin, PrimType},
+    modules::{ItemId, ItemKind, ModuleGraph, ModuleId},
+    project::Module,
+    resolve::{MethodRef, Resolutions},
+    span::Span,
+};
+
+pub mod check;
+
+/// An interned type. Get what it is with [`TyInterner::kind`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct TyId(u32);
+
+impl TyId {
+    /// The `void` type.
+    pub const VOID: TyId = TyId(0);
+    /// The `i32` type.
+    pub const I32: TyId = TyId(1);
+    /// The `f32` type.
+    pub const F32: TyId = TyId(2);
+    /// The `str` type.
+    pub const STR: TyId = TyId(3);
+    /// The `string` type.
+    pub const STRING: TyId = TyId(4);
+    /// The type of expressions that failed to type check.
+    pub const ERROR: TyId = TyId(5);
+    /// A type that isn't known yet.
+    pub const UNKNOWN: TyId = TyId(6);
+    /// The `bool` type.
+    pub const BOOL: TyId = TyId(7);
+}
+
+/// Every kind of type.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum TyKind {
+    /// `void`, the type of functions that don't return anything.
+    Void,
+    /// `i32`
+    I32,
+    /// `f32`
+    F32,
+    /// `bool`
+    Bool,
+    /// `str`, the type of string literals.
+    Str,
+    /// `string`, an owned string.
+    String,
+    /// An array of elements of the inner type.
+    Array(TyId),
+    /// An object type.
+    Obj(ItemId),
+    /// The type of a function's name, which can only be called.
+    FnItem(ItemId),
+    /// The type of a builtin function's name.
+    Builtin(Builtin),
+    /// The type of expressions that failed to type check. It is compatible with every type, so
+    /// one mistake is only reported once.
+    Error,
+    /// A type that couldn't be inferred. It is compatible with every type.
+    Unknown,
+    /// An inference variable, a type that will be inferred from how values of it are used.
+    Infer(u32),
+}
+
+/// Interns types, giving every different type a different [`TyId`].
+#[derive(Debug)]
+pub struct TyInterner {
+    kinds: Vec<TyKind>,
+    ids: HashMap<TyKind, TyId>,
+    /// What every inference variable was bound to.
+    vars: Vec<Option<TyId>>,
+}
+
+impl Default for TyInterner {
+    fn default() -> Self {
+        let mut interner = TyInterner {
+            kinds: Vec::new(),
+            ids: HashMap::new(),
+            vars: Vec::new(),
+        };
+        // In the order of the constants of `TyId`
+        for kind in [
+            TyKind::Void,
+            TyKind::I32,
+            TyKind::F32,
+            TyKind::Str,
+            TyKind::String,
+            TyKind::Error,
+            TyKind::Unknown,
+            TyKind::Bool,
+        ] {
+            interner.intern(kind);
+        }
+        interner
+    }
+}
+
+impl TyInterner {
+    /// Creates an interner holding the builtin types.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Gets the id of `kind`, interning it if it hasn't been seen before.
+    pub fn intern(&mut self, kind: TyKind) -> TyId {
+        if let Some(id) = self.ids.get(&kind) {
+            return *id;
+        }
+        let id = TyId(self.kinds.len() as u32);
+        self.kinds.push(kind.clone());
+        self.ids.insert(kind, id);
+        id
+    }
+
+    /// Gets what the type `id` is.
+    pub fn kind(&self, id: TyId) -> &TyKind {
+        &self.kinds[id.0 as usize]
+    }
+
+    /// Gets the type of a builtin type name. Every `array` gets a new inference variable as its
+    /// element type.
+    pub fn prim(&mut self, prim: PrimType) -> TyId {
+        match prim {
+            PrimType::I32 => TyId::I32,
+            PrimType::F32 => TyId::F32,
+            PrimType::Bool => TyId::BOOL,
+            PrimType::Str => TyId::STR,
+            PrimType::String => TyId::STRING,
+            PrimType::Array => {
+                let elem = self.new_var();
+                self.intern(TyKind::Array(elem))
+            }
+        }
+    }
+
+    /// Creates a new inference variable.
+    pub fn new_var(&mut self) -> TyId {
+        self.vars.push(None);
+        self.intern(TyKind::Infer(self.vars.len() as u32 - 1))
+    }
+
+    /// Follows bound inference variables until reaching a type that isn't one.
+    pub fn shallow(&self, mut id: TyId) -> TyId {
+        while let TyKind::Infer(var) = self.kind(id)
+            && let Some(bound) = self.vars[*var as usize]
+        {
+            id = bound;
+        }
+        id
+    }
+
+    /// Replaces every bound inference variable inside of `id` with what it is bound to, and every
+    /// unbound one with [`TyId::UNKNOWN`].
+    pub fn resolve(&mut self, id: TyId) -> TyId {
+        let id = self.shallow(id);
+        match *self.kind(id) {
+            TyKind::Array(elem) => {
+                let elem = self.resolve(elem);
+                self.intern(TyKind::Array(elem))
+            }
+            TyKind::Infer(_) => TyId::UNKNOWN,
+            _ => id,
+        }
+    }
+
+    /// Checks if `id` still contains an unbound inference variable.
+    pub fn has_vars(&self, id: TyId) -> bool {
+        match *self.kind(self.shallow(id)) {
+            TyKind::Array(elem) => self.has_vars(elem),
+            TyKind::Infer(_) => true,
+            _ => false,
+        }
+    }
+
+    fn occurs(&self, var: u32, id: TyId) -> bool {
+        match *self.kind(self.shallow(id)) {
+            TyKind::Array(elem) => self.occurs(var, elem),
+            TyKind::Infer(other) => other == var,
+            _ => false,
+        }
+    }
+
+    /// Checks if the type is `i32` or `f32`.
+    pub fn is_numeric(&self, id: TyId) -> bool {
+        matches!(self.kind(self.shallow(id)), TyKind::I32 | TyKind::F32)
+    }
+
+    /// Checks if the type is `str` or `string`.
+    pub fn is_string(&self, id: TyId) -> bool {
+        matches!(self.kind(self.shallow(id)), TyKind::Str | TyKind::String)
+    }
+
+    /// Checks if values of the type own memory (`obj` values, `string`s and arrays). Such values
+    /// are moved instead of copied, and dropped at the end of their lifetime.
+    pub fn needs_drop(&self, id: TyId) -> bool {
+        matches!(
+            self.kind(self.shallow(id)),
+            TyKind::Obj(_) | TyKind::String | TyKind::Array(_)
+        )
+    }
+
+    /// Checks if the type is [`Error`](TyKind::Error), [`Unknown`](TyKind::Unknown) or an
+    /// unbound inference variable, which are compatible with every type.
+    pub fn is_unknown(&self, id: TyId) -> bool {
+        matches!(
+            self.kind(self.shallow(id)),
+            TyKind::Error | TyKind::Unknown | TyKind::Infer(_)
+        )
+    }
+
+    /// Checks if a value of type `found` can be used where a value of type `expected` is
+    /// expected, binding inference variables so the types match. Besides equal types, `str` and
+    /// `string` convert into each other.
+    pub fn unify(&mut self, found: TyId, expected: TyId) -> bool {
+        let found = self.shallow(found);
+        let expected = self.shallow(expected);
+        if found == expected {
+            return true;
+        }
+        match (self.kind(found).clone(), self.kind(expected).clone()) {
+            (TyKind::Error | TyKind::Unknown, _) | (_, TyKind::Error | TyKind::Unknown) => true,
+            (TyKind::Infer(var), _) if !self.occurs(var, expected) => {
+                self.vars[var as usize] = Some(expected);
+                true
+            }
+            (_, TyKind::Infer(var)) if !self.occurs(var, found) => {
+                self.vars[var as usize] = Some(found);
+                true
+            }
+            (TyKind::Array(found), TyKind::Array(expected)) => self.unify(found, expected),
+            _ => self.is_string(found) && self.is_string(expected),
+        }
+    }
+
+    /// Gets the name of a type as it would be written in the source.
+    pub fn name(&self, id: TyId, graph: &ModuleGraph) -> String {
+        match self.kind(self.shallow(id)) {
+            TyKind::Void => "void".to_string(),
+            TyKind::I32 => "i32".to_string(),
+            TyKind::F32 => "f32".to_string(),
+            TyKind::Bool => "bool".to_string(),
+            TyKind::Str => "str".to_string(),
+            TyKind::String => "string".to_string(),
+            TyKind::Array(inner) if self.is_unknown(*inner) => "array".to_string(),
+            TyKind::Array(inner) => format!("{}[]", self.name(*inner, graph)),
+            TyKind::Obj(item) => graph.item(*item).name.name.to_string(),
+            TyKind::FnItem(item) => format!("func {}", graph.item(*item).name.name),
+            TyKind::Builtin(builtin) => format!("func {}", builtin.name()),
+            TyKind::Error => "{error}".to_string(),
+            TyKind::Unknown | TyKind::Infer(_) => "_".to_string(),
+        }
+    }
+}
+
+/// The signature of a function or method.
+#[derive(Debug, Clone)]
+pub struct Signature<'src> {
+    /// Name of the function.
+    pub name: Ident<'src>,
+    /// Types of the parameters, not including `self`.
+    pub params: Vec<TyId>,
+    /// Where each parameter was declared.
+    pub param_spans: Vec<Span<'src>>,
+    /// Return type.
+    pub ret: TyId,
+    /// Span of the written return type, if any.
+    pub ret_span: Option<Span<'src>>,
+    /// Whether the function was marked `pub`.
+    pub public: bool,
+}
+
+/// A field of an object type.
+#[derive(Debug, Clone, Copy)]
+pub struct FieldDef<'src> {
+    /// Name of the field.
+    pub name: Ident<'src>,
+    /// Type of the field.
+    pub ty: TyId,
+    /// Span of the field's written type.
+    pub ty_span: Span<'src>,
+    /// Whether the field was marked `pub`.
+    pub public: bool,
+    /// Whether the field has a default value.
+    pub has_default: bool,
+}
+
+/// The types of every item: signatures of functions and methods and fields of object types.
+#[derive(Debug, Default)]
+pub struct ItemTypes<'src> {
+    /// Signatures of functions and extern functions.
+    pub funcs: HashMap<ItemId, Signature<'src>>,
+    /// Signatures of methods.
+    pub methods: HashMap<MethodRef, Signature<'src>>,
+    /// Fields of object types, in declaration order.
+    pub objs: HashMap<ItemId, Vec<FieldDef<'src>>>,
+}
+
+impl<'src> ItemTypes<'src> {
+    /// Collects the types of every item of `modules`. Types that can't be found were already
+    /// reported by name resolution, and become [`TyId::ERROR`].
+    pub fn collect(
+        modules: &[Module<'src>],
+        graph: &ModuleGraph<'src>,
+        res: &Resolutions<'src>,
+        tys: &mut TyInterner,
+    ) -> Self {
+        let mut items = ItemTypes::default();
+        for (index, item) in graph.items.iter().enumerate() {
+            let id = ItemId(index);
+            let decl = &modules[item.module.0].decls[item.decl];
+            match decl {
+                Declaration::Func(func) => {
+                    let sig = signature(&func.sig, func.public, item.module, graph, tys);
+                    items.funcs.insert(id, sig);
+                }
+                Declaration::Extern(func) => {
+                    let sig = signature(&func.sig, func.public, item.module, graph, tys);
+                    items.funcs.insert(id, sig);
+                }
+                Declaration::Obj(obj) => {
+                    let fields = obj
+                        .fields
+                        .iter()
+                        .map(|field| FieldDef {
+                            name: field.name,
+                            ty: lower_type(&field.ty, item.module, graph, tys),
+                            ty_span: field.ty.span(),
+                            public: field.public,
+                            has_default: field.default.is_some(),
+                        })
+                        .collect();
+                    items.objs.insert(id, fields);
+                }
+                _ => unreachable!("items are functions or types"),
+            }
+        }
+        for methods in res.methods.values() {
+            for method in methods.values() {
+                let Declaration::Impl(imp) = &modules[method.module.0].decls[method.decl] else {
+                    unreachable!("methods always come from impl blocks");
+                };
+                let func = &imp.methods[method.method];
+                let sig = signature(&func.sig, func.public, method.module, graph, tys);
+                items.methods.insert(*method, sig);
+            }
+        }
+        items
+    }
+
+    /// Finds the field `name` of the object type `obj`, with its index.
+    pub fn field(&self, obj: ItemId, name: &str) -> Option<(usize, &FieldDef<'src>)> {
+        self.objs
+            .get(&obj)?
+            .iter()
+            .enumerate()
+            .find(|(_, x)| x.name.name == name)
+    }
+}
+
+fn signature<'src>(
+    sig: &crate::ast::FuncSig<'src>,
+    public: bool,
+    module: ModuleId,
+    graph: &ModuleGraph<'src>,
+    tys: &mut TyInterner,
+) -> Signature<'src> {
+    Signature {
+        name: sig.name,
+        params: sig
+            .params
+            .iter()
+            .map(|x| lower_type(&x.ty, module, graph, tys))
+            .collect(),
+        param_spans: sig.params.iter().map(|x| x.ty.span()).collect(),
+        ret: sig
+            .ret
+            .as_ref()
+            .map_or(TyId::VOID, |x| lower_type(x, module, graph, tys)),
+        ret_span: sig.ret.as_ref().map(TypeExpr::span),
+        public,
+    }
+}
+
+/// Converts a written type into a [`TyId`], looking up object types in the scope of `module`.
+pub fn lower_type(
+    ty: &TypeExpr,
+    module: ModuleId,
+    graph: &ModuleGraph,
+    tys: &mut TyInterner,
+) -> TyId {
+    match ty {
+        TypeExpr::Void(_) => TyId::VOID,
+        TypeExpr::Array(inner, _) => {
+            let inner = lower_type(inner, module, graph, tys);
+            tys.intern(TyKind::Array(inner))
+        }
+        TypeExpr::Named(name) => {
+            if let Some(prim) = PrimType::from_name(name.name) {
+                return tys.prim(prim);
+            }
+            match graph.lookup(module, name.name) {
+                Some(item) if graph.item(item).kind == ItemKind::Obj => {
+                    tys.intern(TyKind::Obj(item))
+                }
+                _ => TyId::ERROR,
+            }
+        }
+    }
+}
+
+#[test]
+fn interner_test() {
+    let mut tys = TyInterner::new();
+    assert_eq!(tys.intern(TyKind::String), TyId::STRING);
+    let strings = tys.intern(TyKind::Array(TyId::STRING));
+    assert_eq!(tys.intern(TyKind::Array(TyId::STRING)), strings);
+    assert!(tys.unify(TyId::STR, TyId::STRING));
+    assert!(!tys.unify(TyId::I32, TyId::F32));
+
+    let array = tys.prim(PrimType::Array);
+    assert_ne!(array, strings);
+    assert!(tys.has_vars(array));
+    assert!(tys.unify(array, strings));
+    assert_eq!(tys.resolve(array), strings);
+    // The element type is bound now, so it can't become something else
+    let ints = tys.intern(TyKind::Array(TyId::I32));
+    assert!(!tys.unify(array, ints));
+}
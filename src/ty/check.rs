@@ -0,0 +1,1422 @@
+#![deny(missing_docs)]
+//! Module for the type checker, which computes the type of every expression and binding.
+//!
+//! Checking is bidirectional: the type an expression is expected to have flows down into it, so
+//! integer literals can become `f32` and `[]` can take the element type of the array it's
+//! assigned to. Inferred types (`let`, bare `array`) are inference variables until a later use
+//! binds them, like a `push` onto the array.
+//!
+//! Mismatches are reported at the expression with the wrong type, with a secondary label at
+//! whatever made the checker expect another type (a written type, a parameter or a return type).
+
+use std::{
+    collections::{HashMap, HashSet},
+    slice,
+};
+
+use codespan_reporting::diagnostic::Label;
+
+use crate::{
+    Source,
+    ast::{
+        BinOp, Block, Declaration, Expr, ExprKind, FieldInit, Func, Ident, NodeId, Stmt, StmtKind,
+        UnOp,
+    },
+    builtins::{Builtin, BuiltinMethod, CALL_OPERATOR},
+    diag::{Diag, DiagBuilder},
+    modules::{ItemId, ItemKind, ModuleGraph, ModuleId},
+    project::Module,
+    resolve::{MethodRef, Res, Resolutions, suggest},
+    span::Span,
+    ty::{ItemTypes, Signature, TyId, TyInterner, TyKind, lower_type},
+};
+
+/// The method a method call calls.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Method {
+    /// A method of an `impl` block.
+    User(MethodRef),
+    /// A method built into a primitive type.
+    Builtin(BuiltinMethod),
+}
+
+/// The result of type checking.
+#[derive(Debug)]
+pub struct TypeckResults<'src> {
+    /// Every type of the program.
+    pub tys: TyInterner,
+    /// Signatures and fields of every item.
+    pub items: ItemTypes<'src>,
+    /// The type of every expression.
+    pub exprs: HashMap<NodeId, TyId>,
+    /// The type of every local binding, keyed like [`Resolutions::bindings`].
+    pub bindings: HashMap<NodeId, TyId>,
+    /// The method called by every method call and overloaded operator, keyed by the id of the
+    /// call or the operation.
+    pub methods: HashMap<NodeId, Method>,
+    /// The index of the accessed field of every field access, keyed by the id of the access.
+    pub fields: HashMap<NodeId, usize>,
+    /// Every call of an object through its [call operator](CALL_OPERATOR), keyed by the id of
+    /// the call. The callee is the receiver of these method calls.
+    pub callable: HashSet<NodeId>,
+}
+
+impl<'src> TypeckResults<'src> {
+    /// Gets the type of an expression, which is [`TyId::ERROR`] if it wasn't checked.
+    pub fn expr_ty(&self, id: NodeId) -> TyId {
+        self.exprs.get(&id).copied().unwrap_or(TyId::ERROR)
+    }
+
+    /// Gets the receiver of the method call `call`, if it is one. The receiver of an
+    /// overloaded operator is its left hand side.
+    pub fn receiver<'e>(&self, call: &'e Expr<'src>) -> Option<&'e Expr<'src>> {
+        if !self.methods.contains_key(&call.id) {
+            return None;
+        }
+        match &call.kind {
+            ExprKind::Call { callee, .. } if self.callable.contains(&call.id) => Some(callee),
+            ExprKind::Call { callee, .. } => match &callee.kind {
+                ExprKind::Field { base, .. } => Some(base),
+                _ => None,
+            },
+            ExprKind::Binary { lhs, .. } => Some(lhs),
+            _ => None,
+        }
+    }
+
+    /// Gets the object type and the index of the field read by the field access `expr`.
+    pub fn field(&self, expr: &Expr) -> Option<(ItemId, usize)> {
+        let ExprKind::Field { base, .. } = &expr.kind else {
+            return None;
+        };
+        let index = *self.fields.get(&expr.id)?;
+        match self.tys.kind(self.expr_ty(base.id)) {
+            TyKind::Obj(obj) => Some((*obj, index)),
+            _ => None,
+        }
+    }
+
+    /// Describes the local binding declared or used at `offset` of `src`, like `input: string`,
+    /// for tooling to show when hovering it.
+    pub fn hover(
+        &self,
+        graph: &ModuleGraph<'src>,
+        res: &Resolutions<'src>,
+        src: &Source,
+        offset: u32,
+    ) -> Option<String> {
+        let binding = res.binding_at(src, offset)?;
+        let ty = self.bindings.get(&binding)?;
+        Some(format!(
+            "{}: {}",
+            res.bindings[&binding].name,
+            self.tys.name(*ty, graph)
+        ))
+    }
+}
+
+/// Type checks every function body, field default and `impl` block of `modules`, reporting
+/// mismatches through [`Diag`].
+pub fn check<'src>(
+    modules: &[Module<'src>],
+    graph: &ModuleGraph<'src>,
+    res: &Resolutions<'src>,
+) -> TypeckResults<'src> {
+    let mut tys = TyInterner::new();
+    let items = ItemTypes::collect(modules, graph, res, &mut tys);
+    let mut checker = Checker {
+        graph,
+        res,
+        module: ModuleId(0),
+        ret: TyId::VOID,
+        ret_span: None,
+        binding_spans: HashMap::new(),
+        bulk_arg: None,
+        lets: Vec::new(),
+        out: TypeckResults {
+            tys,
+            items,
+            exprs: HashMap::new(),
+            bindings: HashMap::new(),
+            methods: HashMap::new(),
+            fields: HashMap::new(),
+            callable: HashSet::new(),
+        },
+    };
+    for (index, module) in modules.iter().enumerate() {
+        checker.module = ModuleId(index);
+        checker.check_module(module);
+    }
+    checker.finish()
+}
+
+/// Explains what `bulk` does, for its errors.
+const BULK_HELP: &str =
+    "`bulk f(a, items)` calls `f(a, item)` once for every item of the array `items`";
+
+fn plural(count: usize, word: &str) -> String {
+    if count == 1 {
+        format!("{count} {word}")
+    } else {
+        format!("{count} {word}s")
+    }
+}
+
+struct Checker<'a, 'src> {
+    graph: &'a ModuleGraph<'src>,
+    res: &'a Resolutions<'src>,
+    module: ModuleId,
+    ret: TyId,
+    ret_span: Option<Span<'src>>,
+    /// Where the type of every binding was written.
+    binding_spans: HashMap<NodeId, Span<'src>>,
+    /// Every `let` declaration, which has to have a known type once every body is checked.
+    lets: Vec<(NodeId, Ident<'src>)>,
+    /// The argument of the `bulk` call being checked whose items are passed instead of it.
+    bulk_arg: Option<NodeId>,
+    out: TypeckResults<'src>,
+}
+
+impl<'a, 'src> Checker<'a, 'src> {
+    fn error(&self, message: impl ToString, span: Span<'src>) -> DiagBuilder<'src> {
+        Diag::error(span.source())
+            .with_message(message)
+            .with_label(Label::primary((), span))
+    }
+
+    fn name(&self, ty: TyId) -> String {
+        self.out.tys.name(ty, self.graph)
+    }
+
+    /// Gets what `ty` is, looking through bound inference variables.
+    fn kind(&self, ty: TyId) -> TyKind {
+        self.out.tys.kind(self.out.tys.shallow(ty)).clone()
+    }
+
+    /// Reports `let` declarations whose type couldn't be inferred, and replaces every inference
+    /// variable in the results with what it was inferred to be.
+    fn finish(mut self) -> TypeckResults<'src> {
+        for (binding, name) in &self.lets {
+            if self.out.tys.has_vars(self.out.bindings[binding]) {
+                self.error("type annotations needed", name.span)
+                    .with_note(format!(
+                        "cannot infer the type of `{}`, so it has to be written, like `string[] {} = ...`",
+                        name.name, name.name
+                    ))
+                    .finish()
+                    .emit();
+            }
+        }
+        let out = &mut self.out;
+        for ty in out.exprs.values_mut().chain(out.bindings.values_mut()) {
+            *ty = out.tys.resolve(*ty);
+        }
+        for field in out.items.objs.values_mut().flatten() {
+            field.ty = out.tys.resolve(field.ty);
+        }
+        let sigs = out
+            .items
+            .funcs
+            .values_mut()
+            .chain(out.items.methods.values_mut());
+        for sig in sigs {
+            for param in &mut sig.params {
+                *param = out.tys.resolve(*param);
+            }
+            sig.ret = out.tys.resolve(sig.ret);
+        }
+        self.out
+    }
+
+    fn lower(&mut self, ty: &crate::ast::TypeExpr) -> TyId {
+        lower_type(ty, self.module, self.graph, &mut self.out.tys)
+    }
+
+    /// Finds the item declared by `decls[decl]`, which isn't an item if it has a duplicate name.
+    fn item_of(&self, name: &Ident, decl: usize) -> Option<ItemId> {
+        let item = *self.graph.modules[self.module.0].items.get(name.name)?;
+        (self.graph.item(item).decl == decl).then_some(item)
+    }
+
+    fn check_module(&mut self, module: &Module<'src>) {
+        for (index, decl) in module.decls.iter().enumerate() {
+            match decl {
+                Declaration::Func(func) => {
+                    let sig = self.item_of(&func.sig.name, index);
+                    let sig = sig.map(|x| self.out.items.funcs[&x].clone());
+                    self.check_func(func, sig, None);
+                }
+                Declaration::Obj(obj) => {
+                    let Some(item) = self.item_of(&obj.name, index) else {
+                        continue;
+                    };
+                    for (field, def) in obj.fields.iter().zip(self.out.items.objs[&item].clone()) {
+                        if let Some(default) = &field.default {
+                            self.coerce(default, def.ty, Some(def.ty_span));
+                        }
+                    }
+                }
+                Declaration::Impl(imp) => {
+                    let target = self.graph.modules[self.module.0]
+                        .items
+                        .get(imp.target.name)
+                        .filter(|x| self.graph.item(**x).kind == ItemKind::Obj);
+                    let self_ty = match target {
+                        Some(item) => self.out.tys.intern(TyKind::Obj(*item)),
+                        None => TyId::ERROR,
+                    };
+                    for (method_index, method) in imp.methods.iter().enumerate() {
+                        let method_ref = MethodRef {
+                            module: self.module,
+                            decl: index,
+                            method: method_index,
+                        };
+                        let sig = self.out.items.methods.get(&method_ref).cloned();
+                        self.check_func(method, sig, Some(self_ty));
+                    }
+                }
+                Declaration::Identifier(_) | Declaration::Use(_) | Declaration::Extern(_) => {}
+            }
+        }
+    }
+
+    /// Checks the body of a function. `sig` is the signature callers see, which is `None` if the
+    /// function isn't an item because its name is a duplicate.
+    fn check_func(
+        &mut self,
+        func: &Func<'src>,
+        sig: Option<Signature<'src>>,
+        self_ty: Option<TyId>,
+    ) {
+        if let Some(self_ty) = self_ty {
+            self.out.bindings.insert(func.id, self_ty);
+        }
+        for (index, param) in func.sig.params.iter().enumerate() {
+            let ty = match &sig {
+                Some(sig) => sig.params[index],
+                None => self.lower(&param.ty),
+            };
+            self.out.bindings.insert(param.id, ty);
+            self.binding_spans.insert(param.id, param.ty.span());
+        }
+        self.ret = match (&sig, &func.sig.ret) {
+            (Some(sig), _) => sig.ret,
+            (None, Some(ret)) => self.lower(ret),
+            (None, None) => TyId::VOID,
+        };
+        self.ret_span = func.sig.ret.as_ref().map(|x| x.span());
+        self.check_block(&func.body);
+        if self.ret != TyId::VOID && !self.out.tys.is_unknown(self.ret) && !func.body.diverges() {
+            let mut diag = self.error(
+                format!("function `{}` might not return a value", func.sig.name.name),
+                func.sig.name.span,
+            );
+            if let Some(span) = self.ret_span {
+                diag = diag.with_secondary(
+                    span,
+                    format!("the function returns `{}`", self.name(self.ret)),
+                );
+            }
+            diag.with_note("help: every path through the function has to end with a `return`")
+                .finish()
+                .emit();
+        }
+    }
+
+    fn check_block(&mut self, block: &Block<'src>) {
+        for stmt in &block.stmts {
+            self.check_stmt(stmt);
+        }
+    }
+
+    fn check_stmt(&mut self, stmt: &Stmt<'src>) {
+        match &stmt.kind {
+            StmtKind::Local {
+                ty: Some(written),
+                init,
+                ..
+            } => {
+                let ty = self.lower(written);
+                if ty == TyId::VOID {
+                    self.error("variables can't have type `void`", written.span())
+                        .finish()
+                        .emit();
+                }
+                self.coerce(init, ty, Some(written.span()));
+                self.out.bindings.insert(stmt.id, ty);
+                self.binding_spans.insert(stmt.id, written.span());
+            }
+            StmtKind::Local {
+                ty: None,
+                name,
+                init,
+            } => {
+                let ty = self.check_expr(init);
+                if ty == TyId::VOID {
+                    self.error("variables can't have type `void`", init.span)
+                        .with_note(format!("`{}` doesn't return a value", init.span.apply()))
+                        .finish()
+                        .emit();
+                }
+                self.out.bindings.insert(stmt.id, ty);
+                self.lets.push((stmt.id, *name));
+            }
+            StmtKind::Assign { target, value } => {
+                let ty = self.check_place(target);
+                let span = self.place_span(target);
+                self.coerce(value, ty, span);
+            }
+            StmtKind::Increment(target) => {
+                let ty = self.check_place(target);
+                if !self.out.tys.is_numeric(ty) && !self.out.tys.is_unknown(ty) {
+                    self.error(
+                        format!("cannot increment a value of type `{}`", self.name(ty)),
+                        target.span,
+                    )
+                    .finish()
+                    .emit();
+                }
+            }
+            StmtKind::Expr(expr) => {
+                self.check_expr(expr);
+            }
+            StmtKind::Bulk(call) => {
+                match &call.kind {
+                    ExprKind::Call { args, .. } if !args.is_empty() => {
+                        self.bulk_arg = args.last().map(|x| x.id);
+                    }
+                    ExprKind::Call { .. } => {
+                        self.error("`bulk` call without an array to go through", call.span)
+                            .with_note(BULK_HELP)
+                            .finish()
+                            .emit();
+                    }
+                    _ => {
+                        self.error("`bulk` can only be used on calls", call.span)
+                            .with_note(BULK_HELP)
+                            .finish()
+                            .emit();
+                    }
+                }
+                self.check_expr(call);
+                self.bulk_arg = None;
+            }
+            StmtKind::Return(Some(value)) => self.coerce(value, self.ret, self.ret_span),
+            StmtKind::Return(None) => {
+                if self.ret != TyId::VOID && !self.out.tys.is_unknown(self.ret) {
+                    let mut diag = self.error(
+                        format!("expected a value of type `{}`", self.name(self.ret)),
+                        stmt.span,
+                    );
+                    if let Some(span) = self.ret_span {
+                        diag = diag.with_secondary(span, "expected because of the return type");
+                    }
+                    diag.finish().emit();
+                }
+            }
+            StmtKind::If { cond, then, els } => {
+                self.coerce(cond, TyId::BOOL, None);
+                self.check_block(then);
+                if let Some(els) = els {
+                    self.check_block(els);
+                }
+            }
+            StmtKind::While { cond, body } => {
+                self.coerce(cond, TyId::BOOL, None);
+                self.check_block(body);
+            }
+            StmtKind::For { iter, body, .. } => {
+                let ty = self.check_expr(iter);
+                let elem = match self.kind(ty) {
+                    TyKind::Array(elem) => elem,
+                    _ if self.out.tys.is_unknown(ty) => TyId::ERROR,
+                    _ => {
+                        self.error(
+                            format!("cannot loop over a value of type `{}`", self.name(ty)),
+                            iter.span,
+                        )
+                        .with_note("`for` loops go through the items of an array")
+                        .finish()
+                        .emit();
+                        TyId::ERROR
+                    }
+                };
+                self.out.bindings.insert(stmt.id, elem);
+                self.check_block(body);
+            }
+            StmtKind::Break | StmtKind::Continue => {}
+        }
+    }
+
+    /// Checks an expression being assigned to.
+    fn check_place(&mut self, expr: &Expr<'src>) -> TyId {
+        let ty = self.check_expr(expr);
+        let is_place = match &expr.kind {
+            ExprKind::Name(_) => matches!(self.res.names.get(&expr.id), Some(Res::Local(_)) | None),
+            ExprKind::Field { .. } => true,
+            _ => false,
+        };
+        if !is_place {
+            self.error("cannot assign to this expression", expr.span)
+                .with_note("only variables and fields can be assigned to")
+                .finish()
+                .emit();
+            return TyId::ERROR;
+        }
+        ty
+    }
+
+    /// Gets the span of the written type of a place.
+    fn place_span(&self, expr: &Expr<'src>) -> Option<Span<'src>> {
+        match &expr.kind {
+            ExprKind::Name(_) => match self.res.names.get(&expr.id)? {
+                Res::Local(binding) => self.binding_spans.get(binding).copied(),
+                _ => None,
+            },
+            ExprKind::Field { base, .. } => {
+                let TyKind::Obj(obj) = self.kind(self.out.expr_ty(base.id)) else {
+                    return None;
+                };
+                let field = self.out.fields.get(&expr.id)?;
+                Some(self.out.items.objs[&obj][*field].ty_span)
+            }
+            _ => None,
+        }
+    }
+
+    /// Checks that `expr` has a type compatible with `expected`, which was required by the code at
+    /// `expected_span`.
+    fn coerce(&mut self, expr: &Expr<'src>, expected: TyId, expected_span: Option<Span<'src>>) {
+        let found = self.check_expr_with(expr, Some(expected));
+        if !self.out.tys.unify(found, expected) {
+            self.mismatch(expr.span, found, expected, expected_span);
+        }
+    }
+
+    fn mismatch(
+        &self,
+        span: Span<'src>,
+        found: TyId,
+        expected: TyId,
+        expected_span: Option<Span<'src>>,
+    ) {
+        let expected = self.name(expected);
+        let mut diag = Diag::error(span.source())
+            .with_message("mismatched types")
+            .with_label(Label::primary((), span).with_message(format!(
+                "expected `{expected}`, found `{}`",
+                self.name(found)
+            )));
+        if let Some(expected_span) = expected_span {
+            diag = diag.with_secondary(
+                expected_span,
+                format!("expected `{expected}` because of this"),
+            );
+        }
+        diag.finish().emit();
+    }
+
+    fn check_expr(&mut self, expr: &Expr<'src>) -> TyId {
+        self.check_expr_with(expr, None)
+    }
+
+    /// Checks an expression that is expected to have the type `expected`. The expectation only
+    /// guides literals; callers still have to check the returned type against it.
+    fn check_expr_with(&mut self, expr: &Expr<'src>, expected: Option<TyId>) -> TyId {
+        if self.bulk_arg == Some(expr.id) {
+            self.bulk_arg = None;
+            return self.check_bulk_arg(expr, expected);
+        }
+        let expected = expected.map(|x| self.out.tys.shallow(x));
+        let ty = match &expr.kind {
+            ExprKind::Name(_) => self.check_name(expr),
+            ExprKind::Str(_) => TyId::STR,
+            ExprKind::Int(_) if expected == Some(TyId::F32) => TyId::F32,
+            ExprKind::Int(_) => TyId::I32,
+            ExprKind::Float(_) => TyId::F32,
+            ExprKind::Bool(_) => TyId::BOOL,
+            ExprKind::Array(items) => {
+                let elem = match expected.map(|x| self.kind(x)) {
+                    Some(TyKind::Array(elem)) => elem,
+                    _ => self.out.tys.new_var(),
+                };
+                // Without an expected type, the first item decides the element type
+                let mut first = None;
+                for item in items {
+                    self.coerce(item, elem, first);
+                    first.get_or_insert(item.span);
+                }
+                self.out.tys.intern(TyKind::Array(elem))
+            }
+            ExprKind::Field { base, field } => self.check_field(expr, base, field),
+            ExprKind::Call { callee, args } => self.check_call(expr, callee, args),
+            ExprKind::Construct { fields, .. } => self.check_construct(expr, fields),
+            ExprKind::Binary { op, lhs, rhs } => self.check_binary(expr, *op, lhs, rhs, expected),
+            ExprKind::Unary {
+                op: UnOp::Not,
+                operand,
+            } => {
+                self.coerce(operand, TyId::BOOL, None);
+                TyId::BOOL
+            }
+            ExprKind::Unary { op, operand } => {
+                let ty = self.check_expr_with(operand, expected);
+                match op {
+                    UnOp::Neg if self.out.tys.is_numeric(ty) || self.out.tys.is_unknown(ty) => ty,
+                    UnOp::Neg => {
+                        self.error(
+                            format!("cannot apply unary `-` to `{}`", self.name(ty)),
+                            expr.span,
+                        )
+                        .finish()
+                        .emit();
+                        TyId::ERROR
+                    }
+                    UnOp::Not => unreachable!("`not` is checked above"),
+                }
+            }
+        };
+        self.out.exprs.insert(expr.id, ty);
+        ty
+    }
+
+    /// Checks the array argument of a `bulk` call, giving the type of its items, which are passed
+    /// in its place.
+    fn check_bulk_arg(&mut self, expr: &Expr<'src>, expected: Option<TyId>) -> TyId {
+        let expected = expected.map(|x| self.out.tys.intern(TyKind::Array(x)));
+        let ty = self.check_expr_with(expr, expected);
+        match self.kind(ty) {
+            TyKind::Array(item) => item,
+            _ if self.out.tys.is_unknown(ty) => TyId::ERROR,
+            _ => {
+                self.error(
+                    format!("`bulk` needs an array, found `{}`", self.name(ty)),
+                    expr.span,
+                )
+                .with_note(BULK_HELP)
+                .finish()
+                .emit();
+                TyId::ERROR
+            }
+        }
+    }
+
+    fn check_name(&mut self, expr: &Expr<'src>) -> TyId {
+        match self.res.names.get(&expr.id) {
+            Some(Res::Local(binding)) => self
+                .out
+                .bindings
+                .get(binding)
+                .copied()
+                .unwrap_or(TyId::ERROR),
+            Some(Res::Item(item)) => match self.graph.item(*item).kind {
+                // Naming an object type constructs it with its default values
+                ItemKind::Obj => {
+                    self.missing_fields(*item, expr.span, &[]);
+                    self.out.tys.intern(TyKind::Obj(*item))
+                }
+                ItemKind::Func | ItemKind::Extern => self.out.tys.intern(TyKind::FnItem(*item)),
+            },
+            Some(Res::Builtin(builtin)) => self.out.tys.intern(TyKind::Builtin(*builtin)),
+            None => TyId::ERROR,
+        }
+    }
+
+    fn check_construct(&mut self, expr: &Expr<'src>, fields: &[FieldInit<'src>]) -> TyId {
+        let Some(Res::Item(obj)) = self.res.names.get(&expr.id).copied() else {
+            for field in fields {
+                self.check_expr(&field.value);
+            }
+            return TyId::ERROR;
+        };
+        let ty = self.out.tys.intern(TyKind::Obj(obj));
+        let mut given: Vec<Ident> = Vec::new();
+        for field in fields {
+            let name = field.name;
+            let Some((_, def)) = self.out.items.field(obj, name.name) else {
+                let names = self.out.items.objs[&obj].iter().map(|x| x.name.name);
+                let mut diag = self.error(
+                    format!("no field `{}` on type `{}`", name.name, self.name(ty)),
+                    name.span,
+                );
+                if let Some(suggestion) = suggest(name.name, names) {
+                    diag = diag.with_note(format!("help: did you mean `{suggestion}`?"));
+                }
+                diag.finish().emit();
+                self.check_expr(&field.value);
+                continue;
+            };
+            let def = *def;
+            if let Some(first) = given.iter().find(|x| x.name == name.name) {
+                self.error(
+                    format!("field `{}` is initialized twice", name.name),
+                    name.span,
+                )
+                .with_secondary(first.span, "first initialized here")
+                .finish()
+                .emit();
+            } else if !def.public && self.graph.item(obj).module != self.module {
+                self.error(
+                    format!(
+                        "field `{}` of type `{}` is private",
+                        name.name,
+                        self.name(ty)
+                    ),
+                    name.span,
+                )
+                .finish()
+                .emit();
+            }
+            given.push(name);
+            self.coerce(&field.value, def.ty, Some(def.ty_span));
+        }
+        let given: Vec<_> = given.iter().map(|x| x.name).collect();
+        self.missing_fields(obj, expr.span, &given);
+        ty
+    }
+
+    /// Reports the fields of `obj` that have no default value and aren't in `given`, for the
+    /// constructor at `span`.
+    fn missing_fields(&self, obj: ItemId, span: Span<'src>, given: &[&str]) {
+        let missing: Vec<_> = self.out.items.objs[&obj]
+            .iter()
+            .filter(|x| !x.has_default && !given.contains(&x.name.name))
+            .collect();
+        let Some(first) = missing.first() else {
+            return;
+        };
+        let names: Vec<_> = missing
+            .iter()
+            .map(|x| format!("`{}`", x.name.name))
+            .collect();
+        let list = match names.split_last() {
+            Some((last, [])) => last.clone(),
+            Some((last, rest)) => format!("{} and {last}", rest.join(", ")),
+            None => unreachable!("there is at least one missing field"),
+        };
+        let plural = if missing.len() == 1 { "" } else { "s" };
+        let ty = self.graph.item(obj).name.name;
+        let mut diag = Diag::error(span.source())
+            .with_message(format!(
+                "missing field{plural} {list} in constructor of `{ty}`"
+            ))
+            .with_label(Label::primary((), span).with_message(format!("missing {list}")));
+        for field in &missing {
+            diag = diag.with_secondary(
+                field.name.span,
+                format!("`{}` has no default value", field.name.name),
+            );
+        }
+        diag.with_note(format!(
+            "help: initialize {} by name, like `{ty}({}: ...)`",
+            if missing.len() == 1 { "it" } else { "them" },
+            first.name.name
+        ))
+        .finish()
+        .emit();
+    }
+
+    fn check_field(&mut self, expr: &Expr<'src>, base: &Expr<'src>, field: &Ident<'src>) -> TyId {
+        let base_ty = self.check_expr(base);
+        let obj = match self.kind(base_ty) {
+            TyKind::Obj(obj) => obj,
+            TyKind::Error | TyKind::Unknown | TyKind::Infer(_) => return TyId::ERROR,
+            _ => {
+                self.error(
+                    format!("no field `{}` on type `{}`", field.name, self.name(base_ty)),
+                    field.span,
+                )
+                .finish()
+                .emit();
+                return TyId::ERROR;
+            }
+        };
+        let Some((index, def)) = self.out.items.field(obj, field.name) else {
+            let fields = self.out.items.objs[&obj].iter().map(|x| x.name.name);
+            let mut diag = self.error(
+                format!("no field `{}` on type `{}`", field.name, self.name(base_ty)),
+                field.span,
+            );
+            if let Some(suggestion) = suggest(field.name, fields) {
+                diag = diag.with_note(format!("help: did you mean `{suggestion}`?"));
+            }
+            diag.finish().emit();
+            return TyId::ERROR;
+        };
+        let def = *def;
+        if !def.public && self.graph.item(obj).module != self.module {
+            self.error(
+                format!(
+                    "field `{}` of type `{}` is private",
+                    field.name,
+                    self.name(base_ty)
+                ),
+                field.span,
+            )
+            .with_secondary(def.name.span, "field declared here")
+            .finish()
+            .emit();
+        }
+        self.out.fields.insert(expr.id, index);
+        def.ty
+    }
+
+    fn builtin_method(&mut self, ty: TyId, name: &str) -> Option<(BuiltinMethod, Vec<TyId>, TyId)> {
+        let tys = &self.out.tys;
+        let method = BuiltinMethod::ALL.into_iter().find(|x| x.name() == name)?;
+        match (method, tys.kind(tys.shallow(ty))) {
+            (BuiltinMethod::ToString, _) if tys.is_numeric(ty) || tys.is_string(ty) => {
+                Some((method, Vec::new(), TyId::STRING))
+            }
+            (BuiltinMethod::Len, TyKind::Array(_)) => Some((method, Vec::new(), TyId::I32)),
+            (BuiltinMethod::Len, _) if tys.is_string(ty) => Some((method, Vec::new(), TyId::I32)),
+            (BuiltinMethod::Push, TyKind::Array(elem)) => Some((method, vec![*elem], TyId::VOID)),
+            _ => None,
+        }
+    }
+
+    fn check_call(&mut self, expr: &Expr<'src>, callee: &Expr<'src>, args: &[Expr<'src>]) -> TyId {
+        if let ExprKind::Field { base, field } = &callee.kind {
+            let base_ty = self.check_expr(base);
+            if let TyKind::Obj(obj) = self.kind(base_ty)
+                && let Some(method) = self.res.method(obj, field.name)
+            {
+                let sig = self.out.items.methods[&method].clone();
+                if !sig.public && method.module != self.module {
+                    self.error(
+                        format!(
+                            "method `{}` of type `{}` is private",
+                            field.name,
+                            self.name(base_ty)
+                        ),
+                        field.span,
+                    )
+                    .with_secondary(sig.name.span, "method declared here")
+                    .finish()
+                    .emit();
+                }
+                self.out.methods.insert(expr.id, Method::User(method));
+                let what = format!("method `{}`", field.name);
+                self.check_args(
+                    expr,
+                    args,
+                    &sig.params,
+                    &sig.param_spans,
+                    Some(sig.name.span),
+                    &what,
+                );
+                return sig.ret;
+            }
+            if let Some((method, params, ret)) = self.builtin_method(base_ty, field.name) {
+                self.out.methods.insert(expr.id, Method::Builtin(method));
+                let what = format!("method `{}`", field.name);
+                self.check_args(expr, args, &params, &[], None, &what);
+                return ret;
+            }
+            let is_field = match self.kind(base_ty) {
+                TyKind::Obj(obj) => self.out.items.field(obj, field.name).is_some(),
+                _ => false,
+            };
+            if !is_field {
+                if !self.out.tys.is_unknown(base_ty) {
+                    let mut diag = self.error(
+                        format!(
+                            "no method `{}` on type `{}`",
+                            field.name,
+                            self.name(base_ty)
+                        ),
+                        field.span,
+                    );
+                    if let TyKind::Obj(obj) = self.kind(base_ty)
+                        && let Some(methods) = self.res.methods.get(&obj)
+                        && let Some(suggestion) = suggest(field.name, methods.keys().copied())
+                    {
+                        diag = diag.with_note(format!("help: did you mean `{suggestion}`?"));
+                    }
+                    diag.finish().emit();
+                }
+                self.check_args(expr, args, &[], &[], None, "");
+                return TyId::ERROR;
+            }
+        }
+
+        let callee_ty = self.check_expr(callee);
+        // Calling a type name without field names is the same as naming it
+        if let Some(Res::Item(item)) = self.res.names.get(&callee.id)
+            && self.graph.item(*item).kind == ItemKind::Obj
+        {
+            if let Some(first) = args.first() {
+                self.error(
+                    format!(
+                        "the fields of `{}` must be initialized by name",
+                        self.name(callee_ty)
+                    ),
+                    first.span,
+                )
+                .with_note(format!(
+                    "help: write them as `field: value`, like `{}(field: ...)`",
+                    self.name(callee_ty)
+                ))
+                .finish()
+                .emit();
+                self.check_args(expr, args, &[], &[], None, "");
+            }
+            return callee_ty;
+        }
+        match self.kind(callee_ty) {
+            TyKind::FnItem(item) => {
+                let sig = self.out.items.funcs[&item].clone();
+                let what = format!("function `{}`", sig.name.name);
+                self.check_args(
+                    expr,
+                    args,
+                    &sig.params,
+                    &sig.param_spans,
+                    Some(sig.name.span),
+                    &what,
+                );
+                sig.ret
+            }
+            TyKind::Builtin(builtin) => {
+                let params = match builtin {
+                    Builtin::Print => vec![TyId::STR],
+                    Builtin::Println => Vec::new(),
+                    Builtin::Drop => vec![TyId::UNKNOWN],
+                };
+                let what = format!("function `{}`", builtin.name());
+                self.check_args(expr, args, &params, &[], None, &what);
+                TyId::VOID
+            }
+            TyKind::Obj(obj) if let Some(method) = self.res.method(obj, CALL_OPERATOR) => {
+                let sig = self.out.items.methods[&method].clone();
+                if !sig.public && method.module != self.module {
+                    self.error(
+                        format!(
+                            "the call operator of type `{}` is private",
+                            self.name(callee_ty)
+                        ),
+                        callee.span,
+                    )
+                    .with_secondary(sig.name.span, "call operator declared here")
+                    .finish()
+                    .emit();
+                }
+                self.out.methods.insert(expr.id, Method::User(method));
+                self.out.callable.insert(expr.id);
+                let what = format!("the call operator of `{}`", self.name(callee_ty));
+                self.check_args(
+                    expr,
+                    args,
+                    &sig.params,
+                    &sig.param_spans,
+                    Some(sig.name.span),
+                    &what,
+                );
+                sig.ret
+            }
+            TyKind::Error | TyKind::Unknown | TyKind::Infer(_) => {
+                self.check_args(expr, args, &[], &[], None, "");
+                TyId::ERROR
+            }
+            kind => {
+                let mut diag = self.error(
+                    format!("expected a function, found `{}`", self.name(callee_ty)),
+                    callee.span,
+                );
+                if let TyKind::Obj(obj) = kind {
+                    let name = self.graph.item(obj).name;
+                    diag = diag
+                        .with_secondary(name.span, format!("`{}` has no call operator", name.name))
+                        .with_note(format!(
+                            "help: values of `{}` can be called by giving it a method named `{CALL_OPERATOR}`",
+                            name.name
+                        ));
+                }
+                diag.finish().emit();
+                self.check_args(expr, args, &[], &[], None, "");
+                TyId::ERROR
+            }
+        }
+    }
+
+    /// Checks the arguments of a call against the parameters of the called function. An empty
+    /// `what` means the callee is already known to be wrong, so the arguments are only checked
+    /// on their own.
+    fn check_args(
+        &mut self,
+        call: &Expr<'src>,
+        args: &[Expr<'src>],
+        params: &[TyId],
+        param_spans: &[Span<'src>],
+        decl: Option<Span<'src>>,
+        what: &str,
+    ) {
+        if what.is_empty() || args.len() != params.len() {
+            for arg in args {
+                self.check_expr(arg);
+            }
+            if what.is_empty() {
+                return;
+            }
+            let mut diag = self.error(
+                format!(
+                    "{what} takes {} but {} supplied",
+                    plural(params.len(), "argument"),
+                    if args.len() == 1 {
+                        "1 was".to_string()
+                    } else {
+                        format!("{} were", args.len())
+                    }
+                ),
+                call.span,
+            );
+            if let Some(decl) = decl {
+                diag = diag.with_secondary(decl, "defined here");
+            }
+            diag.finish().emit();
+            return;
+        }
+        for (index, (arg, param)) in args.iter().zip(params).enumerate() {
+            self.coerce(arg, *param, param_spans.get(index).copied());
+        }
+    }
+
+    /// Checks a binary operation. Operators on numbers, `+` and comparisons on strings, and `==`
+    /// and `!=` on booleans are built in, and object types overload them with methods named like
+    /// [`BinOp::method`].
+    fn check_binary(
+        &mut self,
+        expr: &Expr<'src>,
+        op: BinOp,
+        lhs: &Expr<'src>,
+        rhs: &Expr<'src>,
+        expected: Option<TyId>,
+    ) -> TyId {
+        if matches!(op, BinOp::And | BinOp::Or) {
+            self.coerce(lhs, TyId::BOOL, None);
+            self.coerce(rhs, TyId::BOOL, None);
+            return TyId::BOOL;
+        }
+        let expected = expected.filter(|x| self.out.tys.is_numeric(*x));
+        let mut left = self.check_expr_with(lhs, expected);
+        if let TyKind::Obj(obj) = self.kind(left)
+            && let Some(method) = op.method()
+        {
+            return self.check_operator_method(expr, op, method, obj, lhs, rhs);
+        }
+        let mut right = self.check_expr_with(rhs, Some(left));
+        // An integer literal adopts the type of the other operand (`1 + half` with `f32 half`)
+        if left != right && self.out.tys.is_numeric(left) && self.out.tys.is_numeric(right) {
+            if matches!(lhs.kind, ExprKind::Int(_)) {
+                left = right;
+                self.out.exprs.insert(lhs.id, left);
+            } else if matches!(rhs.kind, ExprKind::Int(_)) {
+                right = left;
+                self.out.exprs.insert(rhs.id, right);
+            }
+        }
+        let left = self.out.tys.shallow(left);
+        let right = self.out.tys.shallow(right);
+        let tys = &self.out.tys;
+        let result = if op.is_comparison() { TyId::BOOL } else { left };
+        if tys.is_unknown(left) || tys.is_unknown(right) {
+            return if op.is_comparison() {
+                TyId::BOOL
+            } else {
+                TyId::ERROR
+            };
+        }
+        let strings = tys.is_string(left) && tys.is_string(right);
+        match op {
+            BinOp::Add if strings => return TyId::STRING,
+            BinOp::Eq | BinOp::Ne if strings || (left == right && left == TyId::BOOL) => {
+                return TyId::BOOL;
+            }
+            _ if left == right && tys.is_numeric(left) => return result,
+            _ => {}
+        }
+        Diag::error(lhs.span.source())
+            .with_message(format!(
+                "cannot apply `{}` to `{}` and `{}`",
+                op.symbol(),
+                self.name(left),
+                self.name(right)
+            ))
+            .with_label(Label::primary((), lhs.span).with_message(format!("`{}`", self.name(left))))
+            .with_label(
+                Label::primary((), rhs.span).with_message(format!("`{}`", self.name(right))),
+            )
+            .finish()
+            .emit();
+        TyId::ERROR
+    }
+
+    /// Checks a binary operation whose left hand side is of the object type `obj`, which calls
+    /// the method overloading the operator, named `name`.
+    fn check_operator_method(
+        &mut self,
+        expr: &Expr<'src>,
+        op: BinOp,
+        name: &str,
+        obj: ItemId,
+        lhs: &Expr<'src>,
+        rhs: &Expr<'src>,
+    ) -> TyId {
+        let left = self.out.tys.intern(TyKind::Obj(obj));
+        let Some(method) = self.res.method(obj, name) else {
+            let right = self.check_expr(rhs);
+            self.error(
+                format!(
+                    "cannot apply `{}` to `{}` and `{}`",
+                    op.symbol(),
+                    self.name(left),
+                    self.name(right)
+                ),
+                lhs.span.to(rhs.span),
+            )
+            .with_secondary(
+                self.graph.item(obj).name.span,
+                format!("`{}` doesn't overload `{}`", self.name(left), op.symbol()),
+            )
+            .with_note(format!(
+                "help: `{}` can be overloaded by giving `{}` a method named `{}`",
+                op.symbol(),
+                self.name(left),
+                name
+            ))
+            .finish()
+            .emit();
+            return TyId::ERROR;
+        };
+        let sig = self.out.items.methods[&method].clone();
+        if !sig.public && method.module != self.module {
+            self.error(
+                format!(
+                    "the `{}` operator of type `{}` is private",
+                    op.symbol(),
+                    self.name(left)
+                ),
+                expr.span,
+            )
+            .with_secondary(sig.name.span, "operator declared here")
+            .finish()
+            .emit();
+        }
+        self.out.methods.insert(expr.id, Method::User(method));
+        let what = format!("method `{name}`");
+        self.check_args(
+            expr,
+            slice::from_ref(rhs),
+            &sig.params,
+            &sig.param_spans,
+            Some(sig.name.span),
+            &what,
+        );
+        if !op.is_comparison() {
+            return sig.ret;
+        }
+        if !self.out.tys.unify(sig.ret, TyId::BOOL) {
+            self.error(
+                format!("`{name}` has to return `bool`"),
+                sig.ret_span.unwrap_or(sig.name.span),
+            )
+            .with_secondary(expr.span, format!("`{name}` is used by this comparison"))
+            .finish()
+            .emit();
+        }
+        TyId::BOOL
+    }
+}
+
+#[cfg(test)]
+fn check_source(text: &str) -> usize {
+    let sources = [crate::Source::new(text, "main.scp")];
+    let modules = crate::project::test_modules(&sources);
+    let graph = ModuleGraph::build(&modules);
+    let res = crate::resolve::resolve(&modules, &graph);
+    let errors = crate::diag::error_count();
+    check(&modules, &graph, &res);
+    crate::diag::error_count() - errors
+}
+
+#[test]
+fn check_test() {
+    let errors = check_source(
+        r"identifier app.main
+extern func void print(str msg)
+func i32 twice(i32 x) is
+    return x * 2
+end
+func void start() is
+    i32 val = twice(4)
+    print('val: ' + val.to-string())
+    counter c = counter
+    c.bump(val)
+    c.count++
+    string[] names = ['a', 'b']
+    names.push('c')
+end
+obj counter is
+    count: i32 = 0,
+end
+impl counter is
+    func bump(i32 by) is
+        self.count = self.count + by
+    end
+end",
+    );
+    assert_eq!(errors, 0);
+}
+
+#[test]
+fn check_error_test() {
+    let errors = check_source(
+        r"identifier app.main
+func i32 twice(i32 x) is
+    return 'x'
+end
+func void start() is
+    i32 val = twice(4, 5)
+    str text = 'a' - 'b'
+    val = 'text'
+    twice('4')
+    val.missing()
+    return 1
+end",
+    );
+    // Return type, arity, operands, assignment, argument, missing method and void return
+    assert_eq!(errors, 7);
+}
+
+#[test]
+fn infer_test() {
+    let text = r"identifier app.main
+obj bag is
+    items: array = [],
+    scores: f32[] = [],
+end
+impl bag is
+    func fill() is
+        self.items.push('a')
+        self.scores.push(1)
+    end
+end
+func void start() is
+    let b = bag
+    b.fill()
+    let names = []
+    names.push('x')
+    names.push(2)
+    f32 half = 1 / 2
+    let total = half + 1
+    let empty = []
+end";
+    let sources = [crate::Source::new(text, "main.scp")];
+    let modules = crate::project::test_modules(&sources);
+    let graph = ModuleGraph::build(&modules);
+    let res = crate::resolve::resolve(&modules, &graph);
+    let errors = crate::diag::error_count();
+    let typeck = check(&modules, &graph, &res);
+    // Pushing an `i32` onto `names` and the element type of `empty`
+    assert_eq!(crate::diag::error_count(), errors + 2);
+
+    let hover = |name: &str| {
+        let offset = text.find(&format!("let {name}")).unwrap() + 4;
+        typeck
+            .hover(&graph, &res, &sources[0], offset as u32)
+            .unwrap()
+    };
+    assert_eq!(hover("b"), "b: bag");
+    assert_eq!(hover("names"), "names: str[]");
+    assert_eq!(hover("total"), "total: f32");
+    assert_eq!(hover("empty"), "empty: array");
+    // Hovering a use of a binding works too
+    let offset = text.find("b.fill").unwrap() as u32;
+    assert_eq!(
+        typeck.hover(&graph, &res, &sources[0], offset).unwrap(),
+        "b: bag"
+    );
+
+    let bag = graph.lookup(ModuleId(0), "bag").unwrap();
+    let fields: Vec<_> = typeck.items.objs[&bag]
+        .iter()
+        .map(|x| typeck.tys.name(x.ty, &graph))
+        .collect();
+    assert_eq!(fields, ["str[]", "f32[]"]);
+}
+
+#[test]
+fn constructor_test() {
+    let errors = check_source(
+        r"identifier app.main
+obj point is
+    x: i32,
+    y: i32,
+    label: str = 'p',
+end
+func void start() is
+    point a = point(x: 1, y: 2)
+    point b = point(y: 2, x: 1, label: 'b')
+    point c = point
+    point d = point(x: 1)
+    point e = point(x: 1, x: 2, y: 3)
+    point f = point(x: 1, y: 2, z: 3)
+    point g = point(1, 2)
+end",
+    );
+    // Missing both fields, missing `y`, `x` twice, no field `z` and positional fields, which
+    // are also missing both fields
+    assert_eq!(errors, 6);
+}
+
+#[test]
+fn call_operator_test() {
+    let text = r"identifier app.main
+obj adder is
+    base: i32 = 0,
+end
+impl adder is
+    func i32 op-call(i32 by) is
+        return self.base + by
+    end
+end
+obj holder is
+    add: adder = adder,
+end
+func void start() is
+    adder a = adder
+    i32 three = a(3)
+    holder h = holder
+    i32 four = h.add(4)
+    holder nope = holder
+    nope()
+    a('x')
+end";
+    let sources = [crate::Source::new(text, "main.scp")];
+    let modules = crate::project::test_modules(&sources);
+    let graph = ModuleGraph::build(&modules);
+    let res = crate::resolve::resolve(&modules, &graph);
+    let errors = crate::diag::error_count();
+    let typeck = check(&modules, &graph, &res);
+    // Calling `holder`, which has no call operator, and the `str` argument
+    assert_eq!(crate::diag::error_count(), errors + 2);
+    assert_eq!(typeck.callable.len(), 3);
+}
+
+#[test]
+fn operator_method_test() {
+    let text = r"identifier app.main
+obj vec is
+    x: i32 = 0,
+end
+impl vec is
+    func vec op-add(vec other) is
+        return vec(x: self.x + other.x)
+    end
+    func vec op-mul(i32 by) is
+        return vec(x: self.x * by)
+    end
+end
+func void start() is
+    vec a = vec
+    vec b = a + vec
+    vec c = b * 2
+    vec d = a - b
+    vec e = a + 1
+    i32 n = 1 + 2 * 3
+end";
+    let sources = [crate::Source::new(text, "main.scp")];
+    let modules = crate::project::test_modules(&sources);
+    let graph = ModuleGraph::build(&modules);
+    let res = crate::resolve::resolve(&modules, &graph);
+    let errors = crate::diag::error_count();
+    let typeck = check(&modules, &graph, &res);
+    // `-` isn't overloaded and `op-add` takes a `vec`
+    assert_eq!(crate::diag::error_count(), errors + 2);
+    assert_eq!(typeck.methods.len(), 3);
+}
+
+#[test]
+fn control_flow_test() {
+    assert_eq!(
+        check_source(
+            r"identifier app.main
+func i32 sign(i32 x) is
+    if x < 0 is
+        return -1
+    else if x == 0 is
+        return 0
+    else
+        return 1
+    end
+end
+func i32 first(i32[] xs) is
+    for x in xs is
+        if x > 2 and not (x >= 9) is
+            return x
+        end
+    end
+    while true is
+    end
+end
+func void start() is
+    bool done = 'a' == 'b' or 1.5 <= 2
+    while not done is
+        done = true
+    end
+end",
+        ),
+        0
+    );
+    let errors = check_source(
+        r"identifier app.main
+func i32 maybe(i32 x) is
+    if x != 0 is
+        return x
+    end
+end
+func void start() is
+    if 1 is
+    end
+    for c in 'text' is
+    end
+    bool b = 1 and true
+    bool s = 'a' < 'b'
+    bool m = true == 1
+end",
+    );
+    // Missing return, `i32` condition, looping over `str`, `i32` operand of `and`, `<` on
+    // strings and comparing `bool` to `i32`
+    assert_eq!(errors, 6);
+}
+
+#[test]
+fn bulk_test() {
+    assert_eq!(
+        check_source(
+            r"identifier app.main
+func void show(str label, i32 value) is
+end
+func void start() is
+    string[] lines = ['a', 'b']
+    bulk print(lines)
+    bulk show('n', [1, 2])
+    bulk print(['c'])
+end",
+        ),
+        0
+    );
+    let errors = check_source(
+        r"identifier app.main
+func void show(str label, i32 value) is
+end
+func void start() is
+    bulk print('a')
+    bulk show('n', ['x'])
+    bulk println()
+    bulk 1
+end",
+    );
+    // Not an array, `str` items for an `i32` parameter, no argument and not a call
+    assert_eq!(errors, 4);
+}
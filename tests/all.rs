@@ -1,14 +1,353 @@
-use std::fs;
+use std::{env, fs, path::Path};
 
-use escoop::{Source, diag, lexer::Lexer};
+use escoop::{
+    Source, borrow, diag,
+    emit::{self, HirInfo},
+    lexer::Lexer,
+    mir,
+    modules::{ModuleGraph, ModuleId},
+    ownership,
+    parser::Parser,
+    project::Project,
+    resolve,
+    ty::check,
+};
 
 #[test]
 fn non_ascii() {
     let text = fs::read_to_string("tests/non_ascii.txt").unwrap();
-    let src = Source::new("tests/non_ascii.txt", text.as_str());
+    let src = Source::new(text.as_str(), "tests/non_ascii.txt");
+    let errors = diag::error_count();
     let lexer = Lexer::new(&src);
     lexer.for_each(|x| {
         x.span().apply();
     }); // Exhaust all tokens, if span gets out of sync, we'll know
-    assert!(!diag::error());
+    assert_eq!(diag::error_count(), errors);
+}
+
+/// Compares a dump against the snapshot in `tests/emit`, ignoring line ending differences.
+fn assert_snapshot(dump: Vec<u8>, snapshot: &str) {
+    let expected = fs::read_to_string(format!("tests/emit/{snapshot}")).unwrap();
+    let dump = String::from_utf8(dump).unwrap();
+    assert!(
+        dump.lines().eq(expected.lines()),
+        "`{snapshot}` does not match:\n{dump}"
+    );
+}
+
+#[test]
+fn emit_snapshots() {
+    let path = "escoop-tests/hello-world-simple/entrypoint.scp";
+    let text = fs::read_to_string(path).unwrap();
+    let src = Source::new(text.as_str(), path);
+
+    let mut tokens = Vec::new();
+    emit::tokens(&src, &mut tokens).unwrap();
+    assert_snapshot(tokens, "hello-world-simple.tokens");
+
+    let errors = diag::error_count();
+    let decls = Parser::new(&src).parse();
+    assert_eq!(diag::error_count(), errors);
+    let mut ast = Vec::new();
+    emit::ast(&decls, &mut ast).unwrap();
+    assert_snapshot(ast, "hello-world-simple.ast");
+
+    let project = Project::single_file(path).unwrap();
+    let sources = project.sources();
+    let modules = project.parse(&sources);
+    let graph = ModuleGraph::build(&modules);
+    let res = resolve::resolve(&modules, &graph);
+    let typeck = check::check(&modules, &graph, &res);
+    assert_eq!(diag::error_count(), errors);
+    let mut hir = Vec::new();
+    let borrows = borrow::infer(&modules, &graph, &res, &typeck);
+    let ownership = ownership::analyze(&modules, &graph, &res, &typeck, &borrows);
+    let info = HirInfo {
+        graph: &graph,
+        res: &res,
+        typeck: &typeck,
+        borrows: &borrows,
+        ownership: &ownership,
+    };
+    emit::hir(&modules[0].decls, info, &mut hir).unwrap();
+    assert_snapshot(hir, "hello-world-simple.hir");
+
+    let program = mir::lower::lower(&modules, info, ModuleId(0)).unwrap();
+    mir::validate::validate(&program).unwrap();
+    let mut ir = Vec::new();
+    mir::pretty(&program, &mut ir).unwrap();
+    assert_snapshot(ir, "hello-world-simple.ir");
+}
+
+#[test]
+fn emit_after_subcommand() {
+    let path = env::temp_dir().join(format!("escoop-emit-{}.ir", std::process::id()));
+    let output = std::process::Command::new(env!("CARGO_BIN_EXE_esci"))
+        .args([
+            "run",
+            "escoop-tests/hello-world-simple/entrypoint.scp",
+            "--emit",
+        ])
+        .arg(format!("ir={}", path.display()))
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    assert_eq!(output.stdout, b"Hello, world!val: 0val: 1");
+    let ir = fs::read(&path).unwrap();
+    fs::remove_file(&path).unwrap();
+    assert_snapshot(ir, "hello-world-simple.ir");
+}
+
+#[test]
+fn project_modules() {
+    let project = Project::load(Path::new("escoop-tests/project/Escoop.toml")).unwrap();
+    assert_eq!(project.entry.as_deref(), Some("project.entrypoint"));
+    let sources = project.sources();
+    let errors = diag::error_count();
+    let modules = project.parse(&sources);
+    let graph = ModuleGraph::build(&modules);
+    assert_eq!(diag::error_count(), errors);
+    assert!(graph.lookup(ModuleId(0), "counter").is_some());
+    let names: Vec<_> = modules.iter().map(|x| x.name.as_str()).collect();
+    assert_eq!(
+        names,
+        ["project.entrypoint", "project.util.text", "project-lib.lib"]
+    );
+}
+
+#[test]
+fn project_identifier_mismatch() {
+    let dir = env::temp_dir().join(format!("escoop-identifier-mismatch-{}", std::process::id()));
+    fs::create_dir_all(dir.join("gfx")).unwrap();
+    fs::write(
+        dir.join("Escoop.toml"),
+        r#"[project]
+name = "app"
+version = "1.0.0"
+"#,
+    )
+    .unwrap();
+    fs::write(dir.join("entrypoint.scp"), "identifier app.entrypoint\n").unwrap();
+    fs::write(dir.join("gfx/printer.scp"), "identifier app.printer\n").unwrap();
+
+    let project = Project::load(&dir.join("Escoop.toml")).unwrap();
+    let sources = project.sources();
+    let errors = diag::error_count();
+    let modules = project.parse(&sources);
+    fs::remove_dir_all(&dir).unwrap();
+    assert_eq!(diag::error_count(), errors + 1);
+    assert_eq!(modules[1].name, "app.gfx.printer");
+}
+
+#[test]
+fn run_hello_world() {
+    for args in [
+        ["--engine", "vm"],
+        ["--engine", "interp"],
+        ["-O3", "--engine=vm"],
+        ["-W", "rc-fallback"],
+    ] {
+        let output = std::process::Command::new(env!("CARGO_BIN_EXE_esci"))
+            .args(["run", "escoop-tests/hello-world-simple/entrypoint.scp"])
+            .args(args)
+            .output()
+            .unwrap();
+        assert!(output.status.success(), "{args:?} failed");
+        assert_eq!(output.stdout, b"Hello, world!val: 0val: 1", "{args:?}");
+    }
+}
+
+#[test]
+fn ignored_optimization() {
+    let output = std::process::Command::new(env!("CARGO_BIN_EXE_esci"))
+        .args(["run", "escoop-tests/hello-world-simple/entrypoint.scp"])
+        .args(["-O2", "--engine", "interp"])
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(
+        stdout.contains("which the interpreter doesn't use"),
+        "{stdout}"
+    );
+    assert!(stdout.ends_with("Hello, world!val: 0val: 1"), "{stdout}");
+}
+
+#[test]
+fn run_deep_recursion() {
+    let path = env::temp_dir().join(format!("escoop-recursion-{}.scp", std::process::id()));
+    fs::write(
+        &path,
+        r"identifier app.main
+func i32 sum(i32 n) is
+    return n + sum(n - 1)
+end
+func void start() is
+    print(sum(10).to-string())
+end",
+    )
+    .unwrap();
+    for engine in ["interp", "vm"] {
+        let output = std::process::Command::new(env!("CARGO_BIN_EXE_esci"))
+            .args(["run", "--engine", engine])
+            .arg(&path)
+            .output()
+            .unwrap();
+        assert!(!output.status.success());
+        let stdout = String::from_utf8(output.stdout).unwrap();
+        assert!(stdout.contains("stack overflow"), "{stdout}");
+        assert!(stdout.contains("called from here ("), "{stdout}");
+        assert_eq!(stdout.matches("called from here").count(), 2, "{stdout}");
+    }
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn build_and_run_bytecode() {
+    let output = env::temp_dir().join(format!("hello-world-{}.scpc", std::process::id()));
+    let esci = env!("CARGO_BIN_EXE_esci");
+    let built = std::process::Command::new(esci)
+        .args([
+            "build",
+            "escoop-tests/hello-world-simple/entrypoint.scp",
+            "-o",
+        ])
+        .arg(&output)
+        .status()
+        .unwrap();
+    assert!(built.success());
+    let run = std::process::Command::new(esci)
+        .arg("run")
+        .arg(&output)
+        .output()
+        .unwrap();
+    fs::remove_file(&output).unwrap();
+    assert!(run.status.success());
+    assert_eq!(run.stdout, b"Hello, world!val: 0val: 1");
+}
+
+#[test]
+fn run_bytecode_of_changed_source() {
+    let dir = env::temp_dir().join(format!("escoop-changed-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let source = dir.join("main.scp");
+    fs::write(
+        &source,
+        r"identifier app.main
+func void start() is
+    i32 zero = 0
+    print((1 / zero).to-string())
+end",
+    )
+    .unwrap();
+    let esci = env!("CARGO_BIN_EXE_esci");
+    let built = std::process::Command::new(esci)
+        .arg("build")
+        .arg(&source)
+        .arg("-o")
+        .arg(dir.join("main.scpc"))
+        .status()
+        .unwrap();
+    assert!(built.success());
+    // Shorter than the spans of the program
+    fs::write(&source, "identifier app.main\n").unwrap();
+    let run = std::process::Command::new(esci)
+        .arg("run")
+        .arg(dir.join("main.scpc"))
+        .output()
+        .unwrap();
+    fs::remove_dir_all(&dir).unwrap();
+    assert!(!run.status.success());
+    let stderr = String::from_utf8(run.stderr).unwrap();
+    assert!(stderr.contains("attempt to divide by zero"), "{stderr}");
+    assert!(
+        stderr.contains("changed since the program was compiled"),
+        "{stderr}"
+    );
+}
+
+#[test]
+fn build_c_and_compile() {
+    let dir = env::temp_dir().join(format!("escoop-build-c-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let built = std::process::Command::new(env!("CARGO_BIN_EXE_esci"))
+        .args([
+            "build",
+            "escoop-tests/hello-world-simple/entrypoint.scp",
+            "--target",
+            "c",
+            "-o",
+        ])
+        .arg(dir.join("hello.c"))
+        .status()
+        .unwrap();
+    assert!(built.success());
+    assert!(dir.join("escoop.h").exists());
+    // The output is only compiled where a C compiler is installed
+    if std::process::Command::new("cc")
+        .arg("--version")
+        .output()
+        .is_err()
+    {
+        fs::remove_dir_all(&dir).unwrap();
+        return;
+    }
+    let compiled = std::process::Command::new("cc")
+        .args(["-std=c99", "-o"])
+        .arg(dir.join("hello"))
+        .arg(dir.join("hello.c"))
+        .status()
+        .unwrap();
+    assert!(compiled.success());
+    let run = std::process::Command::new(dir.join("hello"))
+        .output()
+        .unwrap();
+    fs::remove_dir_all(&dir).unwrap();
+    assert!(run.status.success());
+    assert_eq!(run.stdout, b"Hello, world!val: 0val: 1");
+}
+
+#[test]
+fn build_wat() {
+    let path = env::temp_dir().join(format!("escoop-build-wat-{}.wat", std::process::id()));
+    let built = std::process::Command::new(env!("CARGO_BIN_EXE_esci"))
+        .args([
+            "build",
+            "escoop-tests/hello-world-simple/entrypoint.scp",
+            "--target",
+            "wat",
+            "-o",
+        ])
+        .arg(&path)
+        .status()
+        .unwrap();
+    assert!(built.success());
+    let module = fs::read_to_string(&path).unwrap();
+    fs::remove_file(&path).unwrap();
+    assert!(module.starts_with("(module\n"));
+    assert!(module.contains("(export \"start\" (func $hello-world-simple.entrypoint/start))"));
+}
+
+#[test]
+fn build_llvm_ir() {
+    let dir = env::temp_dir().join(format!("escoop-build-llvm-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let built = std::process::Command::new(env!("CARGO_BIN_EXE_esci"))
+        .args([
+            "build",
+            "escoop-tests/hello-world-simple/entrypoint.scp",
+            "--target",
+            "llvm-ir",
+            "-o",
+        ])
+        .arg(dir.join("hello.ll"))
+        .status()
+        .unwrap();
+    assert!(built.success());
+    assert!(dir.join("escoop.h").exists());
+    assert!(dir.join("escoop.c").exists());
+    let module = fs::read_to_string(dir.join("hello.ll")).unwrap();
+    fs::remove_dir_all(&dir).unwrap();
+    assert!(module.contains("\ndefine i32 @main() {\n"));
+    assert!(module.contains("!DIFile(filename: \"entrypoint.scp\""));
 }